@@ -1,19 +1,24 @@
 use clap::Parser;
 use std::{
-    io::Read,
+    io::{Read, Write},
     path::{Path, PathBuf},
     time::Duration,
 };
 
 /// FFMML compiler.
 #[derive(Debug, Parser)]
-#[command(version)]
+#[command(version, args_conflicts_with_subcommands = true)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Input file path.
     #[clap(default_value = "-")]
     input_file: PathBuf,
 
-    /// Output WAV file path.
+    /// Output file path.
+    ///
+    /// If the extension is `.mid`, `.nsf` or `.vgm`, the music is exported as MIDI, NSF or VGM instead of WAV.
     #[clap(short, long)]
     output_file: Option<PathBuf>,
 
@@ -24,6 +29,109 @@ struct Args {
     /// Max duration (seconds).
     #[clap(long, default_value_t = 60)]
     duration: u16,
+
+    /// Renders pulse and triangle waves with band-limited steps to reduce aliasing (WAV only).
+    #[clap(long)]
+    band_limited: bool,
+
+    /// Mixes channels by the NES APU non-linear mixer and output filters (WAV only).
+    #[clap(long)]
+    nes_mixer: bool,
+
+    /// Renders audio by the cycle-accurate 2A03 APU emulator (WAV only).
+    #[clap(long, conflicts_with_all = ["band_limited", "nes_mixer"])]
+    apu: bool,
+
+    /// Writes each channel into its own WAV file (`<OUTPUT>_<CHANNEL>.wav`) instead of mixing them (WAV only).
+    ///
+    /// The stems are not mixed, so they are always rendered as the linear mixer does (without `--nes-mixer` and `--stereo`).
+    /// The state shared by the channels of a sound chip (the N163 multiplexing, and the Sunsoft 5B noise and envelope)
+    /// is reflected in each stem.
+    #[clap(long, conflicts_with_all = ["nes_mixer", "apu"])]
+    stems: bool,
+
+    /// Renders stereo audio with the default panning of the layout (WAV only).
+    ///
+    /// `nes` pans the pulse wave channels to the left and the triangle wave, noise and DPCM channels to the right
+    /// as the common "NES stereo" mod does.
+    #[clap(long, value_enum, conflicts_with_all = ["apu", "stems"])]
+    stereo: Option<StereoLayout>,
+
+    /// Number of times to jump back to the loop points (`L`) before the music ends (WAV and MIDI).
+    ///
+    /// Without this, looping music is rendered until `--duration` in WAV, and loops once in MIDI.
+    /// NSF and VGM files keep the loop points, so this is rejected for them.
+    #[clap(long)]
+    loops: Option<usize>,
+
+    /// Fade-out length after the final loop (seconds, WAV only).
+    #[clap(long, default_value_t = 0, requires = "loops")]
+    fade: u16,
+}
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+enum StereoLayout {
+    Center,
+    Nes,
+}
+
+impl From<StereoLayout> for ffmml::StereoLayout {
+    fn from(layout: StereoLayout) -> Self {
+        match layout {
+            StereoLayout::Center => Self::Center,
+            StereoLayout::Nes => Self::Nes,
+        }
+    }
+}
+
+#[derive(Debug, clap::Subcommand)]
+enum Command {
+    /// Converts a Standard MIDI File into an MML script.
+    ImportMidi(ImportMidiArgs),
+}
+
+#[derive(Debug, clap::Args)]
+struct ImportMidiArgs {
+    /// Input MIDI file path.
+    input_file: PathBuf,
+
+    /// Output MML file path (default: STDOUT).
+    #[clap(short, long)]
+    output_file: Option<PathBuf>,
+}
+
+impl ImportMidiArgs {
+    fn run(&self) -> Result<(), String> {
+        let smf = std::fs::read(&self.input_file).map_err(|e| {
+            format!(
+                "failed to read MIDI file from {} ({e})",
+                self.input_file.to_string_lossy()
+            )
+        })?;
+        let imported = ffmml::midi::import(&smf).map_err(|e| {
+            format!(
+                "failed to convert {} into MML ({e})",
+                self.input_file.to_string_lossy()
+            )
+        })?;
+        for warning in &imported.warnings {
+            eprintln!("Warning: {warning}");
+        }
+
+        match &self.output_file {
+            Some(path) if path != Path::new("-") => {
+                std::fs::write(path, &imported.script).map_err(|e| {
+                    format!(
+                        "failed to write MML text to {} ({e})",
+                        path.to_string_lossy()
+                    )
+                })
+            }
+            _ => std::io::stdout()
+                .write_all(imported.script.as_bytes())
+                .map_err(|e| format!("failed to write MML text to STDOUT ({e})")),
+        }
+    }
 }
 
 impl Args {
@@ -50,6 +158,15 @@ impl Args {
         Ok(mml)
     }
 
+    fn include_resolver(&self) -> ffmml::FileResolver {
+        let dir = if self.input_file == Path::new("-") {
+            Path::new(".")
+        } else {
+            self.input_file.parent().unwrap_or(Path::new("."))
+        };
+        ffmml::FileResolver::new(dir)
+    }
+
     fn input_file_path(&self) -> PathBuf {
         if self.input_file == Path::new("-") {
             PathBuf::from("<STDIN>")
@@ -73,6 +190,36 @@ impl Args {
         }
     }
 
+    fn has_output_extension(&self, extension: &str) -> bool {
+        self.output_file_path()
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case(extension))
+    }
+
+    /// Returns an error if `--loops` or `--fade` is given for a format that does not support it.
+    fn check_loop_options(&self, format: &str) -> Result<(), String> {
+        let keeps_loop_points = matches!(format, "NSF" | "VGM");
+        if keeps_loop_points && self.loops.is_some() {
+            return Err(format!(
+                "--loops cannot be used for {format} files (the loop points are kept in the file)"
+            ));
+        }
+        if format != "WAV" && self.fade != 0 {
+            return Err(format!("--fade cannot be used for {format} files"));
+        }
+        Ok(())
+    }
+
+    fn stem_file_path(&self, channel: ffmml::ChannelName) -> Result<PathBuf, String> {
+        let path = self.output_file_path();
+        if path == Path::new("<STDOUT>") {
+            return Err("--stems cannot write WAV files to STDOUT".to_owned());
+        }
+        let mut name = path.file_stem().unwrap_or_default().to_os_string();
+        name.push(format!("_{channel:?}.wav"));
+        Ok(path.with_file_name(name))
+    }
+
     fn output_file_path(&self) -> PathBuf {
         if let Some(path) = &self.output_file {
             if path == Path::new("-") {
@@ -93,23 +240,126 @@ impl Args {
 fn main() {
     let args = Args::parse();
 
+    if let Some(Command::ImportMidi(args)) = &args.command {
+        if let Err(e) = args.run() {
+            eprintln!("Error: {e}");
+            std::process::exit(1);
+        }
+        return;
+    }
+
     let result: Result<(), String> = (|| {
         // Read input.
         let mml = args.read_input_file()?;
 
         // Parse text.
-        let music: ffmml::Music = mml
-            .parse::<ffmml::Music>()
+        let music = ffmml::Music::with_resolver(&mml, args.include_resolver())
             .map_err(|e| e.file_path(args.input_file_path()).to_string())?;
 
+        if args.has_output_extension("mid") {
+            args.check_loop_options("MIDI")?;
+            // Convert into MIDI.
+            let midi = ffmml::midi::Midi::with_options(
+                &music,
+                ffmml::midi::MidiOptions {
+                    loop_count: args.loops.unwrap_or(1),
+                },
+            )
+            .map_err(|e| e.text(&mml).file_path(args.input_file_path()).to_string())?;
+
+            // Write output.
+            midi.to_writer(args.create_output_writer()?).map_err(|e| {
+                format!(
+                    "failed to write MIDI file to {} ({e})",
+                    args.output_file_path().to_string_lossy()
+                )
+            })?;
+            return Ok(());
+        }
+
+        if args.has_output_extension("vgm") {
+            args.check_loop_options("VGM")?;
+            // Log APU register writes as VGM.
+            let vgm = ffmml::vgm::Vgm::with_options(
+                &music,
+                ffmml::vgm::VgmOptions {
+                    max_duration: Duration::from_secs(u64::from(args.duration)),
+                },
+            )
+            .map_err(|e| e.text(&mml).file_path(args.input_file_path()).to_string())?;
+
+            // Write output.
+            vgm.to_writer(args.create_output_writer()?).map_err(|e| {
+                format!(
+                    "failed to write VGM file to {} ({e})",
+                    args.output_file_path().to_string_lossy()
+                )
+            })?;
+            return Ok(());
+        }
+
+        if args.has_output_extension("nsf") {
+            args.check_loop_options("NSF")?;
+            // Compile into NSF.
+            let nsf = ffmml::nsf::Nsf::new(&music)
+                .map_err(|e| e.text(&mml).file_path(args.input_file_path()).to_string())?;
+
+            // Write output.
+            nsf.to_writer(args.create_output_writer()?).map_err(|e| {
+                format!(
+                    "failed to write NSF file to {} ({e})",
+                    args.output_file_path().to_string_lossy()
+                )
+            })?;
+            return Ok(());
+        }
+
         // Generate audio data.
-        let wav = ffmml::wav::Wav::with_options(
-            &music,
-            ffmml::wav::WavOptions {
-                sample_rate: args.sample_rate,
-                max_duration: Duration::from_secs(u64::from(args.duration)),
+        let options = ffmml::wav::WavOptions {
+            sample_rate: args.sample_rate,
+            max_duration: Duration::from_secs(u64::from(args.duration)),
+            synthesis: if args.band_limited {
+                ffmml::Synthesis::BandLimited
+            } else {
+                ffmml::Synthesis::Naive
             },
-        )
+            mixer_mode: if args.nes_mixer {
+                ffmml::MixerMode::Nes
+            } else {
+                ffmml::MixerMode::Linear
+            },
+            stereo_layout: args.stereo.map(From::from),
+            loop_count: args.loops,
+            fade_out: Duration::from_secs(u64::from(args.fade)),
+        };
+        if args.stems {
+            let stems = ffmml::wav::Wav::stems(&music, options)
+                .map_err(|e| e.text(&mml).file_path(args.input_file_path()).to_string())?;
+
+            // Write outputs.
+            for (channel, wav) in stems {
+                let path = args.stem_file_path(channel)?;
+                let file = std::fs::File::create(&path).map_err(|e| {
+                    format!(
+                        "failed to create output file {} ({e})",
+                        path.to_string_lossy()
+                    )
+                })?;
+                wav.to_writer(std::io::BufWriter::new(file)).map_err(|e| {
+                    format!(
+                        "failed to write WAV file to {} ({e})",
+                        path.to_string_lossy()
+                    )
+                })?;
+            }
+            return Ok(());
+        }
+
+        let wav = if args.apu {
+            ffmml::wav::Wav::with_apu(&music, options)
+        } else {
+            ffmml::wav::Wav::with_options(&music, options)
+        }
         .map_err(|e| e.text(&mml).file_path(args.input_file_path()).to_string())?;
 
         // Write output.
@@ -0,0 +1,186 @@
+use crate::{
+    channel::ChannelName,
+    clocks::{Clock, Clocks},
+    commands::Command,
+    cursor::CommandCursor,
+    music::Music,
+    player::PlayMusicError,
+};
+use std::{collections::BTreeMap, time::Duration};
+
+/// Timing analysis of a [`Music`] returned by [`Music::analyze()`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MusicAnalysis {
+    channels: BTreeMap<ChannelName, ChannelAnalysis>,
+}
+
+impl MusicAnalysis {
+    pub(crate) fn new(music: &Music) -> Result<Self, PlayMusicError> {
+        Self::from_cursors(
+            music
+                .channels()
+                .iter()
+                .map(|(name, channel)| (name, CommandCursor::new(channel.commands))),
+        )
+    }
+
+    /// Analyzes the channels from the first commands of the cursors.
+    pub(crate) fn from_cursors(
+        cursors: impl IntoIterator<Item = (ChannelName, CommandCursor)>,
+    ) -> Result<Self, PlayMusicError> {
+        let mut channels = BTreeMap::new();
+        for (name, cursor) in cursors {
+            let mut walker = ChannelWalker::new(cursor);
+            let analysis = walker.walk().map_err(|mut e| {
+                e.channel = name;
+                e
+            })?;
+            channels.insert(name, analysis);
+        }
+        Ok(Self { channels })
+    }
+
+    /// Returns the analysis of each channel.
+    pub fn channels(&self) -> &BTreeMap<ChannelName, ChannelAnalysis> {
+        &self.channels
+    }
+
+    /// Returns the length of the music (the longest [`ChannelAnalysis::total()`]).
+    pub fn total(&self) -> Duration {
+        self.duration_with_loops(0)
+    }
+
+    /// Returns the length of the music when each channel jumps back to its loop point `loop_count` times
+    /// (the longest [`ChannelAnalysis::duration_with_loops()`]).
+    pub fn duration_with_loops(&self, loop_count: usize) -> Duration {
+        self.channels
+            .values()
+            .map(|c| c.duration_with_loops(loop_count))
+            .max()
+            .unwrap_or_default()
+    }
+}
+
+/// Timing analysis of a channel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChannelAnalysis {
+    intro: Duration,
+    loop_body: Option<Duration>,
+    total: Duration,
+}
+
+impl ChannelAnalysis {
+    /// Returns the duration before the loop point (`L`).
+    ///
+    /// If the channel has no loop point, this is the same as [`ChannelAnalysis::total()`].
+    pub fn intro(&self) -> Duration {
+        self.intro
+    }
+
+    /// Returns the duration of the section after the loop point (`L`), or `None` if the channel has no loop point.
+    pub fn loop_body(&self) -> Option<Duration> {
+        self.loop_body
+    }
+
+    /// Returns the duration to play all the commands of the channel once (the intro and the loop body).
+    pub fn total(&self) -> Duration {
+        self.total
+    }
+
+    /// Returns the duration until the channel ends after jumping back to the loop point `loop_count` times
+    /// (see [`ChannelState::loop_count()`](crate::ChannelState::loop_count)).
+    pub fn duration_with_loops(&self, loop_count: usize) -> Duration {
+        let loop_count = u32::try_from(loop_count).unwrap_or(u32::MAX);
+        let loops = self
+            .loop_body
+            .unwrap_or_default()
+            .saturating_mul(loop_count);
+        self.total.saturating_add(loops)
+    }
+}
+
+/// Walks the commands of a channel only to tick the note clock.
+#[derive(Debug)]
+struct ChannelWalker {
+    cursor: CommandCursor,
+    clocks: Clocks,
+    loop_clock: Option<Clock>,
+}
+
+impl ChannelWalker {
+    fn new(cursor: CommandCursor) -> Self {
+        Self {
+            cursor,
+            clocks: Clocks::new(1), // The sample rate is not used.
+            loop_clock: None,
+        }
+    }
+
+    fn walk(&mut self) -> Result<ChannelAnalysis, PlayMusicError> {
+        while let Some(command) = self.cursor.next_command() {
+            match command {
+                Command::Note(c) => self.clocks.tick_note_clock(c.note_duration()),
+                Command::DirectNote(c) => self.clocks.tick_note_clock(c.note_duration()),
+                Command::DirectFrequency(c) => self.clocks.tick_note_clock(c.note_duration()),
+                Command::RestSign(c) => self.clocks.tick_note_clock(c.note_duration()),
+                Command::Wait(c) => self.clocks.tick_note_clock(c.note_duration()),
+                Command::Tie(c) => {
+                    self.cursor.tie(&c)?;
+                    self.clocks.tick_note_clock(c.note_duration());
+                }
+                Command::Slur(c) => {
+                    let after = self.cursor.slur(c)?;
+                    self.clocks.tick_note_clock(after.note_duration());
+                }
+                Command::DefaultNoteDuration(c) => {
+                    self.clocks
+                        .set_default_note_duration(c.default_note_duration());
+                }
+                Command::Tempo(c) => self.clocks.set_tempo(c.tempo()),
+                Command::DataSkip(_) => self.cursor.skip_to_end(),
+                Command::TrackLoop(_) => self.loop_clock = Some(self.clocks.note_clock()),
+                Command::RepeatStart(c) => self.cursor.repeat_start(c)?,
+                Command::RepeatEnd(c) => self.cursor.repeat_end(c)?,
+                Command::TupletStart(c) => self.cursor.tuplet_start(c, &mut self.clocks)?,
+                Command::TupletEnd(c) => self.cursor.tuplet_end(c)?,
+                Command::Arpeggio(_)
+                | Command::Volume(_)
+                | Command::VolumeUp(_)
+                | Command::VolumeDown(_)
+                | Command::VolumeEnvelope(_)
+                | Command::Octave(_)
+                | Command::OctaveUp(_)
+                | Command::OctaveDown(_)
+                | Command::Detune(_)
+                | Command::PitchEnvelope(_)
+                | Command::PitchSweep(_)
+                | Command::Vibrato(_)
+                | Command::Timbre(_)
+                | Command::Timbres(_)
+                | Command::HardwareEnvelope(_)
+                | Command::LengthCounter(_)
+                | Command::LinearCounter(_)
+                | Command::FdsModulation(_)
+                | Command::FdsMasterVolume(_)
+                | Command::N163WaveOffset(_)
+                | Command::Sunsoft5bNoise(_)
+                | Command::Sunsoft5bEnvelope(_)
+                | Command::Vrc7Sustain(_)
+                | Command::Vrc7UserPatch(_)
+                | Command::Pan(_)
+                | Command::Quantize(_)
+                | Command::QuantizeFrame(_) => {}
+            }
+        }
+
+        let end = self.clocks.note_clock();
+        let intro = self.loop_clock.unwrap_or(end);
+        Ok(ChannelAnalysis {
+            intro: intro.now(),
+            loop_body: self
+                .loop_clock
+                .map(|start| end.now().saturating_sub(start.now())),
+            total: end.now(),
+        })
+    }
+}
@@ -0,0 +1,969 @@
+//! APU: Cycle-accurate emulation of the NES audio processing unit (2A03).
+//!
+//! [`Apu`] emulates the two pulse wave channels, the triangle wave channel, the noise channel,
+//! the DMC channel and the frame counter at CPU-cycle granularity,
+//! and [`ApuPlayer`] renders a music by feeding the register writes derived from the commands into it.
+use crate::{
+    dpcm,
+    oscillators::{LENGTH_TABLE, NOISE_PERIOD_TABLE, PAL_NOISE_PERIOD_TABLE},
+    player::OutputFilters,
+    registers::{RegisterRecorder, Slot, DMC_START, DMC_STOP, DPCM_START},
+    Music, PlayMusicError, Region, Sample,
+};
+
+const DUTY_TABLE: [[u8; 8]; 4] = [
+    [0, 0, 0, 0, 0, 0, 0, 1],
+    [0, 0, 0, 0, 0, 0, 1, 1],
+    [0, 0, 0, 0, 1, 1, 1, 1],
+    [1, 1, 1, 1, 1, 1, 0, 0],
+];
+
+const TRIANGLE_TABLE: [u8; 32] = [
+    15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12,
+    13, 14, 15,
+];
+
+/// CPU cycles of the frame counter steps (4-step mode, 5-step mode).
+const NTSC_FRAME_STEPS: ([u32; 4], [u32; 5]) = (
+    [7457, 14913, 22371, 29830],
+    [7457, 14913, 22371, 29829, 37282],
+);
+const PAL_FRAME_STEPS: ([u32; 4], [u32; 5]) = (
+    [8313, 16627, 24939, 33254],
+    [8313, 16627, 24939, 33253, 41566],
+);
+
+/// [`Apu`] emulates the NES APU (2A03) at CPU-cycle granularity.
+///
+/// Registers (`$4000..=$4017`) are written by [`Apu::write()`], and each call of [`Apu::clock()`] advances one CPU cycle.
+/// The DMC channel fetches samples from the memory written by [`Apu::write_memory()`].
+///
+/// The Dendy region uses the NTSC frame counter, noise and DMC tables with its own CPU clock.
+#[derive(Debug, Clone)]
+pub struct Apu {
+    region: Region,
+    pulses: [Pulse; 2],
+    triangle: Triangle,
+    noise: Noise,
+    dmc: Dmc,
+    frame_counter: FrameCounter,
+    memory: Vec<u8>, // $8000..=$FFFF
+    cycle: u64,
+}
+
+impl Apu {
+    /// Makes a new [`Apu`] instance in the power-up state.
+    pub fn new(region: Region) -> Self {
+        Self {
+            region,
+            pulses: [Pulse::new(true), Pulse::new(false)],
+            triangle: Triangle::default(),
+            noise: Noise::default(),
+            dmc: Dmc::default(),
+            frame_counter: FrameCounter::default(),
+            memory: vec![0; 0x8000],
+            cycle: 0,
+        }
+    }
+
+    /// Returns the TV system of this APU.
+    pub fn region(&self) -> Region {
+        self.region
+    }
+
+    /// Writes `data` into the memory (`$8000..=$FFFF`) from which the DMC channel fetches samples.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the range of the data is out of `$8000..=$FFFF`.
+    pub fn write_memory(&mut self, address: u16, data: &[u8]) {
+        assert!(address >= 0x8000, "DMC memory starts at $8000");
+        let start = usize::from(address - 0x8000);
+        self.memory[start..start + data.len()].copy_from_slice(data);
+    }
+
+    /// Writes a value into an APU register (`$4000..=$4013`, `$4015` or `$4017`).
+    ///
+    /// Writes to other addresses are ignored.
+    pub fn write(&mut self, address: u16, value: u8) {
+        match address {
+            0x4000..=0x4007 => {
+                let pulse = &mut self.pulses[usize::from(address >= 0x4004)];
+                pulse.write(address & 3, value);
+            }
+            0x4008..=0x400B => self.triangle.write(address & 3, value),
+            0x400C..=0x400F => {
+                let table = self.noise_period_table();
+                self.noise.write(address & 3, value, table);
+            }
+            0x4010..=0x4013 => {
+                let table = self.dmc_rate_table();
+                self.dmc.write(address & 3, value, table);
+            }
+            0x4015 => {
+                self.pulses[0].length.set_enabled(value & 0x01 != 0);
+                self.pulses[1].length.set_enabled(value & 0x02 != 0);
+                self.triangle.length.set_enabled(value & 0x04 != 0);
+                self.noise.length.set_enabled(value & 0x08 != 0);
+                self.dmc.set_enabled(value & 0x10 != 0);
+            }
+            0x4017 => {
+                self.frame_counter.five_step = value & 0x80 != 0;
+                self.frame_counter.cycle = 0;
+                if self.frame_counter.five_step {
+                    self.clock_quarter_frame();
+                    self.clock_half_frame();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Advances one CPU cycle.
+    pub fn clock(&mut self) {
+        self.triangle.clock_timer();
+        if self.cycle % 2 == 1 {
+            // Pulse and noise timers are clocked every APU cycle (two CPU cycles).
+            self.pulses[0].clock_timer();
+            self.pulses[1].clock_timer();
+            self.noise.clock_timer();
+        }
+        self.dmc.clock_timer(&self.memory);
+        self.clock_frame_counter();
+        self.cycle += 1;
+    }
+
+    /// Returns the output level of the non-linear mixer (`0.0..=1.0`).
+    ///
+    /// The levels are taken from the lookup tables described in <https://www.nesdev.org/wiki/APU_Mixer>.
+    pub fn output(&self) -> f32 {
+        let pulse = self.pulses[0].output() + self.pulses[1].output();
+        let tnd = 3 * u16::from(self.triangle.output())
+            + 2 * u16::from(self.noise.output())
+            + u16::from(self.dmc.level);
+        let pulse_out = if pulse == 0 {
+            0.0
+        } else {
+            95.52 / (8128.0 / f32::from(pulse) + 100.0)
+        };
+        let tnd_out = if tnd == 0 {
+            0.0
+        } else {
+            163.67 / (24329.0 / f32::from(tnd) + 100.0)
+        };
+        pulse_out + tnd_out
+    }
+
+    fn noise_period_table(&self) -> &'static [u16; 16] {
+        if self.region == Region::Pal {
+            &PAL_NOISE_PERIOD_TABLE
+        } else {
+            &NOISE_PERIOD_TABLE
+        }
+    }
+
+    fn dmc_rate_table(&self) -> &'static [u16; 16] {
+        dpcm::rate_table(self.region)
+    }
+
+    fn clock_frame_counter(&mut self) {
+        let (four_step, five_step) = if self.region == Region::Pal {
+            PAL_FRAME_STEPS
+        } else {
+            NTSC_FRAME_STEPS
+        };
+        self.frame_counter.cycle += 1;
+        let cycle = self.frame_counter.cycle;
+        if self.frame_counter.five_step {
+            if cycle == five_step[0] || cycle == five_step[2] {
+                self.clock_quarter_frame();
+            } else if cycle == five_step[1] || cycle == five_step[4] {
+                self.clock_quarter_frame();
+                self.clock_half_frame();
+            }
+            if cycle == five_step[4] {
+                self.frame_counter.cycle = 0;
+            }
+        } else {
+            if cycle == four_step[0] || cycle == four_step[2] {
+                self.clock_quarter_frame();
+            } else if cycle == four_step[1] || cycle == four_step[3] {
+                self.clock_quarter_frame();
+                self.clock_half_frame();
+            }
+            if cycle == four_step[3] {
+                self.frame_counter.cycle = 0;
+            }
+        }
+    }
+
+    fn clock_quarter_frame(&mut self) {
+        self.pulses[0].envelope.clock();
+        self.pulses[1].envelope.clock();
+        self.noise.envelope.clock();
+        self.triangle.clock_linear_counter();
+    }
+
+    fn clock_half_frame(&mut self) {
+        for pulse in &mut self.pulses {
+            pulse.length.clock();
+            pulse.clock_sweep();
+        }
+        self.triangle.length.clock();
+        self.noise.length.clock();
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+struct FrameCounter {
+    five_step: bool,
+    cycle: u32,
+}
+
+#[derive(Debug, Default, Clone)]
+struct LengthCounter {
+    enabled: bool,
+    halt: bool,
+    value: u8,
+}
+
+impl LengthCounter {
+    fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.value = 0;
+        }
+    }
+
+    fn load(&mut self, index: u8) {
+        if self.enabled {
+            self.value = LENGTH_TABLE[usize::from(index)];
+        }
+    }
+
+    fn clock(&mut self) {
+        if !self.halt && self.value > 0 {
+            self.value -= 1;
+        }
+    }
+
+    fn is_silent(&self) -> bool {
+        self.value == 0
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+struct Envelope {
+    start: bool,
+    looped: bool,
+    constant: bool,
+    period: u8,
+    divider: u8,
+    decay: u8,
+}
+
+impl Envelope {
+    fn write(&mut self, value: u8) {
+        self.looped = value & 0x20 != 0;
+        self.constant = value & 0x10 != 0;
+        self.period = value & 0x0F;
+    }
+
+    fn clock(&mut self) {
+        if self.start {
+            self.start = false;
+            self.decay = 15;
+            self.divider = self.period;
+        } else if self.divider == 0 {
+            self.divider = self.period;
+            if self.decay > 0 {
+                self.decay -= 1;
+            } else if self.looped {
+                self.decay = 15;
+            }
+        } else {
+            self.divider -= 1;
+        }
+    }
+
+    fn volume(&self) -> u8 {
+        if self.constant {
+            self.period
+        } else {
+            self.decay
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Pulse {
+    is_first: bool,
+    duty: u8,
+    step: u8,
+    period: u16,
+    timer: u16,
+    envelope: Envelope,
+    length: LengthCounter,
+    sweep_enabled: bool,
+    sweep_period: u8,
+    sweep_negate: bool,
+    sweep_shift: u8,
+    sweep_divider: u8,
+    sweep_reload: bool,
+}
+
+impl Pulse {
+    fn new(is_first: bool) -> Self {
+        Self {
+            is_first,
+            duty: 0,
+            step: 0,
+            period: 0,
+            timer: 0,
+            envelope: Envelope::default(),
+            length: LengthCounter::default(),
+            sweep_enabled: false,
+            sweep_period: 0,
+            sweep_negate: false,
+            sweep_shift: 0,
+            sweep_divider: 0,
+            sweep_reload: false,
+        }
+    }
+
+    fn write(&mut self, register: u16, value: u8) {
+        match register {
+            0 => {
+                self.duty = value >> 6;
+                self.length.halt = value & 0x20 != 0;
+                self.envelope.write(value);
+            }
+            1 => {
+                self.sweep_enabled = value & 0x80 != 0;
+                self.sweep_period = (value >> 4) & 0x07;
+                self.sweep_negate = value & 0x08 != 0;
+                self.sweep_shift = value & 0x07;
+                self.sweep_reload = true;
+            }
+            2 => self.period = (self.period & 0x700) | u16::from(value),
+            _ => {
+                self.period = (self.period & 0xFF) | (u16::from(value & 0x07) << 8);
+                self.length.load(value >> 3);
+                self.step = 0;
+                self.envelope.start = true;
+            }
+        }
+    }
+
+    fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period;
+            self.step = (self.step + 7) % 8;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn sweep_target(&self) -> u16 {
+        let delta = self.period >> self.sweep_shift;
+        if !self.sweep_negate {
+            self.period + delta
+        } else if self.is_first {
+            // The first pulse channel uses ones' complement.
+            self.period.saturating_sub(delta + 1)
+        } else {
+            self.period.saturating_sub(delta)
+        }
+    }
+
+    fn is_muted_by_sweep(&self) -> bool {
+        self.period < 8 || self.sweep_target() > 0x7FF
+    }
+
+    fn clock_sweep(&mut self) {
+        if self.sweep_divider == 0
+            && self.sweep_enabled
+            && self.sweep_shift > 0
+            && !self.is_muted_by_sweep()
+        {
+            self.period = self.sweep_target();
+        }
+        if self.sweep_divider == 0 || self.sweep_reload {
+            self.sweep_divider = self.sweep_period;
+            self.sweep_reload = false;
+        } else {
+            self.sweep_divider -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.length.is_silent()
+            || self.is_muted_by_sweep()
+            || DUTY_TABLE[usize::from(self.duty)][usize::from(self.step)] == 0
+        {
+            0
+        } else {
+            self.envelope.volume()
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+struct Triangle {
+    step: u8,
+    period: u16,
+    timer: u16,
+    length: LengthCounter,
+    control: bool,
+    linear_reload_value: u8,
+    linear_counter: u8,
+    linear_reload: bool,
+}
+
+impl Triangle {
+    fn write(&mut self, register: u16, value: u8) {
+        match register {
+            0 => {
+                self.control = value & 0x80 != 0;
+                self.length.halt = self.control;
+                self.linear_reload_value = value & 0x7F;
+            }
+            1 => {}
+            2 => self.period = (self.period & 0x700) | u16::from(value),
+            _ => {
+                self.period = (self.period & 0xFF) | (u16::from(value & 0x07) << 8);
+                self.length.load(value >> 3);
+                self.linear_reload = true;
+            }
+        }
+    }
+
+    fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period;
+            if !self.length.is_silent() && self.linear_counter > 0 {
+                self.step = (self.step + 1) % 32;
+            }
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn clock_linear_counter(&mut self) {
+        if self.linear_reload {
+            self.linear_counter = self.linear_reload_value;
+        } else if self.linear_counter > 0 {
+            self.linear_counter -= 1;
+        }
+        if !self.control {
+            self.linear_reload = false;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        // The triangle wave channel keeps outputting the current step even if it is stopped.
+        TRIANGLE_TABLE[usize::from(self.step)]
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Noise {
+    shift_register: u16,
+    mode: bool,
+    period: u16,
+    timer: u16,
+    envelope: Envelope,
+    length: LengthCounter,
+}
+
+impl Default for Noise {
+    fn default() -> Self {
+        Self {
+            shift_register: 1,
+            mode: false,
+            period: NOISE_PERIOD_TABLE[0] / 2,
+            timer: 0,
+            envelope: Envelope::default(),
+            length: LengthCounter::default(),
+        }
+    }
+}
+
+impl Noise {
+    fn write(&mut self, register: u16, value: u8, table: &[u16; 16]) {
+        match register {
+            0 => {
+                self.length.halt = value & 0x20 != 0;
+                self.envelope.write(value);
+            }
+            1 => {}
+            2 => {
+                self.mode = value & 0x80 != 0;
+                // The table is in CPU cycles while the timer is clocked every APU cycle.
+                self.period = table[usize::from(value & 0x0F)] / 2;
+            }
+            _ => {
+                self.length.load(value >> 3);
+                self.envelope.start = true;
+            }
+        }
+    }
+
+    fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period - 1;
+            let tap = if self.mode { 6 } else { 1 };
+            let feedback = (self.shift_register ^ (self.shift_register >> tap)) & 1;
+            self.shift_register = (self.shift_register >> 1) | (feedback << 14);
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.length.is_silent() || self.shift_register & 1 == 1 {
+            0
+        } else {
+            self.envelope.volume()
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+struct Dmc {
+    looped: bool,
+    rate: u16,
+    timer: u16,
+    level: u8,
+    sample_address: u16,
+    sample_length: u16,
+    current_address: u16,
+    bytes_remaining: u16,
+    buffer: Option<u8>,
+    shift_register: u8,
+    bits_remaining: u8,
+    silence: bool,
+}
+
+impl Dmc {
+    fn write(&mut self, register: u16, value: u8, table: &[u16; 16]) {
+        match register {
+            0 => {
+                self.looped = value & 0x40 != 0;
+                self.rate = table[usize::from(value & 0x0F)];
+            }
+            1 => self.level = value & 0x7F,
+            2 => self.sample_address = 0xC000 + u16::from(value) * 64,
+            _ => self.sample_length = u16::from(value) * 16 + 1,
+        }
+    }
+
+    fn set_enabled(&mut self, enabled: bool) {
+        if !enabled {
+            self.bytes_remaining = 0;
+        } else if self.bytes_remaining == 0 {
+            self.restart();
+        }
+    }
+
+    fn restart(&mut self) {
+        self.current_address = self.sample_address;
+        self.bytes_remaining = self.sample_length;
+    }
+
+    fn clock_timer(&mut self, memory: &[u8]) {
+        if self.buffer.is_none() && self.bytes_remaining > 0 {
+            self.buffer = Some(memory[usize::from(self.current_address - 0x8000)]);
+            self.current_address = self.current_address.checked_add(1).unwrap_or(0x8000);
+            self.bytes_remaining -= 1;
+            if self.bytes_remaining == 0 && self.looped {
+                self.restart();
+            }
+        }
+
+        if self.rate == 0 {
+            return;
+        }
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.rate - 1;
+
+        if !self.silence {
+            if self.shift_register & 1 == 1 {
+                if self.level <= 125 {
+                    self.level += 2;
+                }
+            } else if self.level >= 2 {
+                self.level -= 2;
+            }
+        }
+        self.shift_register >>= 1;
+        self.bits_remaining = self.bits_remaining.saturating_sub(1);
+        if self.bits_remaining == 0 {
+            self.bits_remaining = 8;
+            match self.buffer.take() {
+                Some(byte) => {
+                    self.silence = false;
+                    self.shift_register = byte;
+                }
+                None => self.silence = true,
+            }
+        }
+    }
+}
+
+/// [`ApuPlayer`] is an iterator that generates audio samples by [`Apu`].
+///
+/// The channels of the music are assigned to the APU channels in the same way as the NSF and VGM exporters
+/// (up to two pulse wave channels, one triangle wave channel, one noise channel and one DPCM channel).
+/// The register values are updated at every output sample, and the output of the APU is averaged over
+/// the CPU cycles of each sample, and then filtered in the same way as [`MixerMode::Nes`](crate::MixerMode::Nes).
+///
+/// When the high period bits of a pulse wave channel change by one during a note (e.g., by a vibrato),
+/// they are changed by the sweep unit instead of writing `$4003`/`$4007`, which would restart the waveform.
+#[derive(Debug)]
+pub struct ApuPlayer {
+    recorder: RegisterRecorder,
+    apu: Apu,
+    registers: [[Option<u8>; 4]; 5],
+    cycles_per_sample: f64,
+    residual_cycles: f64,
+    filters: OutputFilters,
+    last_error: Option<PlayMusicError>,
+    eos: bool,
+}
+
+impl ApuPlayer {
+    /// Makes a new [`ApuPlayer`] instance.
+    pub fn new(music: &Music, sample_rate: u16) -> Result<Self, PlayMusicError> {
+        let recorder = RegisterRecorder::with_sample_rate(music, "APU", sample_rate, 1)?;
+        let mut apu = Apu::new(music.region());
+        apu.write_memory(DPCM_START, &recorder.samples().data);
+        apu.write(0x4015, DMC_STOP);
+        apu.write(0x4017, 0x40);
+        Ok(Self {
+            recorder,
+            apu,
+            registers: [[None; 4]; 5],
+            cycles_per_sample: f64::from(music.region().cpu_clock_hz()) / f64::from(sample_rate),
+            residual_cycles: 0.0,
+            filters: OutputFilters::new(sample_rate),
+            last_error: None,
+            eos: false,
+        })
+    }
+
+    /// Returns the emulated APU.
+    pub fn apu(&self) -> &Apu {
+        &self.apu
+    }
+
+    /// Takes the last error if it exists.
+    pub fn take_last_error(&mut self) -> Option<PlayMusicError> {
+        self.last_error.take()
+    }
+
+    fn write_registers(&mut self) -> Result<bool, PlayMusicError> {
+        let channels = self.recorder.next_frame()?;
+        for c in &channels {
+            let prev = &mut self.registers[usize::from(c.slot.index())];
+            let mut dmc_control = None;
+            for (i, value) in c.registers.into_iter().enumerate() {
+                let Some(value) = value else {
+                    continue;
+                };
+                if c.slot == Slot::Dmc && i == 1 {
+                    dmc_control = Some(value);
+                } else if (i == 3 && c.retrigger) || prev[i] != Some(value) {
+                    let base = c.slot.base_address();
+                    let is_pulse = matches!(c.slot, Slot::Pulse1 | Slot::Pulse2);
+                    let shifted = is_pulse && i == 3 && !c.retrigger && !c.is_note_start && {
+                        let (low, high) = (prev[2].unwrap_or(0), prev[3].unwrap_or(0));
+                        shift_pulse_high_period(&mut self.apu, base, low, high, value)
+                    };
+                    if !shifted {
+                        self.apu.write(base + i as u16, value);
+                    }
+                    prev[i] = Some(value);
+                }
+            }
+            if let Some(value) = dmc_control {
+                self.apu.write(0x4015, DMC_STOP);
+                if value == DMC_START {
+                    self.apu.write(0x4015, DMC_START);
+                }
+            }
+        }
+        Ok(channels.iter().all(|c| c.is_eos))
+    }
+}
+
+/// Changes the high period bits of a pulse wave channel by one without resetting its phase.
+///
+/// Writing `$4003`/`$4007` restarts the duty cycle sequencer (and the envelope and the length counter),
+/// which is audible as a click when a vibrato crosses a multiple of `$100`.
+/// Instead, the low period bits are set to `$FF` (or `$00`) and the sweep unit (shift `7`) is clocked once by writing
+/// `$4017`, so that the period is carried up (or borrowed down) into the high bits, and then the low bits are restored.
+/// This also clocks the envelopes, the length counters and the linear counter once, and restarts the frame counter.
+///
+/// Returns `false` if the change cannot be made in this way (the other bits of `$4003`/`$4007` are changed,
+/// or the high period bits are changed by more than one).
+fn shift_pulse_high_period(apu: &mut Apu, base: u16, low: u8, prev_high: u8, high: u8) -> bool {
+    let up = match (high & 0x07).wrapping_sub(prev_high & 0x07) {
+        _ if high & 0xF8 != prev_high & 0xF8 => return false,
+        0x01 => true,
+        0xFF => false,
+        _ => return false,
+    };
+    if up {
+        apu.write(base + 2, 0xFF);
+        apu.write(base + 1, 0x87); // Enabled, period 0, shift 7.
+    } else {
+        apu.write(base + 2, 0x00);
+        apu.write(base + 1, 0x8F); // Enabled, period 0, negate, shift 7.
+    }
+    apu.write(0x4017, 0xC0); // Clocks the sweep units immediately.
+    apu.write(0x4017, 0x40);
+    apu.write(base + 1, 0x08);
+    apu.write(base + 2, low);
+    true
+}
+
+impl Iterator for ApuPlayer {
+    type Item = Sample;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.eos {
+            return None;
+        }
+        match self.write_registers() {
+            Ok(eos) => self.eos = eos,
+            Err(e) => {
+                self.last_error = Some(e);
+                self.eos = true;
+                return None;
+            }
+        }
+
+        self.residual_cycles += self.cycles_per_sample;
+        let cycles = self.residual_cycles.floor();
+        self.residual_cycles -= cycles;
+        let mut sum = 0.0;
+        for _ in 0..cycles as usize {
+            self.apu.clock();
+            sum += self.apu.output();
+        }
+        let level = sum / cycles.max(1.0) as f32;
+
+        // The filtered output is roughly within `-0.5..=0.5`.
+        Some(Sample::new(self.filters.apply(level) * 2.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frame_counter_clocks_length_counters_at_half_frames() {
+        for (region, steps) in [
+            (Region::Ntsc, NTSC_FRAME_STEPS.0),
+            (Region::Pal, PAL_FRAME_STEPS.0),
+            (Region::Dendy, NTSC_FRAME_STEPS.0),
+        ] {
+            let mut apu = Apu::new(region);
+            apu.write(0x4015, 0x01);
+            apu.write(0x4000, 0x1F);
+            apu.write(0x4003, 3 << 3); // Length: 2
+            clock(&mut apu, steps[1] - 1);
+            assert_eq!(apu.pulses[0].length.value, 2, "{region:?}");
+            clock(&mut apu, 1);
+            assert_eq!(apu.pulses[0].length.value, 1, "{region:?}");
+            clock(&mut apu, steps[3] - steps[1]);
+            assert_eq!(apu.pulses[0].length.value, 0, "{region:?}");
+        }
+    }
+
+    #[test]
+    fn frame_counter_five_step_mode_clocks_immediately() {
+        let mut apu = Apu::new(Region::Ntsc);
+        apu.write(0x4015, 0x01);
+        apu.write(0x4000, 0x1F);
+        apu.write(0x4003, 3 << 3); // Length: 2
+        apu.write(0x4017, 0x80);
+        assert_eq!(apu.pulses[0].length.value, 1);
+
+        // The 5-step sequence has no half frame at the fourth step.
+        clock(&mut apu, NTSC_FRAME_STEPS.1[1]);
+        assert_eq!(apu.pulses[0].length.value, 0);
+        apu.write(0x4003, 3 << 3);
+        clock(&mut apu, NTSC_FRAME_STEPS.1[3] - NTSC_FRAME_STEPS.1[1]);
+        assert_eq!(apu.pulses[0].length.value, 2);
+        clock(&mut apu, NTSC_FRAME_STEPS.1[4] - NTSC_FRAME_STEPS.1[3]);
+        assert_eq!(apu.pulses[0].length.value, 1);
+    }
+
+    #[test]
+    fn length_counter_halt_and_disable() {
+        let mut apu = Apu::new(Region::Ntsc);
+        apu.write(0x400C, 0x3F); // Halt
+        apu.write(0x400F, 0x08); // Ignored while disabled.
+        assert_eq!(apu.noise.length.value, 0);
+
+        apu.write(0x4015, 0x08);
+        apu.write(0x400F, 0x08); // Length: 254
+        clock(&mut apu, NTSC_FRAME_STEPS.0[3] * 2);
+        assert_eq!(apu.noise.length.value, 254);
+
+        apu.write(0x400C, 0x1F);
+        clock(&mut apu, NTSC_FRAME_STEPS.0[3]);
+        assert_eq!(apu.noise.length.value, 252);
+
+        apu.write(0x4015, 0x00);
+        assert_eq!(apu.noise.length.value, 0);
+    }
+
+    #[test]
+    fn linear_counter_stops_triangle() {
+        let mut apu = Apu::new(Region::Ntsc);
+        apu.write(0x4015, 0x04);
+        apu.write(0x4008, 0x02); // Reload: 2
+        apu.write(0x400A, 0x10);
+        apu.write(0x400B, 0x08);
+
+        // Reloaded at the first quarter frame, and then counts down at the following ones.
+        clock(&mut apu, NTSC_FRAME_STEPS.0[0]);
+        assert_eq!(apu.triangle.linear_counter, 2);
+        clock(&mut apu, NTSC_FRAME_STEPS.0[2] - NTSC_FRAME_STEPS.0[0]);
+        assert_eq!(apu.triangle.linear_counter, 0);
+
+        let step = apu.triangle.step;
+        clock(&mut apu, 1000);
+        assert_eq!(apu.triangle.step, step);
+
+        // The control flag keeps reloading the counter.
+        apu.write(0x4008, 0x82);
+        apu.write(0x400B, 0x08);
+        clock(&mut apu, NTSC_FRAME_STEPS.0[3]);
+        assert_eq!(apu.triangle.linear_counter, 2);
+        assert_ne!(apu.triangle.step, step);
+    }
+
+    #[test]
+    fn sweep_mutes_pulse() {
+        let mut apu = Apu::new(Region::Ntsc);
+        apu.write(0x4015, 0x01);
+        apu.write(0x4000, 0xBF); // Duty 50%, constant volume 15
+                                 // The target period mutes the channel even if the sweep unit is disabled.
+        for (period, sweep, is_muted) in [
+            (7, 0x08, true),
+            (8, 0x08, false),
+            (0x7FF, 0x08, false), // Negated
+            (0x7FF, 0x00, true),
+            (0x556, 0x01, true),
+            (0x555, 0x01, false),
+        ] {
+            apu.write(0x4001, sweep);
+            apu.write(0x4002, (period & 0xFF) as u8);
+            apu.write(0x4003, 0x08 | (period >> 8) as u8);
+            assert_eq!(apu.pulses[0].is_muted_by_sweep(), is_muted, "{period:#X}");
+            let levels = (0..100)
+                .map(|_| {
+                    clock(&mut apu, 64);
+                    apu.pulses[0].output()
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(levels.iter().all(|&v| v == 0), is_muted, "{period:#X}");
+        }
+    }
+
+    #[test]
+    fn sweep_negates_with_ones_complement_on_first_pulse() {
+        let mut apu = Apu::new(Region::Ntsc);
+        for base in [0x4000, 0x4004] {
+            apu.write(base + 1, 0x89); // Enabled, period 0, negate, shift 1
+            apu.write(base + 2, 0x00);
+            apu.write(base + 3, 0x01);
+        }
+        apu.write(0x4017, 0xC0);
+        assert_eq!(apu.pulses[0].period, 0x7F);
+        assert_eq!(apu.pulses[1].period, 0x80);
+    }
+
+    #[test]
+    fn noise_lfsr_sequence_lengths() {
+        for (mode, len) in [(0x00, 32767), (0x80, 93)] {
+            let mut apu = Apu::new(Region::Ntsc);
+            apu.write(0x400E, mode); // Period: 4 CPU cycles (2 APU cycles)
+            let mut steps = 0;
+            loop {
+                clock(&mut apu, 4);
+                steps += 1;
+                if apu.noise.shift_register == 1 {
+                    break;
+                }
+            }
+            assert_eq!(steps, len, "mode={mode:#X}");
+        }
+    }
+
+    #[test]
+    fn pulse_high_period_shift_keeps_phase() {
+        for (start, end) in [(0x0FE, 0x101), (0x201, 0x1FC), (0x700, 0x6FF)] {
+            let mut apu = Apu::new(Region::Ntsc);
+            apu.write(0x4015, 0x02);
+            apu.write(0x4004, 0x9F); // Duty 50%, constant volume 15, not halted
+            apu.write(0x4006, (start & 0xFF) as u8);
+            apu.write(0x4007, 0x08 | (start >> 8) as u8);
+            clock(&mut apu, 1234);
+            let step = apu.pulses[1].step;
+            let length = apu.pulses[1].length.value;
+
+            let prev_high = 0x08 | (start >> 8) as u8;
+            let high = 0x08 | (end >> 8) as u8;
+            assert!(shift_pulse_high_period(
+                &mut apu,
+                0x4004,
+                (end & 0xFF) as u8,
+                prev_high,
+                high
+            ));
+            assert_eq!(apu.pulses[1].period, end, "{start:#X} => {end:#X}");
+            assert_eq!(apu.pulses[1].step, step);
+            assert_eq!(apu.pulses[1].length.value, length - 1);
+        }
+
+        let mut apu = Apu::new(Region::Ntsc);
+        assert!(!shift_pulse_high_period(&mut apu, 0x4000, 0, 0x08, 0x0A));
+        assert!(!shift_pulse_high_period(&mut apu, 0x4000, 0, 0x08, 0x11));
+        assert!(!shift_pulse_high_period(&mut apu, 0x4000, 0, 0x0F, 0x08));
+    }
+
+    #[test]
+    fn vibrato_across_high_period_bits_does_not_restart_pulse() {
+        // `o4 a` is `$0FD`, and the vibrato crosses `$100`.
+        let music =
+            Music::new("@MP0 = { 0 1 12 }\nA l1 HL1 MP0 o4 a").unwrap_or_else(|e| panic!("{e}"));
+        let mut player = ApuPlayer::new(&music, 8000).unwrap_or_else(|e| panic!("{e}"));
+        let mut highs = Vec::new();
+        let mut length = u8::MAX;
+        for _ in 0..8000 {
+            player.next().expect("sample");
+            let pulse = &player.apu.pulses[0];
+            highs.push(pulse.period >> 8);
+            // The length counter is only reloaded by writing `$4003`.
+            assert!(pulse.length.value <= length);
+            length = pulse.length.value;
+        }
+        assert!(highs.contains(&0) && highs.contains(&1));
+        assert!(length > 0);
+    }
+
+    fn clock(apu: &mut Apu, cycles: u32) {
+        for _ in 0..cycles {
+            apu.clock();
+        }
+    }
+}
@@ -1,6 +1,7 @@
 use crate::{
     commands::Command,
     comment::{Comment, CommentsOrWhitespaces},
+    macros::Macros,
     oscillators::Oscillator,
     ParseMusicError,
 };
@@ -33,7 +34,11 @@ impl Channels {
         self.0.insert(name, Channel::new(oscillator));
     }
 
-    pub fn parse(&mut self, parser: &mut Parser) -> Option<Result<(), ParseMusicError>> {
+    pub fn parse(
+        &mut self,
+        parser: &mut Parser,
+        macros: &Macros,
+    ) -> Option<Result<(), ParseMusicError>> {
         let mut channels: BTreeMap<_, Vec<Command>> =
             self.0.keys().copied().map(|k| (k, Vec::new())).collect();
         while !parser.is_eos() {
@@ -53,6 +58,10 @@ impl Channels {
             let mut has_space = true;
             while let Some(command) = parser.parse::<Command>() {
                 for name in &names {
+                    let oscillator = &self.0[name].oscillator;
+                    if let Err(e) = check_timbres(oscillator, &command, macros) {
+                        return Some(Err(e));
+                    }
                     channels
                         .get_mut(name)
                         .expect("unreachable")
@@ -79,6 +88,31 @@ impl Channels {
     }
 }
 
+/// Checks that the timbres set by `@<TIMBRE>` and `@@<MACRO>` are supported by the oscillator.
+fn check_timbres(
+    oscillator: &Oscillator,
+    command: &Command,
+    macros: &Macros,
+) -> Result<(), ParseMusicError> {
+    let max = oscillator.max_timbre();
+    match command {
+        Command::Timbre(c) if c.timbre().get() > max => {
+            Err(ParseMusicError::new(c.timbre(), "unsupported timbre value"))
+        }
+        Command::Timbres(c) => {
+            let timbres = macros.timbres.get(&c.macro_number());
+            if timbres.is_some_and(|m| m.timbres().items().iter().any(|t| t.get() > max)) {
+                return Err(ParseMusicError::new(
+                    c,
+                    "unsupported timbre value in the macro",
+                ));
+            }
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
 /// Channel name.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
 #[allow(missing_docs)]
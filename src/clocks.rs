@@ -15,6 +15,11 @@ impl Clock {
         self.0 -= Ratio::new(numer, denom);
     }
 
+    #[cfg(any(feature = "midi", feature = "nsf"))]
+    pub fn get(self) -> Ratio<u64> {
+        self.0
+    }
+
     pub fn now(self) -> Duration {
         if let Ok(denom) = u32::try_from(*self.0.denom()) {
             Duration::from_secs(*self.0.numer()) / denom
@@ -31,6 +36,7 @@ pub struct Clocks {
     frame_clock: Clock,
     quantize_clock: Clock,
     sample_rate: u16,
+    frame_rate: u64,
     tempo: Tempo,
     quantize: QuantizeMode,
     default_note_duration: DefaultNoteDuration,
@@ -46,6 +52,7 @@ impl Clocks {
             frame_clock: Clock::default(),
             quantize_clock: Clock::default(),
             sample_rate,
+            frame_rate: 60,
             tempo: Tempo::default(),
             quantize: QuantizeMode::default(),
             default_note_duration: DefaultNoteDuration::default(),
@@ -70,10 +77,34 @@ impl Clocks {
         self.sample_rate
     }
 
+    pub fn set_frame_rate(&mut self, frame_rate: u8) {
+        self.frame_rate = u64::from(frame_rate);
+    }
+
     pub fn tick_sample_clock(&mut self) {
         self.sample_clock.tick(1, u64::from(self.sample_rate));
     }
 
+    /// Returns the sample clock at the position (rounded up to a sample boundary).
+    pub fn sample_clock_at(&self, position: Duration) -> Clock {
+        let rate = u128::from(self.sample_rate);
+        let samples = (position.as_nanos() * rate).div_ceil(1_000_000_000);
+        Clock(Ratio::new(
+            u64::try_from(samples).unwrap_or(u64::MAX),
+            u64::from(self.sample_rate),
+        ))
+    }
+
+    /// Ticks the sample clock until it reaches the clock (by one sample at least),
+    /// and returns the number of the ticked samples.
+    pub fn skip_sample_clock_until(&mut self, clock: Clock) -> u64 {
+        let rate = u64::from(self.sample_rate);
+        let current = (self.sample_clock.0 * rate).to_integer();
+        let target = (clock.0 * rate).ceil().to_integer().max(current + 1);
+        self.sample_clock = Clock(Ratio::new(target, rate));
+        target - current
+    }
+
     pub fn tick_note_clock(&mut self, note_duration: NoteDuration) {
         self.quantize_clock = self.note_clock;
 
@@ -114,15 +145,14 @@ impl Clocks {
             }
             QuantizeMode::Frame(q) => {
                 self.quantize_clock.tick(numer, denom);
-                self.quantize_clock.reverse_tick(u64::from(q.get()), 60);
+                self.quantize_clock
+                    .reverse_tick(u64::from(q.get()), self.frame_rate);
             }
         }
     }
 
     pub fn tick_frame_clock_if_need(&mut self) -> bool {
-        let mut next_frame = self.frame_clock;
-        next_frame.tick(1, 60);
-
+        let next_frame = self.next_frame_clock();
         if self.sample_clock < next_frame {
             false
         } else {
@@ -132,6 +162,12 @@ impl Clocks {
         }
     }
 
+    pub fn next_frame_clock(&self) -> Clock {
+        let mut next_frame = self.frame_clock;
+        next_frame.tick(1, self.frame_rate);
+        next_frame
+    }
+
     pub fn reset_frame_clock(&mut self, clock: Clock) {
         self.frame_index = 0;
         self.frame_clock = clock;
@@ -157,6 +193,23 @@ impl Clocks {
         self.default_note_duration = default;
     }
 
+    /// Returns the tempo, the default note duration and the quantization
+    /// (the settings that determine the lengths of the notes).
+    #[cfg(feature = "nsf")]
+    pub fn settings(&self) -> (u8, u8, Option<u8>, Option<u8>) {
+        let (quantize, quantize_frame) = match self.quantize {
+            QuantizeMode::None => (None, None),
+            QuantizeMode::Normal(q) => (Some(q.get()), None),
+            QuantizeMode::Frame(q) => (None, Some(q.get())),
+        };
+        (
+            self.tempo.get(),
+            self.default_note_duration.get(),
+            quantize,
+            quantize_frame,
+        )
+    }
+
     pub fn set_tuplet(&mut self, note_count: usize, note_duration: NoteDuration) {
         if note_count == 0 {
             return;
@@ -1,12 +1,12 @@
 use crate::{
     macros::MacroNumber,
     types::{
-        DefaultNoteDuration, Detune, Int, Note, NoteDuration, Octave, PitchSweep, Quantize,
-        QuantizeFrame, Tempo, Timbre, Volume,
+        DefaultNoteDuration, Detune, Int, LinearCounter, Note, NoteDuration, NoteNumber, Octave,
+        Pan, Period, PitchSweep, Quantize, QuantizeFrame, Tempo, Timbre, Volume,
     },
 };
 use textparse::{
-    components::{Char, Digit, Either, NonEmpty, Not, Str},
+    components::{Char, Digit, Either, Maybe, NonEmpty, Not, Str},
     Parse, Span,
 };
 
@@ -14,11 +14,24 @@ use textparse::{
 #[parse(name = "command")]
 pub enum Command {
     Note(NoteCommand),
+    DirectNote(DirectNoteCommand),
+    DirectFrequency(DirectFrequencyCommand),
     Arpeggio(ArpeggioCommand),
     Volume(VolumeCommand),
     VolumeUp(VolumeUpCommand),
     VolumeDown(VolumeDownCommand),
     VolumeEnvelope(VolumeEnvelopeCommand),
+    HardwareEnvelope(HardwareEnvelopeCommand),
+    LengthCounter(LengthCounterCommand),
+    LinearCounter(LinearCounterCommand),
+    FdsModulation(FdsModulationCommand),
+    FdsMasterVolume(FdsMasterVolumeCommand),
+    N163WaveOffset(N163WaveOffsetCommand),
+    Sunsoft5bNoise(Sunsoft5bNoiseCommand),
+    Sunsoft5bEnvelope(Sunsoft5bEnvelopeCommand),
+    Vrc7Sustain(Vrc7SustainCommand),
+    Vrc7UserPatch(Vrc7UserPatchCommand),
+    Pan(PanCommand),
     Octave(OctaveCommand),
     OctaveUp(OctaveUpCommand),
     OctaveDown(OctaveDownCommand),
@@ -60,6 +73,46 @@ impl NoteCommand {
     }
 }
 
+#[derive(Debug, Clone, Span, Parse)]
+pub struct DirectNoteCommand {
+    _prefix: Char<'n'>,
+    number: NoteNumber,
+    _comma: Maybe<Char<','>>,
+    duration: NoteDuration,
+}
+
+impl DirectNoteCommand {
+    pub fn note(&self) -> Note {
+        self.number.note()
+    }
+
+    pub fn octave(&self) -> Option<Octave> {
+        self.number.octave()
+    }
+
+    pub fn note_duration(&self) -> NoteDuration {
+        self.duration
+    }
+}
+
+#[derive(Debug, Clone, Span, Parse)]
+pub struct DirectFrequencyCommand {
+    _prefix: Str<'@', 'n'>,
+    period: Period,
+    _comma: Maybe<Char<','>>,
+    duration: NoteDuration,
+}
+
+impl DirectFrequencyCommand {
+    pub fn period(&self) -> Period {
+        self.period
+    }
+
+    pub fn note_duration(&self) -> NoteDuration {
+        self.duration
+    }
+}
+
 #[derive(Debug, Clone, Span, Parse)]
 pub struct ArpeggioCommand {
     _prefix: Str<'E', 'N'>,
@@ -124,6 +177,176 @@ impl VolumeEnvelopeCommand {
     }
 }
 
+#[derive(Debug, Clone, Span, Parse)]
+pub struct HardwareEnvelopeCommand {
+    _prefix: Str<'H', 'E'>,
+    period: Either<Int<0, 15>, Off>,
+}
+
+impl HardwareEnvelopeCommand {
+    pub fn period(&self) -> Option<u8> {
+        if let Either::A(n) = self.period {
+            Some(n.get() as u8)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug, Clone, Span, Parse)]
+pub struct LengthCounterCommand {
+    _prefix: Str<'H', 'L'>,
+    index: Either<Int<0, 31>, Off>,
+}
+
+impl LengthCounterCommand {
+    pub fn index(&self) -> Option<u8> {
+        if let Either::A(n) = self.index {
+            Some(n.get() as u8)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug, Clone, Span, Parse)]
+pub struct LinearCounterCommand {
+    _prefix: Str<'H', 'T'>,
+    value: Either<Off, LinearCounter>,
+}
+
+impl LinearCounterCommand {
+    pub fn value(&self) -> Option<LinearCounter> {
+        if let Either::B(v) = self.value {
+            Some(v)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug, Clone, Span, Parse)]
+pub struct FdsModulationCommand {
+    _prefix: Str<'M', 'F'>,
+    args: Either<FdsModulationArgs, Off>,
+}
+
+impl FdsModulationCommand {
+    /// Returns the modulation table macro number, the depth and the speed.
+    pub fn args(&self) -> Option<(MacroNumber, u8, u16)> {
+        if let Either::A(a) = &self.args {
+            Some((a.macro_number, a.depth.get() as u8, a.speed.get() as u16))
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug, Clone, Span, Parse)]
+struct FdsModulationArgs {
+    macro_number: MacroNumber,
+    _comma0: Char<','>,
+    depth: Int<0, 63>,
+    _comma1: Char<','>,
+    speed: Int<0, 4095>,
+}
+
+#[derive(Debug, Clone, Span, Parse)]
+pub struct FdsMasterVolumeCommand {
+    _prefix: Str<'F', 'V'>,
+    level: Int<0, 3>,
+}
+
+impl FdsMasterVolumeCommand {
+    pub fn level(&self) -> u8 {
+        self.level.get() as u8
+    }
+}
+
+#[derive(Debug, Clone, Span, Parse)]
+pub struct N163WaveOffsetCommand {
+    _prefix: Str<'N', 'W'>,
+    offset: Int<0, 255>,
+}
+
+impl N163WaveOffsetCommand {
+    pub fn offset(&self) -> u8 {
+        self.offset.get() as u8
+    }
+}
+
+#[derive(Debug, Clone, Span, Parse)]
+pub struct Sunsoft5bNoiseCommand {
+    _prefix: Str<'S', 'N'>,
+    period: Int<0, 31>,
+}
+
+impl Sunsoft5bNoiseCommand {
+    pub fn period(&self) -> u8 {
+        self.period.get() as u8
+    }
+}
+
+#[derive(Debug, Clone, Span, Parse)]
+pub struct Sunsoft5bEnvelopeCommand {
+    _prefix: Str<'S', 'E'>,
+    args: Either<Sunsoft5bEnvelopeArgs, Off>,
+}
+
+impl Sunsoft5bEnvelopeCommand {
+    /// Returns the shape and the period.
+    pub fn args(&self) -> Option<(u8, u16)> {
+        if let Either::A(a) = &self.args {
+            Some((a.shape.get() as u8, a.period.get() as u16))
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug, Clone, Span, Parse)]
+struct Sunsoft5bEnvelopeArgs {
+    shape: Int<0, 15>,
+    _comma: Char<','>,
+    period: Int<0, 65535>,
+}
+
+#[derive(Debug, Clone, Span, Parse)]
+pub struct Vrc7SustainCommand {
+    _prefix: Str<'S', 'U'>,
+    sustain: Int<0, 1>,
+}
+
+impl Vrc7SustainCommand {
+    pub fn sustain(&self) -> bool {
+        self.sustain.get() == 1
+    }
+}
+
+#[derive(Debug, Clone, Span, Parse)]
+pub struct Vrc7UserPatchCommand {
+    _prefix: Str<'U', 'P'>,
+    macro_number: MacroNumber,
+}
+
+impl Vrc7UserPatchCommand {
+    pub fn macro_number(&self) -> MacroNumber {
+        self.macro_number
+    }
+}
+
+#[derive(Debug, Clone, Span, Parse)]
+pub struct PanCommand {
+    _prefix: Char<'p'>,
+    pan: Pan,
+}
+
+impl PanCommand {
+    pub fn pan(&self) -> Pan {
+        self.pan
+    }
+}
+
 #[derive(Debug, Clone, Span, Parse)]
 pub struct VolumeUpCommand {
     _prefix: Str<'v', '+'>,
@@ -0,0 +1,199 @@
+//! Command cursor: the order and the timing of the commands of a channel (shared by the player and the exporters).
+use crate::{
+    clocks::Clocks,
+    commands::{
+        Command, NoteCommand, RepeatEndCommand, RepeatStartCommand, SlurCommand, TieCommand,
+        TupletEndCommand, TupletStartCommand,
+    },
+    player::PlayMusicError,
+};
+use std::sync::Arc;
+
+/// Cursor over the commands of a channel.
+///
+/// It handles the commands that change the order of the commands or the timing of the notes
+/// (repeats, tuplets, ties and slurs), so that [`MusicPlayer`](crate::MusicPlayer), [`Music::analyze()`](crate::Music::analyze)
+/// and the MIDI exporter interpret them in the same way.
+#[derive(Debug, Clone)]
+pub struct CommandCursor {
+    commands: Arc<Vec<Command>>,
+    index: usize,
+    repeat_stack: Vec<Repeat>,
+}
+
+impl CommandCursor {
+    pub fn new(commands: Arc<Vec<Command>>) -> Self {
+        Self {
+            commands,
+            index: 0,
+            repeat_stack: Vec::new(),
+        }
+    }
+
+    /// Returns a new cursor at the first command.
+    pub fn rewound(&self) -> Self {
+        Self::new(Arc::clone(&self.commands))
+    }
+
+    #[cfg(feature = "nsf")]
+    pub fn commands(&self) -> &[Command] {
+        &self.commands
+    }
+
+    /// Returns the index of the next command.
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// Moves to the command at `index` (e.g., the loop point).
+    pub fn jump(&mut self, index: usize) {
+        self.index = index;
+    }
+
+    /// Moves to the end of the commands (`!`).
+    pub fn skip_to_end(&mut self) {
+        self.index = self.commands.len();
+    }
+
+    /// Returns the next command and advances the cursor, or `None` at the end of the commands.
+    pub fn next_command(&mut self) -> Option<Command> {
+        let command = self.commands.get(self.index).cloned()?;
+        self.index += 1;
+        Some(command)
+    }
+
+    /// Starts a repeat (`[`) just after the command.
+    pub fn repeat_start(&mut self, command: RepeatStartCommand) -> Result<(), PlayMusicError> {
+        let mut stack_size: isize = 1;
+        for command in &self.commands[self.index..] {
+            match command {
+                Command::RepeatStart(_) => stack_size += 1,
+                Command::RepeatEnd(_) => {
+                    stack_size -= 1;
+                    if stack_size == 0 {
+                        break;
+                    }
+                }
+                Command::DataSkip(_) => break,
+                _ => {}
+            }
+        }
+        if stack_size > 0 {
+            return Err(PlayMusicError::new(command, "no maching ']'"));
+        }
+
+        self.repeat_stack.push(Repeat {
+            start_index: self.index,
+            count: 1,
+        });
+        Ok(())
+    }
+
+    /// Jumps back to the start of the repeat (`]`) until it has been played `count` times.
+    pub fn repeat_end(&mut self, command: RepeatEndCommand) -> Result<(), PlayMusicError> {
+        let Some(mut repeat) = self.repeat_stack.pop() else {
+            return Err(PlayMusicError::new(command, "no maching '['"));
+        };
+        if repeat.count < command.count() {
+            self.index = repeat.start_index;
+            repeat.count += 1;
+            self.repeat_stack.push(repeat);
+        }
+        Ok(())
+    }
+
+    /// Divides the duration of the tuplet (`{...}`) among its notes and rests.
+    pub fn tuplet_start(
+        &self,
+        command: TupletStartCommand,
+        clocks: &mut Clocks,
+    ) -> Result<(), PlayMusicError> {
+        let mut note_count = 0;
+        for c in &self.commands[self.index..] {
+            match c {
+                Command::TupletStart(_) => {
+                    return Err(PlayMusicError::new(command, "nested tuplet"));
+                }
+                Command::TupletEnd(c) => {
+                    clocks.set_tuplet(note_count, c.note_duration());
+                    return Ok(());
+                }
+                Command::DataSkip(_) | Command::RepeatStart(_) | Command::RepeatEnd(_) => break,
+                Command::Note(_)
+                | Command::DirectNote(_)
+                | Command::DirectFrequency(_)
+                | Command::RestSign(_)
+                | Command::Wait(_)
+                | Command::Tie(_)
+                | Command::Slur(_) => {
+                    note_count += 1;
+                }
+                _ => {}
+            }
+        }
+        Err(PlayMusicError::new(command, "no maching '}'"))
+    }
+
+    pub fn tuplet_end(&self, command: TupletEndCommand) -> Result<(), PlayMusicError> {
+        for c in self.commands[..self.index - 1].iter().rev() {
+            match c {
+                Command::TupletStart(_) => {
+                    return Ok(());
+                }
+                Command::TupletEnd(_) => {
+                    break;
+                }
+                _ => {}
+            }
+        }
+        Err(PlayMusicError::new(command, "no maching '{'"))
+    }
+
+    /// Checks that the tie (`^`) follows a note.
+    pub fn tie(&self, command: &TieCommand) -> Result<(), PlayMusicError> {
+        if !matches!(
+            self.commands[self.index.saturating_sub(2)],
+            Command::Note(_) | Command::DirectNote(_) | Command::DirectFrequency(_)
+        ) {
+            return Err(PlayMusicError::new(
+                command,
+                "'^' must follow a note command",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Takes the note after the slur (`&`), whose duration extends the previous note.
+    pub fn slur(&mut self, command: SlurCommand) -> Result<NoteCommand, PlayMusicError> {
+        let Command::Note(before) = &self.commands[self.index.saturating_sub(2)] else {
+            return Err(PlayMusicError::new(
+                command,
+                "'&' must follow a note command",
+            ));
+        };
+
+        let Some(Command::Note(after)) = self.commands.get(self.index) else {
+            return Err(PlayMusicError::new(
+                command,
+                "mssing a note command after '&'",
+            ));
+        };
+
+        if before.note().normalize() != after.note().normalize() {
+            return Err(PlayMusicError::new(
+                command,
+                "'&' cannot combine different notes",
+            ));
+        }
+
+        let after = after.clone();
+        self.index += 1;
+        Ok(after)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Repeat {
+    start_index: usize,
+    count: usize,
+}
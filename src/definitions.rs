@@ -1,7 +1,7 @@
 use crate::{
     channel::ChannelNames,
     comment::{Comment, MaybeComment},
-    types::OscillatorKind,
+    types::{self, OscillatorKind, QuotedString},
 };
 use std::marker::PhantomData;
 use textparse::{
@@ -15,6 +15,9 @@ pub enum Definition {
     Composer(Composer),
     Programer(Programer),
     Channel(Channel),
+    Include(Include),
+    OctaveRev(OctaveRev),
+    Region(Region),
 }
 
 #[derive(Debug, Clone, Span, Parse)]
@@ -47,6 +50,33 @@ impl Programer {
     }
 }
 
+#[derive(Debug, Clone, Span, Parse)]
+#[parse(name = "#OCTAVE-REV")]
+pub struct OctaveRev(
+    (
+        Char<'#'>,
+        Str<'O', 'C', 'T', 'A', 'V', 'E', '-', 'R', 'E', 'V'>,
+    ),
+);
+
+#[derive(Debug, Clone, Span, Parse)]
+#[parse(name = "#REGION")]
+pub struct Region {
+    _prefix: (Char<'#'>, Str<'R', 'E', 'G', 'I', 'O', 'N'>),
+    _space: NonEmpty<While<SpaceOrTabOrComment>>,
+    region: OneOfThree<Str<'N', 'T', 'S', 'C'>, Str<'P', 'A', 'L'>, Str<'D', 'E', 'N', 'D', 'Y'>>,
+}
+
+impl Region {
+    pub fn get(&self) -> types::Region {
+        match self.region {
+            OneOfThree::A(_) => types::Region::Ntsc,
+            OneOfThree::B(_) => types::Region::Pal,
+            OneOfThree::C(_) => types::Region::Dendy,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Span)]
 pub struct Channel {
     start: Position,
@@ -88,6 +118,20 @@ impl Parse for Channel {
     }
 }
 
+#[derive(Debug, Clone, Span, Parse)]
+#[parse(name = "#INCLUDE")]
+pub struct Include {
+    _prefix: (Char<'#'>, Str<'I', 'N', 'C', 'L', 'U', 'D', 'E'>),
+    _space: NonEmpty<While<SpaceOrTabOrComment>>,
+    path: QuotedString,
+}
+
+impl Include {
+    pub fn path(&self) -> &str {
+        self.path.get()
+    }
+}
+
 #[derive(Debug, Clone, Span)]
 struct DefineString<T> {
     start: Position,
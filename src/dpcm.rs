@@ -0,0 +1,378 @@
+//! DPCM: 1-bit delta modulation samples played by the DMC channel.
+use crate::{Region, Sample};
+use std::io::{Error, ErrorKind};
+
+/// Periods (in CPU cycles) of the DMC channel output for the 16 rate indexes (NTSC).
+pub const NTSC_RATE_TABLE: [u16; 16] = [
+    428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54,
+];
+
+/// Periods (in CPU cycles) of the DMC channel output for the 16 rate indexes (PAL).
+pub const PAL_RATE_TABLE: [u16; 16] = [
+    398, 354, 316, 298, 276, 236, 210, 198, 176, 148, 132, 118, 98, 78, 66, 50,
+];
+
+/// Returns the rate table of the region ([`PAL_RATE_TABLE`] for PAL, otherwise [`NTSC_RATE_TABLE`]).
+pub fn rate_table(region: Region) -> &'static [u16; 16] {
+    if region == Region::Pal {
+        &PAL_RATE_TABLE
+    } else {
+        &NTSC_RATE_TABLE
+    }
+}
+
+/// Initial value of the DMC output level (`0..=127`).
+pub const INITIAL_LEVEL: u8 = 64;
+
+/// Returns the number of delta bits played per second at the given rate index in the region.
+///
+/// # Panics
+///
+/// Panics if `rate_index` is greater than `15`.
+pub fn bit_rate(region: Region, rate_index: u8) -> f32 {
+    region.cpu_clock_hz() / f32::from(rate_table(region)[usize::from(rate_index)])
+}
+
+/// Encodes PCM samples into DMC data that is played at the given rate index in the region.
+///
+/// The input samples are resampled to [`bit_rate(region, rate_index)`](bit_rate) and
+/// the result is padded to the length that the DMC channel can play (`16 * N + 1` bytes).
+///
+/// # Panics
+///
+/// Panics if `rate_index` is greater than `15`, or `sample_rate` is `0`.
+pub fn encode(samples: &[Sample], sample_rate: u32, region: Region, rate_index: u8) -> Vec<u8> {
+    assert_ne!(sample_rate, 0);
+
+    let step = f64::from(sample_rate) / f64::from(bit_rate(region, rate_index));
+    let bits = (samples.len() as f64 / step).floor() as usize;
+
+    let mut data = Vec::with_capacity(bits / 8 + 1);
+    let mut level = INITIAL_LEVEL;
+    let mut byte = 0;
+    for i in 0..bits {
+        let s = samples[((i as f64 * step) as usize).min(samples.len() - 1)];
+        let target = ((s.get() + 1.0) * 63.5).round() as u8;
+        if target > level {
+            byte |= 1 << (i % 8);
+            if level <= 125 {
+                level += 2;
+            }
+        } else if level >= 2 {
+            level -= 2;
+        }
+        if i % 8 == 7 {
+            data.push(byte);
+            byte = 0;
+        }
+    }
+    if !bits.is_multiple_of(8) {
+        // Fills the remaining bits with alternating deltas to keep the output level.
+        for i in bits % 8..8 {
+            byte |= (i as u8 % 2) << i;
+        }
+        data.push(byte);
+    }
+    while data.len() % 16 != 1 {
+        data.push(0b1010_1010);
+    }
+    data
+}
+
+/// Converts a WAV file (linear PCM, 8 or 16 bits per sample) into DMC data.
+///
+/// Multiple channels are mixed down to mono before encoding by [`encode()`].
+///
+/// # Panics
+///
+/// Panics if `rate_index` is greater than `15`.
+pub fn wav_to_dmc(wav: &[u8], region: Region, rate_index: u8) -> std::io::Result<Vec<u8>> {
+    let (samples, sample_rate) = decode_wav(wav)?;
+    Ok(encode(&samples, sample_rate, region, rate_index))
+}
+
+/// Encodes PCM samples into raw 8-bit PCM data for the MMC5 PCM channel.
+///
+/// The input samples are resampled to one byte per period of the rate index (the same rate as
+/// [`bit_rate(region, rate_index)`](bit_rate)). As `0` is ignored when written to the channel, the values are within `1..=255`.
+///
+/// # Panics
+///
+/// Panics if `rate_index` is greater than `15`, or `sample_rate` is `0`.
+pub fn encode_pcm(samples: &[Sample], sample_rate: u32, region: Region, rate_index: u8) -> Vec<u8> {
+    assert_ne!(sample_rate, 0);
+
+    let step = f64::from(sample_rate) / f64::from(bit_rate(region, rate_index));
+    let len = (samples.len() as f64 / step).floor() as usize;
+    (0..len)
+        .map(|i| {
+            let s = samples[((i as f64 * step) as usize).min(samples.len() - 1)];
+            ((s.get() + 1.0) * 127.5).round().clamp(1.0, 255.0) as u8
+        })
+        .collect()
+}
+
+/// Converts a WAV file (linear PCM, 8 or 16 bits per sample) into raw 8-bit PCM data by [`encode_pcm()`].
+///
+/// # Panics
+///
+/// Panics if `rate_index` is greater than `15`.
+pub fn wav_to_pcm(wav: &[u8], region: Region, rate_index: u8) -> std::io::Result<Vec<u8>> {
+    let (samples, sample_rate) = decode_wav(wav)?;
+    Ok(encode_pcm(&samples, sample_rate, region, rate_index))
+}
+
+/// Decodes DMC data into raw 8-bit PCM data (one byte per delta bit, starting from [`INITIAL_LEVEL`]).
+pub fn dmc_to_pcm(dmc: &[u8]) -> Vec<u8> {
+    let mut level = INITIAL_LEVEL;
+    let mut pcm = Vec::with_capacity(dmc.len() * 8);
+    for i in 0..dmc.len() * 8 {
+        if (dmc[i / 8] >> (i % 8)) & 1 == 1 {
+            if level <= 125 {
+                level += 2;
+            }
+        } else if level >= 2 {
+            level -= 2;
+        }
+        pcm.push((level * 2).max(1));
+    }
+    pcm
+}
+
+/// Decodes a WAV file into mono samples and its sample rate.
+fn decode_wav(wav: &[u8]) -> std::io::Result<(Vec<Sample>, u32)> {
+    let invalid = |reason: &str| Error::new(ErrorKind::InvalidData, reason.to_owned());
+    if wav.len() < 12 || &wav[0..4] != b"RIFF" || &wav[8..12] != b"WAVE" {
+        return Err(invalid("not a WAV file"));
+    }
+
+    let mut fmt = None;
+    let mut data = None;
+    let mut chunks = &wav[12..];
+    while chunks.len() >= 8 {
+        let size = u32::from_le_bytes([chunks[4], chunks[5], chunks[6], chunks[7]]) as usize;
+        let body = chunks
+            .get(8..8 + size)
+            .ok_or_else(|| invalid("truncated WAV chunk"))?;
+        match &chunks[0..4] {
+            b"fmt " if size >= 16 => fmt = Some(body),
+            b"data" => data = Some(body),
+            _ => {}
+        }
+        chunks = chunks.get(8 + size + size % 2..).unwrap_or(&[]);
+    }
+    let fmt = fmt.ok_or_else(|| invalid("missing WAV fmt chunk"))?;
+    let data = data.ok_or_else(|| invalid("missing WAV data chunk"))?;
+
+    let format = u16::from_le_bytes([fmt[0], fmt[1]]);
+    let channels = usize::from(u16::from_le_bytes([fmt[2], fmt[3]]));
+    let sample_rate = u32::from_le_bytes([fmt[4], fmt[5], fmt[6], fmt[7]]);
+    let bits_per_sample = u16::from_le_bytes([fmt[14], fmt[15]]);
+    if format != 1 || channels == 0 || sample_rate == 0 {
+        return Err(invalid("unsupported WAV format"));
+    }
+
+    let values = match bits_per_sample {
+        8 => data
+            .iter()
+            .map(|&v| (f32::from(v) - 128.0) / 128.0)
+            .collect::<Vec<_>>(),
+        16 => data
+            .chunks_exact(2)
+            .map(|v| f32::from(i16::from_le_bytes([v[0], v[1]])) / 32768.0)
+            .collect::<Vec<_>>(),
+        _ => return Err(invalid("unsupported WAV bits per sample")),
+    };
+    let samples = values
+        .chunks_exact(channels)
+        .map(|frame| Sample::new(frame.iter().sum::<f32>() / channels as f32))
+        .collect::<Vec<_>>();
+    Ok((samples, sample_rate))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Music, ParseMusicError};
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn encode_pads_data_to_playable_length() {
+        for len in [0, 1, 100, 1000, 5000] {
+            let data = encode(&vec![Sample::ZERO; len], 44100, Region::Ntsc, 15);
+            assert_eq!(data.len() % 16, 1, "len={len}");
+        }
+    }
+
+    #[test]
+    fn encode_follows_input_level() {
+        // A rising ramp from the minimum to the maximum level (at the bit rate).
+        let bits = 1024;
+        let samples = (0..bits)
+            .map(|i| Sample::new(i as f32 / bits as f32 * 2.0 - 1.0))
+            .collect::<Vec<_>>();
+        let rate = bit_rate(Region::Ntsc, 15).round() as u32;
+        let data = encode(&samples, rate, Region::Ntsc, 15);
+
+        let decoded = dmc_to_pcm(&data);
+        for (i, s) in samples.iter().enumerate().skip(64) {
+            let expected = (s.get() + 1.0) * 127.5;
+            let actual = f32::from(decoded[i]);
+            assert!(
+                (expected - actual).abs() <= 8.0,
+                "i={i}: expected={expected}, actual={actual}"
+            );
+        }
+    }
+
+    #[test]
+    fn encode_keeps_silence_at_initial_level() {
+        let data = encode(&[Sample::ZERO; 4096], 48000, Region::Ntsc, 0);
+        let decoded = dmc_to_pcm(&data);
+        assert!(decoded.iter().all(|&v| v.abs_diff(INITIAL_LEVEL * 2) <= 4));
+    }
+
+    #[test]
+    fn wav_to_dmc_decodes_8_and_16_bit_wav() {
+        let samples = (0..2000)
+            .map(|i| Sample::new((i as f32 / 20.0).sin() * 0.5))
+            .collect::<Vec<_>>();
+        let pcm16 = samples
+            .iter()
+            .flat_map(|s| ((s.get() * 32767.0) as i16).to_le_bytes())
+            .collect::<Vec<_>>();
+        let pcm8 = samples
+            .iter()
+            .map(|s| (s.get() * 127.0 + 128.0) as u8)
+            .collect::<Vec<_>>();
+
+        let expected = encode(&samples, 22050, Region::Ntsc, 8);
+        let dmc16 = wav_to_dmc(&wav(1, 22050, 16, &pcm16), Region::Ntsc, 8).expect("16-bit WAV");
+        let dmc8 = wav_to_dmc(&wav(1, 22050, 8, &pcm8), Region::Ntsc, 8).expect("8-bit WAV");
+        assert_eq!(dmc16.len(), expected.len());
+        assert_eq!(dmc8.len(), expected.len());
+        // Only the quantization errors of the WAV samples flip a few bits.
+        assert!(differing_bits(&dmc16, &expected) * 100 < expected.len() * 8);
+        assert!(differing_bits(&dmc8, &expected) * 100 < expected.len() * 8);
+    }
+
+    #[test]
+    fn wav_to_dmc_mixes_channels_down() {
+        // Left and right cancel each other out.
+        let stereo = (0..1000)
+            .flat_map(|i| {
+                let v = if i % 50 < 25 { 16000i16 } else { -16000 };
+                [v.to_le_bytes(), (-v).to_le_bytes()]
+            })
+            .flatten()
+            .collect::<Vec<_>>();
+        let dmc = wav_to_dmc(&wav(2, 44100, 16, &stereo), Region::Ntsc, 15).expect("stereo WAV");
+        assert_eq!(dmc, encode(&[Sample::ZERO; 1000], 44100, Region::Ntsc, 15));
+    }
+
+    #[test]
+    fn wav_to_dmc_rejects_unsupported_files() {
+        assert!(wav_to_dmc(b"not a wav file", Region::Ntsc, 0).is_err());
+        assert!(wav_to_dmc(&wav(1, 44100, 24, &[0; 30]), Region::Ntsc, 0).is_err());
+        assert!(wav_to_dmc(&wav(1, 0, 16, &[0; 30]), Region::Ntsc, 0).is_err());
+    }
+
+    #[test]
+    fn dpcm_macro_loads_dmc_and_wav_files() {
+        let dmc = vec![0b1111_0000; 17];
+        let pcm = [0u8, 0, 0, 64].repeat(256);
+        let mut files = BTreeMap::new();
+        files.insert("kick.dmc", dmc.clone());
+        files.insert("snare.wav", wav(1, 8000, 16, &pcm));
+
+        let music = Music::with_resolver(
+            "#CHANNEL E 3\n@DPCM0 = { \"kick.dmc\", 15 }\n@DPCM1 = { \"snare.wav\" , 3 }\nE o2 c c+",
+            files,
+        )
+        .unwrap_or_else(|e| panic!("{e}"));
+        let macros = music.macros();
+        let dpcms = macros.dpcms.values().collect::<Vec<_>>();
+        assert_eq!(dpcms.len(), 2);
+        assert_eq!(dpcms[0].pitch(), 15);
+        assert_eq!(*dpcms[0].data(), dmc);
+        assert_eq!(dpcms[1].pitch(), 3);
+        assert_eq!(
+            *dpcms[1].data(),
+            wav_to_dmc(&wav(1, 8000, 16, &pcm), Region::Ntsc, 3).expect("WAV")
+        );
+    }
+
+    #[test]
+    fn wav_is_resampled_at_region_bit_rate() {
+        // One second of silence is encoded into the delta bits played in one second.
+        let pcm = [0u8; 8000 * 2];
+        for region in [Region::Ntsc, Region::Pal, Region::Dendy] {
+            let dmc = wav_to_dmc(&wav(1, 8000, 16, &pcm), region, 15).expect("WAV");
+            let bits = (dmc.len() * 8) as f32;
+            assert!(
+                (bits - bit_rate(region, 15)).abs() <= 16.0 * 8.0,
+                "{region:?}: {bits}"
+            );
+        }
+        assert!(bit_rate(Region::Pal, 15) > bit_rate(Region::Ntsc, 15));
+
+        let files = BTreeMap::from([("a.wav", wav(1, 8000, 16, &pcm))]);
+        let music = Music::with_resolver(
+            "#REGION PAL
+#CHANNEL E 3
+@DPCM0 = { \"a.wav\", 15 }",
+            files,
+        )
+        .unwrap_or_else(|e| panic!("{e}"));
+        let macros = music.macros();
+        let dpcm = macros.dpcms.values().next().expect("@DPCM0");
+        assert_eq!(
+            *dpcm.data(),
+            wav_to_dmc(&wav(1, 8000, 16, &pcm), Region::Pal, 15).expect("WAV")
+        );
+        assert_eq!(
+            *dpcm.pcm(),
+            wav_to_pcm(&wav(1, 8000, 16, &pcm), Region::Pal, 15).expect("WAV")
+        );
+    }
+
+    #[test]
+    fn dpcm_macro_errors() {
+        let error = |mml: &str| -> ParseMusicError {
+            Music::with_resolver(mml, BTreeMap::<&str, Vec<u8>>::new()).expect_err("should fail")
+        };
+        let e = error("@DPCM0 = { \"missing.dmc\", 15 }");
+        assert!(
+            e.to_string().contains("failed to load \"missing.dmc\""),
+            "{e}"
+        );
+        assert!(Music::new("@DPCM0 = { \"a.dmc\", 16 }").is_err());
+        assert!(Music::new("@DPCM128 = { \"a.dmc\", 0 }").is_err());
+    }
+
+    fn differing_bits(a: &[u8], b: &[u8]) -> usize {
+        a.iter()
+            .zip(b)
+            .map(|(a, b)| (a ^ b).count_ones() as usize)
+            .sum()
+    }
+
+    fn wav(channels: u16, sample_rate: u32, bits_per_sample: u16, data: &[u8]) -> Vec<u8> {
+        let block_size = channels * bits_per_sample / 8;
+        let mut wav = Vec::new();
+        wav.extend_from_slice(b"RIFF");
+        wav.extend_from_slice(&(36 + data.len() as u32).to_le_bytes());
+        wav.extend_from_slice(b"WAVEfmt ");
+        wav.extend_from_slice(&16u32.to_le_bytes());
+        wav.extend_from_slice(&1u16.to_le_bytes());
+        wav.extend_from_slice(&channels.to_le_bytes());
+        wav.extend_from_slice(&sample_rate.to_le_bytes());
+        wav.extend_from_slice(&(sample_rate * u32::from(block_size)).to_le_bytes());
+        wav.extend_from_slice(&block_size.to_le_bytes());
+        wav.extend_from_slice(&bits_per_sample.to_le_bytes());
+        wav.extend_from_slice(b"data");
+        wav.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        wav.extend_from_slice(data);
+        wav
+    }
+}
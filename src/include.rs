@@ -0,0 +1,93 @@
+use std::{
+    borrow::Borrow,
+    collections::{BTreeMap, HashMap},
+    hash::Hash,
+    io::{Error, ErrorKind},
+    path::{Path, PathBuf},
+};
+
+/// This trait allows for resolving the paths of external files referred by an MML script.
+///
+/// The paths are specified by `#INCLUDE "<PATH>"` directives and `@DPCM<n> = { "<PATH>", <PITCH> }` macros.
+pub trait IncludeResolver {
+    /// Returns the content of the MML script located at the given path.
+    fn resolve(&mut self, path: &str) -> std::io::Result<String>;
+
+    /// Returns the content of the binary file (e.g., a DPCM sample) located at the given path.
+    ///
+    /// The default implementation fails with [`ErrorKind::Unsupported`].
+    fn resolve_bytes(&mut self, path: &str) -> std::io::Result<Vec<u8>> {
+        let _ = path;
+        Err(Error::new(
+            ErrorKind::Unsupported,
+            "binary files are not supported by this resolver",
+        ))
+    }
+}
+
+impl<K: Borrow<str> + Ord, V: AsRef<[u8]>> IncludeResolver for BTreeMap<K, V> {
+    fn resolve(&mut self, path: &str) -> std::io::Result<String> {
+        self.resolve_bytes(path).and_then(into_string)
+    }
+
+    fn resolve_bytes(&mut self, path: &str) -> std::io::Result<Vec<u8>> {
+        self.get(path)
+            .map(|v| v.as_ref().to_owned())
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, "no such file"))
+    }
+}
+
+impl<K: Borrow<str> + Eq + Hash, V: AsRef<[u8]>> IncludeResolver for HashMap<K, V> {
+    fn resolve(&mut self, path: &str) -> std::io::Result<String> {
+        self.resolve_bytes(path).and_then(into_string)
+    }
+
+    fn resolve_bytes(&mut self, path: &str) -> std::io::Result<Vec<u8>> {
+        self.get(path)
+            .map(|v| v.as_ref().to_owned())
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, "no such file"))
+    }
+}
+
+/// Returns `path` relative to the directory of the including script (`base_path`).
+///
+/// Absolute paths and paths in the root script (`base_path` is `None`) are returned as is.
+pub(crate) fn relative_path(base_path: Option<&str>, path: &str) -> String {
+    match base_path.and_then(|p| Path::new(p).parent()) {
+        Some(dir) if !Path::new(path).is_absolute() => {
+            dir.join(path).to_string_lossy().into_owned()
+        }
+        _ => path.to_owned(),
+    }
+}
+
+fn into_string(bytes: Vec<u8>) -> std::io::Result<String> {
+    String::from_utf8(bytes).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+}
+
+/// [`IncludeResolver`] implementation that reads files from the file system.
+#[derive(Debug, Clone)]
+pub struct FileResolver {
+    root_dir: PathBuf,
+}
+
+impl FileResolver {
+    /// Makes a new [`FileResolver`] instance.
+    ///
+    /// Relative paths are resolved from `root_dir`.
+    pub fn new<P: AsRef<Path>>(root_dir: P) -> Self {
+        Self {
+            root_dir: root_dir.as_ref().to_path_buf(),
+        }
+    }
+}
+
+impl IncludeResolver for FileResolver {
+    fn resolve(&mut self, path: &str) -> std::io::Result<String> {
+        std::fs::read_to_string(self.root_dir.join(path))
+    }
+
+    fn resolve_bytes(&mut self, path: &str) -> std::io::Result<Vec<u8>> {
+        std::fs::read(self.root_dir.join(path))
+    }
+}
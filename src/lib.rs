@@ -4,15 +4,73 @@
 //! But there are (known) differences between FFMML and MCK as follows:
 //!
 //! - FFMML doesn't support the following features:
-//!   - `#INCLUDE` directive
-//!   - `#OCTAVE-REV` directive
-//!   - `@n` command (direct frequency select)
-//!   - `n` command (direct note select)
 //!   - `y` command (direct memory entry)
-//!   - DPCM channel
 //! - FFMML features `#CHANNEL <CHANNEL_NAME> <OSCILLATOR>` directive that defines custom channels:
 //!   - `<CHANNEL_NAME>`: `A..=Z`
-//!   - `<OSCILLATOR>`: `1` (pulse wave), `2` (triangle wave), or `3` (noise)
+//!   - `<OSCILLATOR>`: `0` (pulse wave), `1` (triangle wave), `2` (noise), `3` (DPCM),
+//!     `4` (VRC6 pulse wave), `5` (VRC6 sawtooth), `6` (FDS), `7` (N163), `8` (Sunsoft 5B), `9` (VRC7),
+//!     `10` (MMC5 pulse wave), or `11` (MMC5 PCM)
+//!   - VRC6 pulse wave channels accept `@0..=@7` timbres (duty cycle `(n + 1) / 16`)
+//!   - The volume of VRC6 sawtooth channels (`v0..=v15`) is scaled to the accumulator rate (`0..=42`)
+//! - FDS waveforms are defined by `@FM<n> = { <SAMPLE>... }` macros (64 samples of `0..=63`)
+//!   - On an FDS channel, `@<n>` selects `@FM<n>` (`@0..=@127`)
+//! - FDS modulation tables are defined by `@FMOD<n> = { <CODE>... }` macros (32 codes of `0..=7`):
+//!   - `0`: `+0`, `1`: `+1`, `2`: `+2`, `3`: `+4`, `4`: reset, `5`: `-4`, `6`: `-2`, `7`: `-1` (each code is used twice)
+//! - `MF<TABLE>,<DEPTH>,<SPEED>` command enables the modulation unit of FDS channels:
+//!   - `<TABLE>`: `@FMOD<n>` macro number, `<DEPTH>`: `0..=63`, `<SPEED>`: `0..=4095` (`$4086/$4087`)
+//!   - `MFOF` (or `MF255`) disables it
+//! - `FV<LEVEL>` command sets the master volume of FDS channels (`0`: 100%, `1`: 66%, `2`: 50%, `3`: 40%)
+//! - N163 waves are defined by `@N<n> = { <SAMPLE>... }` macros (`4..=256` samples of `0..=15`, a multiple of 4)
+//!   - On an N163 channel, `@<n>` writes `@N<n>` into the sound RAM (256 samples shared by all N163 channels)
+//!   - Up to 8 N163 channels can be defined, and they are time-multiplexed:
+//!     the more channels, the lower the update rate and the output level of each channel
+//!   - The registers of `N` channels occupy the last `16 * N` samples of the sound RAM, which cannot hold waves
+//! - `NW<OFFSET>` command sets the address (`0..=255`) of the wave of an N163 channel in the sound RAM (default: `0`)
+//! - Up to 3 Sunsoft 5B channels can be defined, and they share the noise and envelope generators:
+//!   - `@0` (tone), `@1` (noise), or `@2` (tone and noise) timbres
+//!   - The volume (`v0..=v15`) is logarithmic (3 dB per step, `v0` is silent)
+//! - `SN<PERIOD>` command sets the period (`0..=31`) of the Sunsoft 5B noise generator
+//! - `SE<SHAPE>,<PERIOD>` command enables the envelope generator of a Sunsoft 5B channel instead of the volume:
+//!   - `<SHAPE>`: `0..=15` (the AY-3-8910 envelope shapes), `<PERIOD>`: `0..=65535` (a ramp takes `512 * <PERIOD>` CPU cycles)
+//!   - The envelope restarts at each note, and `SEOF` (or `SE255`) disables it
+//! - Up to 6 VRC7 FM channels can be defined:
+//!   - `@1..=@15` timbres select the built-in patches, and `@0` selects the user patch
+//!   - Notes key on the channel, and rests (or `q`) key it off, so the sound fades out by the release rate of the patch
+//!   - The volume (`v0..=v15`) attenuates the carrier by 3 dB per step (`v0` is -45 dB)
+//! - VRC7 user patches are defined by `@OPLL<n> = { <BYTE>... }` macros (the 8 bytes of the registers `$00..=$07`)
+//! - `UP<n>` command loads `@OPLL<n>` into the user patch (shared by all VRC7 channels)
+//! - `SU<0|1>` command sets the sustain flag of a VRC7 channel (`SU1` slows down the release)
+//! - MMC5 pulse wave channels are the same as pulse wave channels except that they have no sweep unit,
+//!   so `s` command (pitch sweep) is rejected on them
+//! - DPCM samples are defined by `@DPCM<n> = { "<PATH>", <PITCH> }` macros:
+//!   - `<PATH>`: a DMC file, or a WAV file that is converted to DMC on loading
+//!     (resampled at the DMC rate of the `#REGION` defined before the macro)
+//!   - `<PITCH>`: `0..=15` (rate index)
+//!   - On a DPCM channel, `o2 c` plays `@DPCM0`, `o2 c+` plays `@DPCM1`, and so on
+//!   - On an MMC5 PCM channel, notes select the samples in the same way, and they are played as raw 8-bit PCM
+//!     (one byte per period of `<PITCH>`)
+//! - `n<NOTE_NUMBER>[,<LENGTH>]` command (direct note select) only accepts `24..=95` (`n48` is `o4 c`)
+//! - `@n<PERIOD>[,<LENGTH>]` command (direct frequency select) accepts the following period register values:
+//!   - pulse wave: `8..=2047`, triangle wave: `2..=2047`, noise: `0..=15`, VRC6 pulse wave and sawtooth: `1..=4095`,
+//!     FDS: `0..=4095`, N163: `0..=4095` (the upper 12 bits of the 18-bit frequency register), Sunsoft 5B: `1..=4095`,
+//!     VRC7: `0..=4095` (the block in the upper 3 bits and the F-number in the lower 9 bits), MMC5 pulse wave: `0..=2047`
+//! - `HE<PERIOD>` command enables the hardware volume envelope (decay) of pulse wave and noise channels:
+//!   - `<PERIOD>`: `0..=15` (the envelope decays from 15 to 0 every `<PERIOD> + 1` quarter frames)
+//!   - `HEOF` (or `HE255`) disables it and the constant volume (`v`, `@v`) is used again
+//! - `HL<INDEX>` command loads the hardware length counter of pulse wave and noise channels at each note:
+//!   - `<INDEX>`: `0..=31` (index of the APU length counter table, e.g., `HL8` is 160 half frames)
+//!   - `HLOF` (or `HL255`) halts the counter, and the hardware volume envelope loops while the counter is halted
+//! - `HT<VALUE>` command loads the triangle linear counter (`$4008`) at each note to cut it after `<VALUE>` quarter frames:
+//!   - `<VALUE>`: `0..=127`
+//!   - `HTOF` (or `HT255`) sets the control flag and the note sounds until it ends
+//! - `p<PAN>` command sets the panning of the channel in stereo output (see `MusicPlayer::next_stereo()`):
+//!   - `<PAN>`: `-15..=15` (`-15` is the left, `0` is the center, and `15` is the right)
+//!   - The default panning is given by `StereoLayout` (e.g., `StereoLayout::Nes` for the common "NES stereo" mod)
+//! - `#REGION <NTSC|PAL|DENDY>` directive selects the CPU clock, the frame rate (PAL and Dendy: 50 Hz) and
+//!   the noise and DPCM period tables (PAL)
+//! - `#INCLUDE "<PATH>"` directive can only include definitions and macros (not channel commands)
+//!   - Included scripts (and DPCM sample files) are loaded via `IncludeResolver` (see `Music::with_resolver()`)
+//!   - The paths in an included script are relative to the directory of that script
 //!
 //! [MCK]: https://www.nesdev.org/mckc-e.txt
 //!
@@ -72,19 +130,40 @@
 #[cfg(feature = "wav")]
 pub mod wav;
 
+#[cfg(feature = "apu")]
+pub mod apu;
+pub mod dpcm;
+#[cfg(feature = "midi")]
+pub mod midi;
+#[cfg(feature = "nsf")]
+pub mod nsf;
+#[cfg(feature = "vgm")]
+pub mod vgm;
+
+mod analysis;
 mod channel;
 mod clocks;
 mod commands;
 mod comment;
+mod cursor;
 mod definitions;
+mod include;
 mod macros;
 mod music;
+mod n163;
 mod oscillators;
 mod player;
+#[cfg(any(feature = "apu", feature = "nsf", feature = "vgm"))]
+mod registers;
+mod sunsoft5b;
 mod traits;
 mod types;
+mod vrc7;
 
+pub use self::analysis::{ChannelAnalysis, MusicAnalysis};
 pub use self::channel::ChannelName;
+pub use self::include::{FileResolver, IncludeResolver};
 pub use self::music::{Music, ParseMusicError};
-pub use self::player::{ChannelState, MusicPlayer, PlayMusicError};
-pub use self::types::Sample;
+pub use self::oscillators::Synthesis;
+pub use self::player::{ChannelState, MixerMode, MusicPlayer, PlayMusicError, StereoLayout};
+pub use self::types::{Region, Sample};
@@ -1,11 +1,17 @@
 use crate::{
     comment::CommentsOrWhitespaces,
-    types::{Int, NoteEnvelope, PitchEnvelope, Timbres, Vibrato, VolumeEnvelope},
+    dpcm,
+    include::{self, IncludeResolver},
+    types::{
+        DpcmSample, Int, Note, NoteEnvelope, Octave, PitchEnvelope, Timbre, Timbres, Vibrato,
+        VolumeEnvelope, Wave,
+    },
+    ParseMusicError, Region,
 };
-use std::collections::BTreeMap;
+use std::{collections::BTreeMap, sync::Arc};
 use textparse::{
     components::{Char, Empty, Str},
-    Parse, Parser, Span,
+    Parse, Parser, Position, Span,
 };
 
 #[derive(Debug, Default, Clone)]
@@ -15,11 +21,17 @@ pub struct Macros {
     pub pitches: BTreeMap<MacroNumber, PitchMacro>,
     pub arpeggios: BTreeMap<MacroNumber, ArpeggioMacro>,
     pub vibratos: BTreeMap<MacroNumber, VibratoMacro>,
+    pub dpcms: BTreeMap<MacroNumber, DpcmMacro>,
+    pub fds_waveforms: BTreeMap<MacroNumber, FdsWaveformMacro>,
+    pub fds_modulations: BTreeMap<MacroNumber, FdsModulationMacro>,
+    pub n163_waves: BTreeMap<MacroNumber, N163WaveMacro>,
+    pub vrc7_patches: BTreeMap<MacroNumber, Vrc7PatchMacro>,
 }
 
 impl Macros {
     #[allow(clippy::question_mark)]
-    pub fn parse(&mut self, parser: &mut Parser) -> Option<()> {
+    /// Parses the macros (`base_path` is the path of the included script that defines them).
+    pub fn parse(&mut self, parser: &mut Parser, base_path: Option<&str>) -> Option<()> {
         while parser.peek_char() == Some('@') {
             if let Some(m) = parser.parse::<VolumeMacro>() {
                 self.volumes.insert(m.number(), m);
@@ -31,6 +43,17 @@ impl Macros {
                 self.arpeggios.insert(m.number(), m);
             } else if let Some(m) = parser.parse::<VibratoMacro>() {
                 self.vibratos.insert(m.number(), m);
+            } else if let Some(mut m) = parser.parse::<DpcmMacro>() {
+                m.path = include::relative_path(base_path, m.sample.path());
+                self.dpcms.insert(m.number(), m);
+            } else if let Some(m) = parser.parse::<FdsWaveformMacro>() {
+                self.fds_waveforms.insert(m.number(), m);
+            } else if let Some(m) = parser.parse::<FdsModulationMacro>() {
+                self.fds_modulations.insert(m.number(), m);
+            } else if let Some(m) = parser.parse::<N163WaveMacro>() {
+                self.n163_waves.insert(m.number(), m);
+            } else if let Some(m) = parser.parse::<Vrc7PatchMacro>() {
+                self.vrc7_patches.insert(m.number(), m);
             } else {
                 return None;
             }
@@ -38,11 +61,57 @@ impl Macros {
         }
         Some(())
     }
+
+    /// Loads the sample files of the DPCM macros (WAV files are resampled at the DMC rate of the region).
+    pub fn load_dpcm_samples(
+        &mut self,
+        resolver: &mut dyn IncludeResolver,
+        region: Region,
+    ) -> Result<(), ParseMusicError> {
+        for m in self.dpcms.values_mut().filter(|m| m.data.is_none()) {
+            let path = &m.path;
+            let (data, pcm) = resolver
+                .resolve_bytes(path)
+                .and_then(|data| {
+                    if data.starts_with(b"RIFF") {
+                        let pitch = m.sample.pitch();
+                        Ok((
+                            dpcm::wav_to_dmc(&data, region, pitch)?,
+                            dpcm::wav_to_pcm(&data, region, pitch)?,
+                        ))
+                    } else {
+                        let pcm = dpcm::dmc_to_pcm(&data);
+                        Ok((data, pcm))
+                    }
+                })
+                .map_err(|e| {
+                    ParseMusicError::new(&m.sample, &format!("failed to load {path:?} ({e})"))
+                })?;
+            m.data = Some(Arc::new(data));
+            m.pcm = Some(Arc::new(pcm));
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug, Clone, Copy, Span, Parse)]
 pub struct MacroNumber(Int<0, 127>);
 
+impl MacroNumber {
+    /// Returns the DPCM macro number that the given note plays on a DPCM channel.
+    ///
+    /// `o2 c` plays `@DPCM0`, `o2 c+` plays `@DPCM1`, ..., and `o7 b` plays `@DPCM71`.
+    pub fn from_dpcm_note(note: Note, octave: Octave) -> Self {
+        let n = usize::from(octave.get() - 2) * 12 + note.offset_from_c();
+        Self(Int::new(n as i32))
+    }
+
+    /// Returns the macro number selected by the timbre (e.g., `@3` selects `@FM3` on an FDS channel).
+    pub fn from_timbre(timbre: Timbre) -> Option<Self> {
+        (timbre.get() <= 127).then(|| Self(Int::new(i32::from(timbre.get()))))
+    }
+}
+
 impl PartialEq for MacroNumber {
     fn eq(&self, other: &Self) -> bool {
         self.0.get() == other.0.get()
@@ -152,3 +221,125 @@ impl VibratoMacro {
         &self.vibrato
     }
 }
+
+#[derive(Debug, Clone)]
+pub struct DpcmMacro {
+    key: MacroKey<Str<'D', 'P', 'C', 'M'>>,
+    sample: DpcmSample,
+    path: String,
+    data: Option<Arc<Vec<u8>>>,
+    pcm: Option<Arc<Vec<u8>>>,
+}
+
+impl DpcmMacro {
+    pub fn number(&self) -> MacroNumber {
+        self.key.number
+    }
+
+    pub fn pitch(&self) -> u8 {
+        self.sample.pitch()
+    }
+
+    pub fn data(&self) -> Arc<Vec<u8>> {
+        self.data.clone().unwrap_or_default()
+    }
+
+    /// Returns the sample as raw 8-bit PCM data (played by MMC5 PCM channels).
+    pub fn pcm(&self) -> Arc<Vec<u8>> {
+        self.pcm.clone().unwrap_or_default()
+    }
+}
+
+impl Span for DpcmMacro {
+    fn start_position(&self) -> Position {
+        self.key.start_position()
+    }
+
+    fn end_position(&self) -> Position {
+        self.sample.end_position()
+    }
+}
+
+impl Parse for DpcmMacro {
+    fn parse(parser: &mut Parser) -> Option<Self> {
+        let key = parser.parse()?;
+        let sample: DpcmSample = parser.parse()?;
+        Some(Self {
+            key,
+            path: sample.path().to_owned(),
+            sample,
+            data: None,
+            pcm: None,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Span, Parse)]
+pub struct FdsWaveformMacro {
+    key: MacroKey<Str<'F', 'M'>>,
+    wave: Wave<63>,
+}
+
+impl FdsWaveformMacro {
+    pub const LEN: usize = 64;
+
+    pub fn number(&self) -> MacroNumber {
+        self.key.number
+    }
+
+    pub fn samples(&self) -> &[u8] {
+        self.wave.samples()
+    }
+}
+
+#[derive(Debug, Clone, Span, Parse)]
+pub struct FdsModulationMacro {
+    key: MacroKey<Str<'F', 'M', 'O', 'D'>>,
+    wave: Wave<7>,
+}
+
+impl FdsModulationMacro {
+    pub const LEN: usize = 32;
+
+    pub fn number(&self) -> MacroNumber {
+        self.key.number
+    }
+
+    pub fn samples(&self) -> &[u8] {
+        self.wave.samples()
+    }
+}
+
+#[derive(Debug, Clone, Span, Parse)]
+pub struct N163WaveMacro {
+    key: MacroKey<Char<'N'>>,
+    wave: Wave<15>,
+}
+
+impl N163WaveMacro {
+    pub fn number(&self) -> MacroNumber {
+        self.key.number
+    }
+
+    pub fn samples(&self) -> &[u8] {
+        self.wave.samples()
+    }
+}
+
+#[derive(Debug, Clone, Span, Parse)]
+pub struct Vrc7PatchMacro {
+    key: MacroKey<Str<'O', 'P', 'L', 'L'>>,
+    bytes: Wave<255>,
+}
+
+impl Vrc7PatchMacro {
+    pub const LEN: usize = 8;
+
+    pub fn number(&self) -> MacroNumber {
+        self.key.number
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        self.bytes.samples()
+    }
+}
@@ -0,0 +1,1282 @@
+//! MIDI: Standard MIDI File (SMF) export and import.
+use crate::{
+    channel::ChannelName,
+    clocks::Clocks,
+    commands::Command,
+    cursor::CommandCursor,
+    macros::Macros,
+    oscillators::Oscillator,
+    traits::NthFrameItem,
+    types::{Note, NoteDuration, Octave, Period, Region, Volume, VolumeEnvelope},
+    vrc7, Music, PlayMusicError,
+};
+use num::rational::Ratio;
+use std::{
+    collections::BTreeMap,
+    io::{Error, ErrorKind, Write},
+    sync::Arc,
+};
+use textparse::Span;
+
+/// Ticks per quarter note.
+const DIVISION: u16 = 480;
+
+const DEFAULT_TEMPO: u64 = 120;
+const PERCUSSION_CHANNEL: u8 = 9;
+const NOTE_VELOCITY: u8 = 127;
+const CONTROL_VOLUME: u8 = 7;
+const PITCH_BEND_CENTER: u16 = 8192;
+const PITCH_BEND_RANGE_CENTS: f32 = 200.0;
+
+/// [`Midi`] options.
+#[derive(Debug, Clone)]
+pub struct MidiOptions {
+    /// Number of times to repeat the section after a loop point (`L`).
+    ///
+    /// The default value is `1`.
+    pub loop_count: usize,
+}
+
+impl Default for MidiOptions {
+    fn default() -> Self {
+        Self { loop_count: 1 }
+    }
+}
+
+/// [`Midi`] provides a feature to export music as Standard MIDI File (format 1).
+///
+/// The first track holds the title (`#TITLE`) and tempo changes, and then a track is added for each channel.
+/// The tempo changes (`t`) of all channels are merged into the first track
+/// (the first channel wins if channels change the tempo at the same time),
+/// and the events of each channel are placed at their times on that merged tempo map,
+/// so channels with different tempos keep their timing.
+/// The noise, DPCM and MMC5 PCM channels use the percussion channel (channel 10).
+///
+/// Constant volumes (`v`) are exported as note velocities, while volume envelopes (`@v`) are exported as
+/// channel volume changes (CC7) at each frame. Detunes (`D`) are exported as pitch bends (range: ±2 semitones).
+/// Other effects such as vibratos and sweeps are not exported.
+#[derive(Debug)]
+pub struct Midi {
+    tracks: Vec<Vec<u8>>,
+}
+
+impl Midi {
+    /// Makes a [`Midi`] instance with the default options.
+    pub fn new(music: &Music) -> Result<Self, PlayMusicError> {
+        Self::with_options(music, Default::default())
+    }
+
+    /// Makes a [`Midi`] instance.
+    pub fn with_options(music: &Music, options: MidiOptions) -> Result<Self, PlayMusicError> {
+        // The tempo changes of all channels are needed before converting the times of events into ticks.
+        let mut tempos = Vec::new();
+        for walker in Self::walk_channels(music, &options, &TempoMap::default())? {
+            tempos.extend(walker.tempos);
+        }
+        let tempo_map = TempoMap::new(tempos);
+
+        let mut tracks = Self::walk_channels(music, &options, &tempo_map)?
+            .into_iter()
+            .map(|walker| track(Some(&walker.name.as_char().to_string()), walker.events))
+            .collect::<Vec<_>>();
+        let conductor = tempo_map
+            .changes
+            .iter()
+            .map(|origin| {
+                let micros = (60_000_000 / origin.tempo) as u32;
+                let mut bytes = vec![0xFF, 0x51, 0x03];
+                bytes.extend_from_slice(&micros.to_be_bytes()[1..]);
+                Event::new(origin.tick(origin.seconds), EventOrder::Control, bytes)
+            })
+            .collect();
+        tracks.insert(0, track(music.title(), conductor));
+
+        Ok(Self { tracks })
+    }
+
+    fn walk_channels(
+        music: &Music,
+        options: &MidiOptions,
+        tempo_map: &TempoMap,
+    ) -> Result<Vec<ChannelWalker>, PlayMusicError> {
+        let mut walkers = Vec::new();
+        let mut midi_channels = (0..16).filter(|&c| c != PERCUSSION_CHANNEL).cycle();
+        for (name, channel) in music.channels().iter() {
+            let midi_channel = match channel.oscillator {
+                Oscillator::Noise(_) | Oscillator::Dpcm(_) | Oscillator::Mmc5Pcm(_) => {
+                    PERCUSSION_CHANNEL
+                }
+                _ => midi_channels.next().expect("unreachable"),
+            };
+            let mut walker = ChannelWalker::new(
+                name,
+                channel.commands,
+                channel.oscillator,
+                music.macros(),
+                midi_channel,
+                music.is_octave_reversed(),
+                music.region(),
+                options.loop_count,
+                tempo_map.clone(),
+            );
+            walker.walk().map_err(|mut e| {
+                e.channel = name;
+                e
+            })?;
+            walkers.push(walker);
+        }
+        Ok(walkers)
+    }
+
+    /// Exports this music as MIDI into the writer.
+    pub fn to_writer<W: Write>(&self, mut writer: W) -> std::io::Result<()> {
+        writer.write_all(b"MThd")?;
+        writer.write_all(&6u32.to_be_bytes())?; // Chunk Size
+        writer.write_all(&1u16.to_be_bytes())?; // Format
+        writer.write_all(&(self.tracks.len() as u16).to_be_bytes())?;
+        writer.write_all(&DIVISION.to_be_bytes())?;
+        for track in &self.tracks {
+            writer.write_all(b"MTrk")?;
+            writer.write_all(&(track.len() as u32).to_be_bytes())?;
+            writer.write_all(track)?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+fn track(name: Option<&str>, mut events: Vec<Event>) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    if let Some(name) = name {
+        bytes.extend_from_slice(&[0x00, 0xFF, 0x03]);
+        write_variable_length(&mut bytes, name.len() as u64);
+        bytes.extend_from_slice(name.as_bytes());
+    }
+
+    events.sort_by_key(|e| (e.tick, e.order));
+    let mut last_tick = 0;
+    for event in events {
+        write_variable_length(&mut bytes, event.tick - last_tick);
+        bytes.extend_from_slice(&event.bytes);
+        last_tick = event.tick;
+    }
+    bytes.extend_from_slice(&[0x00, 0xFF, 0x2F, 0x00]); // End of Track
+    bytes
+}
+
+fn write_variable_length(bytes: &mut Vec<u8>, mut n: u64) {
+    let mut buf = vec![(n & 0x7F) as u8];
+    n >>= 7;
+    while n > 0 {
+        buf.push((n & 0x7F) as u8 | 0x80);
+        n >>= 7;
+    }
+    bytes.extend(buf.into_iter().rev());
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum EventOrder {
+    NoteOff,
+    Control,
+    NoteOn,
+}
+
+#[derive(Debug)]
+struct Event {
+    tick: u64,
+    order: EventOrder,
+    bytes: Vec<u8>,
+}
+
+impl Event {
+    fn new(tick: u64, order: EventOrder, bytes: Vec<u8>) -> Self {
+        Self { tick, order, bytes }
+    }
+}
+
+/// Reference point to convert a time (seconds) into a MIDI tick.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct TimeOrigin {
+    seconds: Ratio<u64>,
+    beats: Ratio<u64>,
+    tempo: u64,
+}
+
+impl TimeOrigin {
+    fn beats(&self, seconds: Ratio<u64>) -> Ratio<u64> {
+        self.beats + (seconds - self.seconds) * self.tempo / 60
+    }
+
+    fn tick(&self, seconds: Ratio<u64>) -> u64 {
+        (self.beats(seconds) * u64::from(DIVISION))
+            .round()
+            .to_integer()
+    }
+}
+
+/// Tempo changes of the conductor track.
+#[derive(Debug, Clone, Default)]
+struct TempoMap {
+    changes: Vec<TimeOrigin>,
+}
+
+impl TempoMap {
+    /// Makes a [`TempoMap`] from the tempo changes (seconds, tempo) of all channels.
+    fn new(mut tempos: Vec<(Ratio<u64>, u64)>) -> Self {
+        tempos.sort_by_key(|&(seconds, _)| seconds); // Keeps the channel order at the same time.
+        tempos.dedup_by_key(|&mut (seconds, _)| seconds);
+        let mut map = Self::default();
+        for (seconds, tempo) in tempos {
+            let origin = map.origin(seconds);
+            if map.changes.last().is_some_and(|o| o.tempo == tempo) {
+                continue;
+            }
+            map.changes.push(TimeOrigin {
+                seconds,
+                beats: origin.beats(seconds),
+                tempo,
+            });
+        }
+        map
+    }
+
+    fn origin(&self, seconds: Ratio<u64>) -> TimeOrigin {
+        self.changes
+            .iter()
+            .rev()
+            .find(|o| o.seconds <= seconds)
+            .copied()
+            .unwrap_or(TimeOrigin {
+                seconds: Ratio::default(),
+                beats: Ratio::default(),
+                tempo: DEFAULT_TEMPO,
+            })
+    }
+
+    fn tick(&self, seconds: Ratio<u64>) -> u64 {
+        self.origin(seconds).tick(seconds)
+    }
+}
+
+#[derive(Debug)]
+struct PlayingNote {
+    key: u8,
+    start: Ratio<u64>,
+    end: Ratio<u64>,
+    volume: VolumeEnvelope,
+}
+
+#[derive(Debug)]
+struct ChannelWalker {
+    name: ChannelName,
+    cursor: CommandCursor,
+    oscillator: Oscillator,
+    macros: Arc<Macros>,
+    midi_channel: u8,
+    octave: Octave,
+    octave_reversed: bool,
+    volume: VolumeEnvelope,
+    detune: i8,
+    clocks: Clocks,
+    region: Region,
+    tempo_map: TempoMap,
+    loop_point: Option<usize>,
+    loop_count: usize,
+    playing: Option<PlayingNote>,
+    last_volume: u8,
+    last_pitch_bend: u16,
+    tempos: Vec<(Ratio<u64>, u64)>, // (Seconds, Tempo)
+    events: Vec<Event>,
+}
+
+impl ChannelWalker {
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        name: ChannelName,
+        commands: Arc<Vec<Command>>,
+        oscillator: Oscillator,
+        macros: Arc<Macros>,
+        midi_channel: u8,
+        octave_reversed: bool,
+        region: Region,
+        loop_count: usize,
+        tempo_map: TempoMap,
+    ) -> Self {
+        let mut clocks = Clocks::new(1); // The sample rate is not used.
+        clocks.set_frame_rate(region.frame_rate());
+        Self {
+            name,
+            cursor: CommandCursor::new(commands),
+            oscillator,
+            macros,
+            midi_channel,
+            octave: Octave::default(),
+            octave_reversed,
+            volume: VolumeEnvelope::constant(Volume::default()),
+            detune: 0,
+            clocks,
+            region,
+            tempo_map,
+            loop_point: None,
+            loop_count,
+            playing: None,
+            last_volume: 127,
+            last_pitch_bend: PITCH_BEND_CENTER,
+            tempos: Vec::new(),
+            events: Vec::new(),
+        }
+    }
+
+    fn next_command(&mut self) -> Option<Command> {
+        if let Some(command) = self.cursor.next_command() {
+            return Some(command);
+        }
+        let i = self.loop_point?;
+        if self.loop_count == 0 {
+            return None;
+        }
+        self.loop_count -= 1;
+        self.cursor.jump(i);
+        self.cursor.next_command()
+    }
+
+    fn walk(&mut self) -> Result<(), PlayMusicError> {
+        while let Some(command) = self.next_command() {
+            match command {
+                Command::Note(c) => {
+                    self.play_note(key(c.note(), self.octave), c.note_duration());
+                }
+                Command::DirectNote(c) => {
+                    let octave = c
+                        .octave()
+                        .ok_or_else(|| PlayMusicError::new(&c, "note number out of range"))?;
+                    self.play_note(key(c.note(), octave), c.note_duration());
+                }
+                Command::DirectFrequency(c) => {
+                    let key = self.period_to_key(c.period());
+                    self.play_note(key, c.note_duration());
+                }
+                Command::RestSign(c) => {
+                    self.finish_note();
+                    self.clocks.tick_note_clock(c.note_duration());
+                }
+                Command::Wait(c) => self.extend_note(c.note_duration()),
+                Command::Tie(c) => {
+                    self.cursor.tie(&c)?;
+                    self.extend_note(c.note_duration());
+                }
+                Command::Slur(c) => {
+                    let after = self.cursor.slur(c)?;
+                    self.extend_note(after.note_duration());
+                }
+                Command::Volume(c) => self.volume = VolumeEnvelope::constant(c.volume()),
+                Command::VolumeUp(c) => {
+                    let v = self
+                        .volume
+                        .nth_frame_item(0)
+                        .checked_add(c.count())
+                        .ok_or_else(|| PlayMusicError::new(&c, "volume overflow"))?;
+                    self.volume = VolumeEnvelope::constant(v);
+                }
+                Command::VolumeDown(c) => {
+                    let v = self
+                        .volume
+                        .nth_frame_item(0)
+                        .checked_sub(c.count())
+                        .ok_or_else(|| PlayMusicError::new(&c, "volume underflow"))?;
+                    self.volume = VolumeEnvelope::constant(v);
+                }
+                Command::VolumeEnvelope(c) => {
+                    self.volume = self
+                        .macros
+                        .volumes
+                        .get(&c.macro_number())
+                        .ok_or_else(|| PlayMusicError::new(&c, "undefined macro number"))?
+                        .envelope()
+                        .clone();
+                }
+                Command::Octave(c) => self.octave = c.octave(),
+                Command::OctaveUp(c) => self.shift_octave(c, !self.octave_reversed)?,
+                Command::OctaveDown(c) => self.shift_octave(c, self.octave_reversed)?,
+                Command::Detune(c) => self.detune = c.detune().get(),
+                Command::DefaultNoteDuration(c) => {
+                    self.clocks
+                        .set_default_note_duration(c.default_note_duration());
+                }
+                Command::Tempo(c) => {
+                    let seconds = self.clocks.note_clock().get();
+                    self.tempos.push((seconds, u64::from(c.tempo().get())));
+                    self.clocks.set_tempo(c.tempo());
+                }
+                Command::DataSkip(_) => self.cursor.skip_to_end(),
+                Command::TrackLoop(_) => self.loop_point = Some(self.cursor.index()),
+                Command::RepeatStart(c) => self.cursor.repeat_start(c)?,
+                Command::RepeatEnd(c) => self.cursor.repeat_end(c)?,
+                Command::TupletStart(c) => self.cursor.tuplet_start(c, &mut self.clocks)?,
+                Command::TupletEnd(c) => self.cursor.tuplet_end(c)?,
+                Command::Quantize(c) => self.clocks.set_quantize(c.quantize()),
+                Command::QuantizeFrame(c) => self.clocks.set_quantize_frame(c.quantize_frame()),
+                Command::Arpeggio(_)
+                | Command::PitchEnvelope(_)
+                | Command::PitchSweep(_)
+                | Command::Vibrato(_)
+                | Command::Timbre(_)
+                | Command::Timbres(_)
+                | Command::HardwareEnvelope(_)
+                | Command::LengthCounter(_)
+                | Command::LinearCounter(_)
+                | Command::FdsModulation(_)
+                | Command::FdsMasterVolume(_)
+                | Command::N163WaveOffset(_)
+                | Command::Sunsoft5bNoise(_)
+                | Command::Sunsoft5bEnvelope(_)
+                | Command::Vrc7Sustain(_)
+                | Command::Vrc7UserPatch(_)
+                | Command::Pan(_) => {}
+            }
+        }
+        self.finish_note();
+        Ok(())
+    }
+
+    fn period_to_key(&self, period: Period) -> u8 {
+        let register = f32::from(period.get() + 1);
+        let frequency = match &self.oscillator {
+            Oscillator::PulseWave(_) | Oscillator::Vrc6Pulse(_) | Oscillator::Mmc5Pulse(_) => {
+                self.region.cpu_clock_hz() / 16.0 / register
+            }
+            Oscillator::Sawtooth(_) => self.region.cpu_clock_hz() / 14.0 / register,
+            Oscillator::TriangleWave(_) => self.region.cpu_clock_hz() / 32.0 / register,
+            Oscillator::Sunsoft5b(_) => self.region.cpu_clock_hz() / 32.0 / (register - 1.0),
+            Oscillator::Vrc7(_) => {
+                let period = period.get();
+                vrc7::fnum_to_frequency((period >> 9) as u8, period & 0x1FF)
+            }
+            Oscillator::Fds(_) => self.region.cpu_clock_hz() * f32::from(period.get()) / 4194304.0,
+            Oscillator::N163(o) => o.register_to_frequency(f32::from(period.get()) * 64.0),
+            Oscillator::Noise(_) | Oscillator::Dpcm(_) | Oscillator::Mmc5Pcm(_) => {
+                return 36 + period.get() as u8
+            }
+        };
+        (69.0 + 12.0 * (frequency / 440.0).log2())
+            .round()
+            .clamp(0.0, 127.0) as u8
+    }
+
+    fn shift_octave(&mut self, span: impl Span, up: bool) -> Result<(), PlayMusicError> {
+        self.octave = if up {
+            self.octave
+                .checked_add(1)
+                .ok_or_else(|| PlayMusicError::new(span, "octave oveflow"))?
+        } else {
+            self.octave
+                .checked_sub(1)
+                .ok_or_else(|| PlayMusicError::new(span, "octave underflow"))?
+        };
+        Ok(())
+    }
+
+    fn play_note(&mut self, key: u8, duration: NoteDuration) {
+        self.finish_note();
+
+        let start = self.clocks.note_clock().get();
+        let tick = self.tempo_map.tick(start);
+        let pitch_bend = self.pitch_bend(key);
+        if pitch_bend != self.last_pitch_bend {
+            let [lsb, msb] = [(pitch_bend & 0x7F) as u8, (pitch_bend >> 7) as u8];
+            self.push_event(tick, EventOrder::Control, vec![0xE0, lsb, msb]);
+            self.last_pitch_bend = pitch_bend;
+        }
+        let velocity = if self.volume.is_constant() {
+            self.set_channel_volume(tick, 127);
+            scale_volume(self.volume.nth_frame_item(0)).max(1)
+        } else {
+            NOTE_VELOCITY
+        };
+        self.push_event(tick, EventOrder::NoteOn, vec![0x90, key, velocity]);
+
+        self.clocks.tick_note_clock(duration);
+        self.playing = Some(PlayingNote {
+            key,
+            start,
+            end: self.clocks.quantize_clock().get(),
+            volume: self.volume.clone(),
+        });
+    }
+
+    fn extend_note(&mut self, duration: NoteDuration) {
+        self.clocks.tick_note_clock(duration);
+        if let Some(note) = &mut self.playing {
+            note.end = self.clocks.quantize_clock().get();
+        }
+    }
+
+    fn finish_note(&mut self) {
+        let Some(note) = self.playing.take() else {
+            return;
+        };
+
+        let start_tick = self.tempo_map.tick(note.start);
+        let end_tick = self
+            .tempo_map
+            .tick(note.end.max(note.start))
+            .max(start_tick + 1);
+        if !note.volume.is_constant() {
+            let mut seconds = note.start;
+            for frame in 0.. {
+                let tick = self.tempo_map.tick(seconds);
+                if tick >= end_tick {
+                    break;
+                }
+                let volume = scale_volume(note.volume.nth_frame_item(frame));
+                self.set_channel_volume(tick, volume);
+                seconds += Ratio::new(1, u64::from(self.region.frame_rate()));
+            }
+        }
+        self.push_event(end_tick, EventOrder::NoteOff, vec![0x80, note.key, 0]);
+    }
+
+    fn set_channel_volume(&mut self, tick: u64, volume: u8) {
+        if volume != self.last_volume {
+            self.push_event(
+                tick,
+                EventOrder::Control,
+                vec![0xB0, CONTROL_VOLUME, volume],
+            );
+            self.last_volume = volume;
+        }
+    }
+
+    fn pitch_bend(&self, key: u8) -> u16 {
+        if self.detune == 0 || self.midi_channel == PERCUSSION_CHANNEL {
+            return PITCH_BEND_CENTER;
+        }
+        let frequency = 440.0 * 2f32.powf((f32::from(key) - 69.0) / 12.0);
+        let register = self.region.cpu_clock_hz() / frequency / 16.0;
+        let cents = 1200.0 * (register / (register - f32::from(self.detune))).log2();
+        let bend = f32::from(PITCH_BEND_CENTER) * (1.0 + cents / PITCH_BEND_RANGE_CENTS);
+        bend.round().clamp(0.0, 16383.0) as u16
+    }
+
+    fn push_event(&mut self, tick: u64, order: EventOrder, mut bytes: Vec<u8>) {
+        bytes[0] |= self.midi_channel;
+        self.events.push(Event::new(tick, order, bytes));
+    }
+}
+
+/// Returns the MIDI note number (`o4 c` is `60`).
+fn key(note: Note, octave: Octave) -> u8 {
+    (octave.get() + 1) * 12 + note.offset_from_c() as u8
+}
+
+fn scale_volume(volume: Volume) -> u8 {
+    (u16::from(volume.get()) * 127 / 15) as u8
+}
+
+/// Quantization unit of [`import()`] (a 64th note triplet).
+const UNITS_PER_WHOLE_NOTE: u64 = 192;
+
+const MIN_OCTAVE: i32 = 2;
+const MAX_OCTAVE: i32 = 7;
+const NOTE_NAMES: [&str; 12] = [
+    "c", "c+", "d", "d+", "e", "f", "f+", "g", "g+", "a", "a+", "b",
+];
+
+/// MML script converted from a Standard MIDI File by [`import()`].
+#[derive(Debug, Clone)]
+pub struct ImportedMml {
+    /// MML script.
+    pub script: String,
+
+    /// Warnings reported during the conversion (e.g., notes outside of the octave range).
+    pub warnings: Vec<String>,
+}
+
+/// Converts a Standard MIDI File (format 0 or 1) into an MML script.
+///
+/// Each pair of a track and a MIDI channel that has notes is mapped to an MML channel (`A..=Z`).
+/// The percussion channel (channel 10) is mapped to the noise channel `D`,
+/// and the others are mapped to `A`, `B`, `C` (triangle wave) and then pulse wave channels defined by `#CHANNEL`.
+///
+/// Note timings are quantized to 1/192 of a whole note, and then each duration is written as
+/// the nearest representable lengths (including dotted and tuplet lengths such as `12` or `8.`).
+/// As MML channels are monophonic, overlapping notes are truncated.
+/// Notes outside of the octave range `2..=7` are transposed by octaves into the range with a warning.
+pub fn import(smf: &[u8]) -> std::io::Result<ImportedMml> {
+    let smf = Smf::parse(smf)?;
+    let mut warnings = Vec::new();
+
+    let to_units = |tick: u64| {
+        let ticks_per_whole_note = u64::from(smf.division) * 4;
+        (tick * UNITS_PER_WHOLE_NOTE * 2 + ticks_per_whole_note) / (ticks_per_whole_note * 2)
+    };
+    let tempos = smf
+        .tempos
+        .iter()
+        .map(|&(tick, usecs)| {
+            let tempo = (60_000_000 + u64::from(usecs) / 2) / u64::from(usecs).max(1);
+            (to_units(tick), tempo.clamp(1, 255) as u8)
+        })
+        .collect::<Vec<_>>();
+
+    let mut names = ('A'..='Z').filter(|&c| c != 'D');
+    let mut is_noise_used = false;
+    let mut channels = BTreeMap::new();
+    for (track_index, track) in smf.tracks.iter().enumerate() {
+        for (&midi_channel, notes) in &track.notes {
+            let name = if midi_channel == PERCUSSION_CHANNEL && !is_noise_used {
+                is_noise_used = true;
+                Some('D')
+            } else {
+                names.next()
+            };
+            let Some(name) = name else {
+                warnings.push(format!(
+                    "too many channels: track {} (MIDI channel {}) is dropped",
+                    track_index + 1,
+                    midi_channel + 1
+                ));
+                continue;
+            };
+            let mut comment = format!(
+                "; Track {} (MIDI channel {})",
+                track_index + 1,
+                midi_channel + 1
+            );
+            if let Some(track_name) = &track.name {
+                comment.push_str(&format!(": {track_name}"));
+            }
+            let writer = ChannelScriptWriter::new(name, &tempos);
+            let lines = writer.write(notes, to_units, &mut warnings);
+            channels.insert(name, (comment, lines));
+        }
+    }
+
+    let mut script = String::new();
+    if let Some(title) = smf.tracks.first().and_then(|t| t.name.as_ref()) {
+        script.push_str(&format!("#TITLE {title}\n"));
+    }
+    for &name in channels.keys().filter(|&&c| c > 'D') {
+        script.push_str(&format!("#CHANNEL {name} 0\n"));
+    }
+    for (comment, lines) in channels.values() {
+        script.push('\n');
+        script.push_str(comment);
+        script.push('\n');
+        for line in lines {
+            script.push_str(line);
+            script.push('\n');
+        }
+    }
+    Ok(ImportedMml { script, warnings })
+}
+
+#[derive(Debug)]
+struct SmfNote {
+    key: u8,
+    velocity: u8,
+    start: u64,
+    end: u64,
+}
+
+#[derive(Debug, Default)]
+struct SmfTrack {
+    name: Option<String>,
+    notes: BTreeMap<u8, Vec<SmfNote>>, // MIDI channel => notes
+}
+
+#[derive(Debug)]
+struct Smf {
+    division: u16,
+    tempos: Vec<(u64, u32)>, // (Tick, Microseconds per quarter note)
+    tracks: Vec<SmfTrack>,
+}
+
+impl Smf {
+    fn parse(bytes: &[u8]) -> std::io::Result<Self> {
+        let mut reader = ByteReader { bytes };
+        if reader.take(4).ok() != Some(b"MThd") {
+            return Err(invalid("not a Standard MIDI File"));
+        }
+        let size = reader.u32()? as usize;
+        let mut header = ByteReader {
+            bytes: reader.take(size)?,
+        };
+        let format = header.u16()?;
+        let track_count = header.u16()?;
+        let division = header.u16()?;
+        if format > 1 {
+            return Err(invalid(&format!("unsupported SMF format: {format}")));
+        }
+        if division & 0x8000 != 0 {
+            return Err(invalid("SMPTE time division is not supported"));
+        }
+        if division == 0 {
+            return Err(invalid("invalid time division: 0"));
+        }
+
+        let mut smf = Self {
+            division,
+            tempos: Vec::new(),
+            tracks: Vec::new(),
+        };
+        while smf.tracks.len() < usize::from(track_count) && !reader.bytes.is_empty() {
+            let id = reader.take(4)?;
+            let size = reader.u32()? as usize;
+            let body = reader.take(size)?;
+            if id == b"MTrk" {
+                let track = smf.parse_track(ByteReader { bytes: body })?;
+                smf.tracks.push(track);
+            }
+        }
+        smf.tempos.sort_by_key(|&(tick, _)| tick);
+        Ok(smf)
+    }
+
+    fn parse_track(&mut self, mut reader: ByteReader) -> std::io::Result<SmfTrack> {
+        let mut track = SmfTrack::default();
+        let mut playing = BTreeMap::new(); // (MIDI channel, Key) => (Start tick, Velocity)
+        let mut tick = 0;
+        let mut running_status = None;
+        while !reader.bytes.is_empty() {
+            tick += reader.variable_length()?;
+            let status = if reader.peek()? & 0x80 != 0 {
+                reader.u8()?
+            } else {
+                running_status.ok_or_else(|| invalid("invalid running status"))?
+            };
+            match status {
+                0xFF => {
+                    running_status = None;
+                    let kind = reader.u8()?;
+                    let size = reader.variable_length()? as usize;
+                    let data = reader.take(size)?;
+                    match kind {
+                        0x03 if track.name.is_none() => {
+                            track.name = Some(String::from_utf8_lossy(data).trim().to_owned());
+                        }
+                        0x51 if size == 3 => {
+                            let usecs = u32::from_be_bytes([0, data[0], data[1], data[2]]);
+                            self.tempos.push((tick, usecs));
+                        }
+                        0x2F => break,
+                        _ => {}
+                    }
+                }
+                0xF0 | 0xF7 => {
+                    running_status = None;
+                    let size = reader.variable_length()? as usize;
+                    reader.take(size)?;
+                }
+                0x80..=0xEF => {
+                    running_status = Some(status);
+                    let channel = status & 0x0F;
+                    let data0 = reader.u8()?;
+                    let data1 = if matches!(status >> 4, 0xC | 0xD) {
+                        0
+                    } else {
+                        reader.u8()?
+                    };
+                    match status >> 4 {
+                        0x9 if data1 > 0 => {
+                            if let Some((start, velocity)) =
+                                playing.insert((channel, data0), (tick, data1))
+                            {
+                                track.add_note(channel, data0, velocity, start, tick);
+                            }
+                        }
+                        0x8 | 0x9 => {
+                            if let Some((start, velocity)) = playing.remove(&(channel, data0)) {
+                                track.add_note(channel, data0, velocity, start, tick);
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+                _ => return Err(invalid(&format!("unknown MIDI event: 0x{status:02X}"))),
+            }
+        }
+        for ((channel, key), (start, velocity)) in playing {
+            track.add_note(channel, key, velocity, start, tick);
+        }
+        for notes in track.notes.values_mut() {
+            notes.sort_by_key(|n| (n.start, std::cmp::Reverse(n.key)));
+        }
+        Ok(track)
+    }
+}
+
+impl SmfTrack {
+    fn add_note(&mut self, channel: u8, key: u8, velocity: u8, start: u64, end: u64) {
+        self.notes.entry(channel).or_default().push(SmfNote {
+            key,
+            velocity,
+            start,
+            end,
+        });
+    }
+}
+
+#[derive(Debug)]
+struct ByteReader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> ByteReader<'a> {
+    fn take(&mut self, n: usize) -> std::io::Result<&'a [u8]> {
+        if self.bytes.len() < n {
+            return Err(invalid("unexpected end of SMF data"));
+        }
+        let (taken, rest) = self.bytes.split_at(n);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn peek(&self) -> std::io::Result<u8> {
+        self.bytes
+            .first()
+            .copied()
+            .ok_or_else(|| invalid("unexpected end of SMF data"))
+    }
+
+    fn u8(&mut self) -> std::io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> std::io::Result<u16> {
+        let b = self.take(2)?;
+        Ok(u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> std::io::Result<u32> {
+        let b = self.take(4)?;
+        Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn variable_length(&mut self) -> std::io::Result<u64> {
+        let mut n = 0;
+        for _ in 0..4 {
+            let b = self.u8()?;
+            n = (n << 7) | u64::from(b & 0x7F);
+            if b & 0x80 == 0 {
+                return Ok(n);
+            }
+        }
+        Err(invalid("too long variable-length quantity"))
+    }
+}
+
+fn invalid(reason: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, reason.to_owned())
+}
+
+/// Quantized note (in the units of [`UNITS_PER_WHOLE_NOTE`]).
+#[derive(Debug)]
+struct QuantizedNote {
+    key: u8,
+    velocity: u8,
+    start: u64,
+    end: u64,
+}
+
+#[derive(Debug)]
+struct ChannelScriptWriter<'a> {
+    name: char,
+    tempos: &'a [(u64, u8)],
+    default_length: u64,
+    lines: Vec<String>,
+    line: String,
+    line_start: u64,
+    position: u64,
+    tempo: Option<u8>,
+    octave: Option<i32>,
+    volume: Option<u8>,
+}
+
+impl<'a> ChannelScriptWriter<'a> {
+    fn new(name: char, tempos: &'a [(u64, u8)]) -> Self {
+        Self {
+            name,
+            tempos,
+            default_length: 4,
+            lines: Vec::new(),
+            line: String::new(),
+            line_start: 0,
+            position: 0,
+            tempo: None,
+            octave: None,
+            volume: None,
+        }
+    }
+
+    fn write(
+        mut self,
+        notes: &[SmfNote],
+        to_units: impl Fn(u64) -> u64,
+        warnings: &mut Vec<String>,
+    ) -> Vec<String> {
+        let mut quantized: Vec<QuantizedNote> = Vec::new();
+        let mut is_overlapped = false;
+        let mut last_end = 0;
+        for note in notes {
+            is_overlapped |= note.start < last_end;
+            last_end = last_end.max(note.end);
+
+            let start = to_units(note.start);
+            let end = to_units(note.end).max(start + 1);
+            if let Some(last) = quantized.last_mut() {
+                if start <= last.start {
+                    continue;
+                }
+                last.end = last.end.min(start);
+            }
+            quantized.push(QuantizedNote {
+                key: note.key,
+                velocity: note.velocity,
+                start,
+                end,
+            });
+        }
+        if is_overlapped {
+            warnings.push(format!(
+                "channel {}: overlapping notes are truncated (MML channels are monophonic)",
+                self.name
+            ));
+        }
+
+        let out_of_range = quantized
+            .iter()
+            .filter(|n| !(MIN_OCTAVE..=MAX_OCTAVE).contains(&(i32::from(n.key) / 12 - 1)))
+            .count();
+        if out_of_range > 0 {
+            warnings.push(format!(
+                "channel {}: {out_of_range} note(s) outside of the octave range {MIN_OCTAVE}..={MAX_OCTAVE} are transposed",
+                self.name
+            ));
+        }
+
+        self.default_length = most_frequent_length(&quantized);
+        self.update_tempo();
+        self.line.push_str(&format!(" l{}", self.default_length));
+        for note in &quantized {
+            self.write_rest(note.start);
+            self.write_note(note);
+        }
+        if !self.line.is_empty() {
+            self.flush_line();
+        }
+        self.lines
+    }
+
+    fn write_rest(&mut self, end: u64) {
+        while self.position < end {
+            let next_tempo = self
+                .tempos
+                .iter()
+                .map(|&(position, _)| position)
+                .find(|&p| self.position < p && p < end)
+                .unwrap_or(end);
+            let lengths = self.lengths(next_tempo - self.position);
+            for length in lengths {
+                self.line.push_str(&format!(" r{length}"));
+            }
+            self.position = next_tempo;
+            self.update_tempo();
+            self.break_line_if_needed();
+        }
+    }
+
+    fn write_note(&mut self, note: &QuantizedNote) {
+        self.update_tempo();
+
+        let volume = ((u16::from(note.velocity) * 15 + 63) / 127) as u8;
+        if self.volume != Some(volume) {
+            self.line.push_str(&format!(" v{volume}"));
+            self.volume = Some(volume);
+        }
+
+        let octave = (i32::from(note.key) / 12 - 1).clamp(MIN_OCTAVE, MAX_OCTAVE);
+        match self.octave {
+            Some(o) if o == octave => {}
+            Some(o) if o + 1 == octave => self.line.push_str(" >"),
+            Some(o) if o - 1 == octave => self.line.push_str(" <"),
+            _ => self.line.push_str(&format!(" o{octave}")),
+        }
+        self.octave = Some(octave);
+
+        let name = NOTE_NAMES[usize::from(note.key % 12)];
+        let lengths = self.lengths(note.end - note.start);
+        let tokens = lengths
+            .iter()
+            .map(|length| format!("{name}{length}"))
+            .collect::<Vec<_>>();
+        self.line.push(' ');
+        self.line.push_str(&tokens.join("&"));
+        self.position = note.end;
+        self.break_line_if_needed();
+    }
+
+    fn update_tempo(&mut self) {
+        let tempo = self
+            .tempos
+            .iter()
+            .take_while(|&&(position, _)| position <= self.position)
+            .last()
+            .map_or(DEFAULT_TEMPO as u8, |&(_, tempo)| tempo);
+        if self.tempo != Some(tempo) {
+            self.line.push_str(&format!(" t{tempo}"));
+            self.tempo = Some(tempo);
+        }
+    }
+
+    fn lengths(&self, units: u64) -> Vec<String> {
+        split_length(units)
+            .into_iter()
+            .map(|(n, dots)| {
+                let dots = ".".repeat(dots);
+                if n == self.default_length {
+                    dots
+                } else {
+                    format!("{n}{dots}")
+                }
+            })
+            .collect()
+    }
+
+    fn break_line_if_needed(&mut self) {
+        if self.position >= self.line_start + UNITS_PER_WHOLE_NOTE {
+            self.flush_line();
+            self.line_start = self.position;
+        }
+    }
+
+    fn flush_line(&mut self) {
+        self.lines.push(format!("{}{}", self.name, self.line));
+        self.line.clear();
+    }
+}
+
+/// Returns the length (`n` of `l<n>`) that is used most often in the notes.
+fn most_frequent_length(notes: &[QuantizedNote]) -> u64 {
+    let mut counts = BTreeMap::<u64, usize>::new();
+    for note in notes {
+        for (n, _) in split_length(note.end - note.start) {
+            *counts.entry(n).or_default() += 1;
+        }
+    }
+    counts
+        .into_iter()
+        .max_by_key(|&(n, count)| (count, std::cmp::Reverse(n)))
+        .map_or(4, |(n, _)| n)
+}
+
+/// Splits a duration (in the units of [`UNITS_PER_WHOLE_NOTE`]) into lengths (`(n, dots)`) from the longest one.
+fn split_length(mut units: u64) -> Vec<(u64, usize)> {
+    let mut candidates = Vec::new();
+    for n in (1..=UNITS_PER_WHOLE_NOTE).filter(|n| UNITS_PER_WHOLE_NOTE.is_multiple_of(*n)) {
+        let base = UNITS_PER_WHOLE_NOTE / n;
+        let mut total = 0;
+        for dots in 0..=2 {
+            if !base.is_multiple_of(1 << dots) {
+                break;
+            }
+            total += base >> dots;
+            candidates.push((total, n, dots));
+        }
+    }
+    candidates.sort_by_key(|&(total, n, dots)| (std::cmp::Reverse(total), dots, n));
+
+    let mut lengths = Vec::new();
+    while units > 0 {
+        let &(total, n, dots) = candidates
+            .iter()
+            .find(|&&(total, _, _)| total <= units)
+            .expect("unreachable");
+        lengths.push((n, dots));
+        units -= total;
+    }
+    lengths
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn export_adds_track_for_each_channel() {
+        // The default channels `A..=D` and the channel `E` (even if it has no commands).
+        let smf = export("#TITLE Test\n#CHANNEL E 0\nA l4 c\nC l4 c", 1);
+        let names = smf
+            .tracks
+            .iter()
+            .map(|t| t.name.as_deref().unwrap_or_default())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["Test", "A", "B", "C", "D", "E"]);
+    }
+
+    #[test]
+    fn export_note_ticks() {
+        let smf = export("A t120 l4 o4 c d8 r8 e2", 1);
+        assert_eq!(smf.division, DIVISION);
+        assert_eq!(
+            notes(&smf, 1),
+            [(60, 0, 480), (62, 480, 720), (64, 960, 1920)]
+        );
+    }
+
+    #[test]
+    fn export_slurs_and_ties() {
+        let smf = export("A t120 l4 o4 c & c8 d ^8 r", 1);
+        assert_eq!(notes(&smf, 1), [(60, 0, 720), (62, 720, 1440)]);
+
+        let music = Music::new("A l4 c & d").unwrap_or_else(|e| panic!("{e}"));
+        let e = Midi::new(&music).expect_err("different notes");
+        assert!(e.to_string().contains("'&' cannot combine different notes"));
+    }
+
+    #[test]
+    fn export_loop_count() {
+        for loop_count in [0, 1, 3] {
+            let smf = export("A t120 l4 c L d e", loop_count);
+            assert_eq!(notes(&smf, 1).len(), 1 + 2 * (loop_count + 1));
+        }
+        let smf = export("A t120 l4 c d e", 3);
+        assert_eq!(notes(&smf, 1).len(), 3);
+    }
+
+    #[test]
+    fn export_keeps_timing_of_channel_without_tempo() {
+        // The channel A changes the tempo after the first note, but the channel B keeps `t120`.
+        let smf = export("A t120 l4 c t240 c c c c\nB l4 c c c", 1);
+        assert_eq!(smf.tempos, [(0, 500_000), (480, 250_000)]);
+        assert_eq!(
+            notes(&smf, 1)
+                .iter()
+                .map(|&(_, start, _)| start)
+                .collect::<Vec<_>>(),
+            [0, 480, 960, 1440, 1920]
+        );
+        // 0.5 seconds = 480 ticks at 120 BPM, and 0.5 seconds more = 960 ticks at 240 BPM.
+        assert_eq!(
+            notes(&smf, 2)
+                .iter()
+                .map(|&(_, start, end)| (start, end))
+                .collect::<Vec<_>>(),
+            [(0, 480), (480, 1440), (1440, 2400)]
+        );
+    }
+
+    #[test]
+    fn import_round_trip() {
+        let script = "A t150 l8 o4 c d e4 r4 f2. > c16 c16\nB t150 l4 o3 g r g2 r8 a8";
+        let music = Music::new(script).unwrap_or_else(|e| panic!("{e}"));
+        let mut bytes = Vec::new();
+        Midi::new(&music)
+            .unwrap_or_else(|e| panic!("{e}"))
+            .to_writer(&mut bytes)
+            .expect("write to vec");
+
+        let imported = import(&bytes).expect("valid SMF");
+        assert!(imported.warnings.is_empty(), "{:?}", imported.warnings);
+        let reimported = Music::new(&imported.script).unwrap_or_else(|e| panic!("{e}"));
+        let expected = music.analyze().unwrap_or_else(|e| panic!("{e}"));
+        let actual = reimported.analyze().unwrap_or_else(|e| panic!("{e}"));
+        for name in [ChannelName::A, ChannelName::B] {
+            assert_eq!(
+                actual.channels()[&name].total(),
+                expected.channels()[&name].total()
+            );
+        }
+
+        // The notes are exported again at the same ticks.
+        let original = Smf::parse(&bytes).expect("valid SMF");
+        let smf = export(&imported.script, 1);
+        assert_eq!(notes(&smf, 1), notes(&original, 1));
+        assert_eq!(notes(&smf, 2), notes(&original, 2));
+    }
+
+    #[test]
+    fn import_transposes_notes_outside_of_octave_range() {
+        // `o1 c`, `o4 c` and `o8 c`.
+        let imported =
+            import(&smf(&[(24, 0, 480), (60, 480, 960), (108, 960, 1440)])).expect("valid SMF");
+        assert_eq!(
+            imported.warnings,
+            ["channel A: 2 note(s) outside of the octave range 2..=7 are transposed"]
+        );
+        assert!(
+            imported.script.contains("A t120 l4 v15 o2 c o4 c o7 c"),
+            "{}",
+            imported.script
+        );
+        Music::new(&imported.script).unwrap_or_else(|e| panic!("{e}"));
+    }
+
+    #[test]
+    fn import_quantizes_tuplets_and_dots() {
+        // Eighth note triplets (160 ticks), a dotted eighth note (360 ticks) and a sixteenth note,
+        // with a few ticks of jitter.
+        let imported = import(&smf(&[
+            (60, 0, 160),
+            (62, 161, 320),
+            (64, 322, 480),
+            (65, 480, 838),
+            (67, 842, 960),
+        ]))
+        .expect("valid SMF");
+        assert!(imported.warnings.is_empty(), "{:?}", imported.warnings);
+        assert!(
+            imported.script.contains("A t120 l12 v15 o4 c d e f8. g16"),
+            "{}",
+            imported.script
+        );
+    }
+
+    /// Makes a Standard MIDI File (format 0) that has the notes (key, start tick, end tick) on the MIDI channel 1.
+    fn smf(notes: &[(u8, u32, u32)]) -> Vec<u8> {
+        let mut events = notes
+            .iter()
+            .flat_map(|&(key, start, end)| [(start, 1, [0x90, key, 127]), (end, 0, [0x80, key, 0])])
+            .collect::<Vec<_>>();
+        events.sort_by_key(|&(tick, order, _)| (tick, order));
+
+        let mut track = Vec::new();
+        let mut last_tick = 0;
+        for (tick, _, bytes) in events {
+            write_variable_length(&mut track, u64::from(tick - last_tick));
+            track.extend_from_slice(&bytes);
+            last_tick = tick;
+        }
+        track.extend_from_slice(&[0x00, 0xFF, 0x2F, 0x00]);
+
+        let mut bytes = b"MThd".to_vec();
+        bytes.extend_from_slice(&6u32.to_be_bytes());
+        bytes.extend_from_slice(&0u16.to_be_bytes()); // Format
+        bytes.extend_from_slice(&1u16.to_be_bytes()); // Tracks
+        bytes.extend_from_slice(&DIVISION.to_be_bytes());
+        bytes.extend_from_slice(b"MTrk");
+        bytes.extend_from_slice(&(track.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&track);
+        bytes
+    }
+
+    fn export(script: &str, loop_count: usize) -> Smf {
+        let music = Music::new(script).unwrap_or_else(|e| panic!("{e}"));
+        let mut bytes = Vec::new();
+        Midi::with_options(&music, MidiOptions { loop_count })
+            .unwrap_or_else(|e| panic!("{e}"))
+            .to_writer(&mut bytes)
+            .expect("write to vec");
+        Smf::parse(&bytes).expect("valid SMF")
+    }
+
+    /// Returns the key, start tick and end tick of each note in the track.
+    fn notes(smf: &Smf, track: usize) -> Vec<(u8, u64, u64)> {
+        smf.tracks[track]
+            .notes
+            .values()
+            .flatten()
+            .map(|n| (n.key, n.start, n.end))
+            .collect()
+    }
+}
@@ -1,13 +1,20 @@
 use crate::{
+    analysis::MusicAnalysis,
     channel::Channels,
     comment::CommentsOrWhitespaces,
-    definitions::{Composer, Definition, Programer, Title},
+    definitions::{Composer, Definition, Include, Programer, Title},
+    include::{self, IncludeResolver},
     macros::Macros,
+    n163,
     oscillators::Oscillator,
-    player::MusicPlayer,
+    player::{MusicPlayer, PlayMusicError},
+    sunsoft5b,
+    types::Region,
+    vrc7,
 };
 use std::{
     borrow::Cow,
+    collections::BTreeMap,
     error::Error,
     path::{Path, PathBuf},
     sync::Arc,
@@ -22,62 +29,200 @@ pub struct Music {
     programer: Option<Programer>,
     macros: Arc<Macros>,
     channels: Channels,
+    octave_reversed: bool,
+    region: Region,
 }
 
 impl Music {
     /// Parses the given MML script and creates a [`Music`] instance.
     ///
     /// This is equivalent with `script.parse::<Music>()`.
+    ///
+    /// Note that `#INCLUDE` directives always fail with this method.
+    /// Use [`Music::with_resolver()`] instead if the script includes other scripts.
     pub fn new(script: &str) -> Result<Self, ParseMusicError> {
         script.parse()
     }
 
-    fn parse(parser: &mut Parser) -> Option<Result<Self, ParseMusicError>> {
-        let mut channels = Channels::new();
+    /// Parses the given MML script and creates a [`Music`] instance.
+    ///
+    /// Scripts referred by `#INCLUDE "<PATH>"` directives are loaded via `resolver`.
+    /// Included scripts can contain definitions and macros, but not channel commands.
+    /// The paths in an included script are relative to the directory of that script.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::collections::BTreeMap;
+    ///
+    /// let mut scripts = BTreeMap::new();
+    /// scripts.insert("volumes.mml", "@v0 = { 15 12 10 8 6 3 2 1 0 }");
+    ///
+    /// let music = ffmml::Music::with_resolver("#INCLUDE \"volumes.mml\"\nA @v0 cde", scripts)
+    ///     .unwrap_or_else(|e| panic!("{e}"));
+    /// ```
+    pub fn with_resolver<R: IncludeResolver>(
+        script: &str,
+        mut resolver: R,
+    ) -> Result<Self, ParseMusicError> {
+        let mut parser = Parser::new(script);
+        match Self::parse(&mut parser, &mut resolver) {
+            None => Err(ParseMusicError::from(parser.into_parse_error())),
+            Some(Err(mut e)) => {
+                if !e.included {
+                    e.text = script.to_owned();
+                }
+                Err(e)
+            }
+            Some(Ok(v)) => Ok(v),
+        }
+    }
+
+    fn parse(
+        parser: &mut Parser,
+        resolver: &mut dyn IncludeResolver,
+    ) -> Option<Result<Self, ParseMusicError>> {
+        let mut music = Self {
+            title: None,
+            composer: None,
+            programer: None,
+            macros: Arc::default(),
+            channels: Channels::new(),
+            octave_reversed: false,
+            region: Region::default(),
+        };
+        let mut macros = Macros::default();
+        if let Err(e) = music.parse_header(parser, &mut macros, resolver, &mut Vec::new())? {
+            return Some(Err(e));
+        }
+        music.macros = Arc::new(macros);
+
+        let _: CommentsOrWhitespaces = parser.parse()?;
+        if let Err(e) = music.channels.parse(parser, &music.macros)? {
+            return Some(Err(e));
+        }
+
+        Some(Ok(music))
+    }
 
-        let mut title = None;
-        let mut composer = None;
-        let mut programer = None;
+    fn parse_header(
+        &mut self,
+        parser: &mut Parser,
+        macros: &mut Macros,
+        resolver: &mut dyn IncludeResolver,
+        include_stack: &mut Vec<String>,
+    ) -> Option<Result<(), ParseMusicError>> {
         loop {
             let _: CommentsOrWhitespaces = parser.parse()?;
-            if parser.peek_char() != Some('#') {
-                break;
+            match parser.peek_char() {
+                Some('#') => {}
+                Some('@') => {
+                    macros.parse(parser, include_stack.last().map(String::as_str))?;
+                    continue;
+                }
+                _ => break,
             }
 
             match parser.parse()? {
                 Definition::Title(x) => {
-                    title = Some(x);
+                    self.title = Some(x);
                 }
                 Definition::Composer(x) => {
-                    composer = Some(x);
+                    self.composer = Some(x);
                 }
                 Definition::Programer(x) => {
-                    programer = Some(x);
+                    self.programer = Some(x);
                 }
                 Definition::Channel(x) => {
                     for name in x.channel_names().names() {
-                        channels.add_channel(*name, Oscillator::from_kind(x.oscillator_kind()));
+                        self.channels
+                            .add_channel(*name, Oscillator::from_kind(x.oscillator_kind()));
+                    }
+                    let n163_channels = self
+                        .channels
+                        .iter()
+                        .filter(|(_, c)| matches!(c.oscillator, Oscillator::N163(_)))
+                        .count();
+                    if n163_channels > n163::MAX_CHANNELS {
+                        return Some(Err(ParseMusicError::new(
+                            x,
+                            "too many N163 channels (up to 8)",
+                        )));
+                    }
+                    let sunsoft5b_channels = self
+                        .channels
+                        .iter()
+                        .filter(|(_, c)| matches!(c.oscillator, Oscillator::Sunsoft5b(_)))
+                        .count();
+                    if sunsoft5b_channels > sunsoft5b::MAX_CHANNELS {
+                        return Some(Err(ParseMusicError::new(
+                            x,
+                            "too many Sunsoft 5B channels (up to 3)",
+                        )));
+                    }
+                    let vrc7_channels = self
+                        .channels
+                        .iter()
+                        .filter(|(_, c)| matches!(c.oscillator, Oscillator::Vrc7(_)))
+                        .count();
+                    if vrc7_channels > vrc7::MAX_CHANNELS {
+                        return Some(Err(ParseMusicError::new(
+                            x,
+                            "too many VRC7 channels (up to 6)",
+                        )));
+                    }
+                }
+                Definition::OctaveRev(_) => {
+                    self.octave_reversed = true;
+                }
+                Definition::Region(x) => {
+                    self.region = x.get();
+                }
+                Definition::Include(x) => {
+                    // Loads the samples of the preceding macros so that their errors point to this script.
+                    let result = macros
+                        .load_dpcm_samples(resolver, self.region)
+                        .and_then(|()| self.include(&x, macros, resolver, include_stack));
+                    if let Err(e) = result {
+                        return Some(Err(e));
                     }
                 }
             }
         }
 
-        let _: CommentsOrWhitespaces = parser.parse()?;
-        let mut macros = Macros::default();
-        macros.parse(parser)?;
+        Some(macros.load_dpcm_samples(resolver, self.region))
+    }
 
-        let _: CommentsOrWhitespaces = parser.parse()?;
-        if let Err(e) = channels.parse(parser)? {
-            return Some(Err(e));
+    fn include(
+        &mut self,
+        include: &Include,
+        macros: &mut Macros,
+        resolver: &mut dyn IncludeResolver,
+        include_stack: &mut Vec<String>,
+    ) -> Result<(), ParseMusicError> {
+        let path =
+            &include::relative_path(include_stack.last().map(String::as_str), include.path());
+        if include_stack.iter().any(|p| p == path) {
+            return Err(ParseMusicError::new(include, "recursive #INCLUDE"));
         }
+        let text = resolver.resolve(path).map_err(|e| {
+            ParseMusicError::new(include, &format!("failed to include {path:?} ({e})"))
+        })?;
 
-        Some(Ok(Self {
-            title,
-            composer,
-            programer,
-            macros: Arc::new(macros),
-            channels,
-        }))
+        include_stack.push(path.clone());
+        let mut parser = Parser::new(&text);
+        let result = match self.parse_header(&mut parser, macros, resolver, include_stack) {
+            None => Err(ParseMusicError::from(parser.into_parse_error())),
+            Some(Err(e)) => Err(e),
+            Some(Ok(())) if !parser.is_eos() => Err(ParseMusicError::new(
+                parser.current_position(),
+                "channel commands are not allowed in included scripts",
+            )),
+            Some(Ok(())) => Ok(()),
+        };
+        include_stack.pop();
+
+        result.map_err(|e| e.included(path, &text))
     }
 
     /// Music title defined by `#TITLE <VALUE>` in the script.
@@ -97,6 +242,16 @@ impl Music {
         self.programer.as_ref().map(|x| x.get())
     }
 
+    /// Returns `true` if the meanings of `<` and `>` are swapped by `#OCTAVE-REV` in the script.
+    pub fn is_octave_reversed(&self) -> bool {
+        self.octave_reversed
+    }
+
+    /// Returns the TV system defined by `#REGION <NTSC|PAL|DENDY>` in the script ([`Region::Ntsc`] by default).
+    pub fn region(&self) -> Region {
+        self.region
+    }
+
     pub(crate) fn macros(&self) -> Arc<Macros> {
         self.macros.clone()
     }
@@ -109,21 +264,36 @@ impl Music {
     pub fn play(&self, sample_rate: u16) -> MusicPlayer {
         MusicPlayer::new(self, sample_rate)
     }
+
+    /// Computes the intro, loop body and total durations of each channel without playing the music.
+    ///
+    /// This only walks the commands that affect the timing (notes, rests, tempos, repeats, tuplets and so on),
+    /// so errors in the other commands are not reported.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use std::time::Duration;
+    ///
+    /// let music = ffmml::Music::new("A t120 l4 c d L e f").unwrap_or_else(|e| panic!("{e}"));
+    /// let analysis = music.analyze().unwrap_or_else(|e| panic!("{e}"));
+    ///
+    /// let a = analysis.channels()[&ffmml::ChannelName::A];
+    /// assert_eq!(a.intro(), Duration::from_secs(1));
+    /// assert_eq!(a.loop_body(), Some(Duration::from_secs(1)));
+    /// assert_eq!(a.total(), Duration::from_secs(2));
+    /// assert_eq!(analysis.duration_with_loops(2), Duration::from_secs(4));
+    /// ```
+    pub fn analyze(&self) -> Result<MusicAnalysis, PlayMusicError> {
+        MusicAnalysis::new(self)
+    }
 }
 
 impl std::str::FromStr for Music {
     type Err = ParseMusicError;
 
     fn from_str(text: &str) -> Result<Self, Self::Err> {
-        let mut parser = Parser::new(text);
-        match Self::parse(&mut parser) {
-            None => Err(ParseMusicError::from(parser.into_parse_error())),
-            Some(Err(mut e)) => {
-                e.text = text.to_owned();
-                Err(e)
-            }
-            Some(Ok(v)) => Ok(v),
-        }
+        Self::with_resolver(text, BTreeMap::<String, String>::new())
     }
 }
 
@@ -134,6 +304,7 @@ pub struct ParseMusicError {
     position: Position,
     reason: String,
     file_path: Option<PathBuf>,
+    included: bool,
 }
 
 impl ParseMusicError {
@@ -144,13 +315,32 @@ impl ParseMusicError {
             position: item.start_position(),
             reason: reason.to_owned(),
             file_path: None,
+            included: false,
         }
     }
 
+    fn included(mut self, path: &str, text: &str) -> Self {
+        if self.included {
+            return self;
+        }
+        if self.textparse_error.is_none() {
+            self.text = text.to_owned();
+        }
+        self = self.file_path(path);
+        self.included = true;
+        self
+    }
+
     /// Sets the file path of the target MML script.
     ///
     /// The default value is `<UNKNOWN>`.
+    ///
+    /// If this error occurred in a script included by `#INCLUDE` directive,
+    /// the path of the included script is used instead and this method has no effect.
     pub fn file_path<P: AsRef<Path>>(mut self, file_path: P) -> Self {
+        if self.included {
+            return self;
+        }
         if let Some(e) = self.textparse_error.take() {
             self.textparse_error = Some(Box::new(e.file_path(file_path)));
         } else {
@@ -209,6 +399,93 @@ impl From<ParseError> for ParseMusicError {
             position: Position::new(0),
             reason: String::new(),
             file_path: None,
+            included: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn include_loads_definitions_and_macros() {
+        // The paths are relative to the including script, and `#INCLUDE` can follow macros.
+        let files = BTreeMap::from([
+            (
+                "lib/header.mml",
+                "#TITLE Included\n@v1 = { 1 }\n#INCLUDE \"macros.mml\"",
+            ),
+            (
+                "lib/macros.mml",
+                "#CHANNEL E 1\n@v0 = { 15 10 5 }\n@DPCM0 = { \"a.dmc\", 15 }",
+            ),
+            ("lib/a.dmc", "\u{aa}"),
+        ]);
+        let music = Music::with_resolver("#INCLUDE \"lib/header.mml\"\nE @v0 c", files)
+            .unwrap_or_else(|e| panic!("{e}"));
+        assert_eq!(music.title(), Some("Included"));
+        assert_eq!(music.macros().volumes.len(), 2);
+        assert_eq!(music.macros().dpcms.len(), 1);
+        assert!(music
+            .channels()
+            .iter()
+            .any(|(_, c)| matches!(c.oscillator, Oscillator::TriangleWave(_))));
+    }
+
+    #[test]
+    fn include_errors() {
+        let error = |script: &str, files: &[(&'static str, &'static str)]| {
+            let files = files.iter().copied().collect::<BTreeMap<_, _>>();
+            Music::with_resolver(script, files)
+                .expect_err("should fail")
+                .reason
+        };
+        assert_eq!(
+            error("#INCLUDE \"missing.mml\"", &[]),
+            "failed to include \"missing.mml\" (no such file)"
+        );
+        assert_eq!(
+            error(
+                "#INCLUDE \"a.mml\"",
+                &[
+                    ("a.mml", "#INCLUDE \"b.mml\""),
+                    ("b.mml", "#INCLUDE \"a.mml\"")
+                ]
+            ),
+            "recursive #INCLUDE"
+        );
+        assert_eq!(
+            error("#INCLUDE \"a.mml\"", &[("a.mml", "A cde")]),
+            "channel commands are not allowed in included scripts"
+        );
+        // Unknown directives are reported as parse errors even if they follow macros.
+        let files = BTreeMap::from([("a.mml", "@v0 = { 1 }\n#FOO")]);
+        let e = Music::with_resolver("#INCLUDE \"a.mml\"", files).expect_err("unknown directive");
+        assert!(e.to_string().contains("expected one of CHANNEL"), "{e}");
+        assert!(Music::new("#INCLUDE \"a.mml\"").is_err());
+    }
+
+    #[test]
+    fn octave_rev() {
+        assert!(!Music::new("A c").expect("valid").is_octave_reversed());
+        assert!(Music::new("#OCTAVE-REV\nA c")
+            .expect("valid")
+            .is_octave_reversed());
+    }
+
+    #[test]
+    fn expansion_channel_limits() {
+        for (kind, max) in [(7, 8), (8, 3), (9, 6)] {
+            let channels = |n: usize| {
+                ('E'..)
+                    .take(n)
+                    .map(|name| format!("#CHANNEL {name} {kind}\n"))
+                    .collect::<String>()
+            };
+            assert!(Music::new(&channels(max)).is_ok(), "kind={kind}");
+            let e = Music::new(&channels(max + 1)).expect_err("too many channels");
+            assert!(e.reason.starts_with("too many"), "{e}");
         }
     }
 }
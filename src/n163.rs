@@ -0,0 +1,75 @@
+//! N163: the sound RAM and the channel multiplexer shared by the Namco 163 wavetable channels.
+use std::sync::{Arc, Mutex};
+
+/// Number of 4-bit samples in the sound RAM (128 bytes).
+pub const RAM_SAMPLES: usize = 256;
+
+/// Maximum number of channels.
+pub const MAX_CHANNELS: usize = 8;
+
+/// Number of 4-bit samples occupied by the registers of a channel (8 bytes at the end of the sound RAM).
+const REGISTER_SAMPLES: usize = 16;
+
+/// CPU cycles taken to update a channel (the channels are updated and output one at a time).
+const CYCLES_PER_CHANNEL: f64 = 15.0;
+
+/// State of the chip shared by all N163 channels of a music.
+///
+/// Waves are written into the sound RAM by the channels that select them, so a channel
+/// plays whatever the RAM holds at its wave offset (even if another channel has overwritten it).
+#[derive(Debug, Clone)]
+pub struct Chip {
+    ram: Arc<Mutex<[u8; RAM_SAMPLES]>>,
+    channel_count: usize,
+}
+
+impl Chip {
+    pub fn new(channel_count: usize) -> Self {
+        Self {
+            ram: Arc::new(Mutex::new([0; RAM_SAMPLES])),
+            channel_count: channel_count.clamp(1, MAX_CHANNELS),
+        }
+    }
+
+    /// Number of enabled channels.
+    pub fn channel_count(&self) -> usize {
+        self.channel_count
+    }
+
+    /// Returns the number of samples available for waves (the rest of the RAM holds the channel registers).
+    pub fn wave_area(&self) -> usize {
+        RAM_SAMPLES - REGISTER_SAMPLES * self.channel_count
+    }
+
+    pub fn write_wave(&self, offset: u8, samples: &[u8]) {
+        let mut ram = self.ram.lock().unwrap_or_else(|e| e.into_inner());
+        for (i, &sample) in samples.iter().enumerate() {
+            ram[(usize::from(offset) + i) % RAM_SAMPLES] = sample;
+        }
+    }
+
+    pub fn read(&self, address: u8) -> u8 {
+        self.ram.lock().unwrap_or_else(|e| e.into_inner())[usize::from(address)]
+    }
+
+    /// Returns how many times the channel (`index`) is updated within the CPU cycles `start..end`.
+    pub fn updates(&self, index: usize, start: f64, end: f64) -> u32 {
+        let period = CYCLES_PER_CHANNEL * self.channel_count as f64;
+        let offset = CYCLES_PER_CHANNEL * index as f64;
+        let count = |cycle: f64| ((cycle - offset) / period).ceil().max(0.0);
+        (count(end) - count(start)) as u32
+    }
+
+    /// Returns the ratio of the CPU cycles `start..end` during which the channel (`index`) is output.
+    ///
+    /// With `N` channels enabled, each channel is heard for `15` cycles out of every `15 * N` cycles.
+    pub fn time_share(&self, index: usize, start: f64, end: f64) -> f32 {
+        let period = CYCLES_PER_CHANNEL * self.channel_count as f64;
+        let offset = CYCLES_PER_CHANNEL * index as f64;
+        let output_cycles = |cycle: f64| {
+            let n = (cycle / period).floor();
+            n * CYCLES_PER_CHANNEL + (cycle - n * period - offset).clamp(0.0, CYCLES_PER_CHANNEL)
+        };
+        ((output_cycles(end) - output_cycles(start)) / (end - start)) as f32
+    }
+}
@@ -0,0 +1,2236 @@
+//! NSF: NES Sound Format.
+//!
+//! The generated NSF file consists of a 6502 sound driver, a byte stream per APU channel and the tables used by the streams.
+//! The commands of each channel are compiled into its stream, and the macros (`@v`, `@@`, `@EP`, `@EN` and `@MP`)
+//! into the tables, so the size of the file does not depend on the length of the music:
+//! repeats (`[...]`) are kept as repeats and the loop body (`L`) is stored once.
+//!
+//! The stream holds the notes (with their lengths in 16.16 fixed-point frames), the rests and ties,
+//! and the commands that change the envelopes, the timbre, the sweep and the hardware counters.
+//! The driver runs the envelopes and the vibrato frame by frame, computes the periods from the note tables
+//! and writes the APU registers (60 times per second, or 50 times for `#REGION PAL`).
+//!
+//! The commands resolved at compile time (the octave, `v+`/`v-`, the tempo and the note lengths) depend on the state
+//! of the channel, so a repeat whose body changes that state (e.g., `[c >]4`) is unrolled, and the loop body is compiled
+//! again until it ends in the state it started with.
+//!
+//! Known differences from the other outputs:
+//! - The envelopes step on the frames of the driver, so they can be up to a frame off the player.
+//! - The pitch sweep (`s`) works on the integer period, and is not applied to the noise channel.
+//! - A vibrato whose speed is greater than 84 (longer than 254 frames) or whose depth is greater than 127,
+//!   and macros that need more than 254 frames before looping are not supported.
+use crate::{
+    channel::Channel,
+    clocks::Clocks,
+    commands::{Command, RepeatStartCommand, TrackLoopCommand},
+    cursor::CommandCursor,
+    macros::{ArpeggioMacro, MacroNumber, Macros, VibratoMacro},
+    oscillators::{frequency_to_register, note_semitone, semitone_frequency, Oscillator},
+    registers::{
+        assign_slots, channel_error, load_dpcm_samples, register_to_period, start_position,
+        DpcmSamples, Slot, DMC_START, DMC_STOP, DPCM_START,
+    },
+    traits::NthFrameItem,
+    types::{Note, NoteDuration, Octave, Volume},
+    Music, PlayMusicError, Region,
+};
+use num::rational::Ratio;
+use std::{collections::BTreeMap, io::Write};
+use textparse::Span;
+
+const LOAD_ADDR: u16 = 0x8000;
+const INIT_ADDR: u16 = LOAD_ADDR;
+const PLAY_ADDR: u16 = LOAD_ADDR + 3;
+const VECTORS_ADDR: u16 = 0xFFFA;
+
+const SLOTS: u8 = 5;
+const SLOT_TRIANGLE: u8 = 2;
+const SLOT_NOISE: u8 = 3;
+const SLOT_DMC: u8 = 4;
+
+/// Number of the notes in the period tables (from `o0 a`).
+const NOTES: u8 = 96;
+
+const MAX_TABLE_LEN: usize = 254;
+const MAX_REPEAT_DEPTH: usize = 4;
+const MAX_LOOP_PASSES: usize = 16;
+
+// Stream opcodes (`0x00..OP_PERIOD` are notes).
+const OP_PERIOD: u8 = 0x60; // Period (2 bytes)
+const OP_REST: u8 = 0x61;
+const OP_TIE: u8 = 0x62;
+const OP_LENGTH: u8 = 0x63; // Note length in frames (4 bytes, 16.16 fixed-point)
+const OP_GATE: u8 = 0x64; // Gate length in frames (4 bytes, 16.16 fixed-point)
+const OP_VOLUME: u8 = 0x65; // Table address
+const OP_TIMBRE: u8 = 0x66; // Table address
+const OP_DETUNE: u8 = 0x67; // Table address
+const OP_ARPEGGIO: u8 = 0x68; // Table address (`0` to disable)
+const OP_VIBRATO: u8 = 0x69; // Table address (`0` to disable), Delay
+const OP_SWEEP: u8 = 0x6A; // Speed (`0` to disable), Depth (bit 7: downward)
+const OP_REPEAT_START: u8 = 0x6B; // Count
+const OP_REPEAT_END: u8 = 0x6C;
+const OP_JUMP: u8 = 0x6D; // Stream address
+const OP_END: u8 = 0x6E;
+const OP_DPCM: u8 = 0x6F; // `$4010`, `$4012`, `$4013`
+const OP_HARDWARE: u8 = 0x70; // Control bits, Length counter load, Retrigger flag
+
+/// Labels of the handlers of the opcodes from [`OP_REST`].
+const HANDLERS: [&str; 16] = [
+    "rest",
+    "tie",
+    "length",
+    "gate",
+    "volume",
+    "timbre",
+    "detune",
+    "arpeggio",
+    "vibrato",
+    "sweep",
+    "repeat_start",
+    "repeat_end",
+    "jump",
+    "end",
+    "dpcm",
+    "hardware",
+];
+
+/// [`Nsf`] provides a feature to export music as NSF format.
+///
+/// The channels of the music are assigned to the APU channels by their oscillators
+/// (up to two pulse wave channels, one triangle wave channel, one noise channel and one DPCM channel).
+///
+/// `#REGION DENDY` is not supported because NSF has no flag for it.
+#[derive(Debug)]
+pub struct Nsf {
+    region: Region,
+    title: String,
+    artist: String,
+    copyright: String,
+    data: Vec<u8>,
+}
+
+impl Nsf {
+    /// Makes a [`Nsf`] instance.
+    pub fn new(music: &Music) -> Result<Self, PlayMusicError> {
+        let slots = assign_slots(music, "NSF")?;
+        let region = music.region();
+        if region == Region::Dendy {
+            return Err(music_error(music, "Dendy region is not supported by NSF"));
+        }
+        let samples = load_dpcm_samples(music, &slots, "NSF")?;
+
+        let macros = music.macros();
+        let mut tables = Tables::default();
+        let mut streams = BTreeMap::new();
+        for (name, channel) in music.channels().iter() {
+            let slot = slots[&name];
+            let compiler =
+                StreamCompiler::new(slot, &channel, music, &macros, &samples, &mut tables);
+            let stream = compiler.compile().map_err(|mut e| {
+                e.channel = name;
+                e
+            })?;
+            let periods = (slot != Slot::Dmc).then(|| tables.add(period_table(slot, region)));
+            streams.insert(slot, (stream, periods));
+        }
+
+        let mut data = driver();
+        let directory = data.len();
+        data.resize(directory + usize::from(SLOTS) * 4, 0);
+        let tables_addr = address(data.len());
+        data.extend_from_slice(&tables.data);
+        for (slot, (stream, periods)) in streams {
+            let start = address(data.len());
+            let periods = periods.map_or(0, |offset| tables_addr + offset as u16);
+            let entry = directory + usize::from(slot.index()) * 4;
+            data[entry..entry + 2].copy_from_slice(&start.to_le_bytes());
+            data[entry + 2..entry + 4].copy_from_slice(&periods.to_le_bytes());
+            data.extend_from_slice(&stream.relocate(tables_addr, start));
+        }
+
+        let limit = if samples.data.is_empty() {
+            VECTORS_ADDR
+        } else {
+            DPCM_START
+        };
+        if data.len() > usize::from(limit - LOAD_ADDR) {
+            return Err(music_error(music, "too large music for NSF"));
+        }
+        if !samples.data.is_empty() {
+            data.resize(usize::from(DPCM_START - LOAD_ADDR), 0);
+            data.extend_from_slice(&samples.data);
+        }
+
+        Ok(Self {
+            region,
+            title: music.title().unwrap_or_default().to_owned(),
+            artist: music.composer().unwrap_or_default().to_owned(),
+            copyright: music.programer().unwrap_or_default().to_owned(),
+            data,
+        })
+    }
+
+    /// Exports this music as NSF into the writer.
+    pub fn to_writer<W: Write>(&self, mut writer: W) -> std::io::Result<()> {
+        writer.write_all(b"NESM\x1A")?;
+        writer.write_all(&[1, 1, 1])?; // Version, Total Songs, Starting Song
+        writer.write_all(&LOAD_ADDR.to_le_bytes())?;
+        writer.write_all(&INIT_ADDR.to_le_bytes())?;
+        writer.write_all(&PLAY_ADDR.to_le_bytes())?;
+        writer.write_all(&header_string(&self.title))?;
+        writer.write_all(&header_string(&self.artist))?;
+        writer.write_all(&header_string(&self.copyright))?;
+        writer.write_all(&16639u16.to_le_bytes())?; // NTSC Play Speed (micro seconds)
+        writer.write_all(&[0; 8])?; // No bank switching
+        writer.write_all(&19997u16.to_le_bytes())?; // PAL Play Speed (micro seconds)
+        let pal = u8::from(self.region == Region::Pal);
+        writer.write_all(&[pal, 0, 0, 0, 0, 0])?; // NTSC or PAL, No Extra Sound Chips, Reserved
+        writer.write_all(&self.data)?;
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+fn header_string(s: &str) -> [u8; 32] {
+    let mut bytes = [0; 32];
+    for (b, c) in bytes.iter_mut().zip(s.chars().take(31)) {
+        *b = if c.is_ascii() { c as u8 } else { b'?' };
+    }
+    bytes
+}
+
+fn address(offset: usize) -> u16 {
+    LOAD_ADDR + offset as u16
+}
+
+/// Returns an error that is not specific to a channel (reported at the start of the first channel).
+fn music_error(music: &Music, reason: &str) -> PlayMusicError {
+    let (name, channel) = music.channels().iter().next().expect("unreachable");
+    channel_error(name, start_position(&channel), reason)
+}
+
+/// Returns the period table of the slot: the low bytes and then the high bytes of the periods of the notes from `o0 a`.
+///
+/// The noise table holds the indices of the noise periods instead.
+fn period_table(slot: Slot, region: Region) -> Vec<u8> {
+    let periods = (0..usize::from(NOTES))
+        .map(|s| match slot {
+            Slot::Triangle => {
+                // The triangle wave channel plays an octave below the pulse wave channels.
+                let register = frequency_to_register(region, semitone_frequency(s) / 2.0);
+                register_to_period(register / 2.0)
+            }
+            Slot::Noise | Slot::Dmc => ((s + 9) % 12) as u16, // The offset from C.
+            Slot::Pulse1 | Slot::Pulse2 => {
+                register_to_period(frequency_to_register(region, semitone_frequency(s)))
+            }
+        })
+        .collect::<Vec<_>>();
+    periods
+        .iter()
+        .map(|&p| p as u8)
+        .chain(periods.iter().map(|&p| (p >> 8) as u8))
+        .collect()
+}
+
+/// Tables used by the streams (the same tables are shared).
+#[derive(Debug, Default)]
+struct Tables {
+    data: Vec<u8>,
+    offsets: BTreeMap<Vec<u8>, usize>,
+}
+
+impl Tables {
+    fn add(&mut self, table: Vec<u8>) -> usize {
+        if let Some(&offset) = self.offsets.get(&table) {
+            return offset;
+        }
+        let offset = self.data.len();
+        self.data.extend_from_slice(&table);
+        self.offsets.insert(table, offset);
+        offset
+    }
+}
+
+/// Makes an envelope table of the driver: the number of the frames, the loop point and the values of the frames.
+fn envelope_table(
+    span: impl Span,
+    (len, loop_point): (usize, Option<usize>),
+    value: impl Fn(usize) -> u8,
+) -> Result<Vec<u8>, PlayMusicError> {
+    if len > MAX_TABLE_LEN {
+        return Err(PlayMusicError::new(span, "too long macro for NSF"));
+    }
+    let mut table = vec![len as u8, loop_point.unwrap_or(len - 1) as u8];
+    table.extend((0..len).map(value));
+    Ok(table)
+}
+
+/// Makes the table of the note offsets of an arpeggio macro.
+///
+/// The offsets are accumulated frame by frame, so the table holds the frames until the accumulation saturates
+/// (or ends without a loop point), and then loops over the last loop of the macro.
+fn arpeggio_table(m: &ArpeggioMacro) -> Result<Vec<u8>, PlayMusicError> {
+    let envelope = m.envelope();
+    let frames = match envelope.frames() {
+        (len, None) => (len + 1, Some(len)),
+        (len, Some(loop_point)) => {
+            let start = len.max(usize::from(i8::MAX as u8) + 1);
+            (start + len - loop_point, Some(start))
+        }
+    };
+    envelope_table(m, frames, |i| envelope.nth_frame_item(i) as u8)
+}
+
+/// Makes the sine table of a vibrato macro (a period is `3 * speed` frames).
+fn vibrato_table(m: &VibratoMacro) -> Result<Vec<u8>, PlayMusicError> {
+    let vibrato = m.vibrato();
+    let period = 3 * usize::from(vibrato.speed());
+    if period > MAX_TABLE_LEN {
+        return Err(PlayMusicError::new(m, "too slow vibrato for NSF"));
+    }
+    if vibrato.depth() > i8::MAX as u8 {
+        return Err(PlayMusicError::new(m, "too deep vibrato for NSF"));
+    }
+    let depth = f32::from(vibrato.depth());
+    envelope_table(m, (period, Some(0)), |i| {
+        let phase = i as f32 / period as f32 * 2.0 * std::f32::consts::PI;
+        (depth * phase.sin()).round() as i8 as u8
+    })
+}
+
+/// Byte stream of a channel and the addresses in it that are resolved when the file is laid out.
+#[derive(Debug, Default)]
+struct Stream {
+    bytes: Vec<u8>,
+    relocations: Vec<(usize, Target)>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Target {
+    Table(usize),
+    Stream(usize),
+}
+
+impl Stream {
+    fn push_address(&mut self, target: Target) {
+        self.relocations.push((self.bytes.len(), target));
+        self.bytes.extend_from_slice(&[0, 0]);
+    }
+
+    fn insert(&mut self, offset: usize, bytes: &[u8]) {
+        self.bytes.splice(offset..offset, bytes.iter().copied());
+        for (i, _) in &mut self.relocations {
+            if *i >= offset {
+                *i += bytes.len();
+            }
+        }
+    }
+
+    fn relocate(mut self, tables: u16, start: u16) -> Vec<u8> {
+        for (i, target) in self.relocations {
+            let addr = match target {
+                Target::Table(offset) => tables + offset as u16,
+                Target::Stream(offset) => start + offset as u16,
+            };
+            self.bytes[i..i + 2].copy_from_slice(&addr.to_le_bytes());
+        }
+        self.bytes
+    }
+}
+
+/// Settings of the hardware volume envelope (`HE`), length counter (`HL`) and linear counter (`HT`).
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct Hardware {
+    envelope: Option<u8>,
+    length_index: Option<u8>,
+    linear_counter: Option<u8>,
+}
+
+impl Hardware {
+    /// Returns the operands of [`OP_HARDWARE`].
+    fn operands(self, slot: Slot) -> [u8; 3] {
+        if slot == Slot::Triangle {
+            return match self.linear_counter {
+                Some(reload) => [reload, 0x08, 1],
+                None => [0xFF, 0xF8, 0],
+            };
+        }
+        let halt = if self.length_index.is_none() { 0x20 } else { 0 };
+        let control = match self.envelope {
+            Some(period) => halt | period,
+            None => halt | 0x10,
+        };
+        let retrigger = self.envelope.is_some() || self.length_index.is_some();
+        [
+            control,
+            self.length_index.unwrap_or(31) << 3,
+            u8::from(retrigger),
+        ]
+    }
+}
+
+/// The state of a channel that the compiled commands depend on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct State {
+    octave: u8,
+    volume: Option<u8>,
+    timing: (u8, u8, Option<u8>, Option<u8>),
+    hardware: Hardware,
+}
+
+#[derive(Debug)]
+struct LoopPoint {
+    command: TrackLoopCommand,
+    index: usize,
+    state: State,
+    offset: usize,
+    events: usize,
+}
+
+/// Compiles the commands of a channel into a stream.
+#[derive(Debug)]
+struct StreamCompiler<'a> {
+    slot: Slot,
+    oscillator: Oscillator,
+    cursor: CommandCursor,
+    macros: &'a Macros,
+    samples: &'a DpcmSamples,
+    tables: &'a mut Tables,
+    octave: Octave,
+    octave_reversed: bool,
+    volume: Option<Volume>, // `None` while a volume envelope is used.
+    hardware: Hardware,
+    clocks: Clocks,
+    frame_rate: u8,
+    stream: Stream,
+    length: Option<u32>,
+    gate: Option<u32>,
+    events: usize,
+    repeat_depth: usize,
+    loop_point: Option<LoopPoint>,
+}
+
+impl<'a> StreamCompiler<'a> {
+    fn new(
+        slot: Slot,
+        channel: &Channel,
+        music: &Music,
+        macros: &'a Macros,
+        samples: &'a DpcmSamples,
+        tables: &'a mut Tables,
+    ) -> Self {
+        let frame_rate = music.region().frame_rate();
+        let mut clocks = Clocks::new(1); // The sample rate is not used.
+        clocks.set_frame_rate(frame_rate);
+        Self {
+            slot,
+            oscillator: channel.oscillator.clone(),
+            cursor: CommandCursor::new(channel.commands.clone()),
+            macros,
+            samples,
+            tables,
+            octave: Octave::default(),
+            octave_reversed: music.is_octave_reversed(),
+            volume: Some(Volume::default()),
+            hardware: Hardware::default(),
+            clocks,
+            frame_rate,
+            stream: Stream::default(),
+            length: None,
+            gate: None,
+            events: 0,
+            repeat_depth: 0,
+            loop_point: None,
+        }
+    }
+
+    fn compile(mut self) -> Result<Stream, PlayMusicError> {
+        self.emit_table(OP_VOLUME, vec![1, 0, Volume::default().get()]);
+        self.emit_table(OP_TIMBRE, vec![1, 0, 0]);
+        self.emit_table(OP_DETUNE, vec![1, 0, 0]);
+        self.emit_hardware();
+
+        let end = self.cursor.commands().len();
+        self.compile_until(end)?;
+        let Some(loop_point) = self.loop_point.take() else {
+            self.stream.bytes.push(OP_END);
+            return Ok(self.stream);
+        };
+
+        // The loop body is compiled again until it ends in the state of one of the compiled passes.
+        let mut passes = vec![(loop_point.state.clone(), loop_point.offset)];
+        let mut events = loop_point.events;
+        loop {
+            if self.events == events {
+                // The loop body has no notes.
+                self.stream.bytes.push(OP_END);
+                return Ok(self.stream);
+            }
+            let state = self.state();
+            if let Some(&(_, offset)) = passes.iter().find(|(s, _)| *s == state) {
+                self.stream.bytes.push(OP_JUMP);
+                self.stream.push_address(Target::Stream(offset));
+                return Ok(self.stream);
+            }
+            if passes.len() == MAX_LOOP_PASSES {
+                return Err(PlayMusicError::new(
+                    &loop_point.command,
+                    "the loop body never returns to its starting state",
+                ));
+            }
+            passes.push((state, self.stream.bytes.len()));
+            events = self.events;
+            self.reset_lengths();
+            self.cursor.jump(loop_point.index);
+            self.compile_until(end)?;
+        }
+    }
+
+    fn compile_until(&mut self, end: usize) -> Result<(), PlayMusicError> {
+        while self.cursor.index() < end {
+            let command = self.cursor.next_command().expect("unreachable");
+            self.compile_command(command)?;
+        }
+        Ok(())
+    }
+
+    fn compile_command(&mut self, command: Command) -> Result<(), PlayMusicError> {
+        match command {
+            Command::Note(c) => self.play_note(c.note(), self.octave, c.note_duration())?,
+            Command::DirectNote(c) => {
+                let octave = c
+                    .octave()
+                    .ok_or_else(|| PlayMusicError::new(&c, "note number out of range"))?;
+                self.play_note(c.note(), octave, c.note_duration())?;
+            }
+            Command::DirectFrequency(c) => {
+                if !self.oscillator.clone().set_period(c.period()) {
+                    return Err(PlayMusicError::new(c.period(), "unsupported period value"));
+                }
+                let [lo, hi] = c.period().get().to_le_bytes();
+                self.emit_note(&[OP_PERIOD, lo, hi], c.note_duration(), true);
+            }
+            Command::RestSign(c) => self.emit_note(&[OP_REST], c.note_duration(), false),
+            Command::Wait(c) => self.emit_note(&[OP_TIE], c.note_duration(), true),
+            Command::Tie(c) => {
+                self.cursor.tie(&c)?;
+                self.emit_note(&[OP_TIE], c.note_duration(), true);
+            }
+            Command::Slur(c) => {
+                let after = self.cursor.slur(c)?;
+                self.emit_note(&[OP_TIE], after.note_duration(), true);
+            }
+            Command::Volume(c) => self.set_volume(c.volume()),
+            Command::VolumeUp(c) => {
+                let v = self
+                    .volume
+                    .ok_or_else(|| PlayMusicError::new(&c, "cannot be used with volume envelope"))?
+                    .checked_add(c.count())
+                    .ok_or_else(|| PlayMusicError::new(&c, "volume overflow"))?;
+                self.set_volume(v);
+            }
+            Command::VolumeDown(c) => {
+                let v = self
+                    .volume
+                    .ok_or_else(|| PlayMusicError::new(&c, "cannot be used with volume envelope"))?
+                    .checked_sub(c.count())
+                    .ok_or_else(|| PlayMusicError::new(&c, "volume underflow"))?;
+                self.set_volume(v);
+            }
+            Command::VolumeEnvelope(c) => {
+                let m = self
+                    .macros
+                    .volumes
+                    .get(&c.macro_number())
+                    .ok_or_else(|| PlayMusicError::new(&c, "undefined macro number"))?;
+                let envelope = m.envelope();
+                self.volume = envelope.is_constant().then(|| envelope.nth_frame_item(0));
+                let table =
+                    envelope_table(m, envelope.frames(), |i| envelope.nth_frame_item(i).get())?;
+                self.emit_table(OP_VOLUME, table);
+            }
+            Command::Timbre(c) => self.emit_table(OP_TIMBRE, vec![1, 0, c.timbre().get()]),
+            Command::Timbres(c) => {
+                let m = self
+                    .macros
+                    .timbres
+                    .get(&c.macro_number())
+                    .ok_or_else(|| PlayMusicError::new(&c, "undefined macro number"))?;
+                let timbres = m.timbres();
+                let table =
+                    envelope_table(m, timbres.frames(), |i| timbres.nth_frame_item(i).get())?;
+                self.emit_table(OP_TIMBRE, table);
+            }
+            Command::Detune(c) => self.emit_table(OP_DETUNE, vec![1, 0, c.detune().get() as u8]),
+            Command::PitchEnvelope(c) => {
+                let Some(n) = c.macro_number() else {
+                    self.emit_table(OP_DETUNE, vec![1, 0, 0]);
+                    return Ok(());
+                };
+                let m = self
+                    .macros
+                    .pitches
+                    .get(&n)
+                    .ok_or_else(|| PlayMusicError::new(&c, "undefined macro number"))?;
+                let envelope = m.envelope();
+                let table = envelope_table(m, envelope.frames(), |i| {
+                    envelope.nth_frame_item(i).get() as u8
+                })?;
+                self.emit_table(OP_DETUNE, table);
+            }
+            Command::Arpeggio(c) => {
+                let Some(n) = c.macro_number() else {
+                    self.stream.bytes.extend_from_slice(&[OP_ARPEGGIO, 0, 0]);
+                    return Ok(());
+                };
+                let m = self
+                    .macros
+                    .arpeggios
+                    .get(&n)
+                    .ok_or_else(|| PlayMusicError::new(&c, "undefined macro number"))?;
+                self.emit_table(OP_ARPEGGIO, arpeggio_table(m)?);
+            }
+            Command::PitchSweep(c) => {
+                let sweep = c.sweep();
+                let operands = match (sweep.speed(), sweep.depth()) {
+                    (Some(speed), Some(depth)) if depth < 0 => [speed, 0x80 | depth.unsigned_abs()],
+                    (Some(speed), Some(depth)) => [speed, depth as u8],
+                    _ => [0, 0],
+                };
+                self.stream.bytes.push(OP_SWEEP);
+                self.stream.bytes.extend_from_slice(&operands);
+            }
+            Command::Vibrato(c) => {
+                let Some(n) = c.macro_number() else {
+                    self.stream.bytes.extend_from_slice(&[OP_VIBRATO, 0, 0, 0]);
+                    return Ok(());
+                };
+                let m = self
+                    .macros
+                    .vibratos
+                    .get(&n)
+                    .ok_or_else(|| PlayMusicError::new(&c, "undefined macro number"))?;
+                self.emit_table(OP_VIBRATO, vibrato_table(m)?);
+                self.stream.bytes.push(m.vibrato().delay());
+            }
+            Command::HardwareEnvelope(c) => {
+                if !self.oscillator.set_hardware_envelope(c.period()) {
+                    return Err(PlayMusicError::new(
+                        c,
+                        "hardware envelope is only supported by pulse wave and noise channels",
+                    ));
+                }
+                self.hardware.envelope = c.period();
+                self.emit_hardware();
+            }
+            Command::LengthCounter(c) => {
+                if !self.oscillator.set_length_counter(c.index()) {
+                    return Err(PlayMusicError::new(
+                        c,
+                        "length counter is only supported by pulse wave and noise channels",
+                    ));
+                }
+                self.hardware.length_index = c.index();
+                self.emit_hardware();
+            }
+            Command::LinearCounter(c) => {
+                let value = c.value().map(|v| v.get());
+                if !self.oscillator.set_linear_counter(value) {
+                    return Err(PlayMusicError::new(
+                        c,
+                        "linear counter is only supported by triangle wave channels",
+                    ));
+                }
+                self.hardware.linear_counter = value;
+                self.emit_hardware();
+            }
+            Command::FdsModulation(c) => return Err(unsupported(c)),
+            Command::FdsMasterVolume(c) => return Err(unsupported(c)),
+            Command::N163WaveOffset(c) => return Err(unsupported(c)),
+            Command::Sunsoft5bNoise(c) => return Err(unsupported(c)),
+            Command::Sunsoft5bEnvelope(c) => return Err(unsupported(c)),
+            Command::Vrc7Sustain(c) => return Err(unsupported(c)),
+            Command::Vrc7UserPatch(c) => return Err(unsupported(c)),
+            Command::Pan(_) => {} // The APU output is mono.
+            Command::Octave(c) => self.octave = c.octave(),
+            Command::OctaveUp(c) => self.shift_octave(c, !self.octave_reversed)?,
+            Command::OctaveDown(c) => self.shift_octave(c, self.octave_reversed)?,
+            Command::DefaultNoteDuration(c) => {
+                self.clocks
+                    .set_default_note_duration(c.default_note_duration());
+            }
+            Command::Tempo(c) => self.clocks.set_tempo(c.tempo()),
+            Command::Quantize(c) => self.clocks.set_quantize(c.quantize()),
+            Command::QuantizeFrame(c) => self.clocks.set_quantize_frame(c.quantize_frame()),
+            Command::DataSkip(_) => self.cursor.skip_to_end(),
+            Command::TrackLoop(c) => {
+                if self.repeat_depth > 0 {
+                    return Err(PlayMusicError::new(
+                        c,
+                        "loop point in a repeat is not supported by NSF",
+                    ));
+                }
+                self.reset_lengths();
+                self.loop_point = Some(LoopPoint {
+                    command: c,
+                    index: self.cursor.index(),
+                    state: self.state(),
+                    offset: self.stream.bytes.len(),
+                    events: self.events,
+                });
+            }
+            Command::RepeatStart(c) => {
+                let start = self.cursor.index();
+                let (end, count) = self.repeat_end(c)?;
+                self.compile_repeat(start, end, count)?;
+            }
+            Command::RepeatEnd(c) => return Err(PlayMusicError::new(c, "no maching '['")),
+            Command::TupletStart(c) => self.cursor.tuplet_start(c, &mut self.clocks)?,
+            Command::TupletEnd(c) => self.cursor.tuplet_end(c)?,
+        }
+        Ok(())
+    }
+
+    /// Returns the index of the `]` that matches the `[` and the repeat count.
+    fn repeat_end(&self, command: RepeatStartCommand) -> Result<(usize, usize), PlayMusicError> {
+        let mut depth = 0;
+        for (i, c) in self
+            .cursor
+            .commands()
+            .iter()
+            .enumerate()
+            .skip(self.cursor.index())
+        {
+            match c {
+                Command::RepeatStart(_) => depth += 1,
+                Command::RepeatEnd(c) if depth == 0 => return Ok((i, c.clone().count())),
+                Command::RepeatEnd(_) => depth -= 1,
+                Command::DataSkip(_) => break,
+                _ => {}
+            }
+        }
+        Err(PlayMusicError::new(command, "no maching ']'"))
+    }
+
+    /// Compiles the body of a repeat (the commands in `start..end`) that is played `count` times.
+    ///
+    /// The body is compiled once and repeated by the driver if it ends in the state it started with,
+    /// otherwise the first iteration is unrolled.
+    fn compile_repeat(
+        &mut self,
+        start: usize,
+        end: usize,
+        count: usize,
+    ) -> Result<(), PlayMusicError> {
+        let state = self.state();
+        let offset = self.stream.bytes.len();
+        self.reset_lengths();
+        self.repeat_depth += 1;
+        self.cursor.jump(start);
+        self.compile_until(end)?;
+        self.repeat_depth -= 1;
+        if count > 1 {
+            if self.state() == state && self.repeat_depth < MAX_REPEAT_DEPTH {
+                self.stream.insert(offset, &[OP_REPEAT_START, count as u8]);
+                self.stream.bytes.push(OP_REPEAT_END);
+            } else {
+                return self.compile_repeat(start, end, count - 1);
+            }
+        }
+        self.cursor.jump(end + 1);
+        Ok(())
+    }
+
+    fn state(&self) -> State {
+        State {
+            octave: self.octave.get(),
+            volume: self.volume.map(|v| v.get()),
+            timing: self.clocks.settings(),
+            hardware: self.hardware,
+        }
+    }
+
+    /// Forgets the note and gate lengths known to the driver (at the targets of jumps).
+    fn reset_lengths(&mut self) {
+        self.length = None;
+        self.gate = None;
+    }
+
+    fn shift_octave(&mut self, span: impl Span, up: bool) -> Result<(), PlayMusicError> {
+        self.octave = if up {
+            self.octave
+                .checked_add(1)
+                .ok_or_else(|| PlayMusicError::new(span, "octave oveflow"))?
+        } else {
+            self.octave
+                .checked_sub(1)
+                .ok_or_else(|| PlayMusicError::new(span, "octave underflow"))?
+        };
+        Ok(())
+    }
+
+    fn set_volume(&mut self, volume: Volume) {
+        self.volume = Some(volume);
+        self.emit_table(OP_VOLUME, vec![1, 0, volume.get()]);
+    }
+
+    fn play_note(
+        &mut self,
+        note: Note,
+        octave: Octave,
+        duration: NoteDuration,
+    ) -> Result<(), PlayMusicError> {
+        if self.slot != Slot::Dmc {
+            let semitone = note_semitone(note, octave) as u8;
+            self.emit_note(&[semitone], duration, true);
+            return Ok(());
+        }
+
+        let number = MacroNumber::from_dpcm_note(note, octave);
+        let m = self
+            .macros
+            .dpcms
+            .get(&number)
+            .ok_or_else(|| PlayMusicError::new(note, "undefined DPCM sample"))?;
+        match self.samples.registers(number) {
+            Some((addr, len)) => self.emit_note(&[OP_DPCM, m.pitch(), addr, len], duration, true),
+            None => self.emit_note(&[OP_REST], duration, false), // The sample is empty.
+        }
+        Ok(())
+    }
+
+    /// Emits a note, a rest or a tie that lasts for `duration` (and the length and the gate if they have changed).
+    fn emit_note(&mut self, event: &[u8], duration: NoteDuration, gated: bool) {
+        let start = self.clocks.note_clock().get();
+        self.clocks.tick_note_clock(duration);
+        let length = self.frames(self.clocks.note_clock().get() - start).max(1);
+        if self.length != Some(length) {
+            self.stream.bytes.push(OP_LENGTH);
+            self.stream.bytes.extend_from_slice(&length.to_le_bytes());
+            self.length = Some(length);
+        }
+        if gated {
+            let end = self.clocks.quantize_clock().get();
+            let gate = if end > start {
+                self.frames(end - start)
+            } else {
+                0
+            };
+            if self.gate != Some(gate) {
+                self.stream.bytes.push(OP_GATE);
+                self.stream.bytes.extend_from_slice(&gate.to_le_bytes());
+                self.gate = Some(gate);
+            }
+        }
+        self.stream.bytes.extend_from_slice(event);
+        self.events += 1;
+    }
+
+    /// Converts seconds into frames (16.16 fixed-point number).
+    fn frames(&self, seconds: Ratio<u64>) -> u32 {
+        let frames = seconds * u64::from(self.frame_rate) * 0x10000;
+        frames.round().to_integer().min(i32::MAX as u64) as u32
+    }
+
+    fn emit_table(&mut self, opcode: u8, table: Vec<u8>) {
+        let offset = self.tables.add(table);
+        self.stream.bytes.push(opcode);
+        self.stream.push_address(Target::Table(offset));
+    }
+
+    fn emit_hardware(&mut self) {
+        self.stream.bytes.push(OP_HARDWARE);
+        let operands = self.hardware.operands(self.slot);
+        self.stream.bytes.extend_from_slice(&operands);
+    }
+}
+
+fn unsupported(command: impl Span) -> PlayMusicError {
+    PlayMusicError::new(command, "unsupported command for the APU channels")
+}
+
+// Zero page variables of the driver.
+const ZP_PTR: u8 = 0x00; // Stream pointer of the current channel
+const ZP_TABLE: u8 = 0x02;
+const ZP_BASE: u8 = 0x04; // Period
+const ZP_OFFSET: u8 = 0x06; // Period offset (subtracted from the period)
+const ZP_SHIFT: u8 = 0x08;
+const ZP_JUMP: u8 = 0x0A;
+const ZP_VOLUME: u8 = 0x0C;
+const ZP_TIMBRE: u8 = 0x0D;
+const ZP_DUTY: u8 = 0x0E;
+const ZP_NOISE: u8 = 0x0F;
+const ZP_TMP: u8 = 0x10;
+const ZP_REG: u8 = 0x11;
+const ZP_VALUE: u8 = 0x12;
+
+/// Address of a channel variable of the driver (indexed by the slot in `X`).
+const fn var(n: u16) -> u16 {
+    0x0200 + n * 8
+}
+
+const ACTIVE: u16 = var(0);
+const PTR: [u16; 2] = [var(1), var(2)];
+const WAIT: [u16; 4] = [var(3), var(4), var(5), var(6)]; // Frames until the next event
+const GATE: [u16; 4] = [var(7), var(8), var(9), var(10)]; // Frames until the note is muted
+const LENGTH: [u16; 4] = [var(11), var(12), var(13), var(14)];
+const GATE_LENGTH: [u16; 4] = [var(15), var(16), var(17), var(18)];
+const MUTED: u16 = var(19);
+const RESTING: u16 = var(20);
+const NEW_NOTE: u16 = var(21);
+const NOTE: u16 = var(22);
+const DIRECT: u16 = var(23); // `1` if the period is given by `@n`
+const BASE: [u16; 2] = [var(24), var(25)];
+const VOLUME: [u16; 3] = [var(26), var(27), var(28)]; // Table address and position
+const TIMBRE: [u16; 3] = [var(29), var(30), var(31)];
+const DETUNE: [u16; 3] = [var(32), var(33), var(34)];
+const ARPEGGIO: [u16; 3] = [var(35), var(36), var(37)];
+const VIBRATO: [u16; 3] = [var(38), var(39), var(40)];
+const VIBRATO_DELAY: u16 = var(41);
+const VIBRATO_WAIT: u16 = var(42);
+const SWEEP_SPEED: u16 = var(43);
+const SWEEP_DEPTH: u16 = var(44);
+const SWEEP_COUNT: u16 = var(45);
+const PERIOD: [u16; 2] = [var(46), var(47)]; // Swept period
+const PERIODS: [u16; 2] = [var(48), var(49)]; // Period table address
+const HARDWARE: [u16; 3] = [var(50), var(51), var(52)];
+const LAST: [u16; 3] = [var(53), var(54), var(55)]; // Last values of the registers 0, 2 and 3
+const FORCE: u16 = var(56);
+const REPEAT_DEPTH: u16 = var(57);
+const REPEAT_COUNT: u16 = var(58); // 4 levels (indexed by `level * 8 + slot`)
+const REPEAT_PTR: [u16; 2] = [var(62), var(66)];
+const DMC_STOPPED: u16 = var(70);
+
+/// Builds the 6502 sound driver (`INIT` at `$8000` and `PLAY` at `$8003`).
+///
+/// The driver is followed by the directory of the streams
+/// (the stream address and the period table address of each slot, or zeros if the slot is not used).
+fn driver() -> Vec<u8> {
+    let mut a = Assembler::new(LOAD_ADDR);
+    a.jump(JMP, "init");
+    a.jump(JMP, "play");
+
+    // INIT
+    a.label("init");
+    a.imm(LDA_IMM, DMC_STOP);
+    a.abs(STA_ABS, 0x4015);
+    a.imm(LDA_IMM, 0x40);
+    a.abs(STA_ABS, 0x4017);
+    a.imm(LDA_IMM, 0x08); // Disables the hardware sweep units.
+    a.abs(STA_ABS, 0x4001);
+    a.abs(STA_ABS, 0x4005);
+    a.imm(LDA_IMM, 0);
+    a.op(TAX);
+    a.label("clear");
+    for page in [0x0200, 0x0300, 0x0400] {
+        a.abs(STA_ABSX, page);
+    }
+    a.op(INX);
+    a.branch(BNE, "clear");
+    a.imm(LDX_IMM, SLOTS - 1);
+    a.label("init_slot");
+    a.op(TXA);
+    a.op(ASL_A);
+    a.op(ASL_A);
+    a.op(TAY);
+    for (i, var) in [PTR[0], PTR[1], PERIODS[0], PERIODS[1]]
+        .into_iter()
+        .enumerate()
+    {
+        if i > 0 {
+            a.op(INY);
+        }
+        a.jump(LDA_ABSY, "directory");
+        a.abs(STA_ABSX, var);
+    }
+    // The streams are placed at `$8000..`, so the high byte of the address is zero only if the slot is not used.
+    a.abs(LDA_ABSX, PTR[1]);
+    a.abs(STA_ABSX, ACTIVE);
+    a.imm(LDA_IMM, 1);
+    for var in [MUTED, RESTING, FORCE, DMC_STOPPED] {
+        a.abs(STA_ABSX, var);
+    }
+    a.op(DEX);
+    a.branch(BPL, "init_slot");
+    a.op(RTS);
+
+    // PLAY
+    a.label("play");
+    a.imm(LDX_IMM, 0);
+    a.label("play_slot");
+    a.abs(LDA_ABSX, ACTIVE);
+    a.branch(BEQ, "play_next");
+    a.jump(JSR, "update");
+    a.label("play_next");
+    a.op(INX);
+    a.imm(CPX_IMM, SLOTS);
+    a.branch(BNE, "play_slot");
+    a.op(RTS);
+
+    // Updates the channel of the slot `X` by a frame.
+    a.label("update");
+    a.abs(LDA_ABSX, PTR[0]);
+    a.imm(STA_ZP, ZP_PTR);
+    a.abs(LDA_ABSX, PTR[1]);
+    a.imm(STA_ZP, ZP_PTR + 1);
+    a.imm(LDA_IMM, 0);
+    a.abs(STA_ABSX, NEW_NOTE);
+
+    // Reads the events while the wait is not positive.
+    a.label("event_loop");
+    a.abs(LDA_ABSX, WAIT[3]);
+    a.branch(BMI, "event");
+    for var in &WAIT[..3] {
+        a.abs(ORA_ABSX, *var);
+    }
+    a.branch(BEQ, "event");
+    a.jump(JMP, "events_done");
+    a.label("event");
+    a.jump(JSR, "read_byte");
+    a.imm(CMP_IMM, OP_PERIOD);
+    a.branch(BCS, "not_note");
+    a.abs(STA_ABSX, NOTE);
+    a.imm(LDA_IMM, 0);
+    a.abs(STA_ABSX, DIRECT);
+    a.jump(JSR, "start_note");
+    a.jump(JMP, "event_loop");
+    a.label("not_note");
+    a.branch(BNE, "dispatch");
+    for var in BASE {
+        a.jump(JSR, "read_byte");
+        a.abs(STA_ABSX, var);
+    }
+    a.imm(LDA_IMM, 1);
+    a.abs(STA_ABSX, DIRECT);
+    a.jump(JSR, "start_note");
+    a.jump(JMP, "event_loop");
+    a.label("dispatch");
+    a.imm(SBC_IMM, OP_REST); // The carry is set.
+    a.op(ASL_A);
+    a.op(TAY);
+    a.jump(LDA_ABSY, "handlers");
+    a.imm(STA_ZP, ZP_JUMP);
+    a.op(INY);
+    a.jump(LDA_ABSY, "handlers");
+    a.imm(STA_ZP, ZP_JUMP + 1);
+    a.abs(JMP_IND, u16::from(ZP_JUMP));
+
+    a.label("rest");
+    a.imm(LDA_IMM, 1);
+    a.abs(STA_ABSX, MUTED);
+    a.abs(STA_ABSX, RESTING);
+    a.jump(JSR, "add_length");
+    a.jump(JMP, "event_loop");
+
+    a.label("tie");
+    a.jump(JSR, "start_gate");
+    a.jump(JSR, "add_length");
+    a.abs(LDA_ABSX, RESTING);
+    a.branch(BNE, "tie_done");
+    a.abs(STA_ABSX, MUTED);
+    a.label("tie_done");
+    a.jump(JMP, "event_loop");
+
+    for (label, vars) in [("length", LENGTH), ("gate", GATE_LENGTH)] {
+        a.label(label);
+        for var in vars {
+            a.jump(JSR, "read_byte");
+            a.abs(STA_ABSX, var);
+        }
+        a.jump(JMP, "event_loop");
+    }
+
+    for (label, [lo, hi, position]) in [
+        ("volume", VOLUME),
+        ("timbre", TIMBRE),
+        ("detune", DETUNE),
+        ("arpeggio", ARPEGGIO),
+        ("vibrato", VIBRATO),
+    ] {
+        a.label(label);
+        a.jump(JSR, "read_byte");
+        a.abs(STA_ABSX, lo);
+        a.jump(JSR, "read_byte");
+        a.abs(STA_ABSX, hi);
+        a.imm(LDA_IMM, 0);
+        a.abs(STA_ABSX, position);
+        if label == "vibrato" {
+            a.jump(JSR, "read_byte");
+            a.abs(STA_ABSX, VIBRATO_DELAY);
+            a.abs(STA_ABSX, VIBRATO_WAIT);
+        }
+        a.jump(JMP, "event_loop");
+    }
+
+    for (label, vars) in [
+        ("sweep", &[SWEEP_SPEED, SWEEP_DEPTH][..]),
+        ("hardware", &HARDWARE[..]),
+    ] {
+        a.label(label);
+        for var in vars {
+            a.jump(JSR, "read_byte");
+            a.abs(STA_ABSX, *var);
+        }
+        a.jump(JMP, "event_loop");
+    }
+
+    a.label("repeat_start");
+    a.jump(JSR, "read_byte");
+    a.op(PHA);
+    a.abs(LDA_ABSX, REPEAT_DEPTH);
+    a.jump(JSR, "repeat_index");
+    a.op(PLA);
+    a.abs(STA_ABSY, REPEAT_COUNT);
+    a.imm(LDA_ZP, ZP_PTR);
+    a.abs(STA_ABSY, REPEAT_PTR[0]);
+    a.imm(LDA_ZP, ZP_PTR + 1);
+    a.abs(STA_ABSY, REPEAT_PTR[1]);
+    a.abs(INC_ABSX, REPEAT_DEPTH);
+    a.jump(JMP, "event_loop");
+
+    a.label("repeat_end");
+    a.abs(LDA_ABSX, REPEAT_DEPTH);
+    a.op(SEC);
+    a.imm(SBC_IMM, 1);
+    a.jump(JSR, "repeat_index");
+    a.abs(LDA_ABSY, REPEAT_COUNT);
+    a.op(SEC);
+    a.imm(SBC_IMM, 1);
+    a.abs(STA_ABSY, REPEAT_COUNT);
+    a.branch(BEQ, "repeat_done");
+    a.abs(LDA_ABSY, REPEAT_PTR[0]);
+    a.imm(STA_ZP, ZP_PTR);
+    a.abs(LDA_ABSY, REPEAT_PTR[1]);
+    a.imm(STA_ZP, ZP_PTR + 1);
+    a.jump(JMP, "event_loop");
+    a.label("repeat_done");
+    a.abs(DEC_ABSX, REPEAT_DEPTH);
+    a.jump(JMP, "event_loop");
+
+    a.label("jump");
+    a.jump(JSR, "read_byte");
+    a.op(PHA);
+    a.jump(JSR, "read_byte");
+    a.imm(STA_ZP, ZP_PTR + 1);
+    a.op(PLA);
+    a.imm(STA_ZP, ZP_PTR);
+    a.jump(JMP, "event_loop");
+
+    a.label("end");
+    a.imm(LDA_IMM, 0);
+    a.abs(STA_ABSX, ACTIVE);
+    a.imm(LDA_IMM, 1);
+    a.abs(STA_ABSX, MUTED);
+    a.abs(STA_ABSX, RESTING);
+    a.jump(JMP, "events_done");
+
+    a.label("dpcm");
+    for register in [0x4010, 0x4012, 0x4013] {
+        a.jump(JSR, "read_byte");
+        a.abs(STA_ABS, register);
+    }
+    a.imm(LDA_IMM, DMC_STOP);
+    a.abs(STA_ABS, 0x4015);
+    a.imm(LDA_IMM, DMC_START);
+    a.abs(STA_ABS, 0x4015);
+    a.imm(LDA_IMM, 0);
+    a.abs(STA_ABSX, DMC_STOPPED);
+    a.jump(JSR, "start_note");
+    a.jump(JMP, "event_loop");
+
+    a.label("events_done");
+    a.imm(LDA_ZP, ZP_PTR);
+    a.abs(STA_ABSX, PTR[0]);
+    a.imm(LDA_ZP, ZP_PTR + 1);
+    a.abs(STA_ABSX, PTR[1]);
+
+    // Mutes the note at the end of the gate.
+    a.abs(LDA_ABSX, GATE[3]);
+    a.branch(BMI, "gate_off");
+    for var in &GATE[..3] {
+        a.abs(ORA_ABSX, *var);
+    }
+    a.branch(BNE, "gate_done");
+    a.label("gate_off");
+    a.imm(LDA_IMM, 1);
+    a.abs(STA_ABSX, MUTED);
+    a.label("gate_done");
+
+    a.imm(CPX_IMM, SLOT_DMC);
+    a.branch(BNE, "tone");
+    a.abs(LDA_ABSX, MUTED);
+    a.branch(BEQ, "frame_done");
+    a.abs(LDA_ABSX, DMC_STOPPED);
+    a.branch(BNE, "frame_done");
+    a.imm(LDA_IMM, DMC_STOP);
+    a.abs(STA_ABS, 0x4015);
+    a.imm(LDA_IMM, 1);
+    a.abs(STA_ABSX, DMC_STOPPED);
+    a.branch(BNE, "frame_done");
+    a.label("tone");
+    a.jump(JSR, "envelopes");
+    a.jump(JSR, "registers");
+
+    // Advances the wait and the gate by a frame.
+    a.label("frame_done");
+    a.abs(LDA_ABSX, WAIT[2]);
+    a.op(SEC);
+    a.imm(SBC_IMM, 1);
+    a.abs(STA_ABSX, WAIT[2]);
+    a.abs(LDA_ABSX, WAIT[3]);
+    a.imm(SBC_IMM, 0);
+    a.abs(STA_ABSX, WAIT[3]);
+    a.abs(LDA_ABSX, GATE[3]);
+    a.branch(BMI, "update_done");
+    a.abs(LDA_ABSX, GATE[2]);
+    a.op(SEC);
+    a.imm(SBC_IMM, 1);
+    a.abs(STA_ABSX, GATE[2]);
+    a.abs(LDA_ABSX, GATE[3]);
+    a.imm(SBC_IMM, 0);
+    a.abs(STA_ABSX, GATE[3]);
+    a.label("update_done");
+    a.imm(LDA_IMM, 0);
+    a.abs(STA_ABSX, FORCE);
+    a.op(RTS);
+
+    // Steps the envelopes and computes the volume, the timbre and the period of the frame.
+    a.label("envelopes");
+    step_table(&mut a, VOLUME);
+    a.imm(STA_ZP, ZP_VOLUME);
+    step_table(&mut a, TIMBRE);
+    a.imm(STA_ZP, ZP_TIMBRE);
+
+    // The duty cycle bits of `$4000`/`$4004` and the mode bit of `$400E`.
+    a.imm(LDA_IMM, 0);
+    a.imm(STA_ZP, ZP_DUTY);
+    a.imm(STA_ZP, ZP_NOISE);
+    a.imm(CPX_IMM, SLOT_NOISE);
+    a.branch(BNE, "duty");
+    a.imm(LDA_ZP, ZP_TIMBRE);
+    a.imm(AND_IMM, 1);
+    a.branch(BEQ, "duty_done");
+    a.imm(LDA_IMM, 0x80);
+    a.imm(STA_ZP, ZP_NOISE);
+    a.branch(BNE, "duty_done");
+    a.label("duty");
+    a.imm(CPX_IMM, SLOT_TRIANGLE);
+    a.branch(BCS, "duty_done");
+    a.imm(LDA_ZP, ZP_TIMBRE);
+    a.op(LSR_A);
+    a.op(ROR_A);
+    a.op(ROR_A);
+    a.imm(AND_IMM, 0xC0);
+    a.imm(STA_ZP, ZP_DUTY);
+    a.label("duty_done");
+
+    // The base period: the direct period (`@n`) or the period of the note shifted by the arpeggio.
+    a.abs(LDA_ABSX, DIRECT);
+    a.branch(BEQ, "note_period");
+    a.abs(LDA_ABSX, BASE[0]);
+    a.imm(STA_ZP, ZP_BASE);
+    a.abs(LDA_ABSX, BASE[1]);
+    a.imm(STA_ZP, ZP_BASE + 1);
+    a.jump(JMP, "base_done");
+    a.label("note_period");
+    a.abs(LDA_ABSX, NOTE);
+    a.imm(STA_ZP, ZP_TMP);
+    a.abs(LDA_ABSX, ARPEGGIO[1]);
+    a.branch(BEQ, "lookup");
+    step_table(&mut a, ARPEGGIO);
+    a.imm(STA_ZP, ZP_VALUE);
+    a.op(CLC);
+    a.imm(ADC_ZP, ZP_TMP);
+    a.imm(LDY_ZP, ZP_VALUE);
+    a.branch(BMI, "arpeggio_down");
+    a.branch(BCS, "arpeggio_max");
+    a.branch(BCC, "arpeggio_clamp");
+    a.label("arpeggio_down");
+    a.branch(BCS, "arpeggio_clamp");
+    a.imm(LDA_IMM, 0);
+    a.label("arpeggio_clamp");
+    a.imm(CMP_IMM, NOTES);
+    a.branch(BCC, "arpeggio_done");
+    a.label("arpeggio_max");
+    a.imm(LDA_IMM, NOTES - 1);
+    a.label("arpeggio_done");
+    a.imm(STA_ZP, ZP_TMP);
+    a.label("lookup");
+    a.abs(LDA_ABSX, PERIODS[0]);
+    a.imm(STA_ZP, ZP_TABLE);
+    a.abs(LDA_ABSX, PERIODS[1]);
+    a.imm(STA_ZP, ZP_TABLE + 1);
+    a.imm(LDY_ZP, ZP_TMP);
+    a.imm(LDA_INDY, ZP_TABLE);
+    a.imm(STA_ZP, ZP_BASE);
+    a.op(TYA);
+    a.op(CLC);
+    a.imm(ADC_IMM, NOTES);
+    a.op(TAY);
+    a.imm(LDA_INDY, ZP_TABLE);
+    a.imm(STA_ZP, ZP_BASE + 1);
+    a.label("base_done");
+
+    // The period offset: the detune (or the sweep) and the vibrato (halved on the triangle wave channel).
+    a.imm(LDA_IMM, 0);
+    a.imm(STA_ZP, ZP_OFFSET);
+    a.imm(STA_ZP, ZP_OFFSET + 1);
+    a.imm(CPX_IMM, SLOT_NOISE);
+    a.branch(BNE, "pitch");
+    a.op(RTS);
+    a.label("pitch");
+    step_table(&mut a, DETUNE);
+    a.abs(LDY_ABSX, DIRECT);
+    a.branch(BEQ, "detuned");
+    a.imm(LDA_IMM, 0); // The direct period is not detuned.
+    a.label("detuned");
+    a.abs(LDY_ABSX, SWEEP_SPEED);
+    a.branch(BNE, "sweep_on");
+    a.jump(JSR, "add_offset");
+    a.jump(JMP, "vibrato_step");
+
+    // The sweep starts from the detuned period of the note, and changes the period every `speed` frames.
+    a.label("sweep_on");
+    a.abs(LDY_ABSX, NEW_NOTE);
+    a.branch(BEQ, "sweep_step");
+    a.jump(JSR, "add_offset");
+    a.jump(JSR, "halve_offset");
+    a.jump(JSR, "apply_offset");
+    a.imm(LDA_ZP, ZP_BASE);
+    a.abs(STA_ABSX, PERIOD[0]);
+    a.imm(LDA_ZP, ZP_BASE + 1);
+    a.abs(STA_ABSX, PERIOD[1]);
+    a.label("sweep_step");
+    a.abs(LDA_ABSX, SWEEP_COUNT);
+    a.branch(BNE, "sweep_count");
+    a.abs(LDA_ABSX, PERIOD[0]);
+    a.imm(STA_ZP, ZP_SHIFT);
+    a.abs(LDA_ABSX, PERIOD[1]);
+    a.imm(STA_ZP, ZP_SHIFT + 1);
+    a.abs(LDA_ABSX, SWEEP_DEPTH);
+    a.imm(AND_IMM, 0x0F);
+    a.op(TAY);
+    a.label("sweep_shift");
+    a.imm(LSR_ZP, ZP_SHIFT + 1);
+    a.imm(ROR_ZP, ZP_SHIFT);
+    a.op(DEY);
+    a.branch(BNE, "sweep_shift");
+    a.abs(LDA_ABSX, SWEEP_DEPTH);
+    a.branch(BMI, "sweep_down");
+    a.op(SEC);
+    a.abs(LDA_ABSX, PERIOD[0]);
+    a.imm(SBC_ZP, ZP_SHIFT);
+    a.abs(STA_ABSX, PERIOD[0]);
+    a.abs(LDA_ABSX, PERIOD[1]);
+    a.imm(SBC_ZP, ZP_SHIFT + 1);
+    a.abs(STA_ABSX, PERIOD[1]);
+    a.jump(JMP, "sweep_count");
+    a.label("sweep_down");
+    a.op(CLC);
+    a.abs(LDA_ABSX, PERIOD[0]);
+    a.imm(ADC_ZP, ZP_SHIFT);
+    a.abs(STA_ABSX, PERIOD[0]);
+    a.abs(LDA_ABSX, PERIOD[1]);
+    a.imm(ADC_ZP, ZP_SHIFT + 1);
+    a.abs(STA_ABSX, PERIOD[1]);
+    a.imm(CMP_IMM, 0x08);
+    a.branch(BCC, "sweep_count");
+    a.imm(LDA_IMM, 0x07);
+    a.abs(STA_ABSX, PERIOD[1]);
+    a.imm(LDA_IMM, 0xFF);
+    a.abs(STA_ABSX, PERIOD[0]);
+    a.label("sweep_count");
+    a.abs(INC_ABSX, SWEEP_COUNT);
+    a.abs(LDA_ABSX, SWEEP_COUNT);
+    a.abs(CMP_ABSX, SWEEP_SPEED);
+    a.branch(BNE, "sweep_done");
+    a.imm(LDA_IMM, 0);
+    a.abs(STA_ABSX, SWEEP_COUNT);
+    a.label("sweep_done");
+    a.abs(LDA_ABSX, PERIOD[0]);
+    a.imm(STA_ZP, ZP_BASE);
+    a.abs(LDA_ABSX, PERIOD[1]);
+    a.imm(STA_ZP, ZP_BASE + 1);
+    a.imm(LDA_IMM, 0);
+    a.imm(STA_ZP, ZP_OFFSET);
+    a.imm(STA_ZP, ZP_OFFSET + 1);
+
+    // The vibrato starts after the delay at each note, but its phase continues over the notes.
+    a.label("vibrato_step");
+    a.abs(LDA_ABSX, VIBRATO[1]);
+    a.branch(BEQ, "vibrato_done");
+    a.abs(LDA_ABSX, VIBRATO_WAIT);
+    a.branch(BEQ, "vibrato_on");
+    a.abs(DEC_ABSX, VIBRATO_WAIT);
+    a.jump(JMP, "vibrato_done");
+    a.label("vibrato_on");
+    a.abs(LDA_ABSX, VIBRATO[0]);
+    a.imm(STA_ZP, ZP_TABLE);
+    a.abs(LDA_ABSX, VIBRATO[1]);
+    a.imm(STA_ZP, ZP_TABLE + 1);
+    a.abs(LDA_ABSX, VIBRATO[2]);
+    a.jump(JSR, "table_value");
+    a.jump(JSR, "add_offset");
+    a.abs(LDA_ABSX, VIBRATO[2]);
+    a.jump(JSR, "next_position");
+    a.abs(STA_ABSX, VIBRATO[2]);
+    a.label("vibrato_done");
+    a.jump(JSR, "halve_offset");
+    a.jump(JSR, "apply_offset");
+    a.op(RTS);
+
+    // Writes the registers of the slot `X` (except DMC) if they have been changed.
+    a.label("registers");
+    a.op(TXA);
+    a.op(ASL_A);
+    a.op(ASL_A);
+    a.imm(STA_ZP, ZP_REG);
+    a.abs(LDA_ABSX, MUTED);
+    a.branch(BEQ, "control_on");
+    a.imm(CPX_IMM, SLOT_TRIANGLE);
+    a.branch(BNE, "control_mute");
+    a.imm(LDA_IMM, 0x80);
+    a.branch(BNE, "control_write");
+    a.label("control_mute");
+    a.imm(LDA_IMM, 0x30);
+    a.branch(BNE, "control_duty");
+    a.label("control_on");
+    a.abs(LDA_ABSX, HARDWARE[0]);
+    a.imm(CPX_IMM, SLOT_TRIANGLE);
+    a.branch(BEQ, "control_write");
+    a.imm(AND_IMM, 0x10);
+    a.branch(BEQ, "control_envelope");
+    a.abs(LDA_ABSX, HARDWARE[0]);
+    a.imm(ORA_ZP, ZP_VOLUME);
+    a.jump(JMP, "control_duty");
+    a.label("control_envelope");
+    a.abs(LDA_ABSX, HARDWARE[0]);
+    a.label("control_duty");
+    a.imm(ORA_ZP, ZP_DUTY);
+    a.label("control_write");
+    write_register(&mut a, 0, ["write_0", "skip_0"]);
+    a.imm(LDA_ZP, ZP_BASE);
+    a.imm(ORA_ZP, ZP_NOISE);
+    write_register(&mut a, 2, ["write_2", "skip_2"]);
+    a.imm(LDA_ZP, ZP_BASE + 1);
+    a.abs(ORA_ABSX, HARDWARE[1]);
+    write_register(&mut a, 3, ["write_3", "skip_3"]);
+    a.op(RTS);
+
+    // Starts a note at the current event.
+    a.label("start_note");
+    a.imm(LDA_IMM, 0);
+    for var in [
+        MUTED,
+        RESTING,
+        VOLUME[2],
+        TIMBRE[2],
+        DETUNE[2],
+        ARPEGGIO[2],
+        SWEEP_COUNT,
+    ] {
+        a.abs(STA_ABSX, var);
+    }
+    a.abs(LDA_ABSX, VIBRATO_DELAY);
+    a.abs(STA_ABSX, VIBRATO_WAIT);
+    a.imm(LDA_IMM, 1);
+    a.abs(STA_ABSX, NEW_NOTE);
+    a.jump(JSR, "start_gate");
+    a.jump(JMP, "add_length");
+
+    // GATE = WAIT + GATE_LENGTH
+    a.label("start_gate");
+    a.op(CLC);
+    for i in 0..4 {
+        a.abs(LDA_ABSX, WAIT[i]);
+        a.abs(ADC_ABSX, GATE_LENGTH[i]);
+        a.abs(STA_ABSX, GATE[i]);
+    }
+    a.op(RTS);
+
+    // WAIT += LENGTH
+    a.label("add_length");
+    a.op(CLC);
+    for i in 0..4 {
+        a.abs(LDA_ABSX, WAIT[i]);
+        a.abs(ADC_ABSX, LENGTH[i]);
+        a.abs(STA_ABSX, WAIT[i]);
+    }
+    a.op(RTS);
+
+    // Reads a byte of the current stream into `A`.
+    a.label("read_byte");
+    a.imm(LDY_IMM, 0);
+    a.imm(LDA_INDY, ZP_PTR);
+    a.imm(INC_ZP, ZP_PTR);
+    a.branch(BNE, "read_done");
+    a.imm(INC_ZP, ZP_PTR + 1);
+    a.label("read_done");
+    a.op(RTS);
+
+    // Advances the position `A` of the table at `ZP_TABLE` unless a note has just started.
+    a.label("step");
+    a.abs(LDY_ABSX, NEW_NOTE);
+    a.branch(BNE, "step_done");
+    a.jump(JSR, "next_position");
+    a.label("step_done");
+    a.op(RTS);
+
+    // Returns the position after `A` (the loop point after the last frame).
+    a.label("next_position");
+    a.op(CLC);
+    a.imm(ADC_IMM, 1);
+    a.imm(LDY_IMM, 0);
+    a.imm(CMP_INDY, ZP_TABLE);
+    a.branch(BCC, "next_done");
+    a.op(INY);
+    a.imm(LDA_INDY, ZP_TABLE);
+    a.label("next_done");
+    a.op(RTS);
+
+    // Returns the value of the table at `ZP_TABLE` at the position `A`.
+    a.label("table_value");
+    a.op(CLC);
+    a.imm(ADC_IMM, 2);
+    a.op(TAY);
+    a.imm(LDA_INDY, ZP_TABLE);
+    a.op(RTS);
+
+    // Adds the signed value `A` to the period offset.
+    a.label("add_offset");
+    a.imm(LDY_IMM, 0);
+    a.imm(CMP_IMM, 0x80);
+    a.branch(BCC, "add_positive");
+    a.op(DEY);
+    a.label("add_positive");
+    a.op(CLC);
+    a.imm(ADC_ZP, ZP_OFFSET);
+    a.imm(STA_ZP, ZP_OFFSET);
+    a.op(TYA);
+    a.imm(ADC_ZP, ZP_OFFSET + 1);
+    a.imm(STA_ZP, ZP_OFFSET + 1);
+    a.op(RTS);
+
+    // Halves the period offset on the triangle wave channel (its period is counted in half steps).
+    a.label("halve_offset");
+    a.imm(CPX_IMM, SLOT_TRIANGLE);
+    a.branch(BNE, "halve_done");
+    a.imm(LDA_ZP, ZP_OFFSET + 1);
+    a.imm(CMP_IMM, 0x80);
+    a.imm(ROR_ZP, ZP_OFFSET + 1);
+    a.imm(ROR_ZP, ZP_OFFSET);
+    a.label("halve_done");
+    a.op(RTS);
+
+    // Subtracts the offset from the period and clamps it to `0..=2047`.
+    a.label("apply_offset");
+    a.op(SEC);
+    a.imm(LDA_ZP, ZP_BASE);
+    a.imm(SBC_ZP, ZP_OFFSET);
+    a.imm(STA_ZP, ZP_BASE);
+    a.imm(LDA_ZP, ZP_BASE + 1);
+    a.imm(SBC_ZP, ZP_OFFSET + 1);
+    a.imm(STA_ZP, ZP_BASE + 1);
+    a.branch(BMI, "clamp_low");
+    a.imm(CMP_IMM, 0x08);
+    a.branch(BCC, "apply_done");
+    a.imm(LDA_IMM, 0x07);
+    a.imm(STA_ZP, ZP_BASE + 1);
+    a.imm(LDA_IMM, 0xFF);
+    a.imm(STA_ZP, ZP_BASE);
+    a.op(RTS);
+    a.label("clamp_low");
+    a.imm(LDA_IMM, 0);
+    a.imm(STA_ZP, ZP_BASE);
+    a.imm(STA_ZP, ZP_BASE + 1);
+    a.label("apply_done");
+    a.op(RTS);
+
+    // Returns the index of the repeat variables of the level `A` in `Y` (`level * 8 + slot`).
+    a.label("repeat_index");
+    a.op(ASL_A);
+    a.op(ASL_A);
+    a.op(ASL_A);
+    a.imm(STX_ZP, ZP_TMP);
+    a.op(CLC);
+    a.imm(ADC_ZP, ZP_TMP);
+    a.op(TAY);
+    a.op(RTS);
+
+    a.label("handlers");
+    for label in HANDLERS {
+        a.word(label);
+    }
+    a.label("directory");
+    a.finish()
+}
+
+/// Steps the envelope table (`[address low, address high, position]`) and loads its value into `A`.
+fn step_table(a: &mut Assembler, [lo, hi, position]: [u16; 3]) {
+    a.abs(LDA_ABSX, lo);
+    a.imm(STA_ZP, ZP_TABLE);
+    a.abs(LDA_ABSX, hi);
+    a.imm(STA_ZP, ZP_TABLE + 1);
+    a.abs(LDA_ABSX, position);
+    a.jump(JSR, "step");
+    a.abs(STA_ABSX, position);
+    a.jump(JSR, "table_value");
+}
+
+/// Writes `A` to the register (`0`, `2` or `3`) of the slot if the value has been changed.
+///
+/// The register `3` is also rewritten at each note to restart the hardware counters if the retrigger flag is set.
+/// Otherwise, if only the high period bits of a pulse wave channel are changed by one, they are changed without
+/// resetting the phase in the same way as [`ApuPlayer`](crate::apu::ApuPlayer) (see `shift_pulse_high_period()`).
+fn write_register(a: &mut Assembler, register: u8, [write, skip]: [&'static str; 2]) {
+    let last = LAST[usize::from(register.saturating_sub(1))];
+    a.imm(STA_ZP, ZP_VALUE);
+    if register == 3 {
+        a.abs(LDA_ABSX, NEW_NOTE);
+        a.abs(AND_ABSX, HARDWARE[2]);
+        a.abs(ORA_ABSX, FORCE);
+    } else {
+        a.abs(LDA_ABSX, FORCE);
+    }
+    a.branch(BNE, write);
+    a.imm(LDA_ZP, ZP_VALUE);
+    a.abs(CMP_ABSX, last);
+    a.branch(BEQ, skip);
+    if register == 3 {
+        a.imm(CPX_IMM, SLOT_TRIANGLE);
+        a.branch(BCS, write);
+        a.abs(LDA_ABSX, NEW_NOTE);
+        a.branch(BNE, write);
+        a.imm(LDA_ZP, ZP_VALUE);
+        a.abs(EOR_ABSX, last);
+        a.imm(AND_IMM, 0xF8);
+        a.branch(BNE, write);
+        a.imm(LDA_ZP, ZP_VALUE);
+        a.op(SEC);
+        a.abs(SBC_ABSX, last);
+        a.imm(AND_IMM, 0x07);
+        a.imm(CMP_IMM, 0x01);
+        a.branch(BEQ, "shift_up");
+        a.imm(CMP_IMM, 0x07);
+        a.branch(BNE, write);
+        a.imm(LDA_IMM, 0x00);
+        a.imm(LDY_IMM, 0x8F); // Enabled, period 0, negate, shift 7.
+        a.branch(BNE, "shift");
+        a.label("shift_up");
+        a.imm(LDA_IMM, 0xFF);
+        a.imm(LDY_IMM, 0x87); // Enabled, period 0, shift 7.
+        a.label("shift");
+        a.imm(STY_ZP, ZP_TMP);
+        a.imm(LDY_ZP, ZP_REG);
+        a.abs(STA_ABSY, 0x4002);
+        a.imm(LDA_ZP, ZP_TMP);
+        a.abs(STA_ABSY, 0x4001);
+        a.imm(LDA_IMM, 0xC0); // Clocks the sweep units immediately.
+        a.abs(STA_ABS, 0x4017);
+        a.imm(LDA_IMM, 0x40);
+        a.abs(STA_ABS, 0x4017);
+        a.imm(LDA_IMM, 0x08);
+        a.abs(STA_ABSY, 0x4001);
+        a.abs(LDA_ABSX, LAST[1]);
+        a.abs(STA_ABSY, 0x4002);
+        a.imm(LDA_ZP, ZP_VALUE);
+        a.abs(STA_ABSX, last);
+        a.jump(JMP, skip);
+    }
+    a.label(write);
+    a.imm(LDA_ZP, ZP_VALUE);
+    a.abs(STA_ABSX, last);
+    a.imm(LDY_ZP, ZP_REG);
+    a.abs(STA_ABSY, 0x4000 + u16::from(register));
+    a.label(skip);
+}
+
+const ADC_ABSX: u8 = 0x7D;
+const ADC_IMM: u8 = 0x69;
+const ADC_ZP: u8 = 0x65;
+const AND_ABSX: u8 = 0x3D;
+const AND_IMM: u8 = 0x29;
+const ASL_A: u8 = 0x0A;
+const BCC: u8 = 0x90;
+const BCS: u8 = 0xB0;
+const BEQ: u8 = 0xF0;
+const BMI: u8 = 0x30;
+const BNE: u8 = 0xD0;
+const BPL: u8 = 0x10;
+const CLC: u8 = 0x18;
+const CMP_ABSX: u8 = 0xDD;
+const CMP_IMM: u8 = 0xC9;
+const CMP_INDY: u8 = 0xD1;
+const CPX_IMM: u8 = 0xE0;
+const DEC_ABSX: u8 = 0xDE;
+const DEX: u8 = 0xCA;
+const DEY: u8 = 0x88;
+const EOR_ABSX: u8 = 0x5D;
+const INC_ABSX: u8 = 0xFE;
+const INC_ZP: u8 = 0xE6;
+const INX: u8 = 0xE8;
+const INY: u8 = 0xC8;
+const JMP: u8 = 0x4C;
+const JMP_IND: u8 = 0x6C;
+const JSR: u8 = 0x20;
+const LDA_ABSX: u8 = 0xBD;
+const LDA_ABSY: u8 = 0xB9;
+const LDA_IMM: u8 = 0xA9;
+const LDA_INDY: u8 = 0xB1;
+const LDA_ZP: u8 = 0xA5;
+const LDX_IMM: u8 = 0xA2;
+const LDY_ABSX: u8 = 0xBC;
+const LDY_IMM: u8 = 0xA0;
+const LDY_ZP: u8 = 0xA4;
+const LSR_A: u8 = 0x4A;
+const LSR_ZP: u8 = 0x46;
+const ORA_ABSX: u8 = 0x1D;
+const ORA_ZP: u8 = 0x05;
+const PHA: u8 = 0x48;
+const PLA: u8 = 0x68;
+const ROR_A: u8 = 0x6A;
+const ROR_ZP: u8 = 0x66;
+const RTS: u8 = 0x60;
+const SBC_ABSX: u8 = 0xFD;
+const SBC_IMM: u8 = 0xE9;
+const SBC_ZP: u8 = 0xE5;
+const SEC: u8 = 0x38;
+const STA_ABS: u8 = 0x8D;
+const STA_ABSX: u8 = 0x9D;
+const STA_ABSY: u8 = 0x99;
+const STA_ZP: u8 = 0x85;
+const STX_ZP: u8 = 0x86;
+const STY_ZP: u8 = 0x84;
+const TAX: u8 = 0xAA;
+const TAY: u8 = 0xA8;
+const TXA: u8 = 0x8A;
+const TYA: u8 = 0x98;
+
+/// Minimal 6502 assembler that resolves labels of branch and jump instructions.
+#[derive(Debug)]
+struct Assembler {
+    origin: u16,
+    code: Vec<u8>,
+    labels: BTreeMap<&'static str, u16>,
+    fixups: Vec<(usize, &'static str, bool)>, // (Operand offset, Label, Is relative)
+}
+
+impl Assembler {
+    fn new(origin: u16) -> Self {
+        Self {
+            origin,
+            code: Vec::new(),
+            labels: BTreeMap::new(),
+            fixups: Vec::new(),
+        }
+    }
+
+    fn op(&mut self, opcode: u8) {
+        self.code.push(opcode);
+    }
+
+    fn imm(&mut self, opcode: u8, operand: u8) {
+        self.code.extend_from_slice(&[opcode, operand]);
+    }
+
+    fn abs(&mut self, opcode: u8, operand: u16) {
+        self.code.push(opcode);
+        self.code.extend_from_slice(&operand.to_le_bytes());
+    }
+
+    fn branch(&mut self, opcode: u8, label: &'static str) {
+        self.code.push(opcode);
+        self.fixups.push((self.code.len(), label, true));
+        self.code.push(0);
+    }
+
+    /// Emits an instruction whose operand is the address of the label.
+    fn jump(&mut self, opcode: u8, label: &'static str) {
+        self.code.push(opcode);
+        self.word(label);
+    }
+
+    fn word(&mut self, label: &'static str) {
+        self.fixups.push((self.code.len(), label, false));
+        self.code.extend_from_slice(&[0, 0]);
+    }
+
+    fn label(&mut self, label: &'static str) {
+        let addr = self.origin + self.code.len() as u16;
+        assert!(self.labels.insert(label, addr).is_none());
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        for &(offset, label, relative) in &self.fixups {
+            let target = *self
+                .labels
+                .get(label)
+                .unwrap_or_else(|| panic!("undefined label {label}"));
+            if relative {
+                let next = self.origin + offset as u16 + 1;
+                let delta = i8::try_from(i32::from(target) - i32::from(next))
+                    .unwrap_or_else(|_| panic!("branch target {label} out of range"));
+                self.code[offset] = delta as u8;
+            } else {
+                self.code[offset..offset + 2].copy_from_slice(&target.to_le_bytes());
+            }
+        }
+        self.code
+    }
+}
+
+#[cfg(all(test, feature = "apu"))]
+mod tests {
+    use super::*;
+    use crate::{
+        apu::{Apu, ApuPlayer},
+        player::OutputFilters,
+        Sample,
+    };
+
+    const SAMPLE_RATE: u16 = 48000;
+
+    #[test]
+    fn driver_plays_same_as_apu_player() {
+        // Each channel is compared separately because the loop point rewrites all registers,
+        // which resets the phases of the pulse waves and makes the mixed waves differ.
+        for channel in [
+            "A t150 l8 @1 o4 c e g > c < g e c r L @v0 c e g > c < @2 e c < g > e",
+            "B t150 l4 @0 v10 o3 c r e r L g8. r16 e8 r8 MP0 c2",
+            "C t150 l8 o3 c c g g L c r c r",
+            "D t150 l8 v12 @0 c r @1 c r L c r16 c16 r4",
+            "E t150 l4 o2 c r c r L c r c8 c8 r",
+            "A t150 l8 q6 @@0 [c d]3 EN0 e e ENOF EP0 g g @n300 L v8 [v+ c]4 s9,3 c4 r",
+            "C t150 l8 o4 HT20 c r HTOF c4 r L {c d e} e4 r",
+            "D t150 l8 HL3 HE2 c r HLOF HEOF c4 r L c r",
+        ] {
+            let mml = format!(
+                r#"
+#CHANNEL A 0
+#CHANNEL B 0
+#CHANNEL C 1
+#CHANNEL D 2
+#CHANNEL E 3
+@DPCM0 = {{ "ramp.dmc", 15 }}
+@v0 = {{ 15 14 13 12 11 10 9 8 }}
+@0 = {{ 0 1 2 | 1 }}
+@EN0 = {{ 0 4 -2 }}
+@EP0 = {{ 0 -4 4 }}
+@MP0 = {{ 3 1 4 }}
+{channel}"#
+            );
+
+            // The intro lasts 1.6 seconds, and the loop body is played three times.
+            let blocks = compare_blocks(&mml, 60 * 16 / 10 * 4);
+            for (i, &((rms, crossings), (expected_rms, expected_crossings))) in
+                blocks.iter().enumerate()
+            {
+                assert!(
+                    (rms - expected_rms).abs() <= expected_rms * 0.1 + 0.002
+                        && crossings.abs_diff(expected_crossings) <= expected_crossings / 10 + 2,
+                    "{channel}: block={i}, rms={rms}, expected_rms={expected_rms}, \
+                     crossings={crossings}, expected_crossings={expected_crossings}"
+                );
+            }
+
+            // The third loop is played.
+            let last_loop = &blocks[blocks.len() - 60 * 16 / 10 / 4..];
+            assert!(last_loop.iter().any(|b| b.0 .0 > 0.01), "{channel}");
+        }
+    }
+
+    #[test]
+    fn repeats_are_not_unrolled() {
+        let size = |mml: &str| {
+            let music = Music::new(mml).unwrap_or_else(|e| panic!("{e}"));
+            Nsf::new(&music)
+                .unwrap_or_else(|e| panic!("{e}"))
+                .data
+                .len()
+        };
+        assert_eq!(size("A [c d e f]2"), size("A [c d e f]255"));
+        assert_eq!(size("A [[c d]2 e]2"), size("A [[c d]255 e]255"));
+        assert_eq!(size("A l8 [c8 d]2"), size("A l8 [c8 d]255"));
+
+        // A repeat whose body changes the octave is unrolled.
+        assert!(size("A o2 [c >]2") < size("A o2 [c >]5"));
+        let error = |mml: &str| {
+            let music = Music::new(mml).unwrap_or_else(|e| panic!("{e}"));
+            Nsf::new(&music).expect_err(mml).to_string()
+        };
+        for (mml, reason) in [
+            (
+                "A [c L d]2",
+                "loop point in a repeat is not supported by NSF",
+            ),
+            ("A [c ! d]2", "no maching ']'"),
+            ("A c ]2", "no maching '['"),
+            ("#REGION DENDY\nA c", "Dendy region is not supported by NSF"),
+            ("@MP0 = { 0 85 4 }\nA MP0 c", "too slow vibrato for NSF"),
+        ] {
+            assert!(error(mml).contains(reason), "{mml}");
+        }
+    }
+
+    /// Plays the NSF of `mml` and [`ApuPlayer`] (which takes the register values from [`MusicPlayer`](crate::MusicPlayer))
+    /// for `frames` frames, and returns the RMS and the number of zero crossings of each four frames.
+    fn compare_blocks(mml: &str, frames: usize) -> Vec<((f32, usize), (f32, usize))> {
+        let mut files = BTreeMap::new();
+        files.insert("ramp.dmc", [[0xFF; 16], [0x00; 16]].concat().repeat(8));
+        let music = Music::with_resolver(mml, files).unwrap_or_else(|e| panic!("{e}"));
+        let nsf = Nsf::new(&music).unwrap_or_else(|e| panic!("{e}"));
+
+        let mut nes = Nes::new(&nsf);
+        let actual = (0..frames)
+            .flat_map(|_| nes.next_frame())
+            .collect::<Vec<_>>();
+        let mut player = ApuPlayer::new(&music, SAMPLE_RATE).expect("APU player");
+        let expected = player.by_ref().take(actual.len()).collect::<Vec<_>>();
+        if let Some(e) = player.take_last_error() {
+            panic!("{e}");
+        }
+        assert_eq!(actual.len(), expected.len());
+
+        let block_len = actual.len() / frames * 4;
+        actual
+            .chunks(block_len)
+            .zip(expected.chunks(block_len))
+            .map(|(a, e)| ((rms(a), zero_crossings(a)), (rms(e), zero_crossings(e))))
+            .collect()
+    }
+
+    /// Counts the zero crossings (ignoring the samples close to zero, such as the decaying tails of muted notes).
+    fn zero_crossings(samples: &[Sample]) -> usize {
+        let signs = samples
+            .iter()
+            .filter(|s| s.get().abs() > 0.0001)
+            .map(|s| s.get() < 0.0)
+            .collect::<Vec<_>>();
+        signs.windows(2).filter(|w| w[0] != w[1]).count()
+    }
+
+    fn rms(samples: &[Sample]) -> f32 {
+        let sum = samples.iter().map(|s| s.get() * s.get()).sum::<f32>();
+        (sum / samples.len() as f32).sqrt()
+    }
+
+    /// Plays an NSF file in the same way as [`ApuPlayer`] (without the expansion sound chips).
+    struct Nes {
+        cpu: Cpu,
+        bus: Bus,
+        cycles_per_sample: f64,
+        residual_cycles: f64,
+        filters: OutputFilters,
+    }
+
+    impl Nes {
+        fn new(nsf: &Nsf) -> Self {
+            let mut bus = Bus {
+                ram: [0; 0x800],
+                rom: nsf.data.clone(),
+                apu: Apu::new(nsf.region),
+            };
+            let dmc_memory = (DPCM_START..=0xFFFF)
+                .map(|a| bus.read(a))
+                .collect::<Vec<_>>();
+            bus.apu.write_memory(DPCM_START, &dmc_memory);
+
+            let mut cpu = Cpu {
+                a: 0, // Song number.
+                x: u8::from(nsf.region == Region::Pal),
+                ..Default::default()
+            };
+            cpu.call(&mut bus, INIT_ADDR);
+            Self {
+                cpu,
+                bus,
+                cycles_per_sample: f64::from(nsf.region.cpu_clock_hz()) / f64::from(SAMPLE_RATE),
+                residual_cycles: 0.0,
+                filters: OutputFilters::new(SAMPLE_RATE),
+            }
+        }
+
+        fn next_frame(&mut self) -> Vec<Sample> {
+            self.cpu.call(&mut self.bus, PLAY_ADDR);
+            let samples_per_frame = SAMPLE_RATE / u16::from(self.bus.apu.region().frame_rate());
+            (0..samples_per_frame)
+                .map(|_| {
+                    self.residual_cycles += self.cycles_per_sample;
+                    let cycles = self.residual_cycles.floor();
+                    self.residual_cycles -= cycles;
+                    let mut sum = 0.0;
+                    for _ in 0..cycles as usize {
+                        self.bus.apu.clock();
+                        sum += self.bus.apu.output();
+                    }
+                    let level = sum / cycles.max(1.0) as f32;
+                    Sample::new(self.filters.apply(level) * 2.0)
+                })
+                .collect()
+        }
+    }
+
+    /// Memory map of an NSF player without bank switching (RAM, APU registers and ROM).
+    struct Bus {
+        ram: [u8; 0x800],
+        rom: Vec<u8>,
+        apu: Apu,
+    }
+
+    impl Bus {
+        fn read(&self, addr: u16) -> u8 {
+            match addr {
+                0x0000..=0x1FFF => self.ram[usize::from(addr) & 0x7FF],
+                0x8000..=0xFFFF => self
+                    .rom
+                    .get(usize::from(addr - LOAD_ADDR))
+                    .copied()
+                    .unwrap_or(0),
+                _ => panic!("read from ${addr:04X}"),
+            }
+        }
+
+        fn write(&mut self, addr: u16, value: u8) {
+            match addr {
+                0x0000..=0x1FFF => self.ram[usize::from(addr) & 0x7FF] = value,
+                0x4000..=0x4017 => self.apu.write(addr, value),
+                _ => panic!("write to ${addr:04X}"),
+            }
+        }
+    }
+
+    /// 6502 interpreter that supports only the instructions used by the driver.
+    #[derive(Default)]
+    struct Cpu {
+        a: u8,
+        x: u8,
+        y: u8,
+        sp: u8,
+        pc: u16,
+        zero: bool,
+        negative: bool,
+        carry: bool,
+    }
+
+    impl Cpu {
+        /// Calls the subroutine at `addr` and returns when it returns.
+        fn call(&mut self, bus: &mut Bus, addr: u16) {
+            self.sp = 0xFF;
+            self.push(bus, 0xFF);
+            self.push(bus, 0xFF); // `RTS` jumps to `$0000`.
+            self.pc = addr;
+            for _ in 0..1_000_000 {
+                if self.pc == 0x0000 {
+                    return;
+                }
+                self.step(bus);
+            }
+            panic!("the subroutine at ${addr:04X} does not return");
+        }
+
+        fn step(&mut self, bus: &mut Bus) {
+            let opcode = self.fetch(bus);
+            match opcode {
+                ADC_ABSX | ADC_IMM | ADC_ZP => {
+                    let value = self.load(bus, opcode);
+                    self.add(value);
+                }
+                SBC_ABSX | SBC_IMM | SBC_ZP => {
+                    let value = self.load(bus, opcode);
+                    self.add(!value);
+                }
+                AND_ABSX | AND_IMM => {
+                    let value = self.load(bus, opcode);
+                    self.a = self.set_nz(self.a & value);
+                }
+                ORA_ABSX | ORA_ZP => {
+                    let value = self.load(bus, opcode);
+                    self.a = self.set_nz(self.a | value);
+                }
+                EOR_ABSX => {
+                    let value = self.load(bus, opcode);
+                    self.a = self.set_nz(self.a ^ value);
+                }
+                CMP_ABSX | CMP_IMM | CMP_INDY => {
+                    let value = self.load(bus, opcode);
+                    self.compare(self.a, value);
+                }
+                CPX_IMM => {
+                    let value = self.load(bus, opcode);
+                    self.compare(self.x, value);
+                }
+                LDA_ABSX | LDA_ABSY | LDA_IMM | LDA_INDY | LDA_ZP => {
+                    let value = self.load(bus, opcode);
+                    self.a = self.set_nz(value);
+                }
+                LDX_IMM => {
+                    let value = self.load(bus, opcode);
+                    self.x = self.set_nz(value);
+                }
+                LDY_ABSX | LDY_IMM | LDY_ZP => {
+                    let value = self.load(bus, opcode);
+                    self.y = self.set_nz(value);
+                }
+                STA_ABS | STA_ABSX | STA_ABSY | STA_ZP => {
+                    let addr = self.address(bus, opcode);
+                    bus.write(addr, self.a);
+                }
+                STX_ZP => {
+                    let addr = self.address(bus, opcode);
+                    bus.write(addr, self.x);
+                }
+                STY_ZP => {
+                    let addr = self.address(bus, opcode);
+                    bus.write(addr, self.y);
+                }
+                DEC_ABSX | INC_ABSX | INC_ZP | LSR_ZP | ROR_ZP => {
+                    let addr = self.address(bus, opcode);
+                    let value = bus.read(addr);
+                    let value = self.modify(opcode, value);
+                    bus.write(addr, value);
+                }
+                ASL_A | LSR_A | ROR_A => self.a = self.modify(opcode, self.a),
+                DEX => self.x = self.set_nz(self.x.wrapping_sub(1)),
+                DEY => self.y = self.set_nz(self.y.wrapping_sub(1)),
+                INX => self.x = self.set_nz(self.x.wrapping_add(1)),
+                INY => self.y = self.set_nz(self.y.wrapping_add(1)),
+                TAX => self.x = self.set_nz(self.a),
+                TAY => self.y = self.set_nz(self.a),
+                TXA => self.a = self.set_nz(self.x),
+                TYA => self.a = self.set_nz(self.y),
+                CLC => self.carry = false,
+                SEC => self.carry = true,
+                PHA => self.push(bus, self.a),
+                PLA => {
+                    let value = self.pop(bus);
+                    self.a = self.set_nz(value);
+                }
+                BCC => self.branch(bus, !self.carry),
+                BCS => self.branch(bus, self.carry),
+                BEQ => self.branch(bus, self.zero),
+                BMI => self.branch(bus, self.negative),
+                BNE => self.branch(bus, !self.zero),
+                BPL => self.branch(bus, !self.negative),
+                JMP => self.pc = self.fetch_word(bus),
+                JMP_IND => {
+                    let ptr = self.fetch_word(bus);
+                    let lo = bus.read(ptr);
+                    let hi = bus.read(ptr + 1);
+                    self.pc = u16::from_le_bytes([lo, hi]);
+                }
+                JSR => {
+                    let target = self.fetch_word(bus);
+                    let [lo, hi] = self.pc.wrapping_sub(1).to_le_bytes();
+                    self.push(bus, hi);
+                    self.push(bus, lo);
+                    self.pc = target;
+                }
+                RTS => {
+                    let lo = self.pop(bus);
+                    let hi = self.pop(bus);
+                    self.pc = u16::from_le_bytes([lo, hi]).wrapping_add(1);
+                }
+                _ => panic!("unsupported opcode ${opcode:02X} at ${:04X}", self.pc - 1),
+            }
+        }
+
+        /// Returns the effective address of the operand (the address of the operand itself if immediate).
+        fn address(&mut self, bus: &Bus, opcode: u8) -> u16 {
+            match opcode {
+                ADC_ZP | INC_ZP | LDA_ZP | LDY_ZP | LSR_ZP | ORA_ZP | ROR_ZP | SBC_ZP | STA_ZP
+                | STX_ZP | STY_ZP => u16::from(self.fetch(bus)),
+                STA_ABS => self.fetch_word(bus),
+                ADC_ABSX | AND_ABSX | CMP_ABSX | DEC_ABSX | EOR_ABSX | INC_ABSX | LDA_ABSX
+                | LDY_ABSX | ORA_ABSX | SBC_ABSX | STA_ABSX => {
+                    self.fetch_word(bus).wrapping_add(u16::from(self.x))
+                }
+                LDA_ABSY | STA_ABSY => self.fetch_word(bus).wrapping_add(u16::from(self.y)),
+                CMP_INDY | LDA_INDY => {
+                    let ptr = self.fetch(bus);
+                    let lo = bus.read(u16::from(ptr));
+                    let hi = bus.read(u16::from(ptr.wrapping_add(1)));
+                    u16::from_le_bytes([lo, hi]).wrapping_add(u16::from(self.y))
+                }
+                _ => {
+                    let addr = self.pc;
+                    self.pc = self.pc.wrapping_add(1);
+                    addr
+                }
+            }
+        }
+
+        fn load(&mut self, bus: &Bus, opcode: u8) -> u8 {
+            let addr = self.address(bus, opcode);
+            bus.read(addr)
+        }
+
+        /// Applies a read-modify-write instruction to the value.
+        fn modify(&mut self, opcode: u8, value: u8) -> u8 {
+            let carry_in = u8::from(self.carry) << 7;
+            let result = match opcode {
+                ASL_A => {
+                    self.carry = value & 0x80 != 0;
+                    value << 1
+                }
+                LSR_A | LSR_ZP => {
+                    self.carry = value & 1 == 1;
+                    value >> 1
+                }
+                ROR_A | ROR_ZP => {
+                    self.carry = value & 1 == 1;
+                    carry_in | (value >> 1)
+                }
+                DEC_ABSX => value.wrapping_sub(1),
+                _ => value.wrapping_add(1),
+            };
+            self.set_nz(result)
+        }
+
+        fn add(&mut self, value: u8) {
+            let sum = u16::from(self.a) + u16::from(value) + u16::from(self.carry);
+            self.carry = sum > 0xFF;
+            self.a = self.set_nz(sum as u8);
+        }
+
+        fn fetch(&mut self, bus: &Bus) -> u8 {
+            let value = bus.read(self.pc);
+            self.pc = self.pc.wrapping_add(1);
+            value
+        }
+
+        fn fetch_word(&mut self, bus: &Bus) -> u16 {
+            let lo = self.fetch(bus);
+            let hi = self.fetch(bus);
+            u16::from_le_bytes([lo, hi])
+        }
+
+        fn push(&mut self, bus: &mut Bus, value: u8) {
+            bus.write(0x100 + u16::from(self.sp), value);
+            self.sp = self.sp.wrapping_sub(1);
+        }
+
+        fn pop(&mut self, bus: &Bus) -> u8 {
+            self.sp = self.sp.wrapping_add(1);
+            bus.read(0x100 + u16::from(self.sp))
+        }
+
+        fn branch(&mut self, bus: &Bus, condition: bool) {
+            let offset = self.fetch(bus) as i8;
+            if condition {
+                self.pc = self.pc.wrapping_add_signed(i16::from(offset));
+            }
+        }
+
+        fn compare(&mut self, register: u8, value: u8) {
+            self.carry = register >= value;
+            self.set_nz(register.wrapping_sub(value));
+        }
+
+        fn set_nz(&mut self, value: u8) -> u8 {
+            self.zero = value == 0;
+            self.negative = value & 0x80 != 0;
+            value
+        }
+    }
+}
@@ -1,16 +1,40 @@
 use crate::{
     clocks::Clock,
-    types::{Detune, Letter, Note, Octave, OscillatorKind, Sample, Timbre},
+    dpcm,
+    macros::MacroNumber,
+    n163, sunsoft5b,
+    types::{Detune, Letter, Note, Octave, OscillatorKind, Period, Region, Sample, Timbre, Volume},
+    vrc7,
 };
+use std::sync::Arc;
 
-const MASTER_CLOCK_HZ: f32 = 21477272.7272;
-const SYSTEM_CLOCK_HZ: f32 = MASTER_CLOCK_HZ / 12.0;
+/// Waveform synthesis mode of pulse wave and triangle wave channels (see [`MusicPlayer::set_synthesis()`]).
+///
+/// [`MusicPlayer::set_synthesis()`]: crate::MusicPlayer::set_synthesis
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Synthesis {
+    /// Switches levels at sample boundaries without band-limiting (fast, but high notes alias).
+    #[default]
+    Naive,
+
+    /// Smooths each level step by a polynomial band-limited step (PolyBLEP) to reduce aliasing.
+    BandLimited,
+}
 
 #[derive(Debug, Clone)]
 pub enum Oscillator {
     PulseWave(PulseWave),
     TriangleWave(TriangleWave),
     Noise(Noise),
+    Dpcm(Dpcm),
+    Vrc6Pulse(Vrc6Pulse),
+    Sawtooth(Sawtooth),
+    Fds(Fds),
+    N163(N163),
+    Sunsoft5b(Sunsoft5b),
+    Vrc7(Vrc7),
+    Mmc5Pulse(Mmc5Pulse),
+    Mmc5Pcm(Mmc5Pcm),
 }
 
 impl Oscillator {
@@ -19,6 +43,15 @@ impl Oscillator {
             OscillatorKind::PULSE_WAVE => Self::pulse_wave(),
             OscillatorKind::TRIANGLE_WAVE => Self::triangle_wave(),
             OscillatorKind::NOISE => Self::noise(),
+            OscillatorKind::DPCM => Self::dpcm(),
+            OscillatorKind::VRC6_PULSE => Self::Vrc6Pulse(Vrc6Pulse::new()),
+            OscillatorKind::VRC6_SAWTOOTH => Self::Sawtooth(Sawtooth::new()),
+            OscillatorKind::FDS => Self::Fds(Fds::new()),
+            OscillatorKind::N163 => Self::N163(N163::new()),
+            OscillatorKind::SUNSOFT_5B => Self::Sunsoft5b(Sunsoft5b::new()),
+            OscillatorKind::VRC7 => Self::Vrc7(Vrc7::new()),
+            OscillatorKind::MMC5_PULSE => Self::Mmc5Pulse(Mmc5Pulse::new()),
+            OscillatorKind::MMC5_PCM => Self::Mmc5Pcm(Mmc5Pcm::new()),
             _ => unreachable!(),
         }
     }
@@ -35,11 +68,58 @@ impl Oscillator {
         Self::Noise(Noise::new())
     }
 
+    pub fn dpcm() -> Self {
+        Self::Dpcm(Dpcm::new())
+    }
+
     pub fn sample(&mut self, sample_rate: u16, lfo: Option<&mut PitchLfo>) -> Sample {
         match self {
             Oscillator::PulseWave(o) => o.sample(sample_rate, lfo),
             Oscillator::TriangleWave(o) => o.sample(sample_rate, lfo),
             Oscillator::Noise(o) => o.sample(sample_rate, lfo),
+            Oscillator::Dpcm(o) => o.sample(sample_rate, lfo),
+            Oscillator::Vrc6Pulse(o) => o.pulse.sample(sample_rate, lfo),
+            Oscillator::Sawtooth(o) => o.sample(sample_rate, lfo),
+            Oscillator::Fds(o) => o.sample(sample_rate, lfo),
+            Oscillator::N163(o) => o.sample(sample_rate, lfo),
+            Oscillator::Sunsoft5b(o) => o.sample(sample_rate, lfo),
+            Oscillator::Vrc7(o) => o.sample(sample_rate, lfo),
+            Oscillator::Mmc5Pulse(o) => o.pulse.sample(sample_rate, lfo),
+            Oscillator::Mmc5Pcm(o) => o.sample(sample_rate, lfo),
+        }
+    }
+
+    pub fn set_region(&mut self, region: Region) {
+        match self {
+            Oscillator::PulseWave(o) => o.region = region,
+            Oscillator::TriangleWave(o) => o.region = region,
+            Oscillator::Noise(o) => o.region = region,
+            Oscillator::Dpcm(o) => o.region = region,
+            Oscillator::Vrc6Pulse(o) => o.pulse.region = region,
+            Oscillator::Sawtooth(o) => o.region = region,
+            Oscillator::Fds(o) => o.region = region,
+            Oscillator::N163(o) => o.region = region,
+            Oscillator::Sunsoft5b(o) => o.region = region,
+            Oscillator::Vrc7(o) => o.region = region,
+            Oscillator::Mmc5Pulse(o) => o.pulse.region = region,
+            Oscillator::Mmc5Pcm(o) => o.region = region,
+        }
+    }
+
+    pub fn set_synthesis(&mut self, synthesis: Synthesis) {
+        match self {
+            Oscillator::PulseWave(o) => o.synthesis = synthesis,
+            Oscillator::TriangleWave(o) => o.synthesis = synthesis,
+            Oscillator::Vrc6Pulse(o) => o.pulse.synthesis = synthesis,
+            Oscillator::Sawtooth(o) => o.synthesis = synthesis,
+            Oscillator::Fds(o) => o.synthesis = synthesis,
+            Oscillator::Sunsoft5b(o) => o.synthesis = synthesis,
+            Oscillator::Mmc5Pulse(o) => o.pulse.synthesis = synthesis,
+            Oscillator::Noise(_)
+            | Oscillator::Dpcm(_)
+            | Oscillator::N163(_)
+            | Oscillator::Vrc7(_)
+            | Oscillator::Mmc5Pcm(_) => {}
         }
     }
 
@@ -48,128 +128,1082 @@ impl Oscillator {
             Oscillator::PulseWave(o) => o.mute(mute),
             Oscillator::TriangleWave(o) => o.mute(mute),
             Oscillator::Noise(o) => o.mute(mute),
+            Oscillator::Dpcm(o) => o.mute(mute),
+            Oscillator::Vrc6Pulse(o) => o.pulse.mute(mute),
+            Oscillator::Sawtooth(o) => o.mute = mute,
+            Oscillator::Fds(o) => o.mute = mute,
+            Oscillator::N163(o) => o.mute = mute,
+            Oscillator::Sunsoft5b(o) => o.mute = mute,
+            Oscillator::Vrc7(o) => o.mute(mute),
+            Oscillator::Mmc5Pulse(o) => o.pulse.mute(mute),
+            Oscillator::Mmc5Pcm(o) => o.mute = mute,
+        }
+    }
+
+    pub fn set_frequency(&mut self, note: Note, octave: Octave, detune: Detune) {
+        match self {
+            Oscillator::PulseWave(o) => o.set_frequency(note, octave, detune),
+            Oscillator::TriangleWave(o) => o.set_frequency(note, octave, detune),
+            Oscillator::Noise(o) => o.set_frequency(note, octave, detune),
+            Oscillator::Dpcm(o) => o.set_frequency(note, octave, detune),
+            Oscillator::Vrc6Pulse(o) => o.pulse.set_frequency(note, octave, detune),
+            Oscillator::Sawtooth(o) => o.set_frequency(note, octave, detune),
+            Oscillator::Fds(o) => o.set_frequency(note, octave, detune),
+            Oscillator::N163(o) => o.set_frequency(note, octave, detune),
+            Oscillator::Sunsoft5b(o) => o.set_frequency(note, octave, detune),
+            Oscillator::Vrc7(o) => o.set_frequency(note, octave, detune),
+            Oscillator::Mmc5Pulse(o) => o.pulse.set_frequency(note, octave, detune),
+            Oscillator::Mmc5Pcm(_) => {}
+        }
+    }
+
+    pub fn set_period(&mut self, period: Period) -> bool {
+        match self {
+            Oscillator::PulseWave(o) => o.set_period(period),
+            Oscillator::TriangleWave(o) => o.set_period(period),
+            Oscillator::Noise(o) => o.set_period(period),
+            Oscillator::Dpcm(_) | Oscillator::Mmc5Pcm(_) => false,
+            Oscillator::Vrc6Pulse(o) => o.set_period(period),
+            Oscillator::Sawtooth(o) => o.set_period(period),
+            Oscillator::Fds(o) => o.set_period(period),
+            Oscillator::N163(o) => o.set_period(period),
+            Oscillator::Sunsoft5b(o) => o.set_period(period),
+            Oscillator::Vrc7(o) => o.set_period(period),
+            Oscillator::Mmc5Pulse(o) => o.set_period(period),
+        }
+    }
+
+    pub fn frequency(&self) -> f32 {
+        match self {
+            Oscillator::PulseWave(o) => o.frequency,
+            Oscillator::TriangleWave(o) => o.frequency,
+            Oscillator::Noise(o) => o.frequency,
+            Oscillator::Dpcm(o) => o.frequency,
+            Oscillator::Vrc6Pulse(o) => o.pulse.frequency,
+            Oscillator::Sawtooth(o) => o.frequency,
+            Oscillator::Fds(o) => o.frequency,
+            Oscillator::N163(o) => o.frequency,
+            Oscillator::Sunsoft5b(o) => o.frequency,
+            Oscillator::Vrc7(o) => o.frequency,
+            Oscillator::Mmc5Pulse(o) => o.pulse.frequency,
+            Oscillator::Mmc5Pcm(o) => o.frequency,
+        }
+    }
+
+    pub fn sweep_frequency(&mut self, depth: i8) {
+        match self {
+            Oscillator::PulseWave(o) => o.sweep_frequency(depth),
+            Oscillator::TriangleWave(o) => o.sweep_frequency(depth),
+            Oscillator::Noise(o) => o.sweep_frequency(depth),
+            Oscillator::Dpcm(o) => o.sweep_frequency(depth),
+            Oscillator::Vrc6Pulse(o) => o.pulse.sweep_frequency(depth),
+            Oscillator::Sawtooth(o) => o.sweep_frequency(depth),
+            Oscillator::Fds(o) => o.sweep_frequency(depth),
+            Oscillator::N163(o) => o.sweep_frequency(depth),
+            Oscillator::Sunsoft5b(o) => o.sweep_frequency(depth),
+            Oscillator::Vrc7(o) => o.sweep_frequency(depth),
+            // The player rejects pitch sweeps on MMC5 pulse channels (they have no sweep unit).
+            Oscillator::Mmc5Pulse(_) | Oscillator::Mmc5Pcm(_) => {}
+        }
+    }
+
+    /// Returns the largest timbre value (`@<TIMBRE>`) supported by the oscillator.
+    pub fn max_timbre(&self) -> u8 {
+        match self {
+            Oscillator::PulseWave(_) | Oscillator::Mmc5Pulse(_) => Timbre::DUTY_CYCLE_75,
+            Oscillator::Noise(_) => Timbre::NOISE_LOOPED,
+            Oscillator::Vrc6Pulse(_) => 7,
+            Oscillator::Sunsoft5b(_) => 2,
+            Oscillator::Vrc7(_) => 15,
+            Oscillator::Fds(_) | Oscillator::N163(_) => u8::MAX, // Any macro number.
+            Oscillator::TriangleWave(_)
+            | Oscillator::Dpcm(_)
+            | Oscillator::Sawtooth(_)
+            | Oscillator::Mmc5Pcm(_) => 0,
+        }
+    }
+
+    pub fn set_timbre(&mut self, timbre: Timbre) -> bool {
+        match self {
+            Oscillator::PulseWave(o) => o.set_timbre(timbre),
+            Oscillator::TriangleWave(o) => o.set_timbre(timbre),
+            Oscillator::Noise(o) => o.set_timbre(timbre),
+            Oscillator::Dpcm(o) => o.set_timbre(timbre),
+            Oscillator::Vrc6Pulse(o) => o.set_timbre(timbre),
+            Oscillator::Sawtooth(_) => timbre.get() == 0,
+            Oscillator::Fds(_) | Oscillator::N163(_) => true, // The wave is selected by the player.
+            Oscillator::Sunsoft5b(o) => o.set_timbre(timbre),
+            Oscillator::Vrc7(o) => o.set_timbre(timbre),
+            Oscillator::Mmc5Pulse(o) => o.pulse.set_timbre(timbre),
+            Oscillator::Mmc5Pcm(_) => timbre.get() == 0,
+        }
+    }
+
+    /// Enables the hardware volume envelope with the decay period (`None` means the constant volume).
+    pub fn set_hardware_envelope(&mut self, period: Option<u8>) -> bool {
+        match self {
+            Oscillator::PulseWave(o) => o.hardware_volume.envelope_period = period,
+            Oscillator::Noise(o) => o.hardware_volume.envelope_period = period,
+            Oscillator::Mmc5Pulse(o) => o.pulse.hardware_volume.envelope_period = period,
+            _ => return false,
+        }
+        true
+    }
+
+    /// Enables the hardware length counter with the load index (`None` means that the counter is halted).
+    pub fn set_length_counter(&mut self, index: Option<u8>) -> bool {
+        match self {
+            Oscillator::PulseWave(o) => o.hardware_volume.length_index = index,
+            Oscillator::Noise(o) => o.hardware_volume.length_index = index,
+            Oscillator::Mmc5Pulse(o) => o.pulse.hardware_volume.length_index = index,
+            _ => return false,
+        }
+        true
+    }
+
+    /// Enables the triangle linear counter with the reload value (`None` means that the control flag is set).
+    pub fn set_linear_counter(&mut self, reload: Option<u8>) -> bool {
+        let Oscillator::TriangleWave(o) = self else {
+            return false;
+        };
+        o.linear_counter.reload = reload;
+        true
+    }
+
+    /// Restarts the hardware volume envelope, the length counter and the linear counter
+    /// (as writing `$4003`, `$400B` or `$400F` does).
+    pub fn start_note(&mut self) {
+        match self {
+            Oscillator::PulseWave(o) => o.hardware_volume.start(),
+            Oscillator::TriangleWave(o) => o.linear_counter.start(),
+            Oscillator::Noise(o) => o.hardware_volume.start(),
+            Oscillator::Mmc5Pulse(o) => o.pulse.hardware_volume.start(),
+            Oscillator::Sunsoft5b(o) => o.start_note(),
+            Oscillator::Vrc7(o) => o.voice.key_on(),
+            _ => {}
+        }
+    }
+
+    /// Advances the hardware units (including the Sunsoft 5B noise and envelope generators, the VRC7 envelopes
+    /// and the FDS modulation unit) and the sample playback by the samples without synthesizing them.
+    ///
+    /// The phases of the waveforms are not advanced.
+    pub fn skip(&mut self, sample_rate: u16, samples: u64) {
+        let cycles =
+            |region: Region| region.cpu_clock_hz() * samples as f32 / f32::from(sample_rate);
+        match self {
+            Oscillator::PulseWave(o) => o.hardware_volume.tick(sample_rate, o.region, samples),
+            Oscillator::TriangleWave(o) => o.linear_counter.tick(sample_rate, o.region, samples),
+            Oscillator::Noise(o) => o.hardware_volume.tick(sample_rate, o.region, samples),
+            Oscillator::Dpcm(o) if !o.mute => o.play(cycles(o.region)),
+            Oscillator::Mmc5Pulse(o) => {
+                o.pulse
+                    .hardware_volume
+                    .tick(sample_rate, o.pulse.region, samples);
+            }
+            Oscillator::Mmc5Pcm(o) if !o.mute => o.play(cycles(o.region)),
+            Oscillator::Fds(o) if !o.mute => {
+                let cycles = cycles(o.region);
+                if let Some(m) = &mut o.modulation {
+                    m.modulate(0.0, cycles);
+                }
+            }
+            Oscillator::Sunsoft5b(o) => {
+                o.cycles +=
+                    f64::from(o.region.cpu_clock_hz()) * samples as f64 / f64::from(sample_rate);
+                o.envelope_level = o.chip.advance_to(o.cycles).1;
+            }
+            Oscillator::Vrc7(o) => o.skip(sample_rate, samples),
+            _ => {}
+        }
+    }
+
+    /// Returns the volume after applying the hardware volume envelope and length counter.
+    pub fn output_volume(&self, volume: Volume) -> Volume {
+        match self {
+            Oscillator::PulseWave(o) => o.hardware_volume.apply(volume),
+            Oscillator::Noise(o) => o.hardware_volume.apply(volume),
+            Oscillator::Mmc5Pulse(o) => o.pulse.hardware_volume.apply(volume),
+            Oscillator::Sunsoft5b(o) if o.envelope.is_some() => Volume::new(o.envelope_level / 2),
+            _ => volume,
+        }
+    }
+
+    /// Converts the output volume into the amplitude ratio (`0.0..=1.0`) applied to the samples.
+    ///
+    /// The volume is linear except for the Sunsoft 5B channels, which have logarithmic volume and envelope levels.
+    pub fn volume_ratio(&self, volume: Volume) -> f32 {
+        match self {
+            Oscillator::Sunsoft5b(o) if o.envelope.is_some() => {
+                sunsoft5b::amplitude(o.envelope_level)
+            }
+            Oscillator::Sunsoft5b(_) if volume.get() == 0 => 0.0,
+            Oscillator::Sunsoft5b(_) => sunsoft5b::amplitude(volume.get() * 2 + 1),
+            Oscillator::Vrc7(_) => 10f32.powf(-3.0 * f32::from(15 - volume.get()) / 20.0),
+            _ => volume.as_ratio(),
+        }
+    }
+
+    /// Converts a sample of this oscillator (before applying the volume) into the output level of the APU channel.
+    ///
+    /// The range is `0.0..=15.0` (`0.0..=127.0` for DPCM, `0.0..=31.0` for the VRC6 sawtooth, `0.0..=36.0` for FDS,
+    /// `-15.0..=15.0` for N163 and `0.0..=255.0` for the MMC5 PCM).
+    /// The triangle wave, DPCM and MMC5 PCM channels ignore the volume as the hardware does.
+    pub fn dac_level(&self, sample: Sample, volume: Volume) -> f32 {
+        let ratio = self.volume_ratio(volume);
+        let volume = f32::from(volume.get());
+        match self {
+            Oscillator::PulseWave(o) if o.mute => 0.0,
+            Oscillator::PulseWave(_) => (sample.get() + 1.0) / 2.0 * volume,
+            Oscillator::TriangleWave(_) => (sample.get() + 1.0) / 2.0 * 15.0,
+            Oscillator::Noise(_) => sample.get() * volume,
+            Oscillator::Dpcm(o) => f32::from(o.level),
+            Oscillator::Vrc6Pulse(o) if o.pulse.mute => 0.0,
+            Oscillator::Vrc6Pulse(_) => (sample.get() + 1.0) / 2.0 * volume,
+            Oscillator::Sawtooth(o) if o.mute => 0.0,
+            Oscillator::Sawtooth(_) => (sample.get() + 1.0) / 2.0 * 31.0 * volume / 15.0,
+            Oscillator::Fds(o) if o.mute => 0.0,
+            Oscillator::Fds(_) => (sample.get() + 1.0) / 2.0 * 36.0 * volume / 15.0,
+            Oscillator::N163(o) if o.mute => 0.0,
+            Oscillator::N163(_) => sample.get() * volume,
+            Oscillator::Sunsoft5b(o) if o.mute => 0.0,
+            Oscillator::Sunsoft5b(_) => (sample.get() + 1.0) / 2.0 * 15.0 * ratio,
+            Oscillator::Vrc7(_) => sample.get() * 15.0 * ratio,
+            Oscillator::Mmc5Pulse(o) if o.pulse.mute => 0.0,
+            Oscillator::Mmc5Pulse(_) => (sample.get() + 1.0) / 2.0 * volume,
+            Oscillator::Mmc5Pcm(o) => f32::from(o.level),
+        }
+    }
+}
+
+#[allow(clippy::approx_constant)]
+const FREQUENCY_RATIO_TABLE: [f32; 12] = [
+    1.000000, 1.059463, 1.122462, 1.189207, 1.259921, 1.33484, 1.414214, 1.498307, 1.587401,
+    1.681793, 1.781797, 1.887749,
+];
+
+/// Returns the frequency of the note (equal temperament, `o4 a` is 440 Hz).
+fn note_frequency(note: Note, octave: Octave) -> f32 {
+    semitone_frequency(note_semitone(note, octave))
+}
+
+/// Returns the number of semitones from `o0 a` (27.5 Hz) to the note.
+pub(crate) fn note_semitone(note: Note, octave: Octave) -> usize {
+    let mut o = usize::from(octave.get());
+    if !matches!(note.letter(), Letter::A | Letter::B) {
+        o -= 1;
+    }
+    o * 12 + note.offset_from_a()
+}
+
+/// Returns the frequency of the note `semitone` semitones above `o0 a`.
+pub(crate) fn semitone_frequency(semitone: usize) -> f32 {
+    27.5 * 2f32.powi((semitone / 12) as i32) * FREQUENCY_RATIO_TABLE[semitone % 12]
+}
+
+#[derive(Debug, Clone)]
+pub struct PulseWave {
+    pub(crate) frequency: f32,
+    pub(crate) duty_cycle: f32,
+    phase: f32,
+    pub(crate) mute: bool,
+    synthesis: Synthesis,
+    pub(crate) region: Region,
+    pub(crate) hardware_volume: HardwareVolume,
+}
+
+impl PulseWave {
+    fn new() -> Self {
+        Self {
+            frequency: 0.0, // dummy initial value
+            duty_cycle: 0.125,
+            phase: 0.0,
+            mute: false,
+            synthesis: Synthesis::default(),
+            region: Region::default(),
+            hardware_volume: HardwareVolume::default(),
+        }
+    }
+
+    fn sample(&mut self, sample_rate: u16, lfo: Option<&mut PitchLfo>) -> Sample {
+        self.hardware_volume.tick(sample_rate, self.region, 1);
+        if self.mute {
+            return Sample::ZERO;
+        }
+
+        let frequency = if let Some(lfo) = lfo {
+            let d = lfo.sample(sample_rate);
+            register_to_frequency(
+                self.region,
+                frequency_to_register(self.region, self.frequency) - d,
+            )
+        } else {
+            self.frequency
+        };
+        let dt = frequency / f32::from(sample_rate);
+        self.phase += dt;
+        self.phase -= self.phase.floor();
+        let level = if self.phase > self.duty_cycle {
+            Sample::MAX
+        } else {
+            Sample::MIN
+        };
+        if self.synthesis == Synthesis::Naive {
+            return level;
+        }
+
+        // Falling edge at the phase 0 and rising edge at the duty cycle.
+        let falling = poly_blep(self.phase, dt);
+        let rising = poly_blep((self.phase - self.duty_cycle).rem_euclid(1.0), dt);
+        Sample::new(level.get() - falling + rising)
+    }
+
+    fn mute(&mut self, mute: bool) {
+        self.mute = mute;
+    }
+
+    fn set_frequency(&mut self, note: Note, octave: Octave, detune: Detune) {
+        self.frequency = note_frequency(note, octave);
+        if detune.get() != 0 {
+            let d = f32::from(detune.get());
+            self.frequency = register_to_frequency(
+                self.region,
+                frequency_to_register(self.region, self.frequency) - d,
+            );
+        }
+    }
+
+    fn set_period(&mut self, period: Period) -> bool {
+        if !(8..=2047).contains(&period.get()) {
+            return false;
+        }
+        self.frequency = register_to_frequency(self.region, f32::from(period.get() + 1));
+        true
+    }
+
+    fn sweep_frequency(&mut self, depth: i8) {
+        let mut register = frequency_to_register(self.region, self.frequency);
+        if depth >= 0 {
+            register -= register / 2f32.powi(i32::from(depth));
+        } else {
+            register += register / 2f32.powi(i32::from(-depth));
+        }
+        self.frequency = register_to_frequency(self.region, register);
+    }
+
+    fn set_timbre(&mut self, timbre: Timbre) -> bool {
+        self.duty_cycle = match timbre.get() {
+            Timbre::DUTY_CYCLE_12 => 0.125,
+            Timbre::DUTY_CYCLE_25 => 0.250,
+            Timbre::DUTY_CYCLE_50 => 0.500,
+            Timbre::DUTY_CYCLE_75 => 0.750,
+            _ => return false,
+        };
+        true
+    }
+}
+
+/// Pulse wave channel of VRC6 (8 duty cycles and 12-bit period).
+#[derive(Debug, Clone)]
+pub struct Vrc6Pulse {
+    pulse: PulseWave,
+}
+
+impl Vrc6Pulse {
+    fn new() -> Self {
+        let mut pulse = PulseWave::new();
+        pulse.duty_cycle = 1.0 / 16.0;
+        Self { pulse }
+    }
+
+    fn set_period(&mut self, period: Period) -> bool {
+        if !(1..=4095).contains(&period.get()) {
+            return false;
+        }
+        self.pulse.frequency =
+            register_to_frequency(self.pulse.region, f32::from(period.get() + 1));
+        true
+    }
+
+    fn set_timbre(&mut self, timbre: Timbre) -> bool {
+        if timbre.get() > 7 {
+            return false;
+        }
+        self.pulse.duty_cycle = f32::from(timbre.get() + 1) / 16.0;
+        true
+    }
+}
+
+/// Pulse wave channel of MMC5 (the same as the 2A03 pulse wave channel, but without the sweep unit).
+#[derive(Debug, Clone)]
+pub struct Mmc5Pulse {
+    pulse: PulseWave,
+}
+
+impl Mmc5Pulse {
+    fn new() -> Self {
+        Self {
+            pulse: PulseWave::new(),
+        }
+    }
+
+    // Without the sweep unit, periods less than `8` are not silenced.
+    fn set_period(&mut self, period: Period) -> bool {
+        if period.get() > 2047 {
+            return false;
+        }
+        self.pulse.frequency =
+            register_to_frequency(self.pulse.region, f32::from(period.get() + 1));
+        true
+    }
+}
+
+/// Sawtooth channel of VRC6.
+///
+/// The accumulator is increased seven times per period and its high 5 bits are output,
+/// so the waveform is a 7-step ramp.
+#[derive(Debug, Clone)]
+pub struct Sawtooth {
+    frequency: f32,
+    phase: f32,
+    mute: bool,
+    synthesis: Synthesis,
+    region: Region,
+}
+
+impl Sawtooth {
+    // `(42 * k) >> 3` (the maximum accumulator rate without overflow) normalized to `-1.0..=1.0`.
+    const WAVEFORM: [f32; 7] = [
+        -1.0,
+        -0.67741936,
+        -0.3548387,
+        -0.032258064,
+        0.3548387,
+        0.67741936,
+        1.0,
+    ];
+
+    fn new() -> Self {
+        Self {
+            frequency: 0.0, // dummy initial value
+            phase: 0.0,
+            mute: false,
+            synthesis: Synthesis::default(),
+            region: Region::default(),
+        }
+    }
+
+    fn sample(&mut self, sample_rate: u16, lfo: Option<&mut PitchLfo>) -> Sample {
+        if self.mute {
+            return Sample::ZERO;
+        }
+
+        let frequency = if let Some(lfo) = lfo {
+            let d = lfo.sample(sample_rate);
+            self.register_to_frequency(self.frequency_to_register(self.frequency) - d)
+        } else {
+            self.frequency
+        };
+        let dt = frequency / f32::from(sample_rate);
+        self.phase += dt;
+        self.phase -= self.phase.floor();
+        if self.synthesis == Synthesis::Naive {
+            let i = (self.phase * Self::WAVEFORM.len() as f32).floor() as usize;
+            return Sample::new(Self::WAVEFORM[i % Self::WAVEFORM.len()]);
+        }
+        Sample::new(band_limited_steps(&Self::WAVEFORM, self.phase, dt))
+    }
+
+    fn set_frequency(&mut self, note: Note, octave: Octave, detune: Detune) {
+        self.frequency = note_frequency(note, octave);
+        if detune.get() != 0 {
+            let d = f32::from(detune.get());
+            self.frequency =
+                self.register_to_frequency(self.frequency_to_register(self.frequency) - d);
+        }
+    }
+
+    fn set_period(&mut self, period: Period) -> bool {
+        if !(1..=4095).contains(&period.get()) {
+            return false;
+        }
+        self.frequency = self.register_to_frequency(f32::from(period.get() + 1));
+        true
+    }
+
+    fn sweep_frequency(&mut self, depth: i8) {
+        let mut register = self.frequency_to_register(self.frequency);
+        if depth >= 0 {
+            register -= register / 2f32.powi(i32::from(depth));
+        } else {
+            register += register / 2f32.powi(i32::from(-depth));
+        }
+        self.frequency = self.register_to_frequency(register);
+    }
+
+    // The sawtooth period is 14 CPU cycles per step of the register (16 for the pulse waves).
+    fn frequency_to_register(&self, frequency: f32) -> f32 {
+        frequency_to_register(self.region, frequency) * 16.0 / 14.0
+    }
+
+    fn register_to_frequency(&self, register: f32) -> f32 {
+        register_to_frequency(self.region, register * 14.0 / 16.0)
+    }
+}
+
+/// Wavetable channel of the Famicom Disk System (64-step 6-bit waveform with the modulation unit).
+#[derive(Debug, Clone)]
+pub struct Fds {
+    frequency: f32,
+    waveform: Box<[f32; 64]>,
+    phase: f32,
+    modulation: Option<FdsModulation>,
+    master_volume: u8,
+    mute: bool,
+    synthesis: Synthesis,
+    region: Region,
+}
+
+impl Fds {
+    // The frequency is `CPU clock * pitch / 65536 / 64`.
+    const PITCH_DIVIDER: f32 = 65536.0 * 64.0;
+
+    fn new() -> Self {
+        Self {
+            frequency: 0.0, // dummy initial value
+            waveform: Box::new([0.0; 64]),
+            phase: 0.0,
+            modulation: None,
+            master_volume: 0,
+            mute: false,
+            synthesis: Synthesis::default(),
+            region: Region::default(),
+        }
+    }
+
+    /// Sets the waveform (64 samples in `0..=63`).
+    pub fn set_waveform(&mut self, samples: &[u8]) {
+        for (level, &sample) in self.waveform.iter_mut().zip(samples) {
+            *level = f32::from(sample) / 63.0;
+        }
+    }
+
+    /// Enables the modulation unit with the table (32 codes in `0..=7`), the depth (`0..=63`) and the speed (`0..=4095`).
+    pub fn set_modulation(&mut self, modulation: Option<(&[u8], u8, u16)>) {
+        self.modulation = modulation.map(|(table, depth, speed)| {
+            let mut m = FdsModulation {
+                table: [0; 32],
+                depth,
+                speed,
+                position: 0,
+                accumulator: 0.0,
+                counter: 0,
+            };
+            m.table.copy_from_slice(table);
+            m
+        });
+    }
+
+    /// Sets the master volume (`0`: 100%, `1`: 66%, `2`: 50%, `3`: 40%).
+    pub fn set_master_volume(&mut self, level: u8) {
+        self.master_volume = level;
+    }
+
+    fn sample(&mut self, sample_rate: u16, lfo: Option<&mut PitchLfo>) -> Sample {
+        const MASTER_VOLUMES: [f32; 4] = [1.0, 2.0 / 3.0, 2.0 / 4.0, 2.0 / 5.0];
+
+        if self.mute {
+            return Sample::ZERO;
+        }
+
+        let frequency = if let Some(lfo) = lfo {
+            let d = lfo.sample(sample_rate);
+            register_to_frequency(
+                self.region,
+                frequency_to_register(self.region, self.frequency) - d,
+            )
+        } else {
+            self.frequency
+        };
+        let cycles = self.region.cpu_clock_hz() / f32::from(sample_rate);
+        let mut pitch = frequency * Self::PITCH_DIVIDER / self.region.cpu_clock_hz();
+        if let Some(m) = &mut self.modulation {
+            pitch = m.modulate(pitch, cycles);
+        }
+        let dt = pitch * cycles / Self::PITCH_DIVIDER;
+        self.phase += dt;
+        self.phase -= self.phase.floor();
+
+        let level = if self.synthesis == Synthesis::Naive {
+            self.waveform[(self.phase * 64.0) as usize % 64]
+        } else {
+            band_limited_steps(&self.waveform[..], self.phase, dt)
+        };
+        Sample::new((level * 2.0 - 1.0) * MASTER_VOLUMES[usize::from(self.master_volume)])
+    }
+
+    fn set_frequency(&mut self, note: Note, octave: Octave, detune: Detune) {
+        self.frequency = note_frequency(note, octave);
+        if detune.get() != 0 {
+            // The pitch register is proportional to the frequency.
+            let pitch = self.frequency * Self::PITCH_DIVIDER / self.region.cpu_clock_hz();
+            let pitch = (pitch + f32::from(detune.get())).max(0.0);
+            self.frequency = pitch * self.region.cpu_clock_hz() / Self::PITCH_DIVIDER;
+        }
+    }
+
+    fn set_period(&mut self, period: Period) -> bool {
+        self.frequency = f32::from(period.get()) * self.region.cpu_clock_hz() / Self::PITCH_DIVIDER;
+        true
+    }
+
+    fn sweep_frequency(&mut self, depth: i8) {
+        let mut register = frequency_to_register(self.region, self.frequency);
+        if depth >= 0 {
+            register -= register / 2f32.powi(i32::from(depth));
+        } else {
+            register += register / 2f32.powi(i32::from(-depth));
+        }
+        self.frequency = register_to_frequency(self.region, register);
+    }
+}
+
+/// Wavetable channel of the Namco 163 (4-bit samples in the sound RAM shared by up to 8 channels).
+#[derive(Debug, Clone)]
+pub struct N163 {
+    chip: n163::Chip,
+    index: usize,
+    frequency: f32,
+    wave_offset: u8,
+    wave_length: usize,
+    loaded_wave: Option<u8>,
+    phase: u32,
+    cycles: f64,
+    mute: bool,
+    region: Region,
+}
+
+impl N163 {
+    const MAX_REGISTER: f32 = 0x3FFFF as f32;
+
+    fn new() -> Self {
+        Self {
+            chip: n163::Chip::new(1),
+            index: 0,
+            frequency: 0.0, // dummy initial value
+            wave_offset: 0,
+            wave_length: 4,
+            loaded_wave: None,
+            phase: 0,
+            cycles: 0.0,
+            mute: false,
+            region: Region::default(),
+        }
+    }
+
+    /// Connects this channel to the chip shared with the other N163 channels (`index` is the multiplexer slot).
+    pub fn connect(&mut self, chip: n163::Chip, index: usize) {
+        self.chip = chip;
+        self.index = index;
+    }
+
+    pub fn chip(&self) -> &n163::Chip {
+        &self.chip
+    }
+
+    pub fn wave_offset(&self) -> u8 {
+        self.wave_offset
+    }
+
+    /// Sets the address of the wave in the sound RAM (the wave is written there again when it is selected next).
+    pub fn set_wave_offset(&mut self, offset: u8) {
+        self.wave_offset = offset;
+        self.loaded_wave = None;
+    }
+
+    /// Writes the wave (selected by the timbre) into the sound RAM unless it has already been written.
+    pub fn load_wave(&mut self, timbre: u8, samples: &[u8]) {
+        if self.loaded_wave == Some(timbre) {
+            return;
+        }
+        self.chip.write_wave(self.wave_offset, samples);
+        self.wave_length = samples.len();
+        self.loaded_wave = Some(timbre);
+    }
+
+    fn sample(&mut self, sample_rate: u16, lfo: Option<&mut PitchLfo>) -> Sample {
+        let start = self.cycles;
+        let end = start + f64::from(self.region.cpu_clock_hz()) / f64::from(sample_rate);
+        self.cycles = end;
+        if self.mute {
+            return Sample::ZERO;
+        }
+
+        let frequency = if let Some(lfo) = lfo {
+            let d = lfo.sample(sample_rate);
+            register_to_frequency(
+                self.region,
+                frequency_to_register(self.region, self.frequency) - d,
+            )
+        } else {
+            self.frequency
+        };
+        let register = self.frequency_to_register(frequency).round() as u64;
+        let updates = u64::from(self.chip.updates(self.index, start, end));
+        let length = (self.wave_length as u64) << 16;
+        self.phase = ((u64::from(self.phase) + register * updates) % length) as u32;
+
+        let address = self.wave_offset.wrapping_add((self.phase >> 16) as u8);
+        let level = (f32::from(self.chip.read(address)) - 7.5) / 7.5;
+        Sample::new(level * self.chip.time_share(self.index, start, end))
+    }
+
+    fn set_frequency(&mut self, note: Note, octave: Octave, detune: Detune) {
+        self.frequency = note_frequency(note, octave);
+        if detune.get() != 0 {
+            let d = f32::from(detune.get());
+            self.frequency = register_to_frequency(
+                self.region,
+                frequency_to_register(self.region, self.frequency) - d,
+            );
+        }
+    }
+
+    fn set_period(&mut self, period: Period) -> bool {
+        // The period is the upper 12 bits of the 18-bit frequency register.
+        self.frequency = self.register_to_frequency(f32::from(period.get()) * 64.0);
+        true
+    }
+
+    fn sweep_frequency(&mut self, depth: i8) {
+        let mut register = frequency_to_register(self.region, self.frequency);
+        if depth >= 0 {
+            register -= register / 2f32.powi(i32::from(depth));
+        } else {
+            register += register / 2f32.powi(i32::from(-depth));
+        }
+        self.frequency = register_to_frequency(self.region, register);
+    }
+
+    // The phase advances by the register value every `15 * N` CPU cycles, and a wave cycle is `length << 16`.
+    fn frequency_to_register(&self, frequency: f32) -> f32 {
+        let divider = 15.0 * 65536.0 * self.chip.channel_count() as f32 * self.wave_length as f32;
+        (frequency * divider / self.region.cpu_clock_hz()).clamp(0.0, Self::MAX_REGISTER)
+    }
+
+    pub(crate) fn register_to_frequency(&self, register: f32) -> f32 {
+        let divider = 15.0 * 65536.0 * self.chip.channel_count() as f32 * self.wave_length as f32;
+        register * self.region.cpu_clock_hz() / divider
+    }
+}
+
+/// FM channel of the VRC7 (a modulator and a carrier with one of the 15 built-in patches or the user patch).
+///
+/// Unlike the other channels, muting this channel releases the key and the sound fades out by the release rate.
+#[derive(Debug, Clone)]
+pub struct Vrc7 {
+    chip: vrc7::Chip,
+    voice: vrc7::Voice,
+    instrument: u8,
+    sustain: bool,
+    frequency: f32,
+    clock: f64,
+    output: f32,
+    key_on: bool,
+    region: Region,
+}
+
+impl Vrc7 {
+    fn new() -> Self {
+        Self {
+            chip: vrc7::Chip::new(),
+            voice: vrc7::Voice::default(),
+            instrument: 0,
+            sustain: false,
+            frequency: 0.0, // dummy initial value
+            clock: 0.0,
+            output: 0.0,
+            key_on: false,
+            region: Region::default(),
+        }
+    }
+
+    /// Connects this channel to the chip (the user patch) shared with the other VRC7 channels.
+    pub fn connect(&mut self, chip: vrc7::Chip) {
+        self.chip = chip;
+    }
+
+    pub fn chip(&self) -> &vrc7::Chip {
+        &self.chip
+    }
+
+    /// Sets the sustain flag, which slows down the release after the key is released.
+    pub fn set_sustain(&mut self, sustain: bool) {
+        self.sustain = sustain;
+    }
+
+    // `@0`: the user patch, `@1..=@15`: the built-in patches.
+    fn set_timbre(&mut self, timbre: Timbre) -> bool {
+        if timbre.get() > 15 {
+            return false;
+        }
+        self.instrument = timbre.get();
+        true
+    }
+
+    fn mute(&mut self, mute: bool) {
+        if mute && self.key_on {
+            self.voice.key_off();
+        }
+        self.key_on = !mute;
+    }
+
+    fn sample(&mut self, sample_rate: u16, lfo: Option<&mut PitchLfo>) -> Sample {
+        let frequency = if let Some(lfo) = lfo {
+            let d = lfo.sample(sample_rate);
+            register_to_frequency(
+                self.region,
+                frequency_to_register(self.region, self.frequency) - d,
+            )
+        } else {
+            self.frequency
+        };
+        let (block, fnum) = vrc7::frequency_to_fnum(frequency);
+        let patch = self.chip.patch(self.instrument);
+
+        // The chip runs at its own rate, and the latest output is held.
+        self.clock += vrc7::RATE / f64::from(sample_rate);
+        while self.clock >= 1.0 {
+            self.clock -= 1.0;
+            self.output = self.voice.next_sample(patch, block, fnum, self.sustain);
         }
+        Sample::new(self.output)
     }
 
-    pub fn set_frequency(&mut self, note: Note, octave: Octave, detune: Detune) {
-        match self {
-            Oscillator::PulseWave(o) => o.set_frequency(note, octave, detune),
-            Oscillator::TriangleWave(o) => o.set_frequency(note, octave, detune),
-            Oscillator::Noise(o) => o.set_frequency(note, octave, detune),
-        }
+    /// Advances the chip clock and the envelopes by `samples` samples without generating them.
+    fn skip(&mut self, sample_rate: u16, samples: u64) {
+        let (block, fnum) = vrc7::frequency_to_fnum(self.frequency);
+        let patch = self.chip.patch(self.instrument);
+        self.clock += vrc7::RATE * samples as f64 / f64::from(sample_rate);
+        let steps = self.clock.floor();
+        self.clock -= steps;
+        self.voice
+            .skip(patch, block, fnum, self.sustain, steps as u64);
     }
 
-    pub fn frequency(&self) -> f32 {
-        match self {
-            Oscillator::PulseWave(o) => o.frequency,
-            Oscillator::TriangleWave(o) => o.frequency,
-            Oscillator::Noise(o) => o.frequency,
+    fn set_frequency(&mut self, note: Note, octave: Octave, detune: Detune) {
+        self.frequency = note_frequency(note, octave);
+        if detune.get() != 0 {
+            let d = f32::from(detune.get());
+            self.frequency = register_to_frequency(
+                self.region,
+                frequency_to_register(self.region, self.frequency) - d,
+            );
         }
     }
 
-    pub fn sweep_frequency(&mut self, depth: i8) {
-        match self {
-            Oscillator::PulseWave(o) => o.sweep_frequency(depth),
-            Oscillator::TriangleWave(o) => o.sweep_frequency(depth),
-            Oscillator::Noise(o) => o.sweep_frequency(depth),
-        }
+    // The period is the block (upper 3 bits) and the F-number (lower 9 bits).
+    fn set_period(&mut self, period: Period) -> bool {
+        let period = period.get();
+        self.frequency = vrc7::fnum_to_frequency((period >> 9) as u8, period & 0x1FF);
+        true
     }
 
-    pub fn set_timbre(&mut self, timbre: Timbre) -> bool {
-        match self {
-            Oscillator::PulseWave(o) => o.set_timbre(timbre),
-            Oscillator::TriangleWave(o) => o.set_timbre(timbre),
-            Oscillator::Noise(o) => o.set_timbre(timbre),
+    fn sweep_frequency(&mut self, depth: i8) {
+        let mut register = frequency_to_register(self.region, self.frequency);
+        if depth >= 0 {
+            register -= register / 2f32.powi(i32::from(depth));
+        } else {
+            register += register / 2f32.powi(i32::from(-depth));
         }
+        self.frequency = register_to_frequency(self.region, register);
     }
 }
 
-#[allow(clippy::approx_constant)]
-const FREQUENCY_RATIO_TABLE: [f32; 12] = [
-    1.000000, 1.059463, 1.122462, 1.189207, 1.259921, 1.33484, 1.414214, 1.498307, 1.587401,
-    1.681793, 1.781797, 1.887749,
-];
-
+/// Square channel of the Sunsoft 5B (with the noise and envelope generators shared by the three channels).
 #[derive(Debug, Clone)]
-pub struct PulseWave {
+pub struct Sunsoft5b {
+    chip: sunsoft5b::Chip,
     frequency: f32,
-    duty_cycle: f32,
     phase: f32,
+    tone: bool,
+    noise: bool,
+    envelope: Option<(u8, u16)>,
+    envelope_level: u8,
+    cycles: f64,
     mute: bool,
+    synthesis: Synthesis,
+    region: Region,
 }
 
-impl PulseWave {
+impl Sunsoft5b {
     fn new() -> Self {
         Self {
+            chip: sunsoft5b::Chip::new(),
             frequency: 0.0, // dummy initial value
-            duty_cycle: 0.125,
             phase: 0.0,
+            tone: true,
+            noise: false,
+            envelope: None,
+            envelope_level: 0,
+            cycles: 0.0,
             mute: false,
+            synthesis: Synthesis::default(),
+            region: Region::default(),
+        }
+    }
+
+    /// Connects this channel to the chip shared with the other Sunsoft 5B channels.
+    pub fn connect(&mut self, chip: sunsoft5b::Chip) {
+        self.chip = chip;
+    }
+
+    pub fn chip(&self) -> &sunsoft5b::Chip {
+        &self.chip
+    }
+
+    /// Enables the envelope generator with the shape and the period (`None` means the constant volume).
+    pub fn set_envelope(&mut self, envelope: Option<(u8, u16)>) {
+        self.envelope = envelope;
+        if let Some((shape, period)) = envelope {
+            self.chip.start_envelope(shape, period);
+        }
+    }
+
+    // `@0`: tone, `@1`: noise, `@2`: tone and noise.
+    fn set_timbre(&mut self, timbre: Timbre) -> bool {
+        (self.tone, self.noise) = match timbre.get() {
+            0 => (true, false),
+            1 => (false, true),
+            2 => (true, true),
+            _ => return false,
+        };
+        true
+    }
+
+    fn start_note(&mut self) {
+        if let Some((shape, period)) = self.envelope {
+            self.chip.start_envelope(shape, period);
         }
     }
 
     fn sample(&mut self, sample_rate: u16, lfo: Option<&mut PitchLfo>) -> Sample {
+        self.cycles += f64::from(self.region.cpu_clock_hz()) / f64::from(sample_rate);
+        let (noise, envelope_level) = self.chip.advance_to(self.cycles);
+        self.envelope_level = envelope_level;
         if self.mute {
             return Sample::ZERO;
         }
 
         let frequency = if let Some(lfo) = lfo {
             let d = lfo.sample(sample_rate);
-            register_to_frequency(frequency_to_register(self.frequency) - d)
+            self.register_to_frequency(self.frequency_to_register(self.frequency) - d)
         } else {
             self.frequency
         };
-        self.phase += frequency / f32::from(sample_rate);
+        let dt = frequency / f32::from(sample_rate);
+        self.phase += dt;
         self.phase -= self.phase.floor();
-        if self.phase > self.duty_cycle {
-            Sample::MAX
-        } else {
-            Sample::MIN
-        }
-    }
 
-    fn mute(&mut self, mute: bool) {
-        self.mute = mute;
+        // The tone and the noise are combined by AND (a disabled one is always high).
+        let noise = !self.noise || noise;
+        if !self.tone {
+            return Sample::new(if noise { 1.0 } else { -1.0 });
+        }
+        if !noise {
+            return Sample::new(-1.0);
+        }
+        if self.synthesis == Synthesis::Naive {
+            return Sample::new(if self.phase < 0.5 { 1.0 } else { -1.0 });
+        }
+        Sample::new(band_limited_steps(&[1.0, -1.0], self.phase, dt))
     }
 
     fn set_frequency(&mut self, note: Note, octave: Octave, detune: Detune) {
-        let mut o = i32::from(octave.get());
-        if !matches!(note.letter(), Letter::A | Letter::B) {
-            o -= 1;
-        }
-        let ratio = FREQUENCY_RATIO_TABLE[note.offset_from_a()];
-        let a = 27.5 * 2f32.powi(o);
-        self.frequency = a * ratio;
+        self.frequency = note_frequency(note, octave);
         if detune.get() != 0 {
             let d = f32::from(detune.get());
-            self.frequency = register_to_frequency(frequency_to_register(self.frequency) - d);
+            self.frequency =
+                self.register_to_frequency(self.frequency_to_register(self.frequency) - d);
         }
     }
 
+    fn set_period(&mut self, period: Period) -> bool {
+        if !(1..=4095).contains(&period.get()) {
+            return false;
+        }
+        self.frequency = self.register_to_frequency(f32::from(period.get()));
+        true
+    }
+
     fn sweep_frequency(&mut self, depth: i8) {
-        let mut register = frequency_to_register(self.frequency);
+        let mut register = self.frequency_to_register(self.frequency);
         if depth >= 0 {
             register -= register / 2f32.powi(i32::from(depth));
         } else {
             register += register / 2f32.powi(i32::from(-depth));
         }
-        self.frequency = register_to_frequency(register);
+        self.frequency = self.register_to_frequency(register);
     }
 
-    fn set_timbre(&mut self, timbre: Timbre) -> bool {
-        self.duty_cycle = match timbre.get() {
-            Timbre::DUTY_CYCLE_12 => 0.125,
-            Timbre::DUTY_CYCLE_25 => 0.250,
-            Timbre::DUTY_CYCLE_50 => 0.500,
-            Timbre::DUTY_CYCLE_75 => 0.750,
-            _ => return false,
-        };
-        true
+    // The tone period is 32 CPU cycles per step of the register (16 for the pulse waves, without `+ 1`).
+    fn frequency_to_register(&self, frequency: f32) -> f32 {
+        frequency_to_register(self.region, frequency) / 2.0
+    }
+
+    fn register_to_frequency(&self, register: f32) -> f32 {
+        register_to_frequency(self.region, register * 2.0)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct FdsModulation {
+    table: [u8; 32],
+    depth: u8,
+    speed: u16,
+    position: usize,
+    accumulator: f32,
+    counter: i32, // 7-bit signed
+}
+
+impl FdsModulation {
+    /// Advances the modulation unit by the CPU cycles and returns the modulated pitch.
+    ///
+    /// See: <https://www.nesdev.org/wiki/FDS_audio>
+    fn modulate(&mut self, pitch: f32, cycles: f32) -> f32 {
+        self.accumulator += f32::from(self.speed) * cycles;
+        while self.accumulator >= 65536.0 {
+            self.accumulator -= 65536.0;
+
+            // Each entry of the 32-entry table is used twice.
+            self.counter = match self.table[self.position / 2] {
+                4 => 0,
+                code => {
+                    let delta = [0, 1, 2, 4, 0, -4, -2, -1][usize::from(code)];
+                    (self.counter + delta + 64).rem_euclid(128) - 64
+                }
+            };
+            self.position = (self.position + 1) % 64;
+        }
+
+        let mut temp = self.counter * i32::from(self.depth);
+        let remainder = temp & 0xF;
+        temp >>= 4;
+        if remainder > 0 && temp & 0x80 == 0 {
+            temp += if self.counter < 0 { -1 } else { 2 };
+        }
+        if temp >= 192 {
+            temp -= 256;
+        } else if temp < -64 {
+            temp += 256;
+        }
+        let temp = pitch * temp as f32 / 64.0;
+        (pitch + temp.round()).max(0.0)
     }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-enum MuteState {
+pub(crate) enum MuteState {
     Off,
     Switching,
     On,
@@ -177,10 +1211,13 @@ enum MuteState {
 
 #[derive(Debug, Clone)]
 pub struct TriangleWave {
-    frequency: f32,
+    pub(crate) frequency: f32,
     phase: f32,
-    mute: MuteState,
+    pub(crate) mute: MuteState,
     prev: Sample,
+    synthesis: Synthesis,
+    pub(crate) region: Region,
+    pub(crate) linear_counter: HardwareLinearCounter,
 }
 
 impl TriangleWave {
@@ -190,6 +1227,9 @@ impl TriangleWave {
             phase: 0.0,
             mute: MuteState::Off,
             prev: Sample::ZERO,
+            synthesis: Synthesis::default(),
+            region: Region::default(),
+            linear_counter: HardwareLinearCounter::default(),
         }
     }
 
@@ -238,18 +1278,26 @@ impl TriangleWave {
         ];
         const N: f32 = WAVEFORM.len() as f32;
 
+        self.linear_counter.tick(sample_rate, self.region, 1);
+        if self.linear_counter.is_expired() && self.mute == MuteState::Off {
+            self.mute = MuteState::Switching;
+        }
         if self.mute == MuteState::On {
             return Sample::ZERO;
         }
 
         let frequency = if let Some(lfo) = lfo {
             let d = lfo.sample(sample_rate);
-            register_to_frequency(frequency_to_register(self.frequency) - d)
+            register_to_frequency(
+                self.region,
+                frequency_to_register(self.region, self.frequency) - d,
+            )
         } else {
             self.frequency
         };
 
-        self.phase += frequency / f32::from(sample_rate);
+        let dt = frequency / f32::from(sample_rate);
+        self.phase += dt;
         self.phase -= self.phase.floor();
         let i = (self.phase * N).floor() as usize;
         let s = Sample::new(WAVEFORM[i]);
@@ -260,7 +1308,11 @@ impl TriangleWave {
             return Sample::ZERO;
         }
         self.prev = s;
-        s
+        if self.synthesis == Synthesis::Naive {
+            return s;
+        }
+
+        Sample::new(band_limited_steps(&WAVEFORM, self.phase, dt))
     }
 
     fn set_frequency(&mut self, note: Note, octave: Octave, detune: Detune) {
@@ -273,18 +1325,29 @@ impl TriangleWave {
         self.frequency = a * ratio;
         if detune.get() != 0 {
             let d = f32::from(detune.get());
-            self.frequency = register_to_frequency(frequency_to_register(self.frequency) - d);
+            self.frequency = register_to_frequency(
+                self.region,
+                frequency_to_register(self.region, self.frequency) - d,
+            );
         }
     }
 
     fn sweep_frequency(&mut self, depth: i8) {
-        let mut register = frequency_to_register(self.frequency);
+        let mut register = frequency_to_register(self.region, self.frequency);
         if depth >= 0 {
             register -= register / 2f32.powi(i32::from(depth));
         } else {
             register += register / 2f32.powi(i32::from(-depth));
         }
-        self.frequency = register_to_frequency(register);
+        self.frequency = register_to_frequency(self.region, register);
+    }
+
+    fn set_period(&mut self, period: Period) -> bool {
+        if !(2..=2047).contains(&period.get()) {
+            return false;
+        }
+        self.frequency = register_to_frequency(self.region, f32::from(period.get() + 1)) / 2.0;
+        true
     }
 
     fn set_timbre(&mut self, timbre: Timbre) -> bool {
@@ -292,13 +1355,25 @@ impl TriangleWave {
     }
 }
 
+/// Periods (in CPU cycles) of the noise channel for the 16 period indexes (NTSC).
+pub(crate) const NOISE_PERIOD_TABLE: [u16; 16] = [
+    4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068,
+];
+
+/// Periods (in CPU cycles) of the noise channel for the 16 period indexes (PAL).
+pub(crate) const PAL_NOISE_PERIOD_TABLE: [u16; 16] = [
+    4, 8, 14, 30, 60, 88, 118, 148, 188, 236, 354, 472, 708, 944, 1890, 3778,
+];
+
 #[derive(Debug, Clone)]
 pub struct Noise {
     register: u16,
-    frequency: f32,
-    looped_noise: bool,
+    pub(crate) frequency: f32,
+    pub(crate) looped_noise: bool,
     residual: f32,
-    mute: bool,
+    pub(crate) mute: bool,
+    region: Region,
+    pub(crate) hardware_volume: HardwareVolume,
 }
 
 impl Noise {
@@ -309,14 +1384,29 @@ impl Noise {
             looped_noise: false,
             residual: 0.0,
             mute: false,
+            region: Region::default(),
+            hardware_volume: HardwareVolume::default(),
+        }
+    }
+
+    pub(crate) fn period_table(&self) -> &'static [u16; 16] {
+        if self.region == Region::Pal {
+            &PAL_NOISE_PERIOD_TABLE
+        } else {
+            &NOISE_PERIOD_TABLE
         }
     }
 
     fn set_frequency(&mut self, note: Note, _octave: Octave, _detune: Detune) {
-        const TABLE: [f32; 12] = [
-            4., 8., 16., 32., 64., 96., 128., 160., 202., 254., 380., 508.,
-        ];
-        self.frequency = TABLE[note.offset_from_c()];
+        self.frequency = f32::from(self.period_table()[note.offset_from_c()]);
+    }
+
+    fn set_period(&mut self, period: Period) -> bool {
+        let Some(&frequency) = self.period_table().get(usize::from(period.get())) else {
+            return false;
+        };
+        self.frequency = f32::from(frequency);
+        true
     }
 
     fn set_timbre(&mut self, timbre: Timbre) -> bool {
@@ -329,11 +1419,12 @@ impl Noise {
     }
 
     fn sample(&mut self, sample_rate: u16, _lfo: Option<&mut PitchLfo>) -> Sample {
+        self.hardware_volume.tick(sample_rate, self.region, 1);
         if self.mute {
             return Sample::ZERO;
         }
 
-        let mut n = self.residual + SYSTEM_CLOCK_HZ / f32::from(sample_rate);
+        let mut n = self.residual + self.region.cpu_clock_hz() / f32::from(sample_rate);
         while n >= self.frequency {
             let b = if self.looped_noise {
                 (self.register & 1) ^ ((self.register >> 6) & 1)
@@ -359,6 +1450,269 @@ impl Noise {
     fn sweep_frequency(&mut self, _depth: i8) {}
 }
 
+#[derive(Debug, Clone)]
+pub struct Dpcm {
+    pub(crate) sample: Option<MacroNumber>,
+    pub(crate) triggers: usize,
+    pub(crate) pitch: u8,
+    data: Arc<Vec<u8>>,
+    bit_index: usize,
+    level: u8,
+    frequency: f32,
+    residual: f32,
+    pub(crate) mute: bool,
+    region: Region,
+}
+
+impl Dpcm {
+    fn new() -> Self {
+        Self {
+            sample: None,
+            triggers: 0,
+            pitch: 0,
+            data: Arc::default(),
+            bit_index: 0,
+            level: dpcm::INITIAL_LEVEL,
+            frequency: 0.0, // dummy initial value
+            residual: 0.0,
+            mute: false,
+            region: Region::default(),
+        }
+    }
+
+    pub fn start(&mut self, sample: MacroNumber, data: Arc<Vec<u8>>, pitch: u8) {
+        self.sample = Some(sample);
+        self.triggers += 1;
+        self.pitch = pitch;
+        self.data = data;
+        self.bit_index = 0;
+        self.frequency = f32::from(dpcm::rate_table(self.region)[usize::from(pitch)]);
+        self.residual = 0.0;
+    }
+
+    fn set_frequency(&mut self, _note: Note, _octave: Octave, _detune: Detune) {}
+
+    fn set_timbre(&mut self, timbre: Timbre) -> bool {
+        timbre.get() == 0
+    }
+
+    fn sample(&mut self, sample_rate: u16, _lfo: Option<&mut PitchLfo>) -> Sample {
+        if self.mute {
+            return Sample::ZERO;
+        }
+
+        self.play(self.region.cpu_clock_hz() / f32::from(sample_rate));
+        Sample::new(f32::from(self.level) / 63.5 - 1.0)
+    }
+
+    /// Plays the delta bits for the CPU cycles.
+    fn play(&mut self, cycles: f32) {
+        let mut n = self.residual + cycles;
+        while n >= self.frequency && self.bit_index < self.data.len() * 8 {
+            let bit = (self.data[self.bit_index / 8] >> (self.bit_index % 8)) & 1;
+            if bit == 1 && self.level <= 125 {
+                self.level += 2;
+            } else if bit == 0 && self.level >= 2 {
+                self.level -= 2;
+            }
+            self.bit_index += 1;
+            n -= self.frequency;
+        }
+        self.residual = if self.bit_index < self.data.len() * 8 {
+            n
+        } else {
+            0.0
+        };
+    }
+
+    fn mute(&mut self, mute: bool) {
+        self.mute = mute;
+    }
+
+    fn sweep_frequency(&mut self, _depth: i8) {}
+}
+
+/// Raw 8-bit PCM channel of MMC5 (in the write mode).
+///
+/// The bytes of a sample are written one by one at the rate of the DMC channel, and the output holds the last byte.
+#[derive(Debug, Clone)]
+pub struct Mmc5Pcm {
+    data: Arc<Vec<u8>>,
+    index: usize,
+    level: u8,
+    frequency: f32,
+    residual: f32,
+    mute: bool,
+    region: Region,
+}
+
+impl Mmc5Pcm {
+    const INITIAL_LEVEL: u8 = 128;
+
+    fn new() -> Self {
+        Self {
+            data: Arc::default(),
+            index: 0,
+            level: Self::INITIAL_LEVEL,
+            frequency: 0.0, // dummy initial value
+            residual: 0.0,
+            mute: false,
+            region: Region::default(),
+        }
+    }
+
+    pub fn start(&mut self, data: Arc<Vec<u8>>, pitch: u8) {
+        self.data = data;
+        self.index = 0;
+        self.frequency = f32::from(dpcm::rate_table(self.region)[usize::from(pitch)]);
+        self.residual = 0.0;
+    }
+
+    fn sample(&mut self, sample_rate: u16, _lfo: Option<&mut PitchLfo>) -> Sample {
+        if self.mute {
+            return Sample::ZERO;
+        }
+
+        self.play(self.region.cpu_clock_hz() / f32::from(sample_rate));
+        Sample::new(f32::from(self.level) / 127.5 - 1.0)
+    }
+
+    /// Writes the bytes for the CPU cycles.
+    fn play(&mut self, cycles: f32) {
+        let mut n = self.residual + cycles;
+        while n >= self.frequency && self.index < self.data.len() {
+            // Writing `0` has no effect in the write mode.
+            let value = self.data[self.index];
+            if value != 0 {
+                self.level = value;
+            }
+            self.index += 1;
+            n -= self.frequency;
+        }
+        self.residual = if self.index < self.data.len() { n } else { 0.0 };
+    }
+}
+
+pub(crate) const LENGTH_TABLE: [u8; 32] = [
+    10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14, 12, 16, 24, 18, 48, 20, 96, 22,
+    192, 24, 72, 26, 16, 28, 32, 30,
+];
+
+/// Emulation of the hardware volume envelope and length counter of the pulse wave and noise channels.
+///
+/// The units are clocked by the frame counter (4-step mode): the envelope at every quarter frame
+/// and the length counter at every half frame.
+/// As the hardware does, the envelope loops while the length counter is halted.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct HardwareVolume {
+    pub(crate) envelope_period: Option<u8>,
+    pub(crate) length_index: Option<u8>,
+    decay: u8,
+    divider: u8,
+    length: u8,
+    quarter_frames: usize,
+    phase: f32,
+    pub(crate) triggers: usize,
+}
+
+impl HardwareVolume {
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.envelope_period.is_some() || self.length_index.is_some()
+    }
+
+    fn start(&mut self) {
+        self.decay = 15;
+        self.divider = self.envelope_period.unwrap_or(0);
+        self.length = self
+            .length_index
+            .map_or(0, |i| LENGTH_TABLE[usize::from(i)]);
+        self.triggers += 1;
+    }
+
+    fn tick(&mut self, sample_rate: u16, region: Region, samples: u64) {
+        if !self.is_enabled() {
+            return;
+        }
+        self.phase +=
+            f32::from(region.frame_rate()) * 4.0 * samples as f32 / f32::from(sample_rate);
+        while self.phase >= 1.0 {
+            self.phase -= 1.0;
+            self.quarter_frames += 1;
+            self.clock_envelope();
+            if self.quarter_frames.is_multiple_of(2) && self.length_index.is_some() {
+                self.length = self.length.saturating_sub(1);
+            }
+        }
+    }
+
+    fn clock_envelope(&mut self) {
+        let Some(period) = self.envelope_period else {
+            return;
+        };
+        if self.divider > 0 {
+            self.divider -= 1;
+            return;
+        }
+        self.divider = period;
+        if self.decay > 0 {
+            self.decay -= 1;
+        } else if self.length_index.is_none() {
+            self.decay = 15;
+        }
+    }
+
+    fn apply(&self, volume: Volume) -> Volume {
+        if self.length_index.is_some() && self.length == 0 {
+            Volume::new(0)
+        } else if self.envelope_period.is_some() {
+            Volume::new(self.decay)
+        } else {
+            volume
+        }
+    }
+}
+
+/// Emulation of the triangle linear counter.
+///
+/// The counter is clocked at every quarter frame, and the channel is silenced when it reaches zero
+/// unless the control flag is set (`reload` is `None`).
+#[derive(Debug, Default, Clone)]
+pub(crate) struct HardwareLinearCounter {
+    pub(crate) reload: Option<u8>,
+    counter: u8,
+    reload_flag: bool,
+    phase: f32,
+    pub(crate) triggers: usize,
+}
+
+impl HardwareLinearCounter {
+    fn start(&mut self) {
+        self.reload_flag = true;
+        self.triggers += 1;
+    }
+
+    fn tick(&mut self, sample_rate: u16, region: Region, samples: u64) {
+        let Some(reload) = self.reload else {
+            return;
+        };
+        self.phase +=
+            f32::from(region.frame_rate()) * 4.0 * samples as f32 / f32::from(sample_rate);
+        while self.phase >= 1.0 {
+            self.phase -= 1.0;
+            if self.reload_flag {
+                self.counter = reload;
+                self.reload_flag = false;
+            } else {
+                self.counter = self.counter.saturating_sub(1);
+            }
+        }
+    }
+
+    fn is_expired(&self) -> bool {
+        self.reload.is_some() && !self.reload_flag && self.counter == 0
+    }
+}
+
 #[derive(Debug)]
 pub struct PitchLfo {
     now: Clock,
@@ -368,10 +1722,10 @@ pub struct PitchLfo {
 }
 
 impl PitchLfo {
-    pub fn new(delay: u8, speed: u8, depth: u8) -> Self {
-        let frequency = 20.0 / f32::from(speed);
+    pub fn new(delay: u8, speed: u8, depth: u8, frame_rate: u8) -> Self {
+        let frequency = f32::from(frame_rate) / 3.0 / f32::from(speed);
         let mut start = Clock::default();
-        start.tick(u64::from(delay), 60);
+        start.tick(u64::from(delay), u64::from(frame_rate));
         Self {
             now: Clock::default(),
             start,
@@ -381,17 +1735,30 @@ impl PitchLfo {
     }
 
     pub fn sample(&mut self, sample_rate: u16) -> f32 {
-        self.now.tick(1, u64::from(sample_rate));
-        if self.now < self.start {
-            0.0
-        } else {
-            f32::from(self.depth) * self.sine_wave.sample(sample_rate).get()
+        self.skip(sample_rate, 1);
+        self.current()
+    }
+
+    /// Advances the LFO by the samples without generating them.
+    pub fn skip(&mut self, sample_rate: u16, samples: u64) {
+        self.now.tick(samples, u64::from(sample_rate));
+        if self.now >= self.start {
+            self.sine_wave.skip(sample_rate, samples);
         }
     }
 
     pub fn reset_timer(&mut self) {
         self.now = Clock::default();
     }
+
+    /// Returns the pitch offset (in the unit of the period register) at the current position.
+    pub fn current(&self) -> f32 {
+        if self.now < self.start {
+            0.0
+        } else {
+            f32::from(self.depth) * self.sine_wave.current().get()
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -408,19 +1775,61 @@ impl SineWave {
         }
     }
 
-    pub fn sample(&mut self, sample_rate: u16) -> Sample {
-        use std::f32::consts::PI;
-
-        self.phase += self.frequency / f32::from(sample_rate);
+    fn skip(&mut self, sample_rate: u16, samples: u64) {
+        self.phase += self.frequency * samples as f32 / f32::from(sample_rate);
         self.phase -= self.phase.floor();
-        Sample::new((self.phase * 2.0 * PI).sin())
+    }
+
+    fn current(&self) -> Sample {
+        Sample::new((self.phase * 2.0 * std::f32::consts::PI).sin())
+    }
+}
+
+/// Returns the level of the stepped `waveform` at the `phase`, with each step smoothed by [`poly_blep()`].
+///
+/// `waveform` holds the levels of the equal-width steps of one period, `phase` is the current phase (`0.0..1.0`)
+/// and `dt` is the phase increment per sample.
+fn band_limited_steps(waveform: &[f32], phase: f32, dt: f32) -> f32 {
+    let n = waveform.len() as f32;
+
+    // Each step starts at the phase `k / n` and is corrected if it is within `dt` of the current phase.
+    let dt = dt.min(0.5);
+    let mut v = waveform[(phase * n).floor() as usize % waveform.len()];
+    let first = ((phase - dt) * n).floor() as isize;
+    let last = ((phase + dt) * n).ceil() as isize;
+    for k in first..=last {
+        let step = k.rem_euclid(waveform.len() as isize) as usize;
+        let prev = (step + waveform.len() - 1) % waveform.len();
+        let height = waveform[step] - waveform[prev];
+        if height == 0.0 {
+            continue;
+        }
+        let t = (phase - k as f32 / n).rem_euclid(1.0);
+        v += height / 2.0 * poly_blep(t, dt);
+    }
+    v
+}
+
+/// Returns the correction of an upward step (from `-1.0` to `1.0`) at the phase `0.0`.
+///
+/// `t` is the current phase (`0.0..1.0`) and `dt` is the phase increment per sample.
+fn poly_blep(t: f32, dt: f32) -> f32 {
+    let dt = dt.min(0.5);
+    if t < dt {
+        let x = t / dt;
+        x + x - x * x - 1.0
+    } else if t > 1.0 - dt {
+        let x = (t - 1.0) / dt;
+        x * x + x + x + 1.0
+    } else {
+        0.0
     }
 }
 
-fn frequency_to_register(frequency: f32) -> f32 {
-    SYSTEM_CLOCK_HZ / frequency / 16.0
+pub(crate) fn frequency_to_register(region: Region, frequency: f32) -> f32 {
+    region.cpu_clock_hz() / frequency / 16.0
 }
 
-fn register_to_frequency(register: f32) -> f32 {
-    SYSTEM_CLOCK_HZ / 16.0 / register
+fn register_to_frequency(region: Region, register: f32) -> f32 {
+    region.cpu_clock_hz() / 16.0 / register
 }
@@ -1,22 +1,30 @@
 use crate::{
+    analysis::MusicAnalysis,
     channel::{Channel, ChannelName},
-    clocks::Clocks,
+    clocks::{Clock, Clocks},
     commands::{
         ArpeggioCommand, Command, DataSkipCommand, DefaultNoteDurationCommand, DetuneCommand,
-        NoteCommand, OctaveCommand, OctaveDownCommand, OctaveUpCommand, PitchEnvelopeCommand,
-        PitchSweepCommand, QuantizeCommand, QuantizeFrameCommand, RepeatEndCommand,
-        RepeatStartCommand, RestSignCommand, SlurCommand, TempoCommand, TieCommand, TimbreCommand,
+        DirectFrequencyCommand, DirectNoteCommand, FdsMasterVolumeCommand, FdsModulationCommand,
+        HardwareEnvelopeCommand, LengthCounterCommand, LinearCounterCommand, N163WaveOffsetCommand,
+        NoteCommand, OctaveCommand, OctaveDownCommand, OctaveUpCommand, PanCommand,
+        PitchEnvelopeCommand, PitchSweepCommand, QuantizeCommand, QuantizeFrameCommand,
+        RepeatEndCommand, RepeatStartCommand, RestSignCommand, SlurCommand,
+        Sunsoft5bEnvelopeCommand, Sunsoft5bNoiseCommand, TempoCommand, TieCommand, TimbreCommand,
         TimbresCommand, TrackLoopCommand, TupletEndCommand, TupletStartCommand, VibratoCommand,
-        VolumeCommand, VolumeDownCommand, VolumeEnvelopeCommand, VolumeUpCommand, WaitCommand,
+        VolumeCommand, VolumeDownCommand, VolumeEnvelopeCommand, VolumeUpCommand,
+        Vrc7SustainCommand, Vrc7UserPatchCommand, WaitCommand,
     },
-    macros::Macros,
-    oscillators::{Oscillator, PitchLfo},
+    cursor::CommandCursor,
+    macros::{FdsModulationMacro, FdsWaveformMacro, MacroNumber, Macros, Vrc7PatchMacro},
+    n163,
+    oscillators::{Oscillator, PitchLfo, Synthesis},
+    sunsoft5b,
     traits::NthFrameItem,
     types::{
-        Detune, Note, NoteEnvelope, Octave, PitchEnvelope, PitchSweep, Sample, Timbre, Timbres,
-        Volume, VolumeEnvelope,
+        Detune, Note, NoteDuration, NoteEnvelope, Octave, Pan, Period, PitchEnvelope, PitchSweep,
+        Region, Sample, Timbre, Timbres, Volume, VolumeEnvelope,
     },
-    Music,
+    vrc7, Music,
 };
 use std::{
     borrow::Cow,
@@ -28,24 +36,135 @@ use std::{
 };
 use textparse::{Position, Span};
 
+/// Channel mixer of [`MusicPlayer`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MixerMode {
+    /// Averages the samples of all channels.
+    #[default]
+    Linear,
+
+    /// Mixes the channels by the non-linear mixer of the NES APU (2A03),
+    /// and then applies the output filters of the console (90 Hz and 440 Hz high-pass, and 14 kHz low-pass).
+    ///
+    /// As the hardware does, the volume of triangle wave and DPCM channels is ignored in this mode.
+    Nes,
+}
+
+/// Default panning of the channels in stereo output (see [`MusicPlayer::next_stereo()`]).
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum StereoLayout {
+    /// Centers all channels.
+    #[default]
+    Center,
+
+    /// Pans the channels as the common "NES stereo" mod does by splitting the two audio outputs of the 2A03:
+    /// the pulse wave channels to the left, and the triangle wave, noise and DPCM channels to the right.
+    ///
+    /// The channels of expansion sound chips are centered.
+    Nes,
+}
+
+impl StereoLayout {
+    fn default_pan(self, oscillator: &Oscillator) -> Pan {
+        match (self, oscillator) {
+            (Self::Center, _) => Pan::default(),
+            (Self::Nes, Oscillator::PulseWave(_)) => Pan::LEFT,
+            (
+                Self::Nes,
+                Oscillator::TriangleWave(_) | Oscillator::Noise(_) | Oscillator::Dpcm(_),
+            ) => Pan::RIGHT,
+            (Self::Nes, _) => Pan::default(),
+        }
+    }
+}
+
 /// [`MusicPlayer`] is an iterator that generates audio samples.
 #[derive(Debug)]
 pub struct MusicPlayer {
     channels: BTreeMap<ChannelName, ChannelPlayer>,
+    mixer_mode: MixerMode,
+    filters: OutputFilters,
+    right_filters: OutputFilters,
+    analysis: Option<MusicAnalysis>,
+    loop_count: Option<usize>,
+    fade_out: FadeOut,
 }
 
 impl MusicPlayer {
     pub(crate) fn new(music: &Music, sample_rate: u16) -> Self {
         let macros = music.macros();
-        let channels = music
+        let mut channels: BTreeMap<_, _> = music
             .channels()
             .iter()
             .map(|(name, channel)| {
-                let player = ChannelPlayer::new(channel, macros.clone(), sample_rate);
+                let mut player = ChannelPlayer::new(
+                    channel,
+                    macros.clone(),
+                    sample_rate,
+                    music.is_octave_reversed(),
+                );
+                player.set_region(music.region());
                 (name, player)
             })
             .collect();
-        Self { channels }
+
+        // The N163 channels share the sound RAM and the multiplexer of a chip.
+        let n163_channels = channels
+            .values()
+            .filter(|c| matches!(c.oscillator, Oscillator::N163(_)))
+            .count();
+        let chip = n163::Chip::new(n163_channels);
+        let n163_oscillators = channels
+            .values_mut()
+            .filter_map(|c| match &mut c.oscillator {
+                Oscillator::N163(o) => Some(o),
+                _ => None,
+            });
+        for (i, o) in n163_oscillators.enumerate() {
+            o.connect(chip.clone(), i);
+        }
+
+        // So do the Sunsoft 5B channels with the noise and envelope generators.
+        let chip = sunsoft5b::Chip::new();
+        for c in channels.values_mut() {
+            if let Oscillator::Sunsoft5b(o) = &mut c.oscillator {
+                o.connect(chip.clone());
+            }
+        }
+
+        // And the VRC7 channels with the user patch.
+        let chip = vrc7::Chip::new();
+        for c in channels.values_mut() {
+            if let Oscillator::Vrc7(o) = &mut c.oscillator {
+                o.connect(chip.clone());
+            }
+        }
+        Self {
+            channels,
+            mixer_mode: MixerMode::default(),
+            filters: OutputFilters::new(sample_rate),
+            right_filters: OutputFilters::new(sample_rate),
+            analysis: None,
+            loop_count: None,
+            fade_out: FadeOut::new(sample_rate),
+        }
+    }
+
+    /// Sets the channel mixer.
+    ///
+    /// The default value is [`MixerMode::Linear`].
+    pub fn set_mixer_mode(&mut self, mode: MixerMode) {
+        self.mixer_mode = mode;
+    }
+
+    /// Sets the default panning of the channels in stereo output.
+    ///
+    /// The default value is [`StereoLayout::Center`].
+    /// This should be called before generating samples because it resets the panning set by the `p` commands.
+    pub fn set_stereo_layout(&mut self, layout: StereoLayout) {
+        for channel in self.channels.values_mut() {
+            channel.pan = layout.default_pan(&channel.oscillator);
+        }
     }
 
     /// Returns an iterator that iterates over all of the playing channels.
@@ -55,9 +174,77 @@ impl MusicPlayer {
             .map(|(name, player)| ChannelState::new(*name, player))
     }
 
+    /// Sets the TV system that determines the CPU clock, the frame rate and the period tables.
+    ///
+    /// The default value is the one defined by `#REGION` in the script (see [`Music::region()`]).
+    /// This should be called before generating samples.
+    /// Note that the DPCM samples converted from WAV files keep the DMC rate of the region defined in the script.
+    pub fn set_region(&mut self, region: Region) {
+        for channel in self.channels.values_mut() {
+            channel.set_region(region);
+        }
+    }
+
+    /// Sets the waveform synthesis mode of the pulse wave and triangle wave channels.
+    ///
+    /// The default value is [`Synthesis::Naive`].
+    pub fn set_synthesis(&mut self, synthesis: Synthesis) {
+        for channel in self.channels.values_mut() {
+            channel.oscillator.set_synthesis(synthesis);
+        }
+    }
+
+    /// Sets how many times each channel jumps back to its loop point (`L`) before it ends.
+    ///
+    /// Each channel ends after the jumps, so the music ends when the channel with the longest
+    /// [`ChannelAnalysis::duration_with_loops()`] completes them.
+    /// If a fade-out is set by [`MusicPlayer::set_fade_out()`], the channels keep looping while it fades out from that point.
+    ///
+    /// The timing of the music is analyzed (see [`Music::analyze()`]) when this is first called with `Some`,
+    /// and the error of the analysis is returned.
+    ///
+    /// The default value is `None`, which loops forever.
+    ///
+    /// [`ChannelAnalysis::duration_with_loops()`]: crate::ChannelAnalysis::duration_with_loops
+    pub fn set_loop_count(&mut self, loop_count: Option<usize>) -> Result<(), PlayMusicError> {
+        let mut start = None;
+        if let Some(n) = loop_count {
+            if self.analysis.is_none() {
+                let cursors = self
+                    .channels
+                    .iter()
+                    .map(|(name, c)| (*name, c.cursor.rewound()));
+                self.analysis = Some(MusicAnalysis::from_cursors(cursors)?);
+            }
+            start = self.analysis.as_ref().map(|a| a.duration_with_loops(n));
+        }
+        self.loop_count = loop_count;
+        self.fade_out.set_start(start);
+        self.update_loop_limits();
+        Ok(())
+    }
+
+    /// Sets the length of the fade-out applied after the final loop (see [`MusicPlayer::set_loop_count()`]).
+    ///
+    /// The music keeps playing while the volume linearly decreases to zero, and then ends.
+    /// This has no effect if the loop count is not set.
+    ///
+    /// The default value is [`Duration::ZERO`].
+    pub fn set_fade_out(&mut self, length: Duration) {
+        self.fade_out.set_length(length);
+        self.update_loop_limits();
+    }
+
+    fn update_loop_limits(&mut self) {
+        let limit = self.loop_count.filter(|_| self.fade_out.length == 0);
+        for channel in self.channels.values_mut() {
+            channel.loop_limit = limit;
+        }
+    }
+
     /// Returns `true` if the music completed, or aborted by an error, otherwise `false`.
     pub fn is_eos(&self) -> bool {
-        self.channels.values().all(|c| c.eos)
+        self.fade_out.is_end() || self.channels.values().all(|c| c.eos)
     }
 
     /// Returns the elapsed time since the beginning of this music.
@@ -68,6 +255,97 @@ impl MusicPlayer {
             .unwrap_or_default()
     }
 
+    /// Fast-forwards the player to the position (from the beginning of the music) without synthesizing samples.
+    ///
+    /// The commands before the position are interpreted as they are in playing, so the envelopes, repeats, tuplets
+    /// and loops (see [`ChannelState::loop_count()`]) are in the same state as if the samples had been generated.
+    /// The phases of the waveforms are not kept.
+    ///
+    /// The position is rounded up to a sample boundary.
+    /// This cannot go backward, so the positions before [`MusicPlayer::elapsed()`] are ignored
+    /// (make a new player by [`Music::play()`] to play from an earlier position).
+    pub fn seek(&mut self, position: Duration) {
+        // The channels are advanced together frame by frame because the channels of a sound chip share its state
+        // (e.g., `SN` of a Sunsoft 5B channel changes the noise of the others from that frame).
+        let frame_rate = self
+            .channels
+            .values()
+            .next()
+            .map_or(60, |c| c.region.frame_rate());
+        let frame = Duration::from_secs(1) / u32::from(frame_rate);
+        let mut now = self.elapsed();
+        while now < position {
+            now = (now + frame).min(position);
+            for channel in self.channels.values_mut() {
+                let clock = channel.clocks.sample_clock_at(now);
+                channel.seek(clock);
+            }
+        }
+        self.fade_out.seek(position);
+    }
+
+    /// Generates the next stereo sample (the left and the right) by mixing the panned channels.
+    ///
+    /// This advances the player by a sample as [`Iterator::next()`] does, so the two should not be mixed up.
+    /// If all channels are centered, both sides are the same as the mono sample.
+    ///
+    /// Returns `None` if all channels have ended.
+    pub fn next_stereo(&mut self) -> Option<[Sample; 2]> {
+        let fade = self.fade_out.next_gain()?;
+        let n = self.channels.len() as f32;
+        let mut is_eos = true;
+        let mut linear = [Sample::ZERO; 2];
+        let mut levels = [NesLevels::default(); 2];
+        for c in self.channels.values_mut() {
+            let Some(x) = c.next() else {
+                continue;
+            };
+            is_eos = false;
+            for (side, gain) in c.pan.gains().into_iter().enumerate() {
+                linear[side] = linear[side] + x * gain / n;
+                levels[side].add(&c.oscillator, c.dac_level * gain);
+            }
+        }
+        if is_eos {
+            return None;
+        }
+
+        if self.mixer_mode == MixerMode::Nes {
+            let left = self.filters.apply(levels[0].output()) * 2.0;
+            let right = self.right_filters.apply(levels[1].output()) * 2.0;
+            Some([Sample::new(left * fade), Sample::new(right * fade)])
+        } else {
+            Some(linear.map(|x| x * fade))
+        }
+    }
+
+    /// Generates the next sample of each channel separately, without mixing them.
+    ///
+    /// This advances the player by a sample as [`Iterator::next()`] does, so the two should not be mixed up.
+    /// The samples are not affected by [`MixerMode`] (and the panning), and the channels that have ended yield [`Sample::ZERO`]
+    /// until all channels end (so every channel has the same number of samples).
+    /// Dividing the sum of the samples by the number of channels gives the sample of [`MixerMode::Linear`].
+    ///
+    /// The channels are still played together, so the state shared by the channels of a sound chip is reflected in each stem:
+    /// an N163 channel is as quiet as it is time-multiplexed with the other N163 channels,
+    /// and a Sunsoft 5B channel uses the noise and envelope generators set by the other Sunsoft 5B channels.
+    ///
+    /// Returns `None` if all channels have ended.
+    pub fn next_stems(&mut self) -> Option<BTreeMap<ChannelName, Sample>> {
+        let fade = self.fade_out.next_gain()?;
+        let mut is_eos = true;
+        let stems = self
+            .channels
+            .iter_mut()
+            .map(|(name, c)| {
+                let sample = c.next();
+                is_eos &= sample.is_none();
+                (*name, sample.map_or(Sample::ZERO, |x| x * fade))
+            })
+            .collect();
+        (!is_eos).then_some(stems)
+    }
+
     /// Takes the last error if it exists.
     pub fn take_last_error(&mut self) -> Option<PlayMusicError> {
         for (name, channel) in &mut self.channels {
@@ -84,18 +362,227 @@ impl Iterator for MusicPlayer {
     type Item = Sample;
 
     fn next(&mut self) -> Option<Self::Item> {
+        let fade = self.fade_out.next_gain()?;
+        if self.mixer_mode == MixerMode::Nes {
+            return self.next_nes_sample().map(|x| x * fade);
+        }
+
         let n = self.channels.len() as f32;
         let mut sample = None;
         for x in self.channels.values_mut().flat_map(|c| c.next()) {
             sample = Some(sample.unwrap_or(Sample::ZERO) + x / n);
         }
-        sample
+        sample.map(|x| x * fade)
+    }
+}
+
+impl MusicPlayer {
+    fn next_nes_sample(&mut self) -> Option<Sample> {
+        let mut is_eos = true;
+        let mut levels = NesLevels::default();
+        for c in self.channels.values_mut() {
+            if c.next().is_none() {
+                continue;
+            }
+            is_eos = false;
+            levels.add(&c.oscillator, c.dac_level);
+        }
+        if is_eos {
+            return None;
+        }
+
+        // The filtered output is roughly within `-0.5..=0.5`.
+        Some(Sample::new(self.filters.apply(levels.output()) * 2.0))
+    }
+}
+
+/// Fade-out after the final loop (see [`MusicPlayer::set_loop_count()`]).
+#[derive(Debug, Clone)]
+pub(crate) struct FadeOut {
+    sample_rate: u16,
+    position: u64,
+    start: Option<u64>,
+    length: u64,
+}
+
+impl FadeOut {
+    pub(crate) fn new(sample_rate: u16) -> Self {
+        Self {
+            sample_rate,
+            position: 0,
+            start: None,
+            length: 0,
+        }
+    }
+
+    /// Sets the position where the fade-out starts (`None` means that the music never ends by the fade-out).
+    pub(crate) fn set_start(&mut self, start: Option<Duration>) {
+        self.start = start.map(|d| self.samples(d));
+    }
+
+    pub(crate) fn set_length(&mut self, length: Duration) {
+        self.length = self.samples(length);
+    }
+
+    pub(crate) fn seek(&mut self, position: Duration) {
+        self.position = self.position.max(self.samples(position));
+    }
+
+    pub(crate) fn is_end(&self) -> bool {
+        self.start
+            .is_some_and(|start| self.position >= start.saturating_add(self.length))
+    }
+
+    /// Returns the gain of the next sample, or `None` if the music has ended.
+    pub(crate) fn next_gain(&mut self) -> Option<f32> {
+        if self.is_end() {
+            return None;
+        }
+        let position = self.position;
+        self.position += 1;
+        match self.start {
+            Some(start) if position >= start => {
+                Some(1.0 - (position - start) as f32 / self.length as f32)
+            }
+            _ => Some(1.0),
+        }
+    }
+
+    /// Converts the duration into the number of samples (rounded up).
+    fn samples(&self, duration: Duration) -> u64 {
+        let samples = (duration.as_nanos() * u128::from(self.sample_rate)).div_ceil(1_000_000_000);
+        u64::try_from(samples).unwrap_or(u64::MAX)
+    }
+}
+
+/// Sums of the DAC levels of the channels, which are mixed by the NES APU mixer.
+#[derive(Debug, Default, Clone, Copy)]
+struct NesLevels {
+    pulse: f32,
+    triangle: f32,
+    noise: f32,
+    dpcm: f32,
+    expansion: f32,
+}
+
+impl NesLevels {
+    fn add(&mut self, oscillator: &Oscillator, dac_level: f32) {
+        match oscillator {
+            Oscillator::PulseWave(_) => self.pulse += dac_level,
+            Oscillator::TriangleWave(_) => self.triangle += dac_level,
+            Oscillator::Noise(_) => self.noise += dac_level,
+            Oscillator::Dpcm(_) => self.dpcm += dac_level,
+            Oscillator::Vrc6Pulse(_)
+            | Oscillator::Sawtooth(_)
+            | Oscillator::Fds(_)
+            | Oscillator::N163(_)
+            | Oscillator::Sunsoft5b(_)
+            | Oscillator::Vrc7(_)
+            | Oscillator::Mmc5Pulse(_) => self.expansion += dac_level,
+            // The full scale of the 8-bit PCM is as loud as a pulse wave channel at the full volume.
+            Oscillator::Mmc5Pcm(_) => self.expansion += dac_level * 15.0 / 255.0,
+        }
+    }
+
+    /// Returns the output of the mixer (before the output filters).
+    fn output(&self) -> f32 {
+        // See: https://www.nesdev.org/wiki/APU_Mixer
+        let pulse_out = if self.pulse > 0.0 {
+            95.88 / (8128.0 / self.pulse + 100.0)
+        } else {
+            0.0
+        };
+        let tnd = self.triangle / 8227.0 + self.noise / 12241.0 + self.dpcm / 22638.0;
+        let tnd_out = if tnd > 0.0 {
+            159.79 / (1.0 / tnd + 100.0)
+        } else {
+            0.0
+        };
+
+        // Expansion sound chips are mixed linearly, and a level step is as loud as
+        // a volume step of a 2A03 pulse wave channel at the full volume.
+        let expansion_out = self.expansion * 95.88 / (8128.0 + 1500.0);
+
+        pulse_out + tnd_out + expansion_out
+    }
+}
+
+/// Output filters of the NES (two first-order high-pass filters and a first-order low-pass filter).
+#[derive(Debug)]
+pub(crate) struct OutputFilters {
+    high_pass_90hz: HighPassFilter,
+    high_pass_440hz: HighPassFilter,
+    low_pass_14khz: LowPassFilter,
+}
+
+impl OutputFilters {
+    pub(crate) fn new(sample_rate: u16) -> Self {
+        Self {
+            high_pass_90hz: HighPassFilter::new(sample_rate, 90.0),
+            high_pass_440hz: HighPassFilter::new(sample_rate, 440.0),
+            low_pass_14khz: LowPassFilter::new(sample_rate, 14000.0),
+        }
+    }
+
+    pub(crate) fn apply(&mut self, x: f32) -> f32 {
+        let x = self.high_pass_90hz.apply(x);
+        let x = self.high_pass_440hz.apply(x);
+        self.low_pass_14khz.apply(x)
+    }
+}
+
+#[derive(Debug)]
+struct HighPassFilter {
+    alpha: f32,
+    prev_input: Option<f32>,
+    prev_output: f32,
+}
+
+impl HighPassFilter {
+    fn new(sample_rate: u16, cutoff: f32) -> Self {
+        let rc = 1.0 / (2.0 * std::f32::consts::PI * cutoff);
+        let dt = 1.0 / f32::from(sample_rate);
+        Self {
+            alpha: rc / (rc + dt),
+            prev_input: None,
+            prev_output: 0.0,
+        }
+    }
+
+    fn apply(&mut self, x: f32) -> f32 {
+        // The first input is regarded as the steady state to avoid a click at the beginning.
+        let prev_input = self.prev_input.unwrap_or(x);
+        self.prev_output = self.alpha * (self.prev_output + x - prev_input);
+        self.prev_input = Some(x);
+        self.prev_output
+    }
+}
+
+#[derive(Debug)]
+struct LowPassFilter {
+    alpha: f32,
+    prev_output: f32,
+}
+
+impl LowPassFilter {
+    fn new(sample_rate: u16, cutoff: f32) -> Self {
+        let rc = 1.0 / (2.0 * std::f32::consts::PI * cutoff);
+        let dt = 1.0 / f32::from(sample_rate);
+        Self {
+            alpha: dt / (rc + dt),
+            prev_output: 0.0,
+        }
+    }
+
+    fn apply(&mut self, x: f32) -> f32 {
+        self.prev_output += self.alpha * (x - self.prev_output);
+        self.prev_output
     }
 }
 
 /// An error returned from [`MusicPlayer::take_last_error()`].
 pub struct PlayMusicError {
-    channel: ChannelName,
+    pub(crate) channel: ChannelName,
     position: Position,
     reason: String,
     text: Option<String>,
@@ -103,7 +590,7 @@ pub struct PlayMusicError {
 }
 
 impl PlayMusicError {
-    fn new(span: impl Span, reason: &str) -> Self {
+    pub(crate) fn new(span: impl Span, reason: &str) -> Self {
         Self {
             channel: ChannelName::A, // dummy initial value.
             position: span.start_position(),
@@ -169,51 +656,69 @@ impl std::fmt::Display for PlayMusicError {
 impl Error for PlayMusicError {}
 
 #[derive(Debug)]
-struct ChannelPlayer {
-    oscillator: Oscillator,
-    commands: Arc<Vec<Command>>,
-    command_index: usize,
+pub(crate) struct ChannelPlayer {
+    pub(crate) oscillator: Oscillator,
+    pub(crate) cursor: CommandCursor,
     macros: Arc<Macros>,
     octave: Octave,
+    octave_reversed: bool,
     detune: PitchEnvelope,
     volume: VolumeEnvelope,
     timbre: Timbres,
     clocks: Clocks,
     arpeggio: Option<NoteEnvelope>,
-    loop_point: Option<usize>,
-    repeat_stack: Vec<Repeat>,
+    pub(crate) loop_point: Option<usize>,
+    loop_count: usize,
+    loop_limit: Option<usize>,
     note: Option<Note>,
+    note_octave: Option<Octave>,
+    period: Option<Period>,
     command_span: std::ops::Range<usize>,
-    pitch_lfo: Option<PitchLfo>,
+    pub(crate) pitch_lfo: Option<PitchLfo>,
     pitch_sweep: Option<PitchSweep>,
     last_error: Option<PlayMusicError>,
     eos: bool,
+    dac_level: f32,
+    pan: Pan,
+    region: Region,
 }
 
 impl ChannelPlayer {
-    fn new(channel: Channel, macros: Arc<Macros>, sample_rate: u16) -> Self {
+    fn new(channel: Channel, macros: Arc<Macros>, sample_rate: u16, octave_reversed: bool) -> Self {
         Self {
             oscillator: channel.oscillator,
-            commands: channel.commands,
-            command_index: 0,
+            cursor: CommandCursor::new(channel.commands),
             macros,
             octave: Octave::default(),
+            octave_reversed,
             detune: PitchEnvelope::constant(Detune::default()),
             volume: VolumeEnvelope::constant(Volume::default()),
             timbre: Timbres::constant(Timbre::default()),
             clocks: Clocks::new(sample_rate),
             loop_point: None,
+            loop_count: 0,
+            loop_limit: None,
             arpeggio: None,
-            repeat_stack: Vec::new(),
             note: None,
+            note_octave: None,
+            period: None,
             command_span: std::ops::Range { start: 0, end: 0 },
             pitch_lfo: None,
             pitch_sweep: None,
             last_error: None,
             eos: false,
+            dac_level: 0.0,
+            pan: Pan::default(),
+            region: Region::default(),
         }
     }
 
+    fn set_region(&mut self, region: Region) {
+        self.region = region;
+        self.oscillator.set_region(region);
+        self.clocks.set_frame_rate(region.frame_rate());
+    }
+
     fn sample(&mut self) -> Sample {
         self.clocks.tick_sample_clock();
         let sample = self
@@ -222,15 +727,16 @@ impl ChannelPlayer {
         if self.clocks.sample_clock() >= self.clocks.quantize_clock() {
             self.oscillator.mute(true);
         }
-        let volume = self.current_volume();
-        sample * volume.as_ratio()
+        let volume = self.oscillator.output_volume(self.current_volume());
+        self.dac_level = self.oscillator.dac_level(sample, volume);
+        sample * self.oscillator.volume_ratio(volume)
     }
 
     fn current_timbre(&self) -> Timbre {
         self.timbre.nth_frame_item(self.clocks.frame_index())
     }
 
-    fn current_volume(&self) -> Volume {
+    pub(crate) fn current_volume(&self) -> Volume {
         self.volume.nth_frame_item(self.clocks.frame_index())
     }
 
@@ -239,18 +745,76 @@ impl ChannelPlayer {
         if !self.oscillator.set_timbre(timbre) {
             return Err(PlayMusicError::new(timbre, "unsupported timbre value"));
         }
+        if let Oscillator::Fds(o) = &mut self.oscillator {
+            let waveform = MacroNumber::from_timbre(timbre)
+                .and_then(|n| self.macros.fds_waveforms.get(&n))
+                .ok_or_else(|| PlayMusicError::new(timbre, "undefined FDS waveform macro"))?;
+            if waveform.samples().len() != FdsWaveformMacro::LEN {
+                return Err(PlayMusicError::new(
+                    waveform,
+                    "FDS waveform must have 64 samples",
+                ));
+            }
+            o.set_waveform(waveform.samples());
+        }
+        if let Oscillator::N163(o) = &mut self.oscillator {
+            let wave = MacroNumber::from_timbre(timbre)
+                .and_then(|n| self.macros.n163_waves.get(&n))
+                .ok_or_else(|| PlayMusicError::new(timbre, "undefined N163 wave macro"))?;
+            let len = wave.samples().len();
+            if !len.is_multiple_of(4) {
+                return Err(PlayMusicError::new(
+                    wave,
+                    "N163 wave length must be a multiple of 4",
+                ));
+            }
+            if usize::from(o.wave_offset()) + len > o.chip().wave_area() {
+                return Err(PlayMusicError::new(
+                    wave,
+                    "N163 wave overlaps the channel registers in the sound RAM",
+                ));
+            }
+            o.load_wave(timbre.get(), wave.samples());
+        }
         self.update_frequency()?;
         Ok(())
     }
 
+    fn is_resting(&self) -> bool {
+        self.note.is_none() && self.period.is_none()
+    }
+
     fn update_frequency(&mut self) -> Result<(), PlayMusicError> {
+        if self.is_resting() {
+            return Ok(());
+        }
+
+        let frame_index = self.clocks.frame_index();
+        if let Some((Some(speed), Some(depth))) = self.pitch_sweep.map(|s| (s.speed(), s.depth())) {
+            if frame_index == 0 {
+                self.set_frequency()?;
+            }
+            if frame_index.is_multiple_of(usize::from(speed)) {
+                self.oscillator.sweep_frequency(depth);
+            }
+        } else {
+            self.set_frequency()?;
+        }
+        Ok(())
+    }
+
+    fn set_frequency(&mut self) -> Result<(), PlayMusicError> {
+        if let Some(period) = self.period {
+            self.oscillator.set_period(period);
+            return Ok(());
+        }
         let Some(mut note) = self.note else {
             return Ok(());
         };
 
         let frame_index = self.clocks.frame_index();
         let detune = self.detune.nth_frame_item(frame_index);
-        let mut octave = self.octave;
+        let mut octave = self.note_octave.unwrap_or(self.octave);
 
         if let Some(arpeggio) = &self.arpeggio {
             let result = note.apply_note_number_delta(arpeggio.nth_frame_item(frame_index));
@@ -266,28 +830,74 @@ impl ChannelPlayer {
             };
         };
 
-        if let Some((Some(speed), Some(depth))) = self.pitch_sweep.map(|s| (s.speed(), s.depth())) {
-            if frame_index == 0 {
-                self.oscillator.set_frequency(note, octave, detune);
-            }
-            if frame_index.is_multiple_of(usize::from(speed)) {
-                self.oscillator.sweep_frequency(depth);
-            }
-        } else {
-            self.oscillator.set_frequency(note, octave, detune);
-        }
+        self.oscillator.set_frequency(note, octave, detune);
         Ok(())
     }
 
     fn handle_note_command(&mut self, command: NoteCommand) -> Result<(), PlayMusicError> {
-        self.note = Some(command.note());
+        self.play_note(command.note(), None, command.note_duration())
+    }
+
+    fn handle_direct_note_command(
+        &mut self,
+        command: DirectNoteCommand,
+    ) -> Result<(), PlayMusicError> {
+        let octave = command
+            .octave()
+            .ok_or_else(|| PlayMusicError::new(&command, "note number out of range"))?;
+        self.play_note(command.note(), Some(octave), command.note_duration())
+    }
+
+    fn handle_direct_frequency_command(
+        &mut self,
+        command: DirectFrequencyCommand,
+    ) -> Result<(), PlayMusicError> {
+        if !self.oscillator.set_period(command.period()) {
+            return Err(PlayMusicError::new(
+                command.period(),
+                "unsupported period value",
+            ));
+        }
+        self.note = None;
+        self.note_octave = None;
+        self.period = Some(command.period());
+        self.start_note(command.note_duration())
+    }
+
+    fn play_note(
+        &mut self,
+        note: Note,
+        octave: Option<Octave>,
+        duration: NoteDuration,
+    ) -> Result<(), PlayMusicError> {
+        self.note = Some(note);
+        self.note_octave = octave;
+        self.period = None;
+        if let Oscillator::Dpcm(_) | Oscillator::Mmc5Pcm(_) = &self.oscillator {
+            let number = MacroNumber::from_dpcm_note(note, octave.unwrap_or(self.octave));
+            let m = self
+                .macros
+                .dpcms
+                .get(&number)
+                .ok_or_else(|| PlayMusicError::new(note, "undefined DPCM sample"))?;
+            match &mut self.oscillator {
+                Oscillator::Dpcm(o) => o.start(number, m.data(), m.pitch()),
+                Oscillator::Mmc5Pcm(o) => o.start(m.pcm(), m.pitch()),
+                _ => unreachable!(),
+            }
+        }
         self.update_frequency()?;
-        self.clocks.tick_note_clock(command.note_duration());
+        self.start_note(duration)
+    }
+
+    fn start_note(&mut self, duration: NoteDuration) -> Result<(), PlayMusicError> {
+        self.clocks.tick_note_clock(duration);
         self.clocks.reset_frame_clock(self.clocks.sample_clock());
         self.handle_frame()?;
         if let Some(lfo) = &mut self.pitch_lfo {
             lfo.reset_timer();
         }
+        self.oscillator.start_note();
         self.oscillator.mute(false);
         Ok(())
     }
@@ -312,6 +922,8 @@ impl ChannelPlayer {
         self.clocks.tick_note_clock(command.note_duration());
         self.clocks.reset_frame_clock(self.clocks.sample_clock());
         self.note = None;
+        self.note_octave = None;
+        self.period = None;
         self.handle_frame()?;
         self.oscillator.mute(true);
         Ok(())
@@ -320,69 +932,207 @@ impl ChannelPlayer {
     fn handle_wait_command(&mut self, command: WaitCommand) -> Result<(), PlayMusicError> {
         self.update_frequency()?;
         self.clocks.tick_note_clock(command.note_duration());
-        self.oscillator.mute(self.note.is_none());
+        self.oscillator.mute(self.is_resting());
         Ok(())
     }
 
     fn handle_tie_command(&mut self, command: TieCommand) -> Result<(), PlayMusicError> {
-        if !matches!(
-            self.commands[self.command_index.saturating_sub(2)],
-            Command::Note(_)
-        ) {
+        self.cursor.tie(&command)?;
+        self.clocks.tick_note_clock(command.note_duration());
+        self.oscillator.mute(self.is_resting());
+        Ok(())
+    }
+
+    fn handle_slur_command(&mut self, command: SlurCommand) -> Result<(), PlayMusicError> {
+        let after = self.cursor.slur(command)?;
+        self.clocks.tick_note_clock(after.note_duration());
+        self.oscillator.mute(self.is_resting());
+        Ok(())
+    }
+
+    fn handle_volume_command(&mut self, command: VolumeCommand) -> Result<(), PlayMusicError> {
+        self.volume = VolumeEnvelope::constant(command.volume());
+        Ok(())
+    }
+
+    fn handle_volume_envelope_command(
+        &mut self,
+        command: VolumeEnvelopeCommand,
+    ) -> Result<(), PlayMusicError> {
+        self.volume = self
+            .macros
+            .volumes
+            .get(&command.macro_number())
+            .ok_or_else(|| PlayMusicError::new(command, "undefined macro number"))?
+            .envelope()
+            .clone();
+        Ok(())
+    }
+
+    fn handle_hardware_envelope_command(
+        &mut self,
+        command: HardwareEnvelopeCommand,
+    ) -> Result<(), PlayMusicError> {
+        if !self.oscillator.set_hardware_envelope(command.period()) {
             return Err(PlayMusicError::new(
                 command,
-                "'^' must follow a note command",
+                "hardware envelope is only supported by pulse wave and noise channels",
             ));
         }
-        self.clocks.tick_note_clock(command.note_duration());
-        self.oscillator.mute(self.note.is_none());
         Ok(())
     }
 
-    fn handle_slur_command(&mut self, command: SlurCommand) -> Result<(), PlayMusicError> {
-        let Command::Note(before) = &self.commands[self.command_index.saturating_sub(2)] else {
+    fn handle_length_counter_command(
+        &mut self,
+        command: LengthCounterCommand,
+    ) -> Result<(), PlayMusicError> {
+        if !self.oscillator.set_length_counter(command.index()) {
+            return Err(PlayMusicError::new(
+                command,
+                "length counter is only supported by pulse wave and noise channels",
+            ));
+        }
+        Ok(())
+    }
+
+    fn handle_linear_counter_command(
+        &mut self,
+        command: LinearCounterCommand,
+    ) -> Result<(), PlayMusicError> {
+        if !self
+            .oscillator
+            .set_linear_counter(command.value().map(|v| v.get()))
+        {
+            return Err(PlayMusicError::new(
+                command,
+                "linear counter is only supported by triangle wave channels",
+            ));
+        }
+        Ok(())
+    }
+
+    fn handle_fds_modulation_command(
+        &mut self,
+        command: FdsModulationCommand,
+    ) -> Result<(), PlayMusicError> {
+        let Oscillator::Fds(o) = &mut self.oscillator else {
+            return Err(PlayMusicError::new(
+                command,
+                "modulation is only supported by FDS channels",
+            ));
+        };
+        let Some((n, depth, speed)) = command.args() else {
+            o.set_modulation(None);
+            return Ok(());
+        };
+        let table = self
+            .macros
+            .fds_modulations
+            .get(&n)
+            .ok_or_else(|| PlayMusicError::new(n, "undefined macro number"))?;
+        if table.samples().len() != FdsModulationMacro::LEN {
+            return Err(PlayMusicError::new(
+                table,
+                "FDS modulation table must have 32 entries",
+            ));
+        }
+        o.set_modulation(Some((table.samples(), depth, speed)));
+        Ok(())
+    }
+
+    fn handle_fds_master_volume_command(
+        &mut self,
+        command: FdsMasterVolumeCommand,
+    ) -> Result<(), PlayMusicError> {
+        let Oscillator::Fds(o) = &mut self.oscillator else {
             return Err(PlayMusicError::new(
                 command,
-                "'&' must follow a note command",
+                "master volume is only supported by FDS channels",
             ));
         };
+        o.set_master_volume(command.level());
+        Ok(())
+    }
 
-        let Some(Command::Note(after)) = self.commands.get(self.command_index) else {
+    fn handle_n163_wave_offset_command(
+        &mut self,
+        command: N163WaveOffsetCommand,
+    ) -> Result<(), PlayMusicError> {
+        let Oscillator::N163(o) = &mut self.oscillator else {
             return Err(PlayMusicError::new(
                 command,
-                "mssing a note command after '&'",
+                "wave offset is only supported by N163 channels",
             ));
         };
-        self.command_index += 1;
+        o.set_wave_offset(command.offset());
+        Ok(())
+    }
 
-        if before.note().normalize() != after.note().normalize() {
+    fn handle_sunsoft5b_noise_command(
+        &mut self,
+        command: Sunsoft5bNoiseCommand,
+    ) -> Result<(), PlayMusicError> {
+        let Oscillator::Sunsoft5b(o) = &mut self.oscillator else {
             return Err(PlayMusicError::new(
                 command,
-                "'&' cannot combine different notes",
+                "noise period is only supported by Sunsoft 5B channels",
             ));
-        }
+        };
+        o.chip().set_noise_period(command.period());
+        Ok(())
+    }
 
-        self.clocks.tick_note_clock(after.note_duration());
-        self.oscillator.mute(self.note.is_none());
+    fn handle_sunsoft5b_envelope_command(
+        &mut self,
+        command: Sunsoft5bEnvelopeCommand,
+    ) -> Result<(), PlayMusicError> {
+        let Oscillator::Sunsoft5b(o) = &mut self.oscillator else {
+            return Err(PlayMusicError::new(
+                command,
+                "envelope generator is only supported by Sunsoft 5B channels",
+            ));
+        };
+        o.set_envelope(command.args());
         Ok(())
     }
 
-    fn handle_volume_command(&mut self, command: VolumeCommand) -> Result<(), PlayMusicError> {
-        self.volume = VolumeEnvelope::constant(command.volume());
+    fn handle_pan_command(&mut self, command: PanCommand) -> Result<(), PlayMusicError> {
+        self.pan = command.pan();
         Ok(())
     }
 
-    fn handle_volume_envelope_command(
+    fn handle_vrc7_sustain_command(
         &mut self,
-        command: VolumeEnvelopeCommand,
+        command: Vrc7SustainCommand,
     ) -> Result<(), PlayMusicError> {
-        self.volume = self
+        let Oscillator::Vrc7(o) = &mut self.oscillator else {
+            return Err(PlayMusicError::new(
+                command,
+                "sustain is only supported by VRC7 channels",
+            ));
+        };
+        o.set_sustain(command.sustain());
+        Ok(())
+    }
+
+    fn handle_vrc7_user_patch_command(
+        &mut self,
+        command: Vrc7UserPatchCommand,
+    ) -> Result<(), PlayMusicError> {
+        let Oscillator::Vrc7(o) = &mut self.oscillator else {
+            return Err(PlayMusicError::new(
+                command,
+                "user patch is only supported by VRC7 channels",
+            ));
+        };
+        let patch = self
             .macros
-            .volumes
+            .vrc7_patches
             .get(&command.macro_number())
-            .ok_or_else(|| PlayMusicError::new(command, "undefined macro number"))?
-            .envelope()
-            .clone();
+            .ok_or_else(|| PlayMusicError::new(command, "undefined macro number"))?;
+        let bytes = <[u8; Vrc7PatchMacro::LEN]>::try_from(patch.bytes())
+            .map_err(|_| PlayMusicError::new(patch, "VRC7 patch must have 8 bytes"))?;
+        o.chip().set_user_patch(bytes);
         Ok(())
     }
 
@@ -425,21 +1175,26 @@ impl ChannelPlayer {
     }
 
     fn handle_octave_up_command(&mut self, command: OctaveUpCommand) -> Result<(), PlayMusicError> {
-        self.octave = self
-            .octave
-            .checked_add(1)
-            .ok_or_else(|| PlayMusicError::new(command, "octave oveflow"))?;
-        Ok(())
+        self.shift_octave(command, !self.octave_reversed)
     }
 
     fn handle_octave_down_command(
         &mut self,
         command: OctaveDownCommand,
     ) -> Result<(), PlayMusicError> {
-        self.octave = self
-            .octave
-            .checked_sub(1)
-            .ok_or_else(|| PlayMusicError::new(command, "octave underflow"))?;
+        self.shift_octave(command, self.octave_reversed)
+    }
+
+    fn shift_octave(&mut self, command: impl Span, up: bool) -> Result<(), PlayMusicError> {
+        self.octave = if up {
+            self.octave
+                .checked_add(1)
+                .ok_or_else(|| PlayMusicError::new(command, "octave oveflow"))?
+        } else {
+            self.octave
+                .checked_sub(1)
+                .ok_or_else(|| PlayMusicError::new(command, "octave underflow"))?
+        };
         Ok(())
     }
 
@@ -470,6 +1225,12 @@ impl ChannelPlayer {
         &mut self,
         command: PitchSweepCommand,
     ) -> Result<(), PlayMusicError> {
+        if let Oscillator::Mmc5Pulse(_) = self.oscillator {
+            return Err(PlayMusicError::new(
+                command,
+                "pitch sweep is not supported by MMC5 pulse channels (they have no sweep unit)",
+            ));
+        }
         self.pitch_sweep = Some(command.sweep());
         Ok(())
     }
@@ -486,6 +1247,7 @@ impl ChannelPlayer {
                 vibrato.delay(),
                 vibrato.speed(),
                 vibrato.depth(),
+                self.region.frame_rate(),
             ));
         } else {
             self.pitch_lfo = None;
@@ -527,7 +1289,7 @@ impl ChannelPlayer {
         &mut self,
         _command: DataSkipCommand,
     ) -> Result<(), PlayMusicError> {
-        self.command_index = self.commands.len();
+        self.cursor.skip_to_end();
         Ok(())
     }
 
@@ -535,7 +1297,7 @@ impl ChannelPlayer {
         &mut self,
         _command: TrackLoopCommand,
     ) -> Result<(), PlayMusicError> {
-        self.loop_point = Some(self.command_index);
+        self.loop_point = Some(self.cursor.index());
         Ok(())
     }
 
@@ -543,87 +1305,28 @@ impl ChannelPlayer {
         &mut self,
         command: RepeatStartCommand,
     ) -> Result<(), PlayMusicError> {
-        let mut stack_size: isize = 1;
-        for command in &self.commands[self.command_index..] {
-            match command {
-                Command::RepeatStart(_) => stack_size += 1,
-                Command::RepeatEnd(_) => {
-                    stack_size -= 1;
-                    if stack_size == 0 {
-                        break;
-                    }
-                }
-                Command::DataSkip(_) => break,
-                _ => {}
-            }
-        }
-        if stack_size > 0 {
-            return Err(PlayMusicError::new(command, "no maching ']'"));
-        }
-
-        self.repeat_stack.push(Repeat::new(self.command_index));
-        Ok(())
+        self.cursor.repeat_start(command)
     }
 
     fn handle_repeat_end_command(
         &mut self,
         command: RepeatEndCommand,
     ) -> Result<(), PlayMusicError> {
-        let Some(mut repeat) = self.repeat_stack.pop() else {
-            return Err(PlayMusicError::new(command, "no maching '['"));
-        };
-        if repeat.count < command.count() {
-            self.command_index = repeat.start_index;
-            repeat.count += 1;
-            self.repeat_stack.push(repeat);
-        }
-        Ok(())
+        self.cursor.repeat_end(command)
     }
 
     fn handle_tuplet_start_command(
         &mut self,
         command: TupletStartCommand,
     ) -> Result<(), PlayMusicError> {
-        let mut note_count = 0;
-        for c in &self.commands[self.command_index..] {
-            match c {
-                Command::TupletStart(_) => {
-                    return Err(PlayMusicError::new(command, "nested tuplet"));
-                }
-                Command::TupletEnd(c) => {
-                    self.clocks.set_tuplet(note_count, c.note_duration());
-                    return Ok(());
-                }
-                Command::DataSkip(_) | Command::RepeatStart(_) | Command::RepeatEnd(_) => break,
-                Command::Note(_)
-                | Command::RestSign(_)
-                | Command::Wait(_)
-                | Command::Tie(_)
-                | Command::Slur(_) => {
-                    note_count += 1;
-                }
-                _ => {}
-            }
-        }
-        Err(PlayMusicError::new(command, "no maching '}'"))
+        self.cursor.tuplet_start(command, &mut self.clocks)
     }
 
     fn handle_tuplet_end_command(
         &mut self,
         command: TupletEndCommand,
     ) -> Result<(), PlayMusicError> {
-        for c in self.commands[..self.command_index - 1].iter().rev() {
-            match c {
-                Command::TupletStart(_) => {
-                    return Ok(());
-                }
-                Command::TupletEnd(_) => {
-                    break;
-                }
-                _ => {}
-            }
-        }
-        Err(PlayMusicError::new(command, "no maching '{'"))
+        self.cursor.tuplet_end(command)
     }
 
     fn handle_quantize_command(&mut self, command: QuantizeCommand) -> Result<(), PlayMusicError> {
@@ -644,8 +1347,17 @@ impl Iterator for ChannelPlayer {
     type Item = Sample;
 
     fn next(&mut self) -> Option<Self::Item> {
+        self.advance().then(|| self.sample())
+    }
+}
+
+impl ChannelPlayer {
+    /// Handles the frames and the commands until the next sample is due.
+    ///
+    /// Returns `false` if the channel has ended.
+    fn advance(&mut self) -> bool {
         if self.eos {
-            return None;
+            return false;
         }
 
         while self.last_error.is_none() {
@@ -657,27 +1369,41 @@ impl Iterator for ChannelPlayer {
             }
 
             if self.clocks.sample_clock() < self.clocks.note_clock() {
-                return Some(self.sample());
+                return true;
             }
 
-            let Some(command) = self.commands.get(self.command_index).cloned() else {
-                if let Some(i) = self.loop_point {
-                    self.command_index = i;
+            let Some(command) = self.cursor.next_command() else {
+                let can_loop = self.loop_limit.is_none_or(|n| self.loop_count < n);
+                if let Some(i) = self.loop_point.filter(|_| can_loop) {
+                    self.cursor.jump(i);
+                    self.loop_count += 1;
                     continue;
                 }
                 break;
             };
-            self.command_index += 1;
 
             self.command_span.start = command.start_position().get();
             self.command_span.end = command.end_position().get();
             let result = match command {
                 Command::Note(c) => self.handle_note_command(c),
+                Command::DirectNote(c) => self.handle_direct_note_command(c),
+                Command::DirectFrequency(c) => self.handle_direct_frequency_command(c),
                 Command::Arpeggio(c) => self.handle_arpeggio_command(c),
                 Command::Volume(c) => self.handle_volume_command(c),
                 Command::VolumeUp(c) => self.handle_volume_up_command(c),
                 Command::VolumeDown(c) => self.handle_volume_down_command(c),
                 Command::VolumeEnvelope(c) => self.handle_volume_envelope_command(c),
+                Command::HardwareEnvelope(c) => self.handle_hardware_envelope_command(c),
+                Command::LengthCounter(c) => self.handle_length_counter_command(c),
+                Command::LinearCounter(c) => self.handle_linear_counter_command(c),
+                Command::FdsModulation(c) => self.handle_fds_modulation_command(c),
+                Command::FdsMasterVolume(c) => self.handle_fds_master_volume_command(c),
+                Command::N163WaveOffset(c) => self.handle_n163_wave_offset_command(c),
+                Command::Sunsoft5bNoise(c) => self.handle_sunsoft5b_noise_command(c),
+                Command::Sunsoft5bEnvelope(c) => self.handle_sunsoft5b_envelope_command(c),
+                Command::Vrc7Sustain(c) => self.handle_vrc7_sustain_command(c),
+                Command::Vrc7UserPatch(c) => self.handle_vrc7_user_patch_command(c),
+                Command::Pan(c) => self.handle_pan_command(c),
                 Command::Octave(c) => self.handle_octave_command(c),
                 Command::OctaveUp(c) => self.handle_octave_up_command(c),
                 Command::OctaveDown(c) => self.handle_octave_down_command(c),
@@ -708,21 +1434,29 @@ impl Iterator for ChannelPlayer {
         }
 
         self.eos = true;
-        None
+        false
     }
-}
 
-#[derive(Debug)]
-struct Repeat {
-    start_index: usize,
-    count: usize,
-}
-
-impl Repeat {
-    fn new(start_index: usize) -> Self {
-        Self {
-            start_index,
-            count: 1,
+    /// Fast-forwards to the sample clock without synthesizing samples.
+    ///
+    /// The samples are skipped up to the next frame or command at once.
+    fn seek(&mut self, clock: Clock) {
+        while self.clocks.sample_clock() < clock && self.advance() {
+            let mut until = clock
+                .min(self.clocks.next_frame_clock())
+                .min(self.clocks.note_clock());
+            if self.clocks.sample_clock() < self.clocks.quantize_clock() {
+                until = until.min(self.clocks.quantize_clock());
+            }
+            let samples = self.clocks.skip_sample_clock_until(until);
+            let sample_rate = self.clocks.sample_rate();
+            self.oscillator.skip(sample_rate, samples);
+            if let Some(lfo) = &mut self.pitch_lfo {
+                lfo.skip(sample_rate, samples);
+            }
+            if self.clocks.sample_clock() >= self.clocks.quantize_clock() {
+                self.oscillator.mute(true);
+            }
         }
     }
 }
@@ -731,7 +1465,7 @@ impl Repeat {
 #[derive(Debug)]
 pub struct ChannelState<'a> {
     name: ChannelName,
-    player: &'a ChannelPlayer,
+    pub(crate) player: &'a ChannelPlayer,
 }
 
 impl<'a> ChannelState<'a> {
@@ -761,7 +1495,15 @@ impl<'a> ChannelState<'a> {
 
     /// Returns the current volume.
     pub fn volume(&self) -> u8 {
-        self.player.current_volume().get()
+        self.player
+            .oscillator
+            .output_volume(self.player.current_volume())
+            .get()
+    }
+
+    /// Returns the current panning (`-15` (left) ..= `15` (right)).
+    pub fn pan(&self) -> i8 {
+        self.player.pan.get()
     }
 
     /// Returns the current frequency.
@@ -773,4 +1515,374 @@ impl<'a> ChannelState<'a> {
     pub fn command(&self) -> std::ops::Range<usize> {
         self.player.command_span.clone()
     }
+
+    /// Returns how many times this channel has jumped back to the loop point (`L`).
+    pub fn loop_count(&self) -> usize {
+        self.player.loop_count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: u16 = 8000;
+
+    #[test]
+    fn direct_note_and_frequency() {
+        // `n57` is `o4 a`, and `@n253` is the period register of 440 Hz on the pulse wave channel.
+        let mut player = play("A l4 n57 o4 a @n253");
+        let frequencies = frequencies(&mut player, ChannelName::A, 3);
+        assert_eq!(frequencies[..2], [Some(440.0), Some(440.0)]);
+        assert!((frequencies[2].expect("frequency") - 440.0).abs() < 1.0);
+
+        assert_eq!(play_error("A n23"), "note number out of range");
+        assert_eq!(play_error("A n96"), "note number out of range");
+        assert_eq!(play_error("A @n7"), "unsupported period value");
+        assert_eq!(
+            play_error("#CHANNEL E 3\nE @n100"),
+            "unsupported period value"
+        );
+        assert!(Music::new("A n128").is_err());
+        assert!(Music::new("A @n4096").is_err());
+    }
+
+    #[test]
+    fn octave_rev_swaps_octave_up_and_down() {
+        let mut player = play("#OCTAVE-REV\nA l4 o4 a < a > > a");
+        assert_eq!(
+            frequencies(&mut player, ChannelName::A, 3),
+            [440.0, 880.0, 220.0].map(Some)
+        );
+        assert_eq!(play_error("#OCTAVE-REV\nA o7 <"), "octave oveflow");
+    }
+
+    #[test]
+    fn hardware_counters_cut_notes() {
+        // The notes are cut by the hardware within 0.2 seconds (the whole notes are 2 seconds long).
+        for mml in [
+            "A l1 HL1 HE0 c",
+            "A l1 HL3 c",
+            "D l1 HL1 HE0 c",
+            "D l1 HL3 c",
+            "C l1 HT10 c",
+        ] {
+            let samples = channel_samples(mml, 0.5);
+            let cut = (SAMPLE_RATE / 5) as usize;
+            assert!(!is_constant(&samples[..cut / 2]), "{mml}");
+            assert!(is_constant(&samples[cut..]), "{mml}");
+        }
+        // The envelope loops while the length counter is halted.
+        for mml in ["A l1 HE0 c", "A l1 HLOF c", "C l1 HTOF c"] {
+            let samples = channel_samples(mml, 0.5);
+            assert!(!is_constant(&samples[SAMPLE_RATE as usize / 4..]), "{mml}");
+        }
+
+        assert_eq!(
+            play_error("C HE0 c"),
+            "hardware envelope is only supported by pulse wave and noise channels"
+        );
+        assert_eq!(
+            play_error("C HL0 c"),
+            "length counter is only supported by pulse wave and noise channels"
+        );
+        assert_eq!(
+            play_error("A HT0 c"),
+            "linear counter is only supported by triangle wave channels"
+        );
+        assert_eq!(play_error("C HT127 HT255 c"), "");
+        assert!(Music::new("C HT128 c").is_err());
+        assert!(Music::new("A HE16 c").is_err());
+        assert!(Music::new("A HL32 c").is_err());
+    }
+
+    #[test]
+    fn expansion_timbre_ranges() {
+        for (kind, max) in [(4, 7), (5, 0), (8, 2), (9, 15), (10, 3), (11, 0)] {
+            let header = format!("#CHANNEL E {kind}\n@DPCM0 = {{ \"a.dmc\", 15 }}\n");
+            let files = BTreeMap::from([("a.dmc", vec![0xAA; 17])]);
+            let music = |timbre: u8| {
+                let mml = format!("{header}E o2 @{timbre} c");
+                Music::with_resolver(&mml, files.clone())
+            };
+            let music_max = music(max).unwrap_or_else(|e| panic!("{e}"));
+            assert_eq!(error(&music_max), None, "kind={kind}");
+            assert!(music(max + 1).is_err(), "kind={kind}");
+        }
+        // The timbres are checked against the channels when parsing the script.
+        for mml in ["A @4 c", "C @1 c", "D @2 c", "@0 = { 0 4 }\nA @@0 c"] {
+            assert!(Music::new(mml).is_err(), "{mml}");
+        }
+        assert_eq!(play_error("@0 = { 0 3 }\nA @@0 c"), "");
+
+        // The FDS and N163 timbres select the waveform macros.
+        let header = "#CHANNEL E 6\n#CHANNEL F 7\n";
+        let fds = format!("@FM1 = {{ {} }}\n", "0 ".repeat(64));
+        let n163 = "@N1 = { 0 1 2 3 }\n";
+        assert_eq!(
+            play_error(&format!("{header}{fds}{n163}E @1 c\nF @1 c")),
+            ""
+        );
+        assert_eq!(
+            play_error(&format!("{header}{fds}{n163}E @2 c")),
+            "undefined FDS waveform macro"
+        );
+        assert_eq!(
+            play_error(&format!("{header}{fds}{n163}F @2 c")),
+            "undefined N163 wave macro"
+        );
+        assert_eq!(
+            play_error(&format!("{header}@FM1 = {{ 0 1 2 }}\nE @1 c")),
+            "FDS waveform must have 64 samples"
+        );
+        assert_eq!(
+            play_error(&format!("{header}@N1 = {{ 0 1 2 }}\nF @1 c")),
+            "N163 wave length must be a multiple of 4"
+        );
+        assert_eq!(
+            play_error("#CHANNEL E 9\n@OPLL1 = { 0 1 2 }\nE UP1 c"),
+            "VRC7 patch must have 8 bytes"
+        );
+    }
+
+    #[test]
+    fn stems_have_same_length() {
+        let mut player = play("#CHANNEL E 5\nA l4 c\nC l2 c\nE l8 c");
+        let mut stems = BTreeMap::<_, Vec<Sample>>::new();
+        while let Some(samples) = player.next_stems() {
+            for (name, sample) in samples {
+                stems.entry(name).or_default().push(sample);
+            }
+        }
+        let len = SAMPLE_RATE as usize; // The channel C plays a half note at 120 BPM.
+        for (name, samples) in &stems {
+            assert!(
+                samples.len().abs_diff(len) <= 1,
+                "{name:?}: {}",
+                samples.len()
+            );
+        }
+        // The channels that have ended yield zeros.
+        assert!(stems[&ChannelName::B].iter().all(|x| x.get() == 0.0));
+        assert!(stems[&ChannelName::E][len / 4 + 1..]
+            .iter()
+            .all(|x| x.get() == 0.0));
+    }
+
+    #[test]
+    fn stems_sum_to_linear_mix() {
+        // The N163 channels are multiplexed, and the Sunsoft 5B channels share the noise generator.
+        let script = "#CHANNEL E 7\n#CHANNEL F 7\n#CHANNEL G 8\n#CHANNEL H 8\n\
+                      @N1 = { 0 15 0 15 }\n\
+                      A l8 c e g\nB l8 r e\nC l4 c\nD l8 c r c\nE @1 l8 d f\nF @1 l4 a\nG @1 l8 c\nH @2 l4 e";
+        let mixed = play(script).collect::<Vec<_>>();
+        let mut player = play(script);
+        let stems = std::iter::from_fn(|| player.next_stems()).collect::<Vec<_>>();
+        assert_eq!(stems.len(), mixed.len());
+        for name in stems[0].keys() {
+            assert!(stems.iter().any(|s| s[name].get() != 0.0), "{name:?}");
+        }
+        for (i, (stems, mixed)) in stems.iter().zip(&mixed).enumerate() {
+            let sum = stems.values().map(|x| x.get()).sum::<f32>() / stems.len() as f32;
+            assert!(
+                (sum - mixed.get()).abs() < 1e-5,
+                "{i}: {sum} != {}",
+                mixed.get()
+            );
+        }
+    }
+
+    #[test]
+    fn seek_plays_same_as_skipped_samples() {
+        let ramp = (0..64).map(|i| i.to_string()).collect::<Vec<_>>().join(" ");
+        let vibrato = format!("{}{}", "1 ".repeat(16), "7 ".repeat(16));
+        let script = format!(
+            "#CHANNEL E 6\n#CHANNEL F 8\n#CHANNEL G 8\n#CHANNEL H 9\n\
+             @FM0 = {{ {ramp} }}\n@FMOD0 = {{ {vibrato} }}\n\
+             A l2 @1 HL1 HE3 c e\nC l4 c g c g\nD l2 HL1 HE2 c d\n\
+             E l1 @0 MF0,16,5 a\nF l1 SN31 SE10,400 c\nG l1 @1 c\nH l1 @3 c"
+        );
+        let position = Duration::from_millis(750);
+        let mut player = play(&script);
+        let skipped = (f32::from(SAMPLE_RATE) * position.as_secs_f32()) as usize;
+        let expected = stems(&mut player).split_off(skipped);
+        let mut player = play(&script);
+        player.seek(position);
+        let actual = stems(&mut player);
+        assert_eq!(actual.len(), expected.len());
+
+        let block_len = usize::from(SAMPLE_RATE) / 20;
+        for name in actual[0].keys() {
+            let samples = |stems: &[BTreeMap<ChannelName, Sample>]| {
+                stems.iter().map(|s| s[name].get()).collect::<Vec<_>>()
+            };
+            let (actual, expected) = (samples(&actual), samples(&expected));
+            for (i, (a, e)) in actual
+                .chunks(block_len)
+                .zip(expected.chunks(block_len))
+                .enumerate()
+            {
+                let (rms, expected_rms) = (rms(a), rms(e));
+                let (crossings, expected_crossings) = (zero_crossings(a), zero_crossings(e));
+                assert!(
+                    (rms - expected_rms).abs() <= expected_rms * 0.1 + 0.001
+                        && crossings.abs_diff(expected_crossings) <= expected_crossings / 10 + 2,
+                    "{name:?}: block={i}, rms={rms}, expected_rms={expected_rms}, \
+                     crossings={crossings}, expected_crossings={expected_crossings}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn analysis_follows_player_timing() {
+        for script in [
+            "A l4 [c d]2 {c d e}2 c^8 c&c8",
+            "A l8 [c [d e]3 f]2 r ! c",
+            "A t150 l4 {c&c d}4 q6 c",
+        ] {
+            let music = Music::new(script).unwrap_or_else(|e| panic!("{e}"));
+            let total = music.analyze().unwrap_or_else(|e| panic!("{e}")).total();
+            let samples = music.play(SAMPLE_RATE).count();
+            let expected = total.as_secs_f64() * f64::from(SAMPLE_RATE);
+            assert!(
+                (samples as f64 - expected).abs() <= 1.0,
+                "{script}: {samples} != {expected}"
+            );
+        }
+
+        for (script, reason) in [
+            ("A l4 c & d", "'&' cannot combine different notes"),
+            ("A l4 c & r", "mssing a note command after '&'"),
+            ("A l4 r & c", "'&' must follow a note command"),
+            ("A l4 r ^8", "'^' must follow a note command"),
+            ("A l4 c }", "no maching '{'"),
+        ] {
+            let music = Music::new(script).unwrap_or_else(|e| panic!("{e}"));
+            assert_eq!(error(&music).as_deref(), Some(reason), "{script}");
+            let e = music.analyze().expect_err(script);
+            assert_eq!(e.reason, reason, "{script}");
+        }
+    }
+
+    #[test]
+    fn loop_count_stops_channels() {
+        let script = "A l4 c L d\nB l4 L e";
+        let quarter = usize::from(SAMPLE_RATE) / 2;
+
+        // Without a fade-out, each channel ends after jumping back twice.
+        let mut player = play(script);
+        player.set_loop_count(Some(2)).expect("loop count");
+        let samples = stems(&mut player);
+        assert_eq!(samples.len(), quarter * 4);
+        assert!(samples[quarter * 3..]
+            .iter()
+            .all(|s| s[&ChannelName::B].get() == 0.0));
+        assert!(player.channels().all(|c| c.loop_count() <= 2));
+
+        // With a fade-out, the channels keep looping while fading out.
+        let mut player = play(script);
+        player.set_loop_count(Some(2)).expect("loop count");
+        player.set_fade_out(Duration::from_secs(1));
+        let samples = stems(&mut player);
+        assert_eq!(samples.len(), quarter * 6);
+        assert!(samples[quarter * 3..quarter * 5]
+            .iter()
+            .any(|s| s[&ChannelName::B].get() != 0.0));
+
+        // The timing analysis runs only when the loop count is set.
+        let mut player = play("A l4 c & d");
+        player.set_loop_count(None).expect("no analysis");
+        let e = player.set_loop_count(Some(1)).expect_err("analysis error");
+        assert_eq!(e.reason, "'&' cannot combine different notes");
+    }
+
+    #[test]
+    fn pan_moves_channel_to_side() {
+        let mut player = play("A l4 p-15 c p15 c p0 c");
+        let mut sides = [[0.0f32; 2]; 3];
+        for i in 0..SAMPLE_RATE as usize * 3 / 2 {
+            let Some(stereo) = player.next_stereo() else {
+                break;
+            };
+            for (side, x) in stereo.into_iter().enumerate() {
+                sides[i * 2 / SAMPLE_RATE as usize][side] += x.get().abs();
+            }
+        }
+        let [left, right, center] = sides;
+        assert!(left[0] > 0.0 && left[1] == 0.0, "{left:?}");
+        assert!(right[0] == 0.0 && right[1] > 0.0, "{right:?}");
+        assert_eq!(center[0], center[1]);
+
+        let mut player = play("A p-7 c");
+        player.next();
+        assert_eq!(player.channels().next().map(|c| c.pan()), Some(-7));
+        assert!(Music::new("A p16 c").is_err());
+        assert!(Music::new("A p-16 c").is_err());
+    }
+
+    fn play(mml: &str) -> MusicPlayer {
+        Music::new(mml)
+            .unwrap_or_else(|e| panic!("{e}"))
+            .play(SAMPLE_RATE)
+    }
+
+    /// Plays the music to the end and returns the reason of the error (or an empty string).
+    fn play_error(mml: &str) -> String {
+        let music = Music::new(mml).unwrap_or_else(|e| panic!("{e}"));
+        error(&music).unwrap_or_default()
+    }
+
+    fn error(music: &Music) -> Option<String> {
+        let mut player = music.play(SAMPLE_RATE);
+        while player.next().is_some() {}
+        player.take_last_error().map(|e| e.reason)
+    }
+
+    /// Returns the frequencies of the channel at the middle of each quarter note (at 120 BPM).
+    fn frequencies(player: &mut MusicPlayer, name: ChannelName, count: usize) -> Vec<Option<f32>> {
+        (0..count)
+            .map(|_| {
+                player.seek(player.elapsed() + Duration::from_millis(250));
+                let frequency = player
+                    .channels()
+                    .find(|c| c.channel_name() == name)
+                    .map(|c| c.frequency());
+                player.seek(player.elapsed() + Duration::from_millis(250));
+                frequency
+            })
+            .collect()
+    }
+
+    /// Returns the samples of the music, which plays only one channel, in the first `seconds`.
+    fn channel_samples(mml: &str, seconds: f32) -> Vec<Sample> {
+        play(mml)
+            .take((f32::from(SAMPLE_RATE) * seconds) as usize)
+            .collect()
+    }
+
+    fn is_constant(samples: &[Sample]) -> bool {
+        samples.iter().all(|x| x.get() == samples[0].get())
+    }
+
+    fn stems(player: &mut MusicPlayer) -> Vec<BTreeMap<ChannelName, Sample>> {
+        let stems = std::iter::from_fn(|| player.next_stems()).collect();
+        if let Some(e) = player.take_last_error() {
+            panic!("{e}");
+        }
+        stems
+    }
+
+    fn rms(samples: &[f32]) -> f32 {
+        (samples.iter().map(|x| x * x).sum::<f32>() / samples.len() as f32).sqrt()
+    }
+
+    /// Counts the sign changes around the mean level (the waves of some channels are not centered).
+    fn zero_crossings(samples: &[f32]) -> usize {
+        let mean = samples.iter().sum::<f32>() / samples.len() as f32;
+        samples
+            .windows(2)
+            .filter(|w| (w[0] - mean).signum() != (w[1] - mean).signum())
+            .count()
+    }
 }
@@ -0,0 +1,466 @@
+//! APU register values that reproduce a music frame by frame (shared by the APU emulator and the VGM exporter),
+//! and the assignment of the channels to the APU channels (also used by the NSF exporter).
+//!
+//! This module is the only place that reads the internal state of the channels to build the register values
+//! (see [`Registers`]), so the players and the oscillators need no code for the register-based outputs.
+use crate::{
+    channel::Channel, macros::MacroNumber, oscillators::Oscillator, ChannelName, Music,
+    PlayMusicError,
+};
+#[cfg(any(feature = "apu", feature = "vgm"))]
+use crate::{
+    oscillators::{frequency_to_register, HardwareLinearCounter, HardwareVolume, MuteState},
+    types::Volume,
+    ChannelState, MusicPlayer,
+};
+use std::collections::BTreeMap;
+use textparse::{Position, Span};
+
+#[cfg(feature = "vgm")]
+const SAMPLES_PER_FRAME: u16 = 16;
+
+const DPCM_MAX_SIZE: usize = 0x4000;
+
+/// Start address of the DPCM samples.
+pub const DPCM_START: u16 = 0xC000;
+
+/// Value of the DMC control pseudo-register (the second register of [`Slot::Dmc`]) to start a sample.
+///
+/// The control value is written to `$4015` (after writing [`DMC_STOP`] if starting a sample).
+pub const DMC_START: u8 = 0x1F;
+
+/// Value of the DMC control pseudo-register to stop the playing sample.
+pub const DMC_STOP: u8 = 0x0F;
+
+/// APU channel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Slot {
+    Pulse1,
+    Pulse2,
+    Triangle,
+    Noise,
+    Dmc,
+}
+
+impl Slot {
+    pub fn index(self) -> u8 {
+        self as u8
+    }
+
+    /// Address of the first register of this channel.
+    #[cfg(any(feature = "apu", feature = "vgm"))]
+    pub fn base_address(self) -> u16 {
+        0x4000 + u16::from(self.index()) * 4
+    }
+
+    #[cfg(any(feature = "apu", feature = "vgm"))]
+    pub fn silence(self) -> [Option<u8>; 4] {
+        match self {
+            Slot::Pulse1 | Slot::Pulse2 | Slot::Noise => [Some(0x30), None, None, None],
+            Slot::Triangle => [Some(0x80), None, None, None],
+            Slot::Dmc => [None, Some(DMC_STOP), None, None],
+        }
+    }
+}
+
+#[cfg(any(feature = "apu", feature = "vgm"))]
+/// Register values of a channel at a frame.
+#[derive(Debug)]
+pub struct ChannelFrame {
+    pub slot: Slot,
+    pub registers: [Option<u8>; 4],
+    #[cfg(feature = "vgm")]
+    pub is_looping: bool,
+    #[cfg(feature = "vgm")]
+    pub loop_count: usize,
+    pub is_eos: bool,
+
+    /// `true` if the last register has to be rewritten even if the value is not changed
+    /// (to restart the hardware volume envelope and length counter at a new note).
+    pub retrigger: bool,
+
+    /// `true` if a new note has been started on a pulse wave channel since the previous frame.
+    #[cfg(feature = "apu")]
+    pub is_note_start: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct DpcmSamples {
+    pub data: Vec<u8>,
+    registers: BTreeMap<MacroNumber, (u8, u8)>, // => ($4012, $4013)
+}
+
+impl DpcmSamples {
+    /// Returns the values of `$4012` and `$4013` to play the sample (`None` if the sample is empty).
+    #[cfg(feature = "nsf")]
+    pub fn registers(&self, number: MacroNumber) -> Option<(u8, u8)> {
+        self.registers.get(&number).copied()
+    }
+}
+
+#[cfg(any(feature = "apu", feature = "vgm"))]
+/// [`RegisterRecorder`] plays a music and takes the register values at each frame (60 Hz, or 50 Hz for PAL and Dendy).
+#[derive(Debug)]
+pub struct RegisterRecorder {
+    player: MusicPlayer,
+    slots: BTreeMap<ChannelName, Slot>,
+    samples: DpcmSamples,
+    dpcm_triggers: usize,
+    dpcm_muted: bool,
+    note_triggers: BTreeMap<ChannelName, usize>,
+    #[cfg(feature = "apu")]
+    pulse_notes: BTreeMap<ChannelName, usize>,
+    samples_per_frame: u16,
+}
+
+#[cfg(any(feature = "apu", feature = "vgm"))]
+impl RegisterRecorder {
+    /// Makes a new [`RegisterRecorder`] instance.
+    ///
+    /// `format` is the name of the output format used in error messages.
+    #[cfg(feature = "vgm")]
+    pub fn new(music: &Music, format: &str) -> Result<Self, PlayMusicError> {
+        let frame_rate = u16::from(music.region().frame_rate());
+        Self::with_sample_rate(
+            music,
+            format,
+            frame_rate * SAMPLES_PER_FRAME,
+            SAMPLES_PER_FRAME,
+        )
+    }
+
+    /// Makes a new [`RegisterRecorder`] instance that plays the music at `sample_rate`.
+    ///
+    /// [`RegisterRecorder::next_frame()`] advances `samples_per_frame` samples,
+    /// so a "frame" can be shorter than the actual frame (e.g., a sample).
+    pub fn with_sample_rate(
+        music: &Music,
+        format: &str,
+        sample_rate: u16,
+        samples_per_frame: u16,
+    ) -> Result<Self, PlayMusicError> {
+        let slots = assign_slots(music, format)?;
+        let samples = load_dpcm_samples(music, &slots, format)?;
+        let mut player = music.play(sample_rate);
+
+        // Registers are taken just after the first sample of each frame
+        // so that the commands starting at the frame boundary have been handled.
+        player.next();
+
+        Ok(Self {
+            player,
+            slots,
+            samples,
+            dpcm_triggers: 0,
+            dpcm_muted: true,
+            note_triggers: BTreeMap::new(),
+            #[cfg(feature = "apu")]
+            pulse_notes: BTreeMap::new(),
+            samples_per_frame,
+        })
+    }
+
+    pub fn samples(&self) -> &DpcmSamples {
+        &self.samples
+    }
+
+    /// Returns the register values of the current frame, and then advances to the next frame.
+    pub fn next_frame(&mut self) -> Result<Vec<ChannelFrame>, PlayMusicError> {
+        if let Some(e) = self.player.take_last_error() {
+            return Err(e);
+        }
+
+        let mut frames = Vec::new();
+        for state in self.player.channels() {
+            let slot = self.slots[&state.channel_name()];
+            let mut registers = state.registers();
+            if let Oscillator::Dpcm(o) = &state.player.oscillator {
+                let muted = o.mute || state.is_eos();
+                let trigger = o
+                    .sample
+                    .and_then(|n| self.samples.registers.get(&n))
+                    .filter(|_| o.triggers != self.dpcm_triggers && !muted);
+                if let Some(&(addr, len)) = trigger {
+                    registers[1] = Some(DMC_START);
+                    registers[2] = Some(addr);
+                    registers[3] = Some(len);
+                } else if muted && !self.dpcm_muted {
+                    registers[1] = Some(DMC_STOP);
+                }
+                self.dpcm_triggers = o.triggers;
+                self.dpcm_muted = muted;
+            } else if state.is_eos() {
+                registers = slot.silence();
+            }
+            let retrigger = state
+                .hardware_triggers()
+                .is_some_and(|n| self.note_triggers.insert(state.channel_name(), n) != Some(n))
+                && !state.is_eos();
+            #[cfg(feature = "apu")]
+            let is_note_start = match &state.player.oscillator {
+                Oscillator::PulseWave(o) => {
+                    let n = o.hardware_volume.triggers;
+                    self.pulse_notes.insert(state.channel_name(), n) != Some(n)
+                }
+                _ => false,
+            };
+            frames.push(ChannelFrame {
+                slot,
+                registers,
+                #[cfg(feature = "vgm")]
+                is_looping: state.player.loop_point.is_some(),
+                #[cfg(feature = "vgm")]
+                loop_count: state.loop_count(),
+                is_eos: state.is_eos(),
+                retrigger,
+                #[cfg(feature = "apu")]
+                is_note_start,
+            });
+        }
+
+        for _ in 0..self.samples_per_frame {
+            self.player.next();
+        }
+        Ok(frames)
+    }
+}
+
+/// Assigns the channels of the music to the APU channels by their oscillators.
+///
+/// `format` is the name of the output format used in error messages.
+pub fn assign_slots(
+    music: &Music,
+    format: &str,
+) -> Result<BTreeMap<ChannelName, Slot>, PlayMusicError> {
+    let mut slots = BTreeMap::new();
+    for (name, channel) in music.channels().iter() {
+        let kind = kind_name(&channel.oscillator);
+        let candidates: &[Slot] = match channel.oscillator {
+            Oscillator::PulseWave(_) => &[Slot::Pulse1, Slot::Pulse2],
+            Oscillator::TriangleWave(_) => &[Slot::Triangle],
+            Oscillator::Noise(_) => &[Slot::Noise],
+            Oscillator::Dpcm(_) => &[Slot::Dmc],
+            Oscillator::Vrc6Pulse(_)
+            | Oscillator::Sawtooth(_)
+            | Oscillator::Fds(_)
+            | Oscillator::N163(_)
+            | Oscillator::Sunsoft5b(_)
+            | Oscillator::Vrc7(_)
+            | Oscillator::Mmc5Pulse(_)
+            | Oscillator::Mmc5Pcm(_) => {
+                return Err(channel_error(
+                    name,
+                    start_position(&channel),
+                    &format!("{kind} channels are not supported by {format}"),
+                ));
+            }
+        };
+        let Some(&slot) = candidates.iter().find(|s| !slots.values().any(|x| x == *s)) else {
+            return Err(channel_error(
+                name,
+                start_position(&channel),
+                &format!("too many {kind} channels for {format}"),
+            ));
+        };
+        slots.insert(name, slot);
+    }
+    Ok(slots)
+}
+
+/// Loads the DPCM samples of the music at [`DPCM_START`] if a channel is assigned to the DMC.
+pub fn load_dpcm_samples(
+    music: &Music,
+    slots: &BTreeMap<ChannelName, Slot>,
+    format: &str,
+) -> Result<DpcmSamples, PlayMusicError> {
+    let mut samples = DpcmSamples::default();
+    let Some((&name, _)) = slots.iter().find(|(_, &slot)| slot == Slot::Dmc) else {
+        return Ok(samples);
+    };
+
+    for m in music.macros().dpcms.values() {
+        let sample = m.data();
+        if sample.is_empty() {
+            continue;
+        }
+        let start = samples.data.len();
+        samples.data.extend_from_slice(&sample);
+        if samples.data.len() > DPCM_MAX_SIZE {
+            return Err(channel_error(
+                name,
+                m,
+                &format!("too large DPCM samples for {format}"),
+            ));
+        }
+        samples
+            .data
+            .resize(samples.data.len().next_multiple_of(64), 0);
+
+        let addr = (start / 64) as u8; // ($C000 + start - $C000) / 64
+        let len = ((sample.len() - 1) / 16).min(0xFF) as u8;
+        samples.registers.insert(m.number(), (addr, len));
+    }
+    Ok(samples)
+}
+
+#[cfg(any(feature = "apu", feature = "vgm"))]
+/// APU register values that reproduce the current state of a channel.
+pub trait Registers {
+    /// Returns the values of the four APU registers.
+    ///
+    /// `None` means that the register is not used.
+    /// For DPCM, only the rate register is returned because the sample address and length depend on the memory layout.
+    fn registers(&self) -> [Option<u8>; 4];
+
+    /// Returns how many times notes have been started if the hardware volume envelope, length counter or linear counter is enabled.
+    ///
+    /// In that case, the last register (`$4003`, `$400B` or `$400F`) has to be rewritten at each note to restart them.
+    fn hardware_triggers(&self) -> Option<usize>;
+}
+
+#[cfg(any(feature = "apu", feature = "vgm"))]
+impl Registers for ChannelState<'_> {
+    fn registers(&self) -> [Option<u8>; 4] {
+        let volume = self.player.current_volume();
+        let d = self
+            .player
+            .pitch_lfo
+            .as_ref()
+            .map_or(0.0, |lfo| lfo.current());
+        match &self.player.oscillator {
+            Oscillator::PulseWave(o) => {
+                let duty = (o.duty_cycle * 4.0) as u8; // 0.125 => 0, 0.25 => 1, ...
+                let period = register_to_period(frequency_to_register(o.region, o.frequency) - d);
+                [
+                    Some((duty << 6) | o.hardware_volume.control(volume, o.mute)),
+                    Some(0x08), // Disables the hardware sweep unit.
+                    Some(period as u8),
+                    Some(o.hardware_volume.length_load() | (period >> 8) as u8),
+                ]
+            }
+            Oscillator::TriangleWave(o) => {
+                let control = match o.linear_counter.reload {
+                    _ if o.mute != MuteState::Off => 0x80,
+                    Some(reload) => reload,
+                    None => 0xFF,
+                };
+                let period =
+                    register_to_period((frequency_to_register(o.region, o.frequency) - d) / 2.0);
+                [
+                    Some(control),
+                    None,
+                    Some(period as u8),
+                    Some(o.linear_counter.length_load() | (period >> 8) as u8),
+                ]
+            }
+            Oscillator::Noise(o) => {
+                let index = o
+                    .period_table()
+                    .iter()
+                    .position(|&p| f32::from(p) == o.frequency)
+                    .unwrap_or(0) as u8;
+                [
+                    Some(o.hardware_volume.control(volume, o.mute)),
+                    None,
+                    Some(((o.looped_noise as u8) << 7) | index),
+                    Some(o.hardware_volume.length_load()),
+                ]
+            }
+            Oscillator::Dpcm(o) => [Some(o.pitch), None, None, None],
+            Oscillator::Vrc6Pulse(_)
+            | Oscillator::Sawtooth(_)
+            | Oscillator::Fds(_)
+            | Oscillator::N163(_)
+            | Oscillator::Sunsoft5b(_)
+            | Oscillator::Vrc7(_)
+            | Oscillator::Mmc5Pulse(_)
+            | Oscillator::Mmc5Pcm(_) => [None; 4],
+        }
+    }
+
+    fn hardware_triggers(&self) -> Option<usize> {
+        match &self.player.oscillator {
+            Oscillator::PulseWave(o) => o.hardware_volume.triggers(),
+            Oscillator::TriangleWave(o) => o.linear_counter.triggers(),
+            Oscillator::Noise(o) => o.hardware_volume.triggers(),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(any(feature = "apu", feature = "vgm"))]
+impl HardwareVolume {
+    /// Returns the value of the first register (except the duty bits).
+    fn control(&self, volume: Volume, mute: bool) -> u8 {
+        let halt = if self.length_index.is_none() { 0x20 } else { 0 };
+        match self.envelope_period {
+            _ if mute => 0x30,
+            Some(period) => halt | period,
+            None => halt | 0x10 | volume.get(),
+        }
+    }
+
+    /// Returns the length counter load bits of the last register.
+    fn length_load(&self) -> u8 {
+        self.length_index.unwrap_or(31) << 3
+    }
+
+    fn triggers(&self) -> Option<usize> {
+        self.is_enabled().then_some(self.triggers)
+    }
+}
+
+#[cfg(any(feature = "apu", feature = "vgm"))]
+impl HardwareLinearCounter {
+    /// Returns the length counter load bits of `$400B`.
+    ///
+    /// While the control flag is cleared, the length counter also runs and
+    /// must not be shorter than the linear counter (index `1` is 254 half frames).
+    fn length_load(&self) -> u8 {
+        if self.reload.is_some() {
+            0x08
+        } else {
+            0xF8
+        }
+    }
+
+    fn triggers(&self) -> Option<usize> {
+        self.reload.map(|_| self.triggers)
+    }
+}
+
+pub fn register_to_period(register: f32) -> u16 {
+    (register.round() - 1.0).clamp(0.0, 2047.0) as u16
+}
+
+/// Returns the name of the kind of the channel (used in error messages).
+fn kind_name(oscillator: &Oscillator) -> &'static str {
+    match oscillator {
+        Oscillator::PulseWave(_) => "pulse wave",
+        Oscillator::TriangleWave(_) => "triangle wave",
+        Oscillator::Noise(_) => "noise",
+        Oscillator::Dpcm(_) => "DPCM",
+        Oscillator::Vrc6Pulse(_) => "VRC6 pulse",
+        Oscillator::Sawtooth(_) => "VRC6 sawtooth",
+        Oscillator::Fds(_) => "FDS",
+        Oscillator::N163(_) => "N163",
+        Oscillator::Sunsoft5b(_) => "Sunsoft 5B",
+        Oscillator::Vrc7(_) => "VRC7",
+        Oscillator::Mmc5Pulse(_) => "MMC5 pulse",
+        Oscillator::Mmc5Pcm(_) => "MMC5 PCM",
+    }
+}
+
+pub fn channel_error(name: ChannelName, span: impl Span, reason: &str) -> PlayMusicError {
+    let mut e = PlayMusicError::new(span, reason);
+    e.channel = name;
+    e
+}
+
+/// Returns the start position of the first command of the channel.
+pub fn start_position(channel: &Channel) -> Position {
+    channel
+        .commands
+        .first()
+        .map_or(Position::new(0), |c| c.start_position())
+}
@@ -0,0 +1,145 @@
+//! Sunsoft 5B: the noise generator and the envelope generator shared by the three square channels.
+use std::sync::{Arc, Mutex};
+
+/// Maximum number of channels.
+pub const MAX_CHANNELS: usize = 3;
+
+/// Returns the amplitude (`0.0..=1.0`) of a 5-bit output level (`0..=31`).
+///
+/// The levels are 1.5 dB apart, and the level `0` is silent.
+/// A 4-bit channel volume `v` (`1..=15`) corresponds to the level `2 * v + 1`.
+pub fn amplitude(level: u8) -> f32 {
+    if level == 0 {
+        0.0
+    } else {
+        10f32.powf(f32::from(level.min(31)) * 1.5 / 20.0 - 31.0 * 1.5 / 20.0)
+    }
+}
+
+/// State of the chip shared by all Sunsoft 5B channels of a music.
+///
+/// Each channel advances the chip to its own position, so the generators are clocked
+/// once no matter how many channels are playing.
+#[derive(Debug, Clone)]
+pub struct Chip(Arc<Mutex<State>>);
+
+impl Chip {
+    pub fn new() -> Self {
+        Self(Arc::new(Mutex::new(State {
+            cycles: 0.0,
+            noise_period: 0,
+            noise_counter: 0.0,
+            lfsr: 1,
+            envelope: Envelope::default(),
+        })))
+    }
+
+    /// Sets the period (`0..=31`) of the noise generator.
+    pub fn set_noise_period(&self, period: u8) {
+        self.state().noise_period = period;
+    }
+
+    /// Restarts the envelope generator with the shape (`0..=15`) and the period (`0..=65535`).
+    pub fn start_envelope(&self, shape: u8, period: u16) {
+        self.state().envelope = Envelope {
+            shape,
+            period,
+            counter: 0.0,
+            step: 0,
+            attack: shape & 0b0100 != 0,
+            holding: false,
+        };
+    }
+
+    /// Advances the generators to the CPU cycle, and returns the noise output and the envelope level (`0..=31`).
+    pub fn advance_to(&self, cycles: f64) -> (bool, u8) {
+        let mut state = self.state();
+        let elapsed = cycles - state.cycles;
+        if elapsed > 0.0 {
+            state.cycles = cycles;
+            state.clock_noise(elapsed);
+            state.envelope.clock(elapsed);
+        }
+        (state.lfsr & 1 != 0, state.envelope.level())
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[derive(Debug)]
+struct State {
+    cycles: f64,
+    noise_period: u8,
+    noise_counter: f64,
+    lfsr: u32,
+    envelope: Envelope,
+}
+
+impl State {
+    fn clock_noise(&mut self, cycles: f64) {
+        // The 17-bit LFSR is clocked every `32 * period` CPU cycles.
+        let period = 32.0 * f64::from(self.noise_period.max(1));
+        self.noise_counter += cycles;
+        while self.noise_counter >= period {
+            self.noise_counter -= period;
+            let bit = (self.lfsr ^ (self.lfsr >> 3)) & 1;
+            self.lfsr = (self.lfsr >> 1) | (bit << 16);
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Envelope {
+    shape: u8,
+    period: u16,
+    counter: f64,
+    step: u8,
+    attack: bool,
+    holding: bool,
+}
+
+impl Envelope {
+    const CONTINUE: u8 = 0b1000;
+    const ALTERNATE: u8 = 0b0010;
+    const HOLD: u8 = 0b0001;
+
+    fn clock(&mut self, cycles: f64) {
+        // A ramp has 32 steps, and each step takes `16 * period` CPU cycles.
+        let period = 16.0 * f64::from(self.period.max(1));
+        self.counter += cycles;
+        while self.counter >= period && !self.holding {
+            self.counter -= period;
+            if self.step < 31 {
+                self.step += 1;
+                continue;
+            }
+
+            // End of a ramp.
+            if self.shape & Self::CONTINUE == 0 {
+                self.attack = false;
+                self.holding = true;
+            } else if self.shape & Self::HOLD != 0 {
+                if self.shape & Self::ALTERNATE != 0 {
+                    self.attack = !self.attack;
+                }
+                self.holding = true;
+            } else {
+                if self.shape & Self::ALTERNATE != 0 {
+                    self.attack = !self.attack;
+                }
+                self.step = 0;
+            }
+        }
+    }
+
+    fn level(&self) -> u8 {
+        match (self.holding, self.attack) {
+            (true, true) => 31,
+            (true, false) => 0,
+            (false, true) => self.step,
+            (false, false) => 31 - self.step,
+        }
+    }
+}
@@ -66,7 +66,7 @@ impl Div<f32> for Sample {
 }
 
 #[derive(Debug, Default, Clone, Copy, Span, Parse)]
-pub struct Timbre(Int<0, 3>);
+pub struct Timbre(Int<0, 255>);
 
 impl Timbre {
     pub const DUTY_CYCLE_12: u8 = 0;
@@ -250,6 +250,58 @@ impl Parse for Note {
     }
 }
 
+#[derive(Debug, Clone, Copy, Span, Parse)]
+pub struct NoteNumber(Int<0, 127>);
+
+impl NoteNumber {
+    pub fn note(self) -> Note {
+        const TABLE: [(Letter, i8); 12] = [
+            (Letter::C, 0),
+            (Letter::C, 1),
+            (Letter::D, 0),
+            (Letter::D, 1),
+            (Letter::E, 0),
+            (Letter::F, 0),
+            (Letter::F, 1),
+            (Letter::G, 0),
+            (Letter::G, 1),
+            (Letter::A, 0),
+            (Letter::A, 1),
+            (Letter::B, 0),
+        ];
+        let (letter, accidentals) = TABLE[self.0.get() as usize % 12];
+        Note {
+            start: self.start_position(),
+            letter,
+            accidentals,
+            end: self.end_position(),
+        }
+    }
+
+    pub fn octave(self) -> Option<Octave> {
+        let octave = self.0.get() / 12;
+        (2..=7).contains(&octave).then(|| Octave(Int::new(octave)))
+    }
+}
+
+#[derive(Debug, Clone, Copy, Span, Parse)]
+pub struct Period(Int<0, 4095>);
+
+impl Period {
+    pub const fn get(self) -> u16 {
+        self.0.get() as u16
+    }
+}
+
+#[derive(Debug, Clone, Copy, Span, Parse)]
+pub struct LinearCounter(Int<0, 127>);
+
+impl LinearCounter {
+    pub const fn get(self) -> u8 {
+        self.0.get() as u8
+    }
+}
+
 #[derive(Debug, Clone, Copy, Span, Parse)]
 pub struct DefaultNoteDuration(Int<1, 255>);
 
@@ -399,6 +451,31 @@ impl Detune {
     }
 }
 
+#[derive(Debug, Default, Clone, Copy, Span, Parse)]
+pub struct Pan(Int<-15, 15>);
+
+impl Pan {
+    pub const LEFT: Self = Self::new(-15);
+    pub const RIGHT: Self = Self::new(15);
+
+    pub const fn new(pan: i8) -> Self {
+        Self(Int::new(pan as i32))
+    }
+
+    pub const fn get(self) -> i8 {
+        self.0.get() as i8
+    }
+
+    /// Returns the gains of the left and right outputs.
+    ///
+    /// The centered pan (`0`) outputs the full level to both sides, and the other side is attenuated linearly
+    /// as the pan moves away from the center (e.g., `-15` only outputs to the left).
+    pub fn gains(self) -> [f32; 2] {
+        let pan = f32::from(self.get()) / 15.0;
+        [(1.0 - pan).min(1.0), (1.0 + pan).min(1.0)]
+    }
+}
+
 #[derive(Debug, Clone, Copy, Span, Parse)]
 pub struct Tempo(Int<1, 255>);
 
@@ -418,6 +495,10 @@ impl Default for Tempo {
 pub struct Volume(Int<0, 15>);
 
 impl Volume {
+    pub const fn new(volume: u8) -> Self {
+        Self(Int::new(volume as i32))
+    }
+
     pub const fn get(self) -> u8 {
         self.0.get() as u8
     }
@@ -464,6 +545,12 @@ impl<T> LoopList<T> {
             end: Position::new(0),
         }
     }
+
+    /// Returns the number of the items and the loop point (the last item is held if `None`).
+    #[cfg(feature = "nsf")]
+    fn frames(&self) -> (usize, Option<usize>) {
+        (self.items.len(), self.loop_point)
+    }
 }
 
 impl<T: Parse> Parse for LoopList<T> {
@@ -533,6 +620,13 @@ impl VolumeEnvelope {
     }
 }
 
+#[cfg(feature = "nsf")]
+impl VolumeEnvelope {
+    pub fn frames(&self) -> (usize, Option<usize>) {
+        self.envelope.frames()
+    }
+}
+
 impl NthFrameItem for VolumeEnvelope {
     type Item = Volume;
 
@@ -554,6 +648,13 @@ impl PitchEnvelope {
     }
 }
 
+#[cfg(feature = "nsf")]
+impl PitchEnvelope {
+    pub fn frames(&self) -> (usize, Option<usize>) {
+        self.envelope.frames()
+    }
+}
+
 impl NthFrameItem for PitchEnvelope {
     type Item = Detune;
 
@@ -567,6 +668,13 @@ pub struct NoteEnvelope {
     envelope: LoopList<Int<-128, 127>>,
 }
 
+#[cfg(feature = "nsf")]
+impl NoteEnvelope {
+    pub fn frames(&self) -> (usize, Option<usize>) {
+        self.envelope.frames()
+    }
+}
+
 impl NthFrameItem for NoteEnvelope {
     type Item = i8;
 
@@ -592,6 +700,17 @@ impl Timbres {
             list: LoopList::constant(timbre),
         }
     }
+
+    pub fn items(&self) -> &[Timbre] {
+        &self.list.items
+    }
+}
+
+#[cfg(feature = "nsf")]
+impl Timbres {
+    pub fn frames(&self) -> (usize, Option<usize>) {
+        self.list.frames()
+    }
 }
 
 impl NthFrameItem for Timbres {
@@ -602,6 +721,48 @@ impl NthFrameItem for Timbres {
     }
 }
 
+/// Samples of a wavetable (`{ <SAMPLE> <SAMPLE> ... }`).
+///
+/// The number of samples is checked by the player because it depends on the sound chip.
+#[derive(Debug, Clone, Span)]
+pub struct Wave<const MAX: i32> {
+    start: Position,
+    samples: Vec<u8>,
+    end: Position,
+}
+
+impl<const MAX: i32> Wave<MAX> {
+    pub fn samples(&self) -> &[u8] {
+        &self.samples
+    }
+}
+
+impl<const MAX: i32> Parse for Wave<MAX> {
+    fn parse(parser: &mut Parser) -> Option<Self> {
+        let start = parser.current_position();
+        let _: Char<'{'> = parser.parse()?;
+
+        let mut samples = Vec::new();
+        loop {
+            let _: CommentsOrWhitespaces = parser.parse()?;
+            samples.push(parser.parse::<Int<0, MAX>>()?.get() as u8);
+
+            let _: CommentsOrWhitespaces = parser.parse()?;
+            let _: Maybe<Char<','>> = parser.parse()?;
+            let _: CommentsOrWhitespaces = parser.parse()?;
+            if parser.parse::<Char<'}'>>().is_some() {
+                break;
+            }
+        }
+        let end = parser.current_position();
+        Some(Self {
+            start,
+            samples,
+            end,
+        })
+    }
+}
+
 #[derive(Debug, Clone, Span, Parse)]
 pub struct Vibrato {
     _open: Char<'{'>,
@@ -660,15 +821,117 @@ impl PitchSweep {
     }
 }
 
+#[derive(Debug, Clone, Span, Parse)]
+pub struct DpcmSample {
+    _open: Char<'{'>,
+    _space0: CommentsOrWhitespaces,
+    path: QuotedString,
+    _space1: CommentsOrWhitespaces,
+    _comma: Char<','>,
+    _space2: CommentsOrWhitespaces,
+    pitch: Int<0, 15>,
+    _space3: CommentsOrWhitespaces,
+    _close: Char<'}'>,
+}
+
+impl DpcmSample {
+    pub fn path(&self) -> &str {
+        self.path.get()
+    }
+
+    pub fn pitch(&self) -> u8 {
+        self.pitch.get() as u8
+    }
+}
+
 #[derive(Debug, Clone, Copy, Span, Parse)]
-pub struct OscillatorKind(Int<0, 2>);
+pub struct OscillatorKind(Int<0, 11>);
 
 impl OscillatorKind {
     pub const PULSE_WAVE: u8 = 0;
     pub const TRIANGLE_WAVE: u8 = 1;
     pub const NOISE: u8 = 2;
+    pub const DPCM: u8 = 3;
+    pub const VRC6_PULSE: u8 = 4;
+    pub const VRC6_SAWTOOTH: u8 = 5;
+    pub const FDS: u8 = 6;
+    pub const N163: u8 = 7;
+    pub const SUNSOFT_5B: u8 = 8;
+    pub const VRC7: u8 = 9;
+    pub const MMC5_PULSE: u8 = 10;
+    pub const MMC5_PCM: u8 = 11;
 
     pub const fn get(self) -> u8 {
         self.0.get() as u8
     }
 }
+
+/// TV system of the console, which is selected by `#REGION NTSC|PAL|DENDY` in the script.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Region {
+    /// NTSC (60 Hz).
+    #[default]
+    Ntsc,
+
+    /// PAL (50 Hz).
+    Pal,
+
+    /// Dendy (50 Hz, a PAL clone that uses the NTSC APU tables).
+    Dendy,
+}
+
+impl Region {
+    /// Returns the CPU clock frequency (Hz).
+    pub const fn cpu_clock_hz(self) -> f32 {
+        match self {
+            Region::Ntsc => 21477272.7272 / 12.0,
+            Region::Pal => 26601712.0 / 16.0,
+            Region::Dendy => 26601712.0 / 15.0,
+        }
+    }
+
+    /// Returns the frame rate (Hz) that drives envelopes, `@q` and vibratos.
+    pub const fn frame_rate(self) -> u8 {
+        match self {
+            Region::Ntsc => 60,
+            Region::Pal | Region::Dendy => 50,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Span)]
+pub struct QuotedString {
+    start: Position,
+    value: String,
+    end: Position,
+}
+
+impl QuotedString {
+    pub fn get(&self) -> &str {
+        &self.value
+    }
+}
+
+impl Parse for QuotedString {
+    fn parse(parser: &mut Parser) -> Option<Self> {
+        let start = parser.current_position();
+        let _: Char<'"'> = parser.parse()?;
+        let mut value = String::new();
+        loop {
+            match parser.read_char()? {
+                '"' => break,
+                '\n' => return None,
+                c => value.push(c),
+            }
+        }
+        if value.is_empty() {
+            return None;
+        }
+        let end = parser.current_position();
+        Some(Self { start, value, end })
+    }
+
+    fn name() -> Option<fn() -> String> {
+        Some(|| "a quoted string".to_owned())
+    }
+}
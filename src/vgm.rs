@@ -0,0 +1,327 @@
+//! VGM: Video Game Music (version 1.61, NES APU).
+//!
+//! The generated VGM file holds the APU register writes (`$4000`..=`$4017`) of each frame (60 Hz, or 50 Hz for PAL and Dendy).
+use crate::{
+    registers::{RegisterRecorder, Slot, DMC_START, DMC_STOP, DPCM_START},
+    Music, PlayMusicError, Region,
+};
+use std::{io::Write, time::Duration};
+
+const VERSION: u32 = 0x161;
+const HEADER_SIZE: usize = 0x100;
+const SAMPLE_RATE: u32 = 44100;
+
+const CMD_NES_APU_WRITE: u8 = 0xB4;
+const CMD_WAIT: u8 = 0x61;
+const CMD_WAIT_NTSC_FRAME: u8 = 0x62; // 735 samples
+const CMD_WAIT_PAL_FRAME: u8 = 0x63; // 882 samples
+const CMD_END: u8 = 0x66;
+const CMD_DATA_BLOCK: u8 = 0x67;
+const DATA_BLOCK_NES_APU_RAM: u8 = 0xC2;
+
+/// [`Vgm`] options.
+#[derive(Debug, Clone)]
+pub struct VgmOptions {
+    /// Maximum duration.
+    ///
+    /// If the length of the target music (excluding infinite loops) exceeds this limit,
+    /// the exceeded part will exclude from the output VGM file.
+    ///
+    /// The default value is `Duration::from_secs(600)`.
+    pub max_duration: Duration,
+}
+
+impl VgmOptions {
+    fn max_frames(&self, frame_rate: u8) -> usize {
+        (self.max_duration.as_secs_f64() * f64::from(frame_rate)).floor() as usize
+    }
+}
+
+impl Default for VgmOptions {
+    fn default() -> Self {
+        Self {
+            max_duration: Duration::from_secs(600),
+        }
+    }
+}
+
+/// [`Vgm`] provides a feature to export APU register writes as VGM format.
+///
+/// The channels of the music are assigned to the APU channels by their oscillators
+/// (up to two pulse wave channels, one triangle wave channel, one noise channel and one DPCM channel).
+///
+/// If some channels have loop points (`L`), the loop of the VGM file starts at the last one
+/// and ends when the channel of that loop point returns to it.
+#[derive(Debug)]
+pub struct Vgm {
+    region: Region,
+    title: String,
+    author: String,
+    converter: String,
+    commands: Vec<u8>,
+    total_frames: u32,
+    loop_start: Option<(usize, u32)>, // (Command offset, Frame)
+}
+
+impl Vgm {
+    /// Makes a [`Vgm`] instance with the default options.
+    pub fn new(music: &Music) -> Result<Self, PlayMusicError> {
+        Self::with_options(music, Default::default())
+    }
+
+    /// Makes a [`Vgm`] instance.
+    pub fn with_options(music: &Music, options: VgmOptions) -> Result<Self, PlayMusicError> {
+        let mut recorder = RegisterRecorder::new(music, "VGM")?;
+        let region = music.region();
+        let samples_per_frame = samples_per_frame(region);
+
+        let mut commands = Vec::new();
+        let samples = &recorder.samples().data;
+        if !samples.is_empty() {
+            commands.extend_from_slice(&[CMD_DATA_BLOCK, 0x66, DATA_BLOCK_NES_APU_RAM]);
+            commands.extend_from_slice(&(samples.len() as u32 + 2).to_le_bytes());
+            commands.extend_from_slice(&DPCM_START.to_le_bytes());
+            commands.extend_from_slice(samples);
+        }
+        write_register(&mut commands, 0x4015, 0x0F);
+        write_register(&mut commands, 0x4017, 0x40);
+
+        let mut registers = [[None; 4]; 5];
+        let mut looping_slots = Vec::new();
+        let mut loop_slot = None;
+        let mut loop_start = None;
+        let mut total_frames = 0;
+        for frame in 0..options.max_frames(region.frame_rate()) as u32 {
+            let channels = recorder.next_frame()?;
+            if channels
+                .iter()
+                .any(|c| Some(c.slot) == loop_slot && c.loop_count > 0)
+            {
+                break;
+            }
+
+            let mut force = frame == 0;
+            for c in &channels {
+                if c.is_looping && !looping_slots.contains(&c.slot) {
+                    looping_slots.push(c.slot);
+                    if c.loop_count == 0 {
+                        loop_slot = Some(c.slot);
+                        loop_start = Some((commands.len(), frame));
+                        force = true;
+                    }
+                }
+            }
+
+            for c in &channels {
+                let prev = &mut registers[usize::from(c.slot.index())];
+                let mut dmc_control = None;
+                for (i, value) in c.registers.into_iter().enumerate() {
+                    let Some(value) = value else {
+                        continue;
+                    };
+                    if c.slot == Slot::Dmc && i == 1 {
+                        dmc_control = Some(value);
+                    } else if force || (i == 3 && c.retrigger) || prev[i] != Some(value) {
+                        write_register(&mut commands, c.slot.base_address() + i as u16, value);
+                        prev[i] = Some(value);
+                    }
+                }
+                if let Some(value) = dmc_control {
+                    write_register(&mut commands, 0x4015, DMC_STOP);
+                    if value == DMC_START {
+                        write_register(&mut commands, 0x4015, DMC_START);
+                    }
+                }
+            }
+            write_wait(&mut commands, samples_per_frame);
+            total_frames += 1;
+
+            if channels.iter().all(|c| c.is_eos) {
+                break;
+            }
+        }
+        commands.push(CMD_END);
+
+        Ok(Self {
+            region,
+            title: music.title().unwrap_or_default().to_owned(),
+            author: music.composer().unwrap_or_default().to_owned(),
+            converter: music.programer().unwrap_or_default().to_owned(),
+            commands,
+            total_frames,
+            loop_start,
+        })
+    }
+
+    /// Exports this music as VGM into the writer.
+    pub fn to_writer<W: Write>(&self, mut writer: W) -> std::io::Result<()> {
+        let gd3 = self.gd3();
+        let frame_rate = u32::from(self.region.frame_rate());
+        let samples_per_frame = u32::from(samples_per_frame(self.region));
+        let gd3_offset = HEADER_SIZE + self.commands.len();
+        let file_len = gd3_offset + gd3.len();
+
+        let mut header = [0; HEADER_SIZE];
+        let mut put = |offset: usize, value: u32| {
+            header[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+        };
+        put(0x00, u32::from_le_bytes(*b"Vgm "));
+        put(0x04, (file_len - 0x04) as u32); // EoF Offset
+        put(0x08, VERSION);
+        put(0x14, (gd3_offset - 0x14) as u32); // GD3 Offset
+        put(0x18, self.total_frames * samples_per_frame); // Total Samples
+        if let Some((offset, frame)) = self.loop_start {
+            put(0x1C, (HEADER_SIZE + offset - 0x1C) as u32); // Loop Offset
+            put(0x20, (self.total_frames - frame) * samples_per_frame); // Loop Samples
+        }
+        put(0x24, frame_rate); // Rate
+        put(0x34, (HEADER_SIZE - 0x34) as u32); // VGM Data Offset
+        put(0x84, self.region.cpu_clock_hz().round() as u32); // NES APU Clock
+
+        writer.write_all(&header)?;
+        writer.write_all(&self.commands)?;
+        writer.write_all(&gd3)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    fn gd3(&self) -> Vec<u8> {
+        let strings = [
+            self.title.as_str(), // Track Name (English)
+            "",                  // Track Name (Japanese)
+            "",                  // Game Name (English)
+            "",                  // Game Name (Japanese)
+            "Nintendo Entertainment System",
+            "", // System Name (Japanese)
+            self.author.as_str(),
+            "", // Author (Japanese)
+            "", // Release Date
+            self.converter.as_str(),
+            "", // Notes
+        ];
+        let mut body = Vec::new();
+        for s in strings {
+            for c in s.encode_utf16().chain(std::iter::once(0)) {
+                body.extend_from_slice(&c.to_le_bytes());
+            }
+        }
+
+        let mut gd3 = Vec::new();
+        gd3.extend_from_slice(b"Gd3 ");
+        gd3.extend_from_slice(&0x100u32.to_le_bytes()); // Version
+        gd3.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        gd3.extend_from_slice(&body);
+        gd3
+    }
+}
+
+fn write_register(commands: &mut Vec<u8>, address: u16, value: u8) {
+    commands.extend_from_slice(&[CMD_NES_APU_WRITE, (address - 0x4000) as u8, value]);
+}
+
+fn samples_per_frame(region: Region) -> u16 {
+    (SAMPLE_RATE / u32::from(region.frame_rate())) as u16
+}
+
+fn write_wait(commands: &mut Vec<u8>, samples: u16) {
+    match samples {
+        735 => commands.push(CMD_WAIT_NTSC_FRAME),
+        882 => commands.push(CMD_WAIT_PAL_FRAME),
+        _ => {
+            commands.push(CMD_WAIT);
+            commands.extend_from_slice(&samples.to_le_bytes());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn loop_offset_points_to_loop_start() {
+        let music =
+            Music::new("#CHANNEL A 0\n#CHANNEL C 1\nA t120 l4 c d L e f\nC t120 l4 c d e L f")
+                .unwrap_or_else(|e| panic!("{e}"));
+        let vgm = parse(&music);
+        assert_eq!(&vgm[0x00..0x04], b"Vgm ");
+        assert_eq!(read_u32(&vgm, 0x08), VERSION);
+        assert_eq!(read_u32(&vgm, 0x04) as usize + 0x04, vgm.len());
+
+        // The loop starts at the last loop point (channel C, 1.5 seconds)
+        // and ends when the channel C returns to it (0.5 seconds later).
+        let loop_offset = read_u32(&vgm, 0x1C) as usize + 0x1C;
+        let waits = waits(&vgm);
+        let loop_start = waits
+            .iter()
+            .find(|&&(offset, _)| offset >= loop_offset)
+            .expect("loop start");
+        assert_eq!(loop_start, &(loop_offset, 90 * 735));
+        assert_eq!(read_u32(&vgm, 0x18), 120 * 735); // Total Samples
+        assert_eq!(read_u32(&vgm, 0x20), 30 * 735); // Loop Samples
+    }
+
+    #[test]
+    fn waits_sum_to_total_samples() {
+        for (region, samples_per_frame) in [("NTSC", 735), ("PAL", 882), ("DENDY", 882)] {
+            let music = Music::new(&format!("#REGION {region}\nA t120 l4 c d e f"))
+                .unwrap_or_else(|e| panic!("{e}"));
+            let vgm = parse(&music);
+            let (_, samples) = *waits(&vgm).last().expect("end");
+            assert_eq!(samples, read_u32(&vgm, 0x18), "{region}"); // Total Samples
+
+            // 2 seconds and the frame where the channel ends.
+            let frames = 2 * u32::from(music.region().frame_rate()) + 1;
+            assert_eq!(samples, frames * samples_per_frame, "{region}");
+        }
+    }
+
+    #[test]
+    fn no_loop_offset_without_loop_point() {
+        let music = Music::new("A t120 l4 c d e f").unwrap_or_else(|e| panic!("{e}"));
+        let vgm = parse(&music);
+        assert_eq!(read_u32(&vgm, 0x1C), 0);
+        assert_eq!(read_u32(&vgm, 0x20), 0);
+    }
+
+    fn parse(music: &Music) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        Vgm::new(music)
+            .unwrap_or_else(|e| panic!("{e}"))
+            .to_writer(&mut bytes)
+            .expect("write to vec");
+        bytes
+    }
+
+    fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(bytes[offset..offset + 4].try_into().expect("4 bytes"))
+    }
+
+    /// Returns the offset of each command and the samples waited before the command.
+    fn waits(vgm: &[u8]) -> Vec<(usize, u32)> {
+        let mut offset = read_u32(vgm, 0x34) as usize + 0x34;
+        let mut samples = 0;
+        let mut waits = Vec::new();
+        loop {
+            waits.push((offset, samples));
+            match vgm[offset] {
+                CMD_NES_APU_WRITE => offset += 3,
+                CMD_WAIT => {
+                    samples += u32::from(u16::from_le_bytes([vgm[offset + 1], vgm[offset + 2]]));
+                    offset += 3;
+                }
+                CMD_WAIT_NTSC_FRAME => {
+                    samples += 735;
+                    offset += 1;
+                }
+                CMD_WAIT_PAL_FRAME => {
+                    samples += 882;
+                    offset += 1;
+                }
+                CMD_DATA_BLOCK => offset += 7 + read_u32(vgm, offset + 3) as usize,
+                CMD_END => return waits,
+                command => panic!("unexpected command {command:#04X} at {offset:#X}"),
+            }
+        }
+    }
+}
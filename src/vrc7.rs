@@ -0,0 +1,375 @@
+//! VRC7: the 2-operator FM synthesis (a derivative of the YM2413 OPLL) of Konami VRC7.
+use std::{
+    f32::consts::PI,
+    sync::{Arc, Mutex},
+};
+
+/// Maximum number of channels.
+pub const MAX_CHANNELS: usize = 6;
+
+/// Number of samples generated by the chip per second (3.579545 MHz / 72, independent of the region).
+pub const RATE: f64 = 3_579_545.0 / 72.0;
+
+/// Built-in patches (instrument `1..=15`, `0` is the user patch) dumped from the chip.
+pub const PATCHES: [[u8; 8]; 16] = [
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+    [0x03, 0x21, 0x05, 0x06, 0xE8, 0x81, 0x42, 0x27],
+    [0x13, 0x41, 0x14, 0x0D, 0xD8, 0xF6, 0x23, 0x12],
+    [0x11, 0x11, 0x08, 0x08, 0xFA, 0xB2, 0x20, 0x12],
+    [0x31, 0x61, 0x0C, 0x07, 0xA8, 0x64, 0x61, 0x27],
+    [0x32, 0x21, 0x1E, 0x06, 0xE1, 0x76, 0x01, 0x28],
+    [0x02, 0x01, 0x06, 0x00, 0xA3, 0xE2, 0xF4, 0xF4],
+    [0x21, 0x61, 0x1D, 0x07, 0x82, 0x81, 0x11, 0x07],
+    [0x23, 0x21, 0x22, 0x17, 0xA2, 0x72, 0x01, 0x17],
+    [0x35, 0x11, 0x25, 0x00, 0x40, 0x73, 0x72, 0x01],
+    [0xB5, 0x01, 0x0F, 0x0F, 0xA8, 0xA5, 0x51, 0x02],
+    [0x17, 0xC1, 0x24, 0x07, 0xF8, 0xF8, 0x22, 0x12],
+    [0x71, 0x23, 0x11, 0x06, 0x65, 0x74, 0x18, 0x16],
+    [0x01, 0x02, 0xD3, 0x05, 0xC9, 0x95, 0x03, 0x02],
+    [0x61, 0x63, 0x0C, 0x00, 0x94, 0xC0, 0x33, 0xF6],
+    [0x21, 0x72, 0x0D, 0x00, 0xC1, 0xD5, 0x56, 0x06],
+];
+
+/// Frequency multipliers (doubled).
+const MULTIPLIERS: [f32; 16] = [
+    1.0, 2.0, 4.0, 6.0, 8.0, 10.0, 12.0, 14.0, 16.0, 18.0, 20.0, 20.0, 24.0, 24.0, 30.0, 30.0,
+];
+
+/// Key scale level attenuation (dB) by the upper 4 bits of the F-number at the block `7`.
+const KEY_SCALE_LEVELS: [f32; 16] = [
+    0.0, 18.0, 24.0, 27.75, 30.0, 32.25, 33.75, 35.25, 36.0, 37.5, 38.25, 39.0, 39.75, 40.5, 41.25,
+    42.0,
+];
+
+/// Modulation index of the modulator output at the full scale (radians).
+const MODULATION_INDEX: f32 = 8.0 * PI;
+
+/// Maximum attenuation of the envelope generator (dB).
+const MAX_ATTENUATION: f32 = 48.0;
+
+/// User patch shared by all VRC7 channels of a music.
+#[derive(Debug, Clone)]
+pub struct Chip(Arc<Mutex<[u8; 8]>>);
+
+impl Chip {
+    pub fn new() -> Self {
+        Self(Arc::new(Mutex::new([0; 8])))
+    }
+
+    pub fn set_user_patch(&self, patch: [u8; 8]) {
+        *self.0.lock().unwrap_or_else(|e| e.into_inner()) = patch;
+    }
+
+    /// Returns the patch of the instrument (`0` is the user patch).
+    pub fn patch(&self, instrument: u8) -> [u8; 8] {
+        if instrument == 0 {
+            *self.0.lock().unwrap_or_else(|e| e.into_inner())
+        } else {
+            PATCHES[usize::from(instrument & 0x0F)]
+        }
+    }
+}
+
+/// Converts a frequency into the block (octave) and the 9-bit F-number.
+pub fn frequency_to_fnum(frequency: f32) -> (u8, u16) {
+    for block in 0..8 {
+        let fnum = (f64::from(frequency) * f64::from(1 << (19 - block)) / RATE).round();
+        if fnum < 512.0 {
+            return (block, fnum.max(0.0) as u16);
+        }
+    }
+    (7, 511)
+}
+
+/// Converts the block and the F-number into the frequency.
+pub fn fnum_to_frequency(block: u8, fnum: u16) -> f32 {
+    (f64::from(fnum) * RATE / f64::from(1 << (19 - u32::from(block)))) as f32
+}
+
+/// Modulator and carrier of a channel.
+#[derive(Debug, Clone, Default)]
+pub struct Voice {
+    modulator: Operator,
+    carrier: Operator,
+    feedback: [f32; 2],
+    time: f32,
+}
+
+impl Voice {
+    pub fn key_on(&mut self) {
+        self.modulator.key_on();
+        self.carrier.key_on();
+    }
+
+    pub fn key_off(&mut self) {
+        self.modulator.key_off();
+        self.carrier.key_off();
+    }
+
+    /// Advances the envelopes and the LFO by `steps` samples at [`RATE`] without generating samples.
+    ///
+    /// The phases of the operators are not kept.
+    pub fn skip(&mut self, patch: [u8; 8], block: u8, fnum: u16, sustain: bool, steps: u64) {
+        let dt = (1.0 / RATE) as f32;
+        self.time = ((f64::from(self.time) + steps as f64 / RATE) % 100.0) as f32;
+        let key = Key { block, fnum };
+        let (m, c) = (
+            OperatorPatch::modulator(patch),
+            OperatorPatch::carrier(patch),
+        );
+        for _ in 0..steps {
+            if self.modulator.is_steady(&m) && self.carrier.is_steady(&c) {
+                break;
+            }
+            self.modulator.update_envelope(&m, key, sustain, dt);
+            self.carrier.update_envelope(&c, key, sustain, dt);
+        }
+    }
+
+    /// Generates a sample (`-1.0..=1.0`) at [`RATE`] before applying the channel volume.
+    pub fn next_sample(&mut self, patch: [u8; 8], block: u8, fnum: u16, sustain: bool) -> f32 {
+        let dt = (1.0 / RATE) as f32;
+        self.time = (self.time + dt) % 100.0;
+        let lfo = Lfo {
+            // 3.7 Hz tremolo (4.8 dB) and 6.4 Hz vibrato (about 7 cents).
+            am: 4.8 * (1.0 - (2.0 * PI * 3.7 * self.time).cos()) / 2.0,
+            vib: 1.0 + 0.0041 * (2.0 * PI * 6.4 * self.time).sin(),
+        };
+        let key = Key { block, fnum };
+
+        let m = OperatorPatch::modulator(patch);
+        let feedback = match patch[3] & 0b111 {
+            0 => 0.0,
+            fb => (self.feedback[0] + self.feedback[1]) / 2.0 * PI / 16.0 * (1 << (fb - 1)) as f32,
+        };
+        let total_level = f32::from(patch[2] & 0x3F) * 0.75;
+        let modulation =
+            self.modulator
+                .next_sample(&m, key, lfo, sustain, feedback, total_level, dt);
+        self.feedback = [self.feedback[1], modulation];
+
+        let c = OperatorPatch::carrier(patch);
+        self.carrier.next_sample(
+            &c,
+            key,
+            lfo,
+            sustain,
+            modulation * MODULATION_INDEX,
+            0.0,
+            dt,
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Key {
+    block: u8,
+    fnum: u16,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Lfo {
+    am: f32,
+    vib: f32,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct OperatorPatch {
+    am: bool,
+    vib: bool,
+    sustained: bool,
+    ksr: bool,
+    multiplier: f32,
+    ksl: u8,
+    rectified: bool,
+    attack_rate: u8,
+    decay_rate: u8,
+    sustain_level: u8,
+    release_rate: u8,
+}
+
+impl OperatorPatch {
+    fn new(patch: [u8; 8], i: usize, ksl: u8, rectified: bool) -> Self {
+        Self {
+            am: patch[i] & 0x80 != 0,
+            vib: patch[i] & 0x40 != 0,
+            sustained: patch[i] & 0x20 != 0,
+            ksr: patch[i] & 0x10 != 0,
+            multiplier: MULTIPLIERS[usize::from(patch[i] & 0x0F)] / 2.0,
+            ksl,
+            rectified,
+            attack_rate: patch[4 + i] >> 4,
+            decay_rate: patch[4 + i] & 0x0F,
+            sustain_level: patch[6 + i] >> 4,
+            release_rate: patch[6 + i] & 0x0F,
+        }
+    }
+
+    fn modulator(patch: [u8; 8]) -> Self {
+        Self::new(patch, 0, patch[2] >> 6, patch[3] & 0x08 != 0)
+    }
+
+    fn carrier(patch: [u8; 8]) -> Self {
+        Self::new(patch, 1, patch[3] >> 6, patch[3] & 0x10 != 0)
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+enum EnvelopeState {
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+    #[default]
+    Off,
+}
+
+#[derive(Debug, Clone)]
+struct Operator {
+    phase: f32,
+    state: EnvelopeState,
+    attenuation: f32,
+}
+
+impl Default for Operator {
+    fn default() -> Self {
+        Self {
+            phase: 0.0,
+            state: EnvelopeState::Off,
+            attenuation: MAX_ATTENUATION,
+        }
+    }
+}
+
+impl Operator {
+    fn key_on(&mut self) {
+        self.phase = 0.0;
+        self.state = EnvelopeState::Attack;
+    }
+
+    fn key_off(&mut self) {
+        if self.state != EnvelopeState::Off {
+            self.state = EnvelopeState::Release;
+        }
+    }
+
+    /// Returns `true` if the envelope no longer changes until the key is changed.
+    fn is_steady(&self, patch: &OperatorPatch) -> bool {
+        match self.state {
+            EnvelopeState::Off => true,
+            EnvelopeState::Sustain => patch.sustained,
+            _ => false,
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn next_sample(
+        &mut self,
+        patch: &OperatorPatch,
+        key: Key,
+        lfo: Lfo,
+        sustain: bool,
+        modulation: f32,
+        total_level: f32,
+        dt: f32,
+    ) -> f32 {
+        self.update_envelope(patch, key, sustain, dt);
+        if self.state == EnvelopeState::Off {
+            return 0.0;
+        }
+
+        let mut increment =
+            f32::from(key.fnum) * f32::from(1u16 << key.block) / 524288.0 * patch.multiplier;
+        if patch.vib {
+            increment *= lfo.vib;
+        }
+        self.phase = (self.phase + increment) % 1.0;
+
+        let mut wave = (2.0 * PI * self.phase + modulation).sin();
+        if patch.rectified && wave < 0.0 {
+            wave = 0.0;
+        }
+
+        let key_scale = (KEY_SCALE_LEVELS[usize::from(key.fnum >> 5)]
+            - 6.0 * f32::from(7 - key.block))
+        .max(0.0)
+            * [0.0, 0.25, 0.5, 1.0][usize::from(patch.ksl)];
+        let am = if patch.am { lfo.am } else { 0.0 };
+        let attenuation = self.attenuation + total_level + key_scale + am;
+        wave * 10f32.powf(-attenuation / 20.0)
+    }
+
+    fn update_envelope(&mut self, patch: &OperatorPatch, key: Key, sustain: bool, dt: f32) {
+        let key_scale = (key.block << 1) | (key.fnum >> 8) as u8;
+        let key_scale = if patch.ksr { key_scale } else { key_scale >> 2 };
+        let rate = |r: u8| {
+            if r == 0 {
+                0
+            } else {
+                (4 * r + key_scale).min(63)
+            }
+        };
+        match self.state {
+            EnvelopeState::Attack => {
+                let rate = rate(patch.attack_rate);
+                if rate >= 60 {
+                    self.attenuation = 0.0;
+                } else if rate > 0 {
+                    // Approaches 0 dB exponentially (from the max attenuation in about the attack time).
+                    let time = 2.826 / rate_factor(rate);
+                    let k = 65f32.ln() / time;
+                    self.attenuation = (self.attenuation + 0.75) * (-k * dt).exp() - 0.75;
+                }
+                if self.attenuation <= 0.0 {
+                    self.attenuation = 0.0;
+                    self.state = EnvelopeState::Decay;
+                }
+            }
+            EnvelopeState::Decay => {
+                let level = f32::from(patch.sustain_level) * 3.0;
+                self.attenuation += decay_slope(rate(patch.decay_rate)) * dt;
+                if self.attenuation >= level {
+                    self.attenuation = level;
+                    self.state = EnvelopeState::Sustain;
+                }
+            }
+            EnvelopeState::Sustain => {
+                // Percussive tones keep decaying while the key is on.
+                if !patch.sustained {
+                    self.attenuation += decay_slope(rate(patch.release_rate)) * dt;
+                }
+            }
+            EnvelopeState::Release => {
+                let release_rate = if sustain {
+                    5
+                } else if patch.sustained {
+                    patch.release_rate
+                } else {
+                    7
+                };
+                self.attenuation += decay_slope(rate(release_rate)) * dt;
+            }
+            EnvelopeState::Off => {}
+        }
+        if self.attenuation >= MAX_ATTENUATION {
+            self.attenuation = MAX_ATTENUATION;
+            if self.state != EnvelopeState::Attack {
+                self.state = EnvelopeState::Off;
+            }
+        }
+    }
+}
+
+// Each rate step of 4 doubles the speed, and the lower 2 bits add quarters.
+fn rate_factor(rate: u8) -> f32 {
+    2f32.powi(i32::from(rate / 4) - 1) * (1.0 + f32::from(rate % 4) / 4.0)
+}
+
+/// Returns the decay speed (dB per second) of the effective rate.
+fn decay_slope(rate: u8) -> f32 {
+    if rate == 0 {
+        0.0
+    } else {
+        96.0 / 39.28 * rate_factor(rate)
+    }
+}
@@ -1,7 +1,7 @@
 //! WAV: RIFF waveform Audio Format.
-use crate::{Music, PlayMusicError};
+use crate::{ChannelName, MixerMode, Music, PlayMusicError, StereoLayout, Synthesis};
 use byteorder::{LittleEndian, WriteBytesExt};
-use std::{io::Write, time::Duration};
+use std::{collections::BTreeMap, io::Write, time::Duration};
 
 /// [`Wav`] options.
 #[derive(Debug, Clone)]
@@ -18,6 +18,40 @@ pub struct WavOptions {
     ///
     /// The default value is `Duration::from_secs(60)`.
     pub max_duration: Duration,
+
+    /// Waveform synthesis mode of the pulse wave and triangle wave channels.
+    ///
+    /// The default value is [`Synthesis::Naive`].
+    pub synthesis: Synthesis,
+
+    /// Channel mixer.
+    ///
+    /// The default value is [`MixerMode::Linear`].
+    pub mixer_mode: MixerMode,
+
+    /// Default panning of the channels in stereo output.
+    ///
+    /// If this is `Some`, the output WAV has two channels (see [`MusicPlayer::next_stereo()`]), otherwise one.
+    ///
+    /// The default value is `None`.
+    ///
+    /// [`MusicPlayer::next_stereo()`]: crate::MusicPlayer::next_stereo
+    pub stereo_layout: Option<StereoLayout>,
+
+    /// Number of times to jump back to the loop points (`L`) before the music ends.
+    ///
+    /// If this is `None`, looping music is played until [`WavOptions::max_duration`]
+    /// (see [`MusicPlayer::set_loop_count()`]).
+    ///
+    /// The default value is `None`.
+    ///
+    /// [`MusicPlayer::set_loop_count()`]: crate::MusicPlayer::set_loop_count
+    pub loop_count: Option<usize>,
+
+    /// Length of the fade-out after the final loop (see [`WavOptions::loop_count`]).
+    ///
+    /// The default value is `Duration::ZERO`.
+    pub fade_out: Duration,
 }
 
 impl WavOptions {
@@ -31,6 +65,11 @@ impl Default for WavOptions {
         Self {
             sample_rate: 48000,
             max_duration: Duration::from_secs(60),
+            synthesis: Synthesis::default(),
+            mixer_mode: MixerMode::default(),
+            stereo_layout: None,
+            loop_count: None,
+            fade_out: Duration::ZERO,
         }
     }
 }
@@ -39,6 +78,7 @@ impl Default for WavOptions {
 #[derive(Debug)]
 pub struct Wav {
     sample_rate: u32,
+    channels: u16,
     samples: Vec<i16>,
 }
 
@@ -51,7 +91,92 @@ impl Wav {
     /// Makes a [`Wav`] instance.
     pub fn with_options(music: &Music, options: WavOptions) -> Result<Self, PlayMusicError> {
         let mut player = music.play(options.sample_rate);
+        player.set_synthesis(options.synthesis);
+        player.set_mixer_mode(options.mixer_mode);
+        player.set_loop_count(options.loop_count)?;
+        player.set_fade_out(options.fade_out);
+        let (channels, samples) = if let Some(layout) = options.stereo_layout {
+            player.set_stereo_layout(layout);
+            let samples = std::iter::from_fn(|| player.next_stereo())
+                .take(options.max_samples())
+                .flat_map(|s| s.map(|s| s.to_i16()))
+                .collect::<Vec<_>>();
+            (2, samples)
+        } else {
+            let samples = (&mut player)
+                .take(options.max_samples())
+                .map(|s| s.to_i16())
+                .collect::<Vec<_>>();
+            (1, samples)
+        };
+        if let Some(e) = player.take_last_error() {
+            return Err(e);
+        }
+        Ok(Self {
+            sample_rate: u32::from(options.sample_rate),
+            channels,
+            samples,
+        })
+    }
+
+    /// Makes a [`Wav`] instance for each channel of the music (see [`MusicPlayer::next_stems()`]).
+    ///
+    /// [`WavOptions::mixer_mode`] and [`WavOptions::stereo_layout`] are ignored because the channels are not mixed
+    /// (the stems sum up to the [`MixerMode::Linear`] mix multiplied by the number of channels).
+    /// Each stem still reflects the state shared with the other channels of the same sound chip
+    /// (the N163 multiplexing, and the Sunsoft 5B noise and envelope generators).
+    ///
+    /// [`MusicPlayer::next_stems()`]: crate::MusicPlayer::next_stems
+    pub fn stems(
+        music: &Music,
+        options: WavOptions,
+    ) -> Result<BTreeMap<ChannelName, Self>, PlayMusicError> {
+        let mut player = music.play(options.sample_rate);
+        player.set_synthesis(options.synthesis);
+        player.set_loop_count(options.loop_count)?;
+        player.set_fade_out(options.fade_out);
+        let mut stems = player
+            .channels()
+            .map(|c| (c.channel_name(), Vec::new()))
+            .collect::<BTreeMap<_, _>>();
+        for _ in 0..options.max_samples() {
+            let Some(samples) = player.next_stems() else {
+                break;
+            };
+            for (name, sample) in samples {
+                stems.entry(name).or_default().push(sample.to_i16());
+            }
+        }
+        if let Some(e) = player.take_last_error() {
+            return Err(e);
+        }
+        Ok(stems
+            .into_iter()
+            .map(|(name, samples)| {
+                let wav = Self {
+                    sample_rate: u32::from(options.sample_rate),
+                    channels: 1,
+                    samples,
+                };
+                (name, wav)
+            })
+            .collect())
+    }
+
+    /// Makes a [`Wav`] instance whose audio samples are generated by the cycle-accurate APU emulator ([`ApuPlayer`](crate::apu::ApuPlayer)).
+    ///
+    /// [`WavOptions::synthesis`], [`WavOptions::mixer_mode`] and [`WavOptions::stereo_layout`] are ignored
+    /// because the emulator has its own waveforms and (mono) mixer.
+    #[cfg(feature = "apu")]
+    pub fn with_apu(music: &Music, options: WavOptions) -> Result<Self, PlayMusicError> {
+        let mut player = crate::apu::ApuPlayer::new(music, options.sample_rate)?;
+        let mut fade_out = crate::player::FadeOut::new(options.sample_rate);
+        if let Some(n) = options.loop_count {
+            fade_out.set_start(Some(music.analyze()?.duration_with_loops(n)));
+        }
+        fade_out.set_length(options.fade_out);
         let samples = (&mut player)
+            .map_while(|s| Some(s * fade_out.next_gain()?))
             .take(options.max_samples())
             .map(|s| s.to_i16())
             .collect::<Vec<_>>();
@@ -60,6 +185,7 @@ impl Wav {
         }
         Ok(Self {
             sample_rate: u32::from(options.sample_rate),
+            channels: 1,
             samples,
         })
     }
@@ -88,14 +214,18 @@ impl Wav {
         writer.write_all(b"fmt ")?;
         writer.write_u32::<LittleEndian>(16)?; // Chunk Size
         writer.write_u16::<LittleEndian>(1)?; // Format: 1=Linear PCM
-        writer.write_u16::<LittleEndian>(1)?; // Channels
+        writer.write_u16::<LittleEndian>(self.channels)?; // Channels
         writer.write_u32::<LittleEndian>(self.sample_rate)?;
-        writer.write_u32::<LittleEndian>(self.sample_rate * 2)?; // Bytes per Second
-        writer.write_u16::<LittleEndian>(2)?; // Block Size
+        writer.write_u32::<LittleEndian>(self.sample_rate * u32::from(self.block_size()))?; // Bytes per Second
+        writer.write_u16::<LittleEndian>(self.block_size())?; // Block Size
         writer.write_u16::<LittleEndian>(16)?; // Bits per Sample
         Ok(())
     }
 
+    fn block_size(&self) -> u16 {
+        self.channels * 2
+    }
+
     fn write_data_chunk<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
         writer.write_all(b"data")?;
         writer.write_u32::<LittleEndian>((self.samples.len() * 2) as u32)?;
@@ -0,0 +1,126 @@
+//! Measures aliasing of rendered pulse and triangle wave sweeps.
+use ffmml::{Music, Synthesis};
+
+const SAMPLE_RATE: u16 = 44100;
+const FFT_SIZE: usize = 32768;
+
+/// (Note, MIDI note number) of the sweep (each note lasts one second).
+const SWEEP: [(&str, i32); 8] = [
+    ("o6 c", 84),
+    ("e", 88),
+    ("g", 91),
+    ("> c", 96),
+    ("e", 100),
+    ("g", 103),
+    ("a", 105),
+    ("b", 107),
+];
+
+#[test]
+fn band_limited_pulse_wave_reduces_aliasing() {
+    check_sweep('A', 0);
+}
+
+#[test]
+fn band_limited_triangle_wave_reduces_aliasing() {
+    // The triangle wave sounds an octave lower than the pulse wave.
+    check_sweep('C', -12);
+}
+
+fn check_sweep(channel: char, transpose: i32) {
+    let naive = render_sweep(channel, Synthesis::Naive);
+    let band_limited = render_sweep(channel, Synthesis::BandLimited);
+    for (i, &(note, key)) in SWEEP.iter().enumerate() {
+        let frequency = 440.0 * 2f64.powf(f64::from(key + transpose - 69) / 12.0);
+        let naive = alias_ratio(&naive[i], frequency);
+        let band_limited = alias_ratio(&band_limited[i], frequency);
+        assert!(
+            band_limited < naive / 4.0,
+            "channel {channel}, {note}: naive={naive}, band_limited={band_limited}"
+        );
+    }
+}
+
+/// Renders the sweep and returns a window of each note.
+fn render_sweep(channel: char, synthesis: Synthesis) -> Vec<Vec<f64>> {
+    let notes = SWEEP.iter().map(|(n, _)| *n).collect::<Vec<_>>().join(" ");
+    let mml = format!("{channel} t240 l1 q8 {notes}");
+    let music = mml.parse::<Music>().unwrap_or_else(|e| panic!("{e}"));
+    let mut player = music.play(SAMPLE_RATE);
+    player.set_synthesis(synthesis);
+    let samples = (&mut player)
+        .map(|s| f64::from(s.get()))
+        .collect::<Vec<_>>();
+    assert!(player.take_last_error().is_none());
+
+    let note_len = usize::from(SAMPLE_RATE);
+    (0..SWEEP.len())
+        .map(|i| {
+            let start = i * note_len + (note_len - FFT_SIZE) / 2;
+            samples[start..start + FFT_SIZE].to_vec()
+        })
+        .collect()
+}
+
+/// Returns the ratio of the power that is not on the harmonics of `frequency` (i.e., aliases).
+fn alias_ratio(samples: &[f64], frequency: f64) -> f64 {
+    let bin_width = f64::from(SAMPLE_RATE) / FFT_SIZE as f64;
+    let spectrum = power_spectrum(samples);
+    let mut total = 0.0;
+    let mut alias = 0.0;
+    for (i, power) in spectrum.iter().enumerate() {
+        let f = i as f64 * bin_width;
+        let harmonic = (f / frequency).round() * frequency;
+        let is_harmonic = (f - harmonic).abs() <= bin_width * 4.0;
+        if harmonic == 0.0 && is_harmonic {
+            // DC offset.
+            continue;
+        }
+        total += power;
+        if !is_harmonic {
+            alias += power;
+        }
+    }
+    alias / total
+}
+
+/// Returns the power spectrum (`0..=FFT_SIZE / 2`) of the Hann-windowed samples.
+fn power_spectrum(samples: &[f64]) -> Vec<f64> {
+    let n = samples.len();
+    let mut re = samples
+        .iter()
+        .enumerate()
+        .map(|(i, x)| {
+            let w = 0.5 - 0.5 * (2.0 * std::f64::consts::PI * i as f64 / n as f64).cos();
+            x * w
+        })
+        .collect::<Vec<_>>();
+    let mut im = vec![0.0; n];
+
+    // Iterative radix-2 FFT.
+    let bits = n.trailing_zeros();
+    for i in 0..n {
+        let j = i.reverse_bits() >> (usize::BITS - bits);
+        if i < j {
+            re.swap(i, j);
+        }
+    }
+    let mut len = 2;
+    while len <= n {
+        let angle = -2.0 * std::f64::consts::PI / len as f64;
+        for start in (0..n).step_by(len) {
+            for k in 0..len / 2 {
+                let (sin, cos) = (angle * k as f64).sin_cos();
+                let (a, b) = (start + k, start + k + len / 2);
+                let tr = re[b] * cos - im[b] * sin;
+                let ti = re[b] * sin + im[b] * cos;
+                re[b] = re[a] - tr;
+                im[b] = im[a] - ti;
+                re[a] += tr;
+                im[a] += ti;
+            }
+        }
+        len *= 2;
+    }
+    (0..=n / 2).map(|i| re[i] * re[i] + im[i] * im[i]).collect()
+}